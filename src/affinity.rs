@@ -0,0 +1,59 @@
+use std::{io, str::FromStr};
+
+/// Cores past this can't be named.
+const MAX_CORES: usize = 128;
+
+/// CPU cores a thread may run on, from a list like `0-3,8,10-11`, e.g. to keep capture
+/// on the big cores of a big.LITTLE CPU or encoding on the other CCD of a Ryzen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreSet(u128);
+
+impl FromStr for CoreSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut mask = 0u128;
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let parse = |core: &str| {
+                core.trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|core| *core < MAX_CORES)
+                    .ok_or_else(|| format!("not a core from 0 to {}: {core}", MAX_CORES - 1))
+            };
+            let (first, last) = (parse(first)?, parse(last)?);
+            if first > last {
+                return Err(format!("backwards core range: {part}"));
+            }
+            for core in first..=last {
+                mask |= 1 << core;
+            }
+        }
+        if mask == 0 {
+            return Err(format!("no cores in {s:?}"));
+        }
+        Ok(Self(mask))
+    }
+}
+
+impl CoreSet {
+    pub fn contains(&self, core: usize) -> bool {
+        core < MAX_CORES && self.0 & (1 << core) != 0
+    }
+
+    /// Keep the calling thread on these cores, and the threads it starts from now on,
+    /// which inherit it.
+    pub fn pin_current_thread(&self) -> io::Result<()> {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for core in (0..MAX_CORES).filter(|core| self.contains(*core)) {
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+        let res =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use gstreamer::{glib, Message, MessageView};
+
+pub mod silence;
+
+/// Rate the capture graph runs at; anything else gets resampled.
+pub const CAPTURE_RATE: u32 = 48000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioSource {
+    /// Whatever is playing on the default output.
+    Desktop,
+    /// The default input device.
+    Microphone,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AudioConfig {
+    pub source: AudioSource,
+    pub rate: u32,
+    pub channels: u32,
+    /// Warn when the track stays silent for this long.
+    pub silence_after: Option<Duration>,
+    /// Noise suppression for the microphone, muting frames whose voice probability is
+    /// below this threshold, 0.0 - 1.0.
+    #[cfg(feature = "rnnoise")]
+    pub denoise_gate: Option<f32>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            source: AudioSource::Desktop,
+            rate: CAPTURE_RATE,
+            channels: 2,
+            silence_after: Some(Duration::from_secs(10)),
+            #[cfg(feature = "rnnoise")]
+            denoise_gate: None,
+        }
+    }
+}
+
+impl AudioConfig {
+    fn source_desc(&self) -> &'static str {
+        match self.source {
+            AudioSource::Desktop => "pulsesrc device=@DEFAULT_MONITOR@",
+            AudioSource::Microphone => "pulsesrc",
+        }
+    }
+
+    /// RNNoise only runs on 48 kHz float audio, so it has to sit before the resampler.
+    #[cfg(feature = "rnnoise")]
+    fn denoise_desc(&self) -> String {
+        match (self.source, self.denoise_gate) {
+            (AudioSource::Microphone, Some(threshold)) => format!(
+                " ! audioconvert ! audio/x-raw,format=F32LE ! audiornnoise voice-activity-threshold={:.2}",
+                threshold.clamp(0.0, 1.0)
+            ),
+            _ => String::new(),
+        }
+    }
+
+    #[cfg(not(feature = "rnnoise"))]
+    fn denoise_desc(&self) -> String {
+        String::new()
+    }
+
+    /// Raw audio at the requested rate/channel count. Resampling only kicks in
+    /// when the capture clock differs from the target, e.g. for 44.1 kHz RTMP ingest.
+    pub fn raw_chain(&self) -> String {
+        let level = if self.silence_after.is_some() {
+            " ! level post-messages=true interval=500000000"
+        } else {
+            ""
+        };
+        format!(
+            "{} ! audio/x-raw,rate={CAPTURE_RATE}{}{level} ! audioconvert ! audioresample quality=10 ! audio/x-raw,rate={},channels={}",
+            self.source_desc(),
+            self.denoise_desc(),
+            self.rate,
+            self.channels
+        )
+    }
+
+    /// Encoded AAC, ready for a muxer.
+    pub fn encoded_chain(&self) -> String {
+        format!("{} ! avenc_aac ! aacparse", self.raw_chain())
+    }
+}
+
+/// Extract the stream time and loudest channel RMS (dB) from a `level` element message.
+pub fn level_rms_db(msg: &Message) -> Option<(Duration, f64)> {
+    let MessageView::Element(elem) = msg.view() else {
+        return None;
+    };
+    let s = elem.structure()?;
+    if s.name() != "level" {
+        return None;
+    }
+
+    let time = s.get::<u64>("running-time").ok()?;
+    let rms = s
+        .get::<&glib::ValueArray>("rms")
+        .ok()?
+        .iter()
+        .filter_map(|v| v.get::<f64>().ok())
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    Some((Duration::from_nanos(time), rms))
+}
@@ -0,0 +1,75 @@
+use std::{fmt::Write, path::Path, time::Duration};
+
+/// Anything quieter than this counts as silence.
+const SILENCE_THRESHOLD_DB: f64 = -60.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SilenceMarker {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SilenceEvent {
+    /// Audio has been silent for longer than the configured duration, starting at this time.
+    Started(Duration),
+    /// Audio came back at this time.
+    Ended(Duration),
+}
+
+pub struct SilenceDetector {
+    min_duration: Duration,
+    quiet_since: Option<Duration>,
+    reported: bool,
+    pub markers: Vec<SilenceMarker>,
+}
+
+impl SilenceDetector {
+    pub fn new(min_duration: Duration) -> Self {
+        Self {
+            min_duration,
+            quiet_since: None,
+            reported: false,
+            markers: vec![],
+        }
+    }
+
+    /// Feed the loudest channel's RMS level at the given stream time.
+    pub fn update(&mut self, time: Duration, rms_db: f64) -> Option<SilenceEvent> {
+        if rms_db < SILENCE_THRESHOLD_DB {
+            let since = *self.quiet_since.get_or_insert(time);
+            if !self.reported && time.saturating_sub(since) >= self.min_duration {
+                self.reported = true;
+                self.markers.push(SilenceMarker {
+                    start: since,
+                    end: None,
+                });
+                return Some(SilenceEvent::Started(since));
+            }
+            return None;
+        }
+
+        self.quiet_since = None;
+        if self.reported {
+            self.reported = false;
+            if let Some(marker) = self.markers.last_mut() {
+                marker.end = Some(time);
+            }
+            return Some(SilenceEvent::Ended(time));
+        }
+        None
+    }
+
+    /// Write one `silence <start> <end>` line per marker, in seconds.
+    pub fn write_markers(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = String::new();
+        for marker in self.markers.iter() {
+            let end = marker
+                .end
+                .map(|e| format!("{:.3}", e.as_secs_f64()))
+                .unwrap_or_else(|| "-".into());
+            let _ = writeln!(out, "silence {:.3} {}", marker.start.as_secs_f64(), end);
+        }
+        std::fs::write(path, out)
+    }
+}
@@ -0,0 +1,378 @@
+use std::time::{Duration, Instant};
+
+use smithay_client_toolkit::{
+    delegate_shm,
+    shm::{Shm, ShmHandler},
+};
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_output::Transform,
+        wl_registry::{self, WlRegistry},
+        wl_shm,
+    },
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+
+use crate::{pw_capture::DrmFormat, wl_client_desktop::OutputState};
+
+use super::{
+    protocols::{
+        image_capture_source::{
+            ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+            ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+        },
+        image_copy_capture::{
+            ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1, FailureReason},
+            ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+            ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+        },
+    },
+    shm::{shm_format_to_fourcc, BufferSpec, ShmTarget},
+    CaptureBackend, CursorSwitch, FrameCallback,
+};
+
+/// 32 bit formats we can hand on, best first.
+const SHM_FORMATS: [wl_shm::Format; 4] = [
+    wl_shm::Format::Xrgb8888,
+    wl_shm::Format::Argb8888,
+    wl_shm::Format::Xbgr8888,
+    wl_shm::Format::Abgr8888,
+];
+
+#[derive(Default)]
+struct Constraints {
+    size: Option<(u32, u32)>,
+    shm_formats: Vec<wl_shm::Format>,
+}
+
+impl Constraints {
+    /// A buffer in the first offered format among `preferred` fourccs, or in the best
+    /// offered one if none of them are.
+    fn spec(&self, preferred: &[u32]) -> Option<BufferSpec> {
+        let (width, height) = self.size?;
+        let offered = SHM_FORMATS
+            .into_iter()
+            .filter(|f| self.shm_formats.contains(f));
+        let format = offered
+            .clone()
+            .find(|f| preferred.contains(&shm_format_to_fourcc(*f)))
+            .or_else(|| offered.clone().next())?;
+        Some(BufferSpec {
+            format,
+            width,
+            height,
+            stride: width * 4,
+        })
+    }
+}
+
+struct CopyState {
+    shm: Shm,
+    target: ShmTarget,
+    /// Constraints sent since the last `done`.
+    pending: Constraints,
+    /// The constraints of the last `done`.
+    constraints: Constraints,
+    /// How many `done`s there were, to tell new constraints from the ones a buffer was
+    /// made for.
+    constraints_serial: u64,
+    ready: bool,
+    failed: Option<WEnum<FailureReason>>,
+    stopped: bool,
+}
+
+/// Captures a single output with `ext_image_copy_capture_manager_v1`, the standardized
+/// successor to wlr-screencopy. Frames are copied into shared memory.
+pub struct ExtImageCopy {
+    queue: EventQueue<CopyState>,
+    state: CopyState,
+    /// Kept for a new session when the cursor is switched, since the cursor is an
+    /// option of the session.
+    manager: ExtImageCopyCaptureManagerV1,
+    session: ExtImageCopyCaptureSessionV1,
+    source: ExtImageCaptureSourceV1,
+    transform: Transform,
+    cursor: CursorSwitch,
+    /// Whether the session paints the cursor.
+    painted: bool,
+}
+
+impl ExtImageCopy {
+    /// Fails if the compositor doesn't support ext-image-copy-capture, or the output
+    /// can't be captured with only shared memory buffers.
+    pub fn new(
+        connection: &Connection,
+        output: &OutputState,
+        cursor: CursorSwitch,
+    ) -> Result<Self, String> {
+        let (globals, queue) = registry_queue_init::<CopyState>(connection)
+            .map_err(|e| format!("wayland globals: {e}"))?;
+        let qh = queue.handle();
+
+        let sources: ExtOutputImageCaptureSourceManagerV1 = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|_| "compositor does not support ext-image-capture-source".to_string())?;
+        let manager: ExtImageCopyCaptureManagerV1 = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|_| "compositor does not support ext-image-copy-capture".to_string())?;
+        let shm = Shm::bind(&globals, &qh).map_err(|e| format!("wl_shm: {e}"))?;
+
+        let painted = cursor.painted();
+        let source = sources.create_source(&output.wl_output, &qh, ());
+        let session = manager.create_session(&source, session_options(painted), &qh, ());
+        sources.destroy();
+
+        let mut capture = Self {
+            queue,
+            state: CopyState {
+                shm,
+                target: ShmTarget::default(),
+                pending: Constraints::default(),
+                constraints: Constraints::default(),
+                constraints_serial: 0,
+                ready: false,
+                failed: None,
+                stopped: false,
+            },
+            manager,
+            session,
+            source,
+            transform: output.transform(),
+            cursor,
+            painted,
+        };
+        capture.wait_for_constraints(0)?;
+        Ok(capture)
+    }
+
+    /// Wait for constraints newer than the `seen`th ones.
+    fn wait_for_constraints(&mut self, seen: u64) -> Result<(), String> {
+        while self.state.constraints_serial == seen && !self.state.stopped {
+            self.queue
+                .blocking_dispatch(&mut self.state)
+                .map_err(|e| format!("dispatch: {e}"))?;
+        }
+        if self.state.stopped {
+            return Err("capture session stopped right away".into());
+        }
+        if self.state.constraints.spec(&[]).is_none() {
+            return Err("no usable shm format offered".into());
+        }
+        Ok(())
+    }
+
+    /// Replace the session with one that paints the cursor as the switch says.
+    fn switch_cursor(&mut self) -> Result<(), String> {
+        self.painted = self.cursor.painted();
+        self.session.destroy();
+        let qh = self.queue.handle();
+        self.session =
+            self.manager
+                .create_session(&self.source, session_options(self.painted), &qh, ());
+        self.state.pending = Constraints::default();
+        self.wait_for_constraints(self.state.constraints_serial)
+    }
+}
+
+fn session_options(paint_cursors: bool) -> ext_image_copy_capture_manager_v1::Options {
+    if paint_cursors {
+        ext_image_copy_capture_manager_v1::Options::PaintCursors
+    } else {
+        ext_image_copy_capture_manager_v1::Options::empty()
+    }
+}
+
+impl Drop for ExtImageCopy {
+    fn drop(&mut self) {
+        self.session.destroy();
+        self.source.destroy();
+        self.manager.destroy();
+    }
+}
+
+impl CaptureBackend for ExtImageCopy {
+    fn name(&self) -> &'static str {
+        "ext-image-copy-capture"
+    }
+
+    fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    fn run(
+        mut self: Box<Self>,
+        fps: u32,
+        formats: Vec<DrmFormat>,
+        mut on_frame: FrameCallback,
+    ) -> Result<(), String> {
+        let interval = Duration::from_secs(1) / fps.max(1);
+        let qh = self.queue.handle();
+        let preferred: Vec<u32> = formats.iter().map(|f| f.code).collect();
+
+        loop {
+            let started = Instant::now();
+            if self.cursor.painted() != self.painted {
+                self.switch_cursor()?;
+            }
+            self.state.ready = false;
+            self.state.failed = None;
+
+            let seen = self.state.constraints_serial;
+            let spec = self
+                .state
+                .constraints
+                .spec(&preferred)
+                .ok_or("no usable shm format offered after a format change")?;
+            let buffer = self.state.target.buffer(&self.state.shm, spec, &qh)?;
+
+            // the compositor finishes a capture once something changed, so this also
+            // keeps static screens from costing anything
+            let frame = self.session.create_frame(&qh, ());
+            frame.attach_buffer(buffer);
+            frame.damage_buffer(0, 0, spec.width as i32, spec.height as i32);
+            frame.capture();
+            while !self.state.ready && self.state.failed.is_none() && !self.state.stopped {
+                self.queue
+                    .blocking_dispatch(&mut self.state)
+                    .map_err(|e| format!("dispatch: {e}"))?;
+            }
+            frame.destroy();
+
+            match self.state.failed {
+                // the new constraints may come before or after the failure
+                Some(WEnum::Value(FailureReason::BufferConstraints)) => {
+                    match self.wait_for_constraints(seen) {
+                        Err(_) if self.state.stopped => return Ok(()),
+                        result => result?,
+                    }
+                    continue;
+                }
+                Some(WEnum::Value(FailureReason::Stopped)) => return Ok(()),
+                Some(_) => return Err("image copy capture failed".into()),
+                None if self.state.stopped => return Ok(()),
+                None => {}
+            }
+
+            self.state.target.deliver(&mut on_frame);
+
+            if let Some(rest) = interval.checked_sub(started.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for CopyState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: <ExtImageCopyCaptureSessionV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                state.pending.size = Some((width, height));
+            }
+            ext_image_copy_capture_session_v1::Event::ShmFormat {
+                format: WEnum::Value(format),
+            } => state.pending.shm_formats.push(format),
+            ext_image_copy_capture_session_v1::Event::Done => {
+                state.constraints = std::mem::take(&mut state.pending);
+                state.constraints_serial += 1;
+            }
+            ext_image_copy_capture_session_v1::Event::Stopped => state.stopped = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for CopyState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureFrameV1,
+        event: <ExtImageCopyCaptureFrameV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Ready => state.ready = true,
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                state.failed = Some(reason)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for CopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtOutputImageCaptureSourceManagerV1,
+        _event: <ExtOutputImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCaptureSourceV1, ()> for CopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCaptureSourceV1,
+        _event: <ExtImageCaptureSourceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for CopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCopyCaptureManagerV1,
+        _event: <ExtImageCopyCaptureManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for CopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlBuffer,
+        _event: <WlBuffer as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for CopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl ShmHandler for CopyState {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+
+delegate_shm!(CopyState);
@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::wl_registry::{self, WlRegistry},
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+
+use crate::{
+    portal::CursorMode,
+    preset::Tuning,
+    pw_capture::{self, DrmFormat},
+    wl_client_desktop::OutputState,
+};
+
+use super::{
+    protocols::kde_screencast::{
+        zkde_screencast_stream_unstable_v1::{self, ZkdeScreencastStreamUnstableV1},
+        zkde_screencast_unstable_v1::{Pointer, ZkdeScreencastUnstableV1},
+    },
+    CaptureBackend, FrameCallback,
+};
+
+#[derive(Default)]
+struct ScreencastState {
+    node_id: Option<u32>,
+    failed: Option<String>,
+}
+
+/// Captures a single output through KWin's `zkde_screencast_unstable_v1`, which hands
+/// out a PipeWire node on the session's PipeWire without a portal dialog.
+pub struct KdeScreencast {
+    /// KWin stops the stream once this is closed.
+    stream: ZkdeScreencastStreamUnstableV1,
+    node_id: u32,
+}
+
+impl KdeScreencast {
+    /// Fails if the compositor isn't KWin, or refuses to stream the output.
+    pub fn new(
+        connection: &Connection,
+        output: &OutputState,
+        cursor: CursorMode,
+    ) -> Result<Self, String> {
+        let (globals, mut queue) = registry_queue_init::<ScreencastState>(connection)
+            .map_err(|e| format!("wayland globals: {e}"))?;
+        let qh = queue.handle();
+
+        let screencast: ZkdeScreencastUnstableV1 = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|_| "compositor does not support zkde-screencast".to_string())?;
+
+        let pointer = match cursor {
+            CursorMode::Hidden => Pointer::Hidden,
+            CursorMode::Embedded => Pointer::Embedded,
+            CursorMode::Metadata => Pointer::Metadata,
+        };
+        let stream = screencast.stream_output(&output.wl_output, pointer.into(), &qh, ());
+        screencast.destroy();
+
+        let mut state = ScreencastState::default();
+        while state.node_id.is_none() && state.failed.is_none() {
+            queue
+                .blocking_dispatch(&mut state)
+                .map_err(|e| format!("dispatch: {e}"))?;
+        }
+        if let Some(error) = state.failed {
+            stream.close();
+            return Err(format!("KWin refused the stream: {error}"));
+        }
+
+        Ok(Self {
+            stream,
+            node_id: state.node_id.expect("screencast node id"),
+        })
+    }
+
+    /// The PipeWire node KWin streams the output to.
+    pub fn node_id(&self) -> u32 {
+        self.node_id
+    }
+}
+
+impl Drop for KdeScreencast {
+    fn drop(&mut self) {
+        self.stream.close();
+    }
+}
+
+impl CaptureBackend for KdeScreencast {
+    fn name(&self) -> &'static str {
+        "kde-screencast"
+    }
+
+    fn run(
+        self: Box<Self>,
+        fps: u32,
+        formats: Vec<DrmFormat>,
+        on_frame: FrameCallback,
+    ) -> Result<(), String> {
+        let on_frame = RefCell::new(on_frame);
+        pw_capture::pipewire_init_stream(
+            "lensing",
+            None,
+            self.node_id,
+            fps,
+            &Tuning::default(),
+            formats,
+            move |format, frame| (on_frame.borrow_mut())(format, frame),
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl Dispatch<ZkdeScreencastStreamUnstableV1, ()> for ScreencastState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZkdeScreencastStreamUnstableV1,
+        event: <ZkdeScreencastStreamUnstableV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zkde_screencast_stream_unstable_v1::Event::Created { node } => {
+                state.node_id = Some(node)
+            }
+            zkde_screencast_stream_unstable_v1::Event::Failed { error } => {
+                state.failed = Some(error)
+            }
+            zkde_screencast_stream_unstable_v1::Event::Closed => {
+                state.failed = Some("stream closed".into())
+            }
+        }
+    }
+}
+
+impl Dispatch<ZkdeScreencastUnstableV1, ()> for ScreencastState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZkdeScreencastUnstableV1,
+        _event: <ZkdeScreencastUnstableV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for ScreencastState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+use wayland_client::{protocol::wl_output::Transform, Connection};
+
+use crate::{
+    portal::CursorMode,
+    pw_capture::{DrmFormat, PipewireFrame, PipewireFrameFormat},
+    wl_client_desktop::OutputState,
+    Capture,
+};
+
+pub mod ext_image_copy;
+pub mod kde_screencast;
+mod protocols;
+pub mod region;
+mod shm;
+pub mod wlr_screencopy;
+
+pub type FrameCallback = Box<dyn FnMut(&PipewireFrameFormat, &PipewireFrame)>;
+
+/// The cursor mode of a capture, which may be changed while it runs. The screencopy
+/// backends pick it up with the next frame; the portal and KWin keep the mode they
+/// started with, as their streams can't be asked for another.
+#[derive(Debug, Clone, Default)]
+pub struct CursorSwitch(Arc<Mutex<CursorMode>>);
+
+impl CursorSwitch {
+    pub fn new(mode: CursorMode) -> Self {
+        Self(Arc::new(Mutex::new(mode)))
+    }
+
+    pub fn mode(&self) -> CursorMode {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, mode: CursorMode) {
+        *self.0.lock().unwrap() = mode;
+    }
+
+    /// Screencopy has no cursor metadata, so only a hidden cursor stays out of frames.
+    fn painted(&self) -> bool {
+        self.mode() != CursorMode::Hidden
+    }
+}
+
+/// A source of captured frames.
+pub trait CaptureBackend {
+    fn name(&self) -> &'static str;
+
+    /// What has to be applied to frames to match the logical layout. The screencopy
+    /// protocols hand out frames the way the output scans them out, while the portal and
+    /// KWin rotate them for us.
+    fn transform(&self) -> Transform {
+        Transform::Normal
+    }
+
+    /// Deliver frames to `on_frame` until the source goes away.
+    /// `formats` are the dmabuf formats the caller can import; backends that only
+    /// deliver shared memory pick the shm format by them where they have a choice.
+    fn run(
+        self: Box<Self>,
+        fps: u32,
+        formats: Vec<DrmFormat>,
+        on_frame: FrameCallback,
+    ) -> Result<(), String>;
+}
+
+/// Pick the most direct way the compositor offers to capture `output`:
+/// ext-image-copy-capture, then wlr-screencopy, then KWin's screencast protocol, and the
+/// portal last. The portal asks the user for a monitor, which may not be `output`.
+pub fn detect(
+    connection: &Connection,
+    output: &OutputState,
+    cursor: &CursorSwitch,
+) -> Result<Box<dyn CaptureBackend>, String> {
+    let backend = detect_any(connection, output, cursor)?;
+    crate::crash_report::note("backend", backend.name());
+    Ok(backend)
+}
+
+fn detect_any(
+    connection: &Connection,
+    output: &OutputState,
+    cursor: &CursorSwitch,
+) -> Result<Box<dyn CaptureBackend>, String> {
+    match ext_image_copy::ExtImageCopy::new(connection, output, cursor.clone()) {
+        Ok(backend) => return Ok(Box::new(backend)),
+        Err(e) => println!("Not using ext-image-copy-capture: {e}"),
+    }
+    match wlr_screencopy::WlrScreencopy::new(connection, output, cursor.clone()) {
+        Ok(backend) => return Ok(Box::new(backend)),
+        Err(e) => println!("Not using wlr-screencopy: {e}"),
+    }
+    match kde_screencast::KdeScreencast::new(connection, output, cursor.mode()) {
+        Ok(backend) => return Ok(Box::new(backend)),
+        Err(e) => println!("Not using zkde-screencast: {e}"),
+    }
+    Capture::monitor(None, cursor.mode())
+        .map(|capture| Box::new(capture) as Box<dyn CaptureBackend>)
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,296 @@
+use crate::{
+    pw_capture::{
+        DrmFormat, PipewireDmabufPlane, PipewireFrame, PipewireFrameData, PipewireFrameFormat,
+    },
+    wl_client_desktop::{OutputState, WlClientDesktopState},
+};
+
+use wayland_client::protocol::wl_output::Transform;
+
+use super::{CaptureBackend, CursorSwitch, FrameCallback};
+
+/// A rectangle of the desktop in logical coordinates, like the compositor lays out outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// `X:Y:W:H`
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid region: {s}");
+        let numbers: Vec<i32> = s
+            .split(':')
+            .map(|n| n.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        let [x, y, width, height] = numbers[..] else {
+            return Err(invalid());
+        };
+        if width <= 0 || height <= 0 {
+            return Err(invalid());
+        }
+        Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// A rectangle of an output's frames, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelRect {
+    /// Where this rectangle of a frame in the logical orientation is in a frame of
+    /// `size` that still needs `transform` applied.
+    pub fn untransformed(&self, transform: Transform, size: (u32, u32)) -> PixelRect {
+        let (w, h) = (size.0 as i64, size.1 as i64);
+        let flipped = matches!(
+            transform,
+            Transform::Flipped
+                | Transform::Flipped90
+                | Transform::Flipped180
+                | Transform::Flipped270
+        );
+        // transforms turn the frame counter-clockwise, after flipping it around the
+        // vertical axis
+        let corner = |x: i64, y: i64| {
+            let (x, y) = match transform {
+                Transform::_90 | Transform::Flipped90 => (w - y, x),
+                Transform::_180 | Transform::Flipped180 => (w - x, h - y),
+                Transform::_270 | Transform::Flipped270 => (y, h - x),
+                _ => (x, y),
+            };
+            if flipped {
+                (w - x, y)
+            } else {
+                (x, y)
+            }
+        };
+
+        let (x0, y0) = corner(self.x as i64, self.y as i64);
+        let (x1, y1) = corner(
+            self.x as i64 + self.width as i64,
+            self.y as i64 + self.height as i64,
+        );
+        PixelRect {
+            x: x0.min(x1).max(0) as u32,
+            y: y0.min(y1).max(0) as u32,
+            width: (x0 - x1).unsigned_abs() as u32,
+            height: (y0 - y1).unsigned_abs() as u32,
+        }
+    }
+
+    /// The parts of `damage` of the whole frame that are in this rectangle, relative to
+    /// it.
+    pub fn crop_damage(&self, damage: &[PixelRect]) -> Vec<PixelRect> {
+        damage
+            .iter()
+            .filter_map(|d| {
+                let (x0, y0) = (d.x.max(self.x), d.y.max(self.y));
+                let x1 = (d.x + d.width).min(self.x + self.width);
+                let y1 = (d.y + d.height).min(self.y + self.height);
+                (x1 > x0 && y1 > y0).then(|| PixelRect {
+                    x: x0 - self.x,
+                    y: y0 - self.y,
+                    width: x1 - x0,
+                    height: y1 - y0,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Region {
+    /// The output the region lies on, and where the region is in that output's frames.
+    /// Regions spanning more than one output are not supported.
+    pub fn locate<'a>(
+        &self,
+        desktop: &'a WlClientDesktopState,
+    ) -> Option<(&'a OutputState, PixelRect)> {
+        let output = desktop.outputs.iter().find(|o| {
+            self.x >= o.logical_pos.0
+                && self.y >= o.logical_pos.1
+                && self.x + self.width <= o.logical_pos.0 + o.logical_size.0
+                && self.y + self.height <= o.logical_pos.1 + o.logical_size.1
+        })?;
+
+        let (x, y) = output.to_pixels((self.x as f64, self.y as f64));
+        let scale = output.scale();
+
+        Some((
+            output,
+            PixelRect {
+                x: x.round() as u32,
+                y: y.round() as u32,
+                width: (self.width as f64 * scale).round() as u32,
+                height: (self.height as f64 * scale).round() as u32,
+            },
+        ))
+    }
+}
+
+/// Delivers only a rectangle of another backend's frames.
+///
+/// Dmabufs are cropped without a copy: only linear buffers are negotiated, and the
+/// plane offset is moved to the first pixel of the region, so whatever imports the
+/// buffer on the GPU only ever samples the region. Shared memory frames are copied
+/// row by row into a buffer of the region's size.
+pub struct RegionCapture {
+    inner: Box<dyn CaptureBackend>,
+    rect: PixelRect,
+}
+
+impl RegionCapture {
+    pub fn new(inner: Box<dyn CaptureBackend>, rect: PixelRect) -> Self {
+        Self { inner, rect }
+    }
+}
+
+/// Pick a backend for the output `region` is on, see [`super::detect`].
+pub fn detect(
+    desktop: &WlClientDesktopState,
+    region: &Region,
+    cursor: &CursorSwitch,
+) -> Result<RegionCapture, String> {
+    let (output, rect) = region
+        .locate(desktop)
+        .ok_or_else(|| format!("region {region:?} is not within a single output"))?;
+    let inner = super::detect(&desktop.connection, output, cursor)?;
+    // the mode is the size of frames that weren't turned yet
+    let size = (output.size.0.max(0) as u32, output.size.1.max(0) as u32);
+    let rect = rect.untransformed(inner.transform(), size);
+    Ok(RegionCapture::new(inner, rect))
+}
+
+impl CaptureBackend for RegionCapture {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn transform(&self) -> Transform {
+        self.inner.transform()
+    }
+
+    fn run(
+        self: Box<Self>,
+        fps: u32,
+        formats: Vec<DrmFormat>,
+        mut on_frame: FrameCallback,
+    ) -> Result<(), String> {
+        let rect = self.rect;
+
+        // tiled layouts can't be cropped by moving the offset
+        let mut linear: Vec<DrmFormat> = vec![];
+        for format in formats {
+            if !linear.iter().any(|f| f.code == format.code) {
+                linear.push(DrmFormat {
+                    code: format.code,
+                    // DRM_FORMAT_MOD_LINEAR
+                    modifier: 0,
+                });
+            }
+        }
+
+        let mut copy: Vec<u8> = vec![];
+        self.inner.run(
+            fps,
+            linear,
+            Box::new(move |format, frame| {
+                if rect.x + rect.width > format.width || rect.y + rect.height > format.height {
+                    // the output changed mode under us; skip until it is back
+                    return;
+                }
+                let cropped_format = PipewireFrameFormat {
+                    width: rect.width,
+                    height: rect.height,
+                    ..*format
+                };
+
+                // the cursor is where it is in the frame, so it moves with the crop
+                let cursor = frame.cursor.clone().map(|mut cursor| {
+                    cursor.position.0 -= rect.x as i32;
+                    cursor.position.1 -= rect.y as i32;
+                    cursor
+                });
+                let damage = frame.damage.as_ref().map(|damage| rect.crop_damage(damage));
+
+                match &frame.data {
+                    PipewireFrameData::Dmabuf { planes } => {
+                        let Some(plane) = planes.first() else {
+                            return;
+                        };
+                        if format.modifier != 0 || planes.len() > 1 {
+                            // the producer ignored our formats, there's nothing to crop here
+                            return;
+                        }
+                        let bpp = plane.stride as u32 / format.width.max(1);
+                        let planes = vec![PipewireDmabufPlane {
+                            offset: plane.offset + rect.y * plane.stride as u32 + rect.x * bpp,
+                            ..*plane
+                        }];
+                        on_frame(
+                            &cropped_format,
+                            &PipewireFrame {
+                                data: PipewireFrameData::Dmabuf { planes },
+                                cursor,
+                                damage,
+                                header: frame.header,
+                            },
+                        );
+                    }
+                    PipewireFrameData::Shm { ptr, size, stride } => {
+                        let stride = *stride as usize;
+                        let bpp = stride / format.width.max(1) as usize;
+                        let row = rect.width as usize * bpp;
+                        let src = unsafe { std::slice::from_raw_parts(*ptr, *size) };
+
+                        copy.clear();
+                        for y in rect.y..rect.y + rect.height {
+                            let start = y as usize * stride + rect.x as usize * bpp;
+                            let Some(pixels) = src.get(start..start + row) else {
+                                return;
+                            };
+                            copy.extend_from_slice(pixels);
+                        }
+
+                        on_frame(
+                            &cropped_format,
+                            &PipewireFrame {
+                                data: PipewireFrameData::Shm {
+                                    ptr: copy.as_ptr(),
+                                    size: copy.len(),
+                                    stride: row as i32,
+                                },
+                                cursor,
+                                damage,
+                                header: frame.header,
+                            },
+                        );
+                    }
+                    PipewireFrameData::Unchanged => on_frame(
+                        &cropped_format,
+                        &PipewireFrame {
+                            data: PipewireFrameData::Unchanged,
+                            cursor,
+                            damage,
+                            header: frame.header,
+                        },
+                    ),
+                }
+            }),
+        )
+    }
+}
@@ -0,0 +1,115 @@
+use smithay_client_toolkit::shm::{raw::RawPool, Shm};
+use wayland_client::{
+    protocol::{wl_buffer::WlBuffer, wl_shm},
+    Dispatch, QueueHandle,
+};
+
+use crate::pw_capture::{PipewireFrame, PipewireFrameData, PipewireFrameFormat};
+
+const DRM_FORMAT_ARGB8888: u32 = 0x34325241;
+const DRM_FORMAT_XRGB8888: u32 = 0x34325258;
+
+/// wl_shm formats are DRM fourccs, except for the two that predate that convention.
+pub fn shm_format_to_fourcc(format: wl_shm::Format) -> u32 {
+    match format {
+        wl_shm::Format::Argb8888 => DRM_FORMAT_ARGB8888,
+        wl_shm::Format::Xrgb8888 => DRM_FORMAT_XRGB8888,
+        other => other.into(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSpec {
+    pub format: wl_shm::Format,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+}
+
+impl BufferSpec {
+    pub fn size(&self) -> usize {
+        self.stride as usize * self.height as usize
+    }
+}
+
+/// A shared memory buffer for the compositor to copy frames into, remade whenever the
+/// frame layout changes.
+#[derive(Default)]
+pub struct ShmTarget {
+    pool: Option<RawPool>,
+    buffer: Option<(WlBuffer, BufferSpec)>,
+}
+
+impl ShmTarget {
+    /// A buffer laid out as `spec`, replacing the current one if it doesn't fit.
+    pub fn buffer<D>(
+        &mut self,
+        shm: &Shm,
+        spec: BufferSpec,
+        qh: &QueueHandle<D>,
+    ) -> Result<&WlBuffer, String>
+    where
+        D: Dispatch<WlBuffer, ()> + 'static,
+    {
+        if self.buffer.as_ref().map(|(_, s)| *s) != Some(spec) {
+            if let Some((buffer, _)) = self.buffer.take() {
+                buffer.destroy();
+            }
+
+            let pool = match self.pool.as_mut() {
+                Some(pool) => {
+                    pool.resize(spec.size())
+                        .map_err(|e| format!("could not resize shm pool: {e}"))?;
+                    pool
+                }
+                None => {
+                    let pool = RawPool::new(spec.size(), shm)
+                        .map_err(|e| format!("could not create shm pool: {e}"))?;
+                    self.pool.insert(pool)
+                }
+            };
+
+            let buffer = pool.create_buffer(
+                0,
+                spec.width as i32,
+                spec.height as i32,
+                spec.stride as i32,
+                spec.format,
+                (),
+                qh,
+            );
+            self.buffer = Some((buffer, spec));
+        }
+
+        Ok(&self.buffer.as_ref().expect("shm buffer").0)
+    }
+
+    /// Hand whatever was last copied into the buffer to `on_frame`.
+    pub fn deliver(&mut self, on_frame: &mut dyn FnMut(&PipewireFrameFormat, &PipewireFrame)) {
+        let (Some(pool), Some((_, spec))) = (self.pool.as_mut(), self.buffer.as_ref()) else {
+            return;
+        };
+        let format = PipewireFrameFormat {
+            width: spec.width,
+            height: spec.height,
+            format: shm_format_to_fourcc(spec.format),
+            // DRM_FORMAT_MOD_LINEAR
+            modifier: 0,
+        };
+        on_frame(
+            &format,
+            &PipewireFrame {
+                data: PipewireFrameData::Shm {
+                    ptr: pool.mmap().as_ptr(),
+                    size: spec.size(),
+                    stride: spec.stride as i32,
+                },
+                // screencopy paints the cursor or leaves it out, it has no metadata
+                cursor: None,
+                // copies without damage are of the whole output
+                damage: None,
+                header: None,
+            },
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::{Duration, Instant};
+
+use smithay_client_toolkit::{
+    delegate_shm,
+    reexports::protocols_wlr::screencopy::v1::client::{
+        zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+        zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+    },
+    shm::{Shm, ShmHandler},
+};
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_output::{Transform, WlOutput},
+        wl_registry::{self, WlRegistry},
+    },
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+
+use crate::{pw_capture::DrmFormat, wl_client_desktop::OutputState};
+
+use super::{
+    shm::{BufferSpec, ShmTarget},
+    CaptureBackend, CursorSwitch, FrameCallback,
+};
+
+struct ScreencopyState {
+    shm: Shm,
+    manager: ZwlrScreencopyManagerV1,
+    target: ShmTarget,
+    spec: Option<BufferSpec>,
+    ready: bool,
+    failed: bool,
+}
+
+impl ScreencopyState {
+    /// Copy the frame into our buffer, making a new one if the frame doesn't fit.
+    fn copy(&mut self, frame: &ZwlrScreencopyFrameV1, qh: &QueueHandle<Self>) {
+        let Some(spec) = self.spec else {
+            self.failed = true;
+            return;
+        };
+
+        match self.target.buffer(&self.shm, spec, qh) {
+            Ok(buffer) => frame.copy(buffer),
+            Err(e) => {
+                println!("Screencopy: {e}");
+                self.failed = true;
+            }
+        }
+    }
+}
+
+/// Captures a single output with `zwlr_screencopy_manager_v1`, without going through
+/// the portal. Frames are copied into shared memory.
+pub struct WlrScreencopy {
+    queue: EventQueue<ScreencopyState>,
+    state: ScreencopyState,
+    output: WlOutput,
+    transform: Transform,
+    cursor: CursorSwitch,
+}
+
+impl WlrScreencopy {
+    /// Fails if the compositor doesn't support wlr-screencopy.
+    pub fn new(
+        connection: &Connection,
+        output: &OutputState,
+        cursor: CursorSwitch,
+    ) -> Result<Self, String> {
+        let (globals, queue) = registry_queue_init::<ScreencopyState>(connection)
+            .map_err(|e| format!("wayland globals: {e}"))?;
+        let qh = queue.handle();
+
+        let manager = globals
+            .bind(&qh, 1..=3, ())
+            .map_err(|_| "compositor does not support wlr-screencopy".to_string())?;
+        let shm = Shm::bind(&globals, &qh).map_err(|e| format!("wl_shm: {e}"))?;
+
+        Ok(Self {
+            queue,
+            state: ScreencopyState {
+                shm,
+                manager,
+                target: ShmTarget::default(),
+                spec: None,
+                ready: false,
+                failed: false,
+            },
+            output: output.wl_output.clone(),
+            transform: output.transform(),
+            cursor,
+        })
+    }
+}
+
+impl CaptureBackend for WlrScreencopy {
+    fn name(&self) -> &'static str {
+        "wlr-screencopy"
+    }
+
+    fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    fn run(
+        mut self: Box<Self>,
+        fps: u32,
+        _formats: Vec<DrmFormat>,
+        mut on_frame: FrameCallback,
+    ) -> Result<(), String> {
+        let interval = Duration::from_secs(1) / fps.max(1);
+        let qh = self.queue.handle();
+
+        loop {
+            let started = Instant::now();
+            self.state.ready = false;
+            self.state.failed = false;
+
+            let frame = self.state.manager.capture_output(
+                self.cursor.painted() as i32,
+                &self.output,
+                &qh,
+                (),
+            );
+            while !self.state.ready && !self.state.failed {
+                self.queue
+                    .blocking_dispatch(&mut self.state)
+                    .map_err(|e| format!("dispatch: {e}"))?;
+            }
+            frame.destroy();
+
+            if self.state.failed {
+                return Err("screencopy failed, is the output gone?".into());
+            }
+
+            self.state.target.deliver(&mut on_frame);
+
+            if let Some(rest) = interval.checked_sub(started.elapsed()) {
+                std::thread::sleep(rest);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for ScreencopyState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrScreencopyFrameV1,
+        event: <ZwlrScreencopyFrameV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_screencopy_frame_v1::Event::Buffer {
+                format,
+                width,
+                height,
+                stride,
+            } => {
+                let WEnum::Value(format) = format else {
+                    state.failed = true;
+                    return;
+                };
+                state.spec = Some(BufferSpec {
+                    format,
+                    width,
+                    height,
+                    stride,
+                });
+                // from version 3 on, the compositor can offer more buffer types before buffer_done
+                if proxy.version() < 3 {
+                    state.copy(proxy, qhandle);
+                }
+            }
+            zwlr_screencopy_frame_v1::Event::BufferDone => state.copy(proxy, qhandle),
+            zwlr_screencopy_frame_v1::Event::Ready { .. } => state.ready = true,
+            zwlr_screencopy_frame_v1::Event::Failed => state.failed = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrScreencopyManagerV1, ()> for ScreencopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrScreencopyManagerV1,
+        _event: <ZwlrScreencopyManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for ScreencopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlBuffer,
+        _event: <WlBuffer as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for ScreencopyState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegistry,
+        _event: wl_registry::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl ShmHandler for ScreencopyState {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+
+delegate_shm!(ScreencopyState);
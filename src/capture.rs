@@ -0,0 +1,229 @@
+use std::{cell::RefCell, os::fd::RawFd, rc::Rc};
+
+use crate::{
+    backend::{CaptureBackend, FrameCallback},
+    capture_thread::{CaptureThread, FrameStream, Overflow},
+    error::LensingError,
+    latest_frame::LatestFrame,
+    portal::{self, CursorMode, PortalSession, PortalStream},
+    preset::Tuning,
+    pw_capture::{self, DrmFormat, PipewireFrame, PipewireFrameFormat},
+    reconnect::Backoff,
+};
+
+/// Frames [`Capture::frames`] keeps for a consumer that is behind.
+const QUEUED_FRAMES: usize = 2;
+
+/// A screen capture for embedding lensing in other programs.
+///
+/// The portal is asked for a source when the capture is created; frames are
+/// delivered once [`Capture::run`] is called.
+pub struct Capture {
+    session: PortalSession,
+    /// How the session was asked for, to ask again after losing it.
+    select: fn(Option<&str>, CursorMode) -> Result<PortalSession, LensingError>,
+}
+
+impl Capture {
+    /// Ask the user for a monitor. Blocks until a selection was made.
+    pub fn monitor(restore_token: Option<&str>, cursor: CursorMode) -> Result<Self, LensingError> {
+        Ok(Self {
+            session: portal::select_monitor(restore_token, cursor)?,
+            select: portal::select_monitor,
+        })
+    }
+
+    /// Ask the user for a window. Blocks until a selection was made.
+    pub fn window(restore_token: Option<&str>, cursor: CursorMode) -> Result<Self, LensingError> {
+        Ok(Self {
+            session: portal::select_window(restore_token, cursor)?,
+            select: portal::select_window,
+        })
+    }
+
+    /// The streams the portal handed out; frames are delivered from the first one.
+    pub fn streams(&self) -> &[PortalStream] {
+        &self.session.streams
+    }
+
+    /// The cursor mode the portal agreed to, which may not be the one asked for.
+    pub fn cursor(&self) -> CursorMode {
+        self.session.cursor
+    }
+
+    /// Pass this to the next capture to skip the selection dialog, if the portal allows.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.session.restore_token.as_deref()
+    }
+
+    /// The PipeWire remote for this capture, e.g. for a GStreamer `pipewiresrc fd=`. It
+    /// is closed along with the capture.
+    pub fn pipewire_fd(&self) -> RawFd {
+        self.session.fd
+    }
+
+    /// Deliver frames to `on_frame` until the stream ends.
+    /// `formats` are the DRM formats and modifiers the caller can import as dmabufs;
+    /// if the producer can't share dmabufs, the same formats arrive in shared memory.
+    pub fn run<F>(
+        self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+        on_frame: F,
+    ) -> Result<(), LensingError>
+    where
+        F: Fn(&PipewireFrameFormat, &PipewireFrame) + 'static,
+    {
+        let stream = self.session.streams.first().ok_or(LensingError::NoStream)?;
+
+        pw_capture::pipewire_init_stream(
+            "lensing",
+            Some(self.session.pipewire_fd()?),
+            stream.node_id,
+            fps,
+            tuning,
+            formats,
+            on_frame,
+        )
+    }
+
+    /// Like [`Capture::run`], but when the stream fails or the portal closes the session,
+    /// e.g. because xdg-desktop-portal was restarted, asks the portal again with the
+    /// restore token and carries on delivering frames, as often as `tuning.reconnect`
+    /// allows. The error is the last one once it doesn't.
+    pub fn run_reconnecting<F>(
+        self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+        on_frame: F,
+    ) -> Result<(), LensingError>
+    where
+        F: Fn(&PipewireFrameFormat, &PipewireFrame) + 'static,
+    {
+        let on_frame = Rc::new(on_frame);
+        let mut backoff = Backoff::new(tuning.reconnect);
+        let Self {
+            mut session,
+            select,
+        } = self;
+        loop {
+            let node_id = session
+                .streams
+                .first()
+                .ok_or(LensingError::NoStream)?
+                .node_id;
+            backoff.started();
+            let deliver = on_frame.clone();
+            let result = pw_capture::pipewire_run_stream(
+                "lensing",
+                Some(session.pipewire_fd()?),
+                node_id,
+                fps,
+                tuning,
+                formats.clone(),
+                None,
+                move |format, frame| deliver(format, frame),
+            );
+            let mut error = match result {
+                Err(e) => e,
+                Ok(()) if session.closed() => LensingError::SessionClosed,
+                Ok(()) => return Ok(()),
+            };
+            // closed before asking for the next one, not left open next to it
+            let (restore_token, cursor) = (session.restore_token.clone(), session.cursor);
+            drop(session);
+            session = loop {
+                let Some(delay) = backoff.failed() else {
+                    return Err(error);
+                };
+                println!(
+                    "Capture lost ({error}), try {} of {} in {} ms",
+                    backoff.attempt(),
+                    tuning.reconnect.attempts,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                match select(restore_token.as_deref(), cursor) {
+                    Ok(session) => break session,
+                    Err(e) => error = e,
+                }
+            };
+        }
+    }
+
+    /// Like [`Capture::run`], but on a thread of its own, so it doesn't block the caller.
+    /// Frames arrive on [`CaptureThread::frames`], at most `capacity` of them waiting.
+    /// The portal session is closed when the thread is dropped.
+    pub fn spawn(
+        self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<CaptureThread, LensingError> {
+        let stream = self.session.streams.first().ok_or(LensingError::NoStream)?;
+        let thread = CaptureThread::spawn(
+            Some(self.session.pipewire_fd()?),
+            stream.node_id,
+            fps,
+            tuning,
+            formats,
+            capacity,
+            overflow,
+        )?;
+        Ok(thread.with_session(self.session))
+    }
+
+    /// The frames as an async [`futures::Stream`], for GUI and server programs that wait
+    /// on other things too. A consumer that is behind gets the freshest frames.
+    pub fn frames(
+        self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+    ) -> Result<FrameStream, LensingError> {
+        Ok(self
+            .spawn(fps, tuning, formats, QUEUED_FRAMES, Overflow::DropOldest)?
+            .into_stream())
+    }
+
+    /// Only ever the newest frame, polled without waiting, for render loops such as a
+    /// VR compositor's that must not block on the capture.
+    pub fn latest(
+        self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+    ) -> Result<LatestFrame, LensingError> {
+        let stream = self.session.streams.first().ok_or(LensingError::NoStream)?;
+        let fd = self.session.pipewire_fd()?;
+        let latest = LatestFrame::spawn(Some(fd), stream.node_id, fps, tuning, formats)?;
+        Ok(latest.with_session(self.session))
+    }
+}
+
+impl CaptureBackend for Capture {
+    fn name(&self) -> &'static str {
+        "portal"
+    }
+
+    fn run(
+        self: Box<Self>,
+        fps: u32,
+        formats: Vec<DrmFormat>,
+        on_frame: FrameCallback,
+    ) -> Result<(), String> {
+        let on_frame = RefCell::new(on_frame);
+        Capture::run_reconnecting(
+            *self,
+            fps,
+            &Tuning::default(),
+            formats,
+            move |format, frame| (on_frame.borrow_mut())(format, frame),
+        )
+        .map_err(|e| e.to_string())
+    }
+}
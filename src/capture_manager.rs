@@ -0,0 +1,312 @@
+use std::{
+    os::fd::{AsRawFd, BorrowedFd, IntoRawFd, OwnedFd},
+    sync::mpsc::{self, Receiver, SyncSender},
+};
+
+use crate::{
+    error::LensingError,
+    portal::{self, CursorMode, PortalSession, PortalStream},
+    preset::Tuning,
+    pw_capture::{
+        self, CursorMeta, DrmFormat, FrameHeader, PipewireDmabufPlane, PipewireFrame,
+        PipewireFrameData, PipewireFrameFormat, StreamRequest,
+    },
+    wl_client_desktop::WlClientDesktopState,
+};
+
+/// Frames a stream may have waiting in the channel before new ones are dropped.
+const QUEUED_FRAMES: usize = 2;
+
+#[derive(Debug)]
+pub struct OwnedDmabufPlane {
+    pub fd: OwnedFd,
+    pub offset: u32,
+    pub stride: i32,
+}
+
+impl OwnedDmabufPlane {
+    /// The plane as the stream callback has it, for APIs that take those, like
+    /// `gl_import`. Valid as long as `self` is.
+    pub fn as_plane(&self) -> PipewireDmabufPlane {
+        PipewireDmabufPlane {
+            fd: self.fd.as_raw_fd(),
+            offset: self.offset,
+            stride: self.stride,
+        }
+    }
+}
+
+/// The pixels of a [`PipewireFrame`], outliving the stream callback.
+///
+/// Dmabuf planes are duplicated fds of the producer's buffer, so the pixels may change
+/// once the producer reuses it; shared memory frames are copied.
+#[derive(Debug)]
+pub enum OwnedFrame {
+    Dmabuf { planes: Vec<OwnedDmabufPlane> },
+    Shm { data: Vec<u8>, stride: i32 },
+}
+
+impl OwnedFrame {
+    /// Fails for [`PipewireFrameData::Unchanged`], which has no pixels to keep.
+    pub(crate) fn copy(frame: &PipewireFrame) -> std::io::Result<Self> {
+        Ok(match &frame.data {
+            PipewireFrameData::Dmabuf { planes } => OwnedFrame::Dmabuf {
+                planes: planes
+                    .iter()
+                    .map(|p| {
+                        let fd = unsafe { BorrowedFd::borrow_raw(p.fd) }.try_clone_to_owned()?;
+                        Ok(OwnedDmabufPlane {
+                            fd,
+                            offset: p.offset,
+                            stride: p.stride,
+                        })
+                    })
+                    .collect::<std::io::Result<_>>()?,
+            },
+            PipewireFrameData::Shm { ptr, size, stride } => OwnedFrame::Shm {
+                data: unsafe { std::slice::from_raw_parts(*ptr, *size) }.to_vec(),
+                stride: *stride,
+            },
+            PipewireFrameData::Unchanged => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the frame has no pixels of its own",
+                ))
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CaptureEvent {
+    Frame {
+        output: String,
+        format: PipewireFrameFormat,
+        frame: OwnedFrame,
+        cursor: Option<CursorMeta>,
+        /// The timestamp and sequence number of the frame, for syncing it to other
+        /// streams and counting dropped frames.
+        header: Option<FrameHeader>,
+    },
+    /// The stream for `output` renegotiated, e.g. because the output changed its mode or
+    /// scale, and the frames from now on are in `format`. Sent before the first of them.
+    FormatChanged {
+        output: String,
+        format: PipewireFrameFormat,
+    },
+    /// The stream for `output` is over, because it was stopped, the output went away or
+    /// PipeWire failed. The other streams carry on.
+    Ended {
+        output: String,
+        error: Option<String>,
+    },
+}
+
+struct Running {
+    output: String,
+    requests: pipewire::channel::Sender<StreamRequest>,
+}
+
+/// Captures several outputs at once, one PipeWire stream per output, each on its own
+/// thread. Frames of all streams arrive on a single channel, tagged with the output name.
+pub struct CaptureManager {
+    session: PortalSession,
+    /// Output name and stream, in the order the portal listed them.
+    outputs: Vec<(String, PortalStream)>,
+    running: Vec<Running>,
+    sender: SyncSender<CaptureEvent>,
+    receiver: Option<Receiver<CaptureEvent>>,
+}
+
+impl CaptureManager {
+    /// Ask the user for any number of monitors. Blocks until a selection was made.
+    pub fn select(
+        desktop: &WlClientDesktopState,
+        cursor: CursorMode,
+    ) -> Result<Self, LensingError> {
+        Ok(Self::new(portal::select_monitors(true, cursor)?, desktop))
+    }
+
+    /// Name the streams of `session` after the outputs they are at. Streams the outputs
+    /// can't be told from are named after their node.
+    pub fn new(session: PortalSession, desktop: &WlClientDesktopState) -> Self {
+        let outputs = session
+            .streams
+            .iter()
+            .map(|stream| {
+                let name = desktop
+                    .outputs
+                    .iter()
+                    .find(|o| Some(o.logical_pos) == stream.position)
+                    .map(|o| o.name.clone())
+                    .unwrap_or_else(|| format!("node-{}", stream.node_id));
+                (name, *stream)
+            })
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_FRAMES * outputs.len().max(1));
+        Self {
+            session,
+            outputs,
+            running: vec![],
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        self.outputs.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn restore_token(&self) -> Option<&str> {
+        self.session.restore_token.as_deref()
+    }
+
+    /// The channel the frames of all streams arrive on. There is only one, so this
+    /// returns `None` after the first call.
+    ///
+    /// A stream that has frames waiting in the channel drops new ones, so a slow consumer
+    /// always gets recent frames and never holds up the other streams.
+    pub fn events(&mut self) -> Option<Receiver<CaptureEvent>> {
+        self.receiver.take()
+    }
+
+    /// Start a stream for every output that isn't streaming yet. Every stream sends a
+    /// [`CaptureEvent::Ended`] once it is over.
+    pub fn start(
+        &mut self,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+    ) -> Result<(), LensingError> {
+        for (output, stream) in self.outputs.iter() {
+            if self.running.iter().any(|r| &r.output == output) {
+                continue;
+            }
+            self.running.push(spawn_stream(
+                self.session.fd,
+                output,
+                stream.node_id,
+                fps,
+                *tuning,
+                formats.clone(),
+                self.sender.clone(),
+            )?);
+        }
+        Ok(())
+    }
+
+    /// End the stream for `output`, leaving the others running. Returns `false` if it
+    /// wasn't streaming.
+    pub fn stop(&mut self, output: &str) -> bool {
+        let Some(i) = self.running.iter().position(|r| r.output == output) else {
+            return false;
+        };
+        let running = self.running.remove(i);
+        // the stream may have ended on its own already
+        let _ = running.requests.send(StreamRequest::Stop);
+        true
+    }
+
+    /// Ask the stream of `output` for `fps` frames per second from now on, e.g. fewer
+    /// while nothing moves and more again on activity. The producer renegotiates, which
+    /// takes a moment, and may not go as low or high. Returns `false` if `output` isn't
+    /// streaming.
+    pub fn set_fps(&self, output: &str, fps: u32) -> bool {
+        let Some(running) = self.running.iter().find(|r| r.output == output) else {
+            return false;
+        };
+        let _ = running.requests.send(StreamRequest::Framerate(fps));
+        true
+    }
+
+    /// Forget about a stream that sent [`CaptureEvent::Ended`], so [`CaptureManager::start`]
+    /// starts it again.
+    pub fn ended(&mut self, output: &str) {
+        self.running.retain(|r| r.output != output);
+    }
+}
+
+impl Drop for CaptureManager {
+    fn drop(&mut self) {
+        for running in self.running.drain(..) {
+            let _ = running.requests.send(StreamRequest::Stop);
+        }
+    }
+}
+
+fn spawn_stream(
+    remote_fd: i32,
+    output: &str,
+    node_id: u32,
+    fps: u32,
+    tuning: Tuning,
+    formats: Vec<DrmFormat>,
+    events: SyncSender<CaptureEvent>,
+) -> Result<Running, LensingError> {
+    // every stream connects on its own, and PipeWire takes ownership of the fd
+    let fd = unsafe { BorrowedFd::borrow_raw(remote_fd) }
+        .try_clone_to_owned()
+        .map_err(|e| LensingError::Stream(format!("duplicating the PipeWire fd: {e}")))?;
+    let (requests, request_receiver) = pipewire::channel::channel();
+
+    let name = output.to_string();
+    std::thread::Builder::new()
+        .name(format!("capture {output}"))
+        .spawn(move || {
+            let frames = events.clone();
+            let frame_output = name.clone();
+            let last_format = std::cell::Cell::new(None);
+            let result = pw_capture::pipewire_run_stream(
+                "lensing",
+                Some(fd.into_raw_fd()),
+                node_id,
+                fps,
+                &tuning,
+                formats,
+                Some(request_receiver),
+                move |format, frame| {
+                    // whoever takes the events wants pixels, not just a moved cursor
+                    if matches!(frame.data, PipewireFrameData::Unchanged) {
+                        return;
+                    }
+                    let (cursor, header) = (frame.cursor.clone(), frame.header);
+                    let frame = match OwnedFrame::copy(frame) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            println!("Dropping a frame of {frame_output}: {e}");
+                            return;
+                        }
+                    };
+                    let previous = last_format.replace(Some(*format));
+                    if matches!(previous, Some(previous) if previous != *format) {
+                        // unlike frames, this one mustn't get lost
+                        let _ = frames.send(CaptureEvent::FormatChanged {
+                            output: frame_output.clone(),
+                            format: *format,
+                        });
+                    }
+                    let event = CaptureEvent::Frame {
+                        output: frame_output.clone(),
+                        format: *format,
+                        frame,
+                        cursor,
+                        header,
+                    };
+                    // a full channel drops the frame; once nobody listens anymore, the
+                    // manager stops us when it is dropped
+                    let _ = frames.try_send(event);
+                },
+            );
+            let _ = events.send(CaptureEvent::Ended {
+                output: name,
+                error: result.err().map(|e| e.to_string()),
+            });
+        })
+        .map_err(|e| LensingError::Thread(e.to_string()))?;
+
+    Ok(Running {
+        output: output.to_string(),
+        requests,
+    })
+}
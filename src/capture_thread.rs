@@ -0,0 +1,359 @@
+use std::{
+    collections::VecDeque,
+    os::fd::RawFd,
+    pin::Pin,
+    sync::{
+        mpsc::{RecvTimeoutError, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+
+use crate::{
+    backend::region::PixelRect,
+    capture_manager::OwnedFrame,
+    error::LensingError,
+    portal::PortalSession,
+    preset::Tuning,
+    pw_capture::{
+        self, CursorMeta, DrmFormat, FrameHeader, PipewireFrameData, PipewireFrameFormat,
+        StreamRequest,
+    },
+};
+
+/// What a full frame channel does with a new frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Drop the new frame, the ones waiting go first.
+    DropNewest,
+    /// Make room by dropping the oldest frame waiting, so a slow consumer always gets
+    /// the freshest.
+    #[default]
+    DropOldest,
+}
+
+/// A frame of a [`CaptureThread`].
+#[derive(Debug)]
+pub struct CapturedFrame {
+    pub format: PipewireFrameFormat,
+    pub frame: OwnedFrame,
+    pub cursor: Option<CursorMeta>,
+    /// See [`pw_capture::PipewireFrame::damage`].
+    pub damage: Option<Vec<PixelRect>>,
+    pub header: Option<FrameHeader>,
+}
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<CapturedFrame>,
+    ended: bool,
+    /// The task of a [`FrameStream`] waiting for a frame.
+    waker: Option<Waker>,
+}
+
+impl Queue {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Channel {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    fn push(&self, frame: CapturedFrame) {
+        let mut queue = self.lock();
+        if queue.frames.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropNewest => return,
+                Overflow::DropOldest => {
+                    queue.frames.pop_front();
+                }
+            }
+        }
+        queue.frames.push_back(frame);
+        queue.wake();
+        self.ready.notify_one();
+    }
+
+    fn end(&self) {
+        let mut queue = self.lock();
+        queue.ended = true;
+        queue.wake();
+        self.ready.notify_all();
+    }
+}
+
+/// Calls its function however the capture thread ends, panics included.
+struct OnDrop<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> Drop for OnDrop<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f();
+        }
+    }
+}
+
+/// The thread of a PipeWire stream and the way to ask it things. Dropping it ends the
+/// stream without waiting for the thread.
+pub(crate) struct StreamThread {
+    requests: pipewire::channel::Sender<StreamRequest>,
+    thread: Option<JoinHandle<Result<(), LensingError>>>,
+}
+
+impl StreamThread {
+    /// Connect to `node_id` like [`pw_capture::pipewire_run_stream`] does, and hand
+    /// every frame with pixels to `deliver`. `ended` is called once the stream is over.
+    pub(crate) fn spawn<D, E>(
+        remote_fd: Option<RawFd>,
+        node_id: u32,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+        deliver: D,
+        ended: E,
+    ) -> Result<Self, LensingError>
+    where
+        D: Fn(CapturedFrame) + Send + 'static,
+        E: FnOnce() + Send + 'static,
+    {
+        let (requests, request_receiver) = pipewire::channel::channel();
+        let tuning = *tuning;
+        let thread = std::thread::Builder::new()
+            .name(format!("capture node {node_id}"))
+            .spawn(move || {
+                let _ended = OnDrop(Some(ended));
+                pw_capture::pipewire_run_stream(
+                    "lensing",
+                    remote_fd,
+                    node_id,
+                    fps,
+                    &tuning,
+                    formats,
+                    Some(request_receiver),
+                    move |format, frame| {
+                        // consumers want pixels, not just a moved cursor
+                        if matches!(frame.data, PipewireFrameData::Unchanged) {
+                            return;
+                        }
+                        match OwnedFrame::copy(frame) {
+                            Ok(owned) => deliver(CapturedFrame {
+                                format: *format,
+                                frame: owned,
+                                cursor: frame.cursor.clone(),
+                                damage: frame.damage.clone(),
+                                header: frame.header,
+                            }),
+                            Err(e) => println!("Dropping a frame of node {node_id}: {e}"),
+                        }
+                    },
+                )
+            })
+            .map_err(|e| LensingError::Thread(e.to_string()))?;
+
+        Ok(Self {
+            requests,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn set_fps(&self, fps: u32) {
+        let _ = self.requests.send(StreamRequest::Framerate(fps));
+    }
+
+    pub(crate) fn stop(mut self) -> Result<(), LensingError> {
+        // the stream may have ended on its own already
+        let _ = self.requests.send(StreamRequest::Stop);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(LensingError::Thread("panicked".into()))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for StreamThread {
+    fn drop(&mut self) {
+        let _ = self.requests.send(StreamRequest::Stop);
+    }
+}
+
+/// The frames of a [`CaptureThread`], ending when the stream does.
+pub struct Frames {
+    channel: Arc<Channel>,
+}
+
+impl Frames {
+    /// Wait for the next frame; `None` once the stream ended and every frame was taken.
+    pub fn recv(&self) -> Option<CapturedFrame> {
+        let mut queue = self.channel.lock();
+        loop {
+            if let Some(frame) = queue.frames.pop_front() {
+                return Some(frame);
+            }
+            if queue.ended {
+                return None;
+            }
+            queue = self.channel.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// The next frame if there is one waiting, [`TryRecvError::Disconnected`] once the
+    /// stream ended and every frame was taken.
+    pub fn try_recv(&self) -> Result<CapturedFrame, TryRecvError> {
+        let mut queue = self.channel.lock();
+        match queue.frames.pop_front() {
+            Some(frame) => Ok(frame),
+            None if queue.ended => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Like [`Frames::recv`], but waits no longer than `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<CapturedFrame, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.channel.lock();
+        loop {
+            if let Some(frame) = queue.frames.pop_front() {
+                return Ok(frame);
+            }
+            if queue.ended {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self.channel.ready.wait_timeout(queue, left).unwrap().0;
+        }
+    }
+
+    /// Every frame until the stream ends.
+    pub fn iter(&self) -> impl Iterator<Item = CapturedFrame> + '_ {
+        std::iter::from_fn(|| self.recv())
+    }
+}
+
+/// A PipeWire stream running on a thread of its own, for programs that have a loop of
+/// their own to run. Dropping it ends the stream without waiting for the thread.
+pub struct CaptureThread {
+    frames: Frames,
+    stream: StreamThread,
+    /// The portal session of the stream, closed after it.
+    _session: Option<PortalSession>,
+}
+
+impl CaptureThread {
+    /// Connect to `node_id` like [`pw_capture::pipewire_run_stream`] does. At most
+    /// `capacity` frames wait for the consumer, what happens to more is up to `overflow`.
+    ///
+    /// The stream takes ownership of `remote_fd`.
+    pub fn spawn(
+        remote_fd: Option<RawFd>,
+        node_id: u32,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<Self, LensingError> {
+        let channel = Arc::new(Channel {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        });
+        let (pushing, ending) = (channel.clone(), channel.clone());
+        let stream = StreamThread::spawn(
+            remote_fd,
+            node_id,
+            fps,
+            tuning,
+            formats,
+            move |frame| pushing.push(frame),
+            move || ending.end(),
+        )?;
+        Ok(Self {
+            frames: Frames { channel },
+            stream,
+            _session: None,
+        })
+    }
+
+    /// Keep `session` open as long as the stream runs.
+    pub(crate) fn with_session(mut self, session: PortalSession) -> Self {
+        self._session = Some(session);
+        self
+    }
+
+    pub fn frames(&self) -> &Frames {
+        &self.frames
+    }
+
+    /// The frames as a [`Stream`], for async code to `select!` on next to other events.
+    /// Dropping the stream ends the capture.
+    pub fn into_stream(self) -> FrameStream {
+        FrameStream { thread: self }
+    }
+
+    /// See [`crate::CaptureManager::set_fps`].
+    pub fn set_fps(&self, fps: u32) {
+        self.stream.set_fps(fps);
+    }
+
+    /// End the stream and wait for its thread. The error it ended with, if it did
+    /// before.
+    pub fn stop(self) -> Result<(), LensingError> {
+        self.stream.stop()
+    }
+}
+
+/// The frames of a [`CaptureThread`] as a [`Stream`], which works with any executor;
+/// it ends when the capture does.
+pub struct FrameStream {
+    thread: CaptureThread,
+}
+
+impl FrameStream {
+    /// See [`CaptureThread::set_fps`].
+    pub fn set_fps(&self, fps: u32) {
+        self.thread.set_fps(fps);
+    }
+
+    /// See [`CaptureThread::stop`].
+    pub fn stop(self) -> Result<(), LensingError> {
+        self.thread.stop()
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = CapturedFrame;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<CapturedFrame>> {
+        let mut queue = self.thread.frames.channel.lock();
+        if let Some(frame) = queue.frames.pop_front() {
+            return Poll::Ready(Some(frame));
+        }
+        if queue.ended {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,729 @@
+use std::time::Duration;
+
+use lensing::{
+    audio::{AudioConfig, AudioSource},
+    encode::{
+        screenshot::{ScreenshotConfig, Settle},
+        stereo::Stereo,
+        stream::StreamTarget,
+        view::ViewTuning,
+        Container, EncoderBackend, LosslessCodec, VideoCodec,
+    },
+    log::LogSink,
+    mirror::magnifier::{Magnifier, MAX_DEAD_ZONE, MAX_ZOOM},
+    preset::Tuning,
+    sink::SinkSpec,
+    stitch::Overlays,
+    wl_client_desktop::{OutputSelector, WindowFilter},
+};
+
+/// What to do when the captured output disappears mid-recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputGonePolicy {
+    /// Finish the recording.
+    Stop,
+    /// Wait for an output with the same name and continue into a new file.
+    Wait,
+    /// Continue on another output.
+    Fallback(String),
+}
+
+pub enum Command {
+    ListOutputs {
+        /// As JSON, for scripts.
+        json: bool,
+    },
+    ListWindows,
+    Stitch {
+        location: String,
+        follow_focus: bool,
+        overlays: Overlays,
+        scene: Option<String>,
+    },
+    Window {
+        location: String,
+        follow: Option<String>,
+        /// The window to capture, if not left to the user.
+        target: Option<WindowFilter>,
+    },
+    Monitor {
+        location: String,
+        on_gone: OutputGonePolicy,
+    },
+    /// Show outputs in windows, one each.
+    Mirror {
+        /// The outputs to show, the focused one if none are given.
+        outputs: Vec<String>,
+        /// Output to show the first window fullscreen on.
+        fullscreen_on: Option<String>,
+        /// Pass input on the windows on to the outputs they show.
+        interactive: bool,
+        /// Pass the local gamepads on while a window has focus.
+        gamepads: bool,
+        /// How far the windows are zoomed in to start with.
+        magnifier: Magnifier,
+    },
+    /// Encode an output and send it over the network.
+    Stream {
+        target: StreamTarget,
+    },
+    /// Play a stream from `stream`, audio included.
+    View {
+        target: StreamTarget,
+        tuning: ViewTuning,
+        /// In percent.
+        volume: u32,
+    },
+    /// Send a command to the control socket of a running session.
+    Ctl {
+        request: String,
+    },
+    /// Save a PNG of an output once the screen settled.
+    Screenshot {
+        location: String,
+        config: ScreenshotConfig,
+    },
+    /// Encode a raw recording.
+    Transcode {
+        input: String,
+        output: String,
+    },
+}
+
+pub struct Args {
+    pub command: Command,
+    pub audio: Option<AudioConfig>,
+    pub silence_markers: bool,
+    /// Log input events next to the recording.
+    pub input_events: bool,
+    /// Print how the PipeWire streams are doing now and then.
+    pub stats: bool,
+    pub tuning: Tuning,
+    /// The output to capture, if not left to the portal's dialog.
+    pub output: Option<OutputSelector>,
+    pub sinks: Vec<SinkSpec>,
+    /// Where output goes, stdout if empty.
+    pub log_sinks: Vec<LogSink>,
+    /// Environment variables to set before connecting to anything.
+    pub env_overrides: Vec<(&'static str, String)>,
+}
+
+const USAGE: &str = "usage: lensing [--json]
+       lensing [stitch [FILE] | window [FILE] | monitor [FILE]] [OPTIONS]
+       lensing record [FILE] [OPTIONS]
+       lensing windows
+       lensing mirror [OUTPUT...]
+       lensing stream [rtsp://[ADDRESS][:PORT][/PATH] | rtp://HOST[:PORT]] [OPTIONS]
+       lensing view [rtsp://HOST[:PORT][/PATH] | rtp://ADDRESS[:PORT]] [OPTIONS]
+       lensing screenshot [FILE] [OPTIONS]
+       lensing transcode FILE.lraw [FILE] [OPTIONS]
+       lensing ctl attach SPEC | detach ID | list | hud ID | scene NAME | scenes | raise [OUTPUT]
+                   | cursor [MODE] | volume [PERCENT|mute|unmute] | latency [MS]
+
+commands:
+  record                   record an output to FILE (default recording.mkv), the same
+                           as monitor; .mp4 files are written as MP4; p and Enter
+                           pauses and resumes, leaving the pause out of the file;
+                           .lraw files keep the frames as they are, compressed with
+                           zstd, for when encoding can't keep up, e.g. 4K at 144 Hz
+  screenshot               save a PNG of an output to FILE (default screenshot.png), for
+                           UI tests: see --trigger and --settle-frames/--settle-quiet
+  transcode                encode a .lraw recording into FILE (default the same name
+                           as .mkv), with the codec and encoder options
+  windows                  list open windows, for --app-id and --title
+  mirror                   show outputs live in windows, one per OUTPUT or the focused
+                           one; f toggles fullscreen, c the cursor, q closes a window
+  stream                   encode an output without B-frames and serve it over RTSP
+                           (default rtsp://0.0.0.0:8554/lensing, rtsp builds only) or
+                           push RTP over UDP to HOST (default port 5000); with --audio,
+                           RTSP streams carry the audio too
+  view                     play a stream, by default rtsp://localhost:8554/lensing, with
+                           its audio through PipeWire; rtp:// listens on ADDRESS, or joins
+                           it if it is a multicast group, and takes the stream as --codec
+  ctl                      control a running monitor session that has --sink,
+                           e.g. `ctl attach file=clip.mkv` to start recording a preview,
+                           a stitch session with --image, e.g. `ctl scene brb`, or a
+                           mirror, e.g. `ctl raise` from a hotkey to bring it to the front
+
+options:
+  --wayland-display NAME   talk to this compositor instead of $WAYLAND_DISPLAY,
+                           e.g. one nested inside the current session
+  --pipewire-remote NAME   PipeWire daemon to use instead of $PIPEWIRE_REMOTE
+  --dbus-address ADDRESS   session bus to find the portal on, instead of
+                           $DBUS_SESSION_BUS_ADDRESS, e.g. for a test session
+  --log SINK               where output goes: stdout (default), stderr, journald or
+                           file=PATH; repeat to log to several
+  --fullscreen-on OUTPUT   mirror: show the mirror fullscreen on OUTPUT, e.g. a projector
+  --interactive            mirror: pass pointer, touch and pen input on the windows on to
+                           the outputs they show, through the RemoteDesktop portal, e.g.
+                           to draw on a monitor from a pen display; g locks the
+                           pointer to the window for mouse-look in games
+  --gamepads               mirror: while a window has focus, pass the gamepads on as
+                           uinput copies, for a session that doesn't see them; needs
+                           read access to /dev/input/event* and write access to
+                           /dev/uinput
+  --zoom LEVEL             mirror: magnify the middle of the outputs, from 1 (default)
+                           to 16; + and - double and halve it, 0 resets it, the arrow
+                           keys pan
+  --zoom-filter FILTER     mirror: linear (default) or nearest, for crisp pixels when
+                           magnified; n toggles it
+  --zoom-follow            mirror: pan the magnified view along with the pointer, p
+                           toggles it; needs the pointer position from the portal or
+                           KWin, the screencopy protocols don't send it
+  --zoom-dead-zone SHARE   mirror: how far the pointer may get from the middle of the
+                           view before it follows, as a share of the view (default 0.1)
+  --latency MS             view: how long to wait for late packets, and for audio and
+                           video to line up (default 200, on a LAN 50 with
+                           --low-latency); `ctl latency` changes it and shows what the
+                           viewer adds
+  --volume PERCENT         view: volume to start at (default 100), change it with
+                           `ctl volume`
+  --hw-decode              view: decode with VA-API, NVDEC or V4L2 and show the frames
+                           in a window that takes them off the GPU as they are, for 4K
+                           on small machines like a Raspberry Pi
+  --follow-focus           stitch: pan and zoom to the output with the focused window
+  --image SPEC             stitch: draw a still image over the desktop, e.g.
+                           logo.png,x=20,y=20,scale=0.5,opacity=0.8 or brb.png,scene=brb
+                           (images with scene=NAME only show in those scenes)
+  --scene NAME             stitch: scene to start in
+  --html SPEC              stitch: draw a web page over the desktop, e.g. for alerts,
+                           https://example.com/alerts,x=0,y=0,size=800x600 (html builds only)
+  --background SPEC        stitch: fill the canvas where nothing is captured with a
+                           color or gradient, e.g. #202020 or #101830:#000000[:horizontal]
+  --text SPEC              stitch: draw text, e.g. x=20,y=20,font=Sans 24,text=Hello or
+                           interval=5,command=playerctl metadata title (also file=PATH)
+  --low-latency            minimal buffering and no B-frames, for live mirroring; view:
+                           drop late packets and decode frame by frame
+  --archive                keep every frame, encode losslessly and verify the frame count
+  --game                   low latency, VRR friendly, hardware encoding, 60 fps
+  --fps N                  cap the capture rate, e.g. --game --fps 120
+  --skip-late              skip frames the encoder can't start on in time, so overload
+                           lowers the frame rate instead of adding latency (on with
+                           --low-latency and --game)
+  --pre-record SECS        keep the last SECS seconds in memory and wait for Enter to
+                           start the recording, which then begins with them, from the
+                           first keyframe; not with --sink
+  --write-buffer MIB       let this much of the recording wait for a slow disk, written
+                           behind with io_uring (default 64, 0 writes from the pipeline)
+  --capture-cores LIST     run capture threads on these cores, e.g. 0-3,8, to keep them
+                           on the big cores or one CCD and away from encoding
+  --encode-cores LIST      run encoding on these cores, e.g. 8-15
+  --max-planes N           drop dmabufs with more planes than this (default 4)
+  --hw-encode              encode dmabuf frames with VA-API without them leaving the GPU,
+                           x264 only if VA-API is missing
+  --encoder BACKEND        auto, nvenc, va or software (default auto: VA-API, then
+                           NVENC, then software; --game prefers nvenc)
+  --codec h264|hevc|vp9    video codec (default h264), hevc for 4K recordings
+  --cursor MODE            hidden, embedded (default) or metadata: the cursor left out,
+                           drawn into the frames, or sent alongside for the consumer to
+                           draw, as mirror windows do; the portal falls back to what it
+                           offers
+  --bitrate KBPS           target bitrate of lossy encoding, in kbit/s
+  --gop N                  frames from one keyframe to the next in lossy encoding, e.g.
+                           60 for streams viewers join; per sink with --sink ...,gop=N
+  --container mkv|mp4|lraw file format, instead of going by the file extension
+  --stereo LAYOUT[:OFFSET] pack the capture for VR video players, the same picture for
+                           either eye: sbs, hsbs (half width), tab or htab (half
+                           height); OFFSET moves the views together by that many
+                           pixels, bringing the screen closer, or apart if negative
+  --lossless ffv1|x264     encode losslessly (--archive uses ffv1)
+  --visually-lossless      lossy, but without visible artifacts
+  --on-output-gone POLICY  monitor: stop, wait, or fallback:OUTPUT (default stop)
+  --output SELECTOR        monitor, stream, screenshot: capture this output, by name
+                           (DP-3), position:X,Y or primary; picked without the dialog
+                           once there is a restore token for it
+  --trigger start|stdin    screenshot: start waiting for the screen to settle right away
+                           (default) or after a line on stdin
+  --settle-frames N        screenshot: take it after N frames that changed the screen
+  --settle-quiet MS        screenshot: take it once the screen stayed the same for MS
+  --settle-timeout SECS    screenshot: fail if the screen didn't settle by then
+                           (default 10)
+  --reconnect POLICY       monitor: when the stream fails or the portal session closes,
+                           ask the portal again and record on into the next segment,
+                           off or ATTEMPTS[:DELAY_MS[:MAX_DELAY_MS]], the delay doubling
+                           from try to try (default 5:1000:30000)
+  --sink SPEC              monitor: send the capture to one more sink, e.g.
+                           file=out.mkv,fps=60 or mirror,fps=30,crop=X:Y:W:H,scale=WxH
+                           or v4l2[=/dev/videoN],scale=1280x720,fps=30[,format=nv12]
+                           for a webcam other apps can pick (the first free
+                           v4l2loopback device unless given), or pipewire[=NAME]
+                           to publish a video source OBS and others can pick
+                           add filter=ELEMENT for extra GStreamer processing, in
+                           double quotes if it has commas, and
+                           hud to show capture stats on a mirror (h or `ctl hud ID` toggles);
+                           file sinks take gop=N and bframes=on|off of their own
+  --follow APP_ID          window: when the window closes, wait for the app
+                           to come back and keep recording into a new file
+  --app-id APP_ID          window: capture a window of this app, waiting for it
+                           to open if needed
+  --title TEXT             window: capture a window whose title contains TEXT
+  --audio desktop|mic      record an audio track
+  --audio-rate HZ          output sample rate (default 48000)
+  --audio-channels N       output channel count (default 2)
+  --silence-after SECS     with --audio, warn after this much silence, 0 to disable
+                           (default 10)
+  --silence-markers        with --audio, write silence markers next to the recording
+  --input-events           write when keys, buttons and the pointer were used next to
+                           the recording, to match glitches up with input
+                           (needs read access to /dev/input)
+  --json                   without a command: list the outputs as a JSON array of
+                           objects with name, make, model, x, y, width, height (the
+                           logical ones), pixel_width, pixel_height, scale and transform
+  --stats                  print every 5 seconds how the streams lensing reads frames
+                           from itself, e.g. in mirror, are doing: frame rate, frames
+                           skipped and lost, format and latency
+  --denoise-gate THRESHOLD suppress microphone noise, and mute what RNNoise rates less
+                           likely to be speech than THRESHOLD, 0.0 - 1.0 (rnnoise
+                           builds only)";
+
+fn usage_exit(msg: &str) -> ! {
+    eprintln!("{msg}\n\n{USAGE}");
+    std::process::exit(1);
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    let Some(value) = value else {
+        usage_exit(&format!("{flag} needs a value"));
+    };
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_exit(&format!("invalid value for {flag}: {value}")))
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    /// Parse `args`, without the program name.
+    fn parse_from(mut args: impl Iterator<Item = String>) -> Self {
+        let mut positional = vec![];
+        let mut audio: Option<AudioConfig> = None;
+        let mut silence_after: Option<u64> = None;
+        let mut silence_markers = false;
+        let mut input_events = false;
+        let mut stats = false;
+        let mut json = false;
+        let mut follow: Option<String> = None;
+        let mut target: Option<WindowFilter> = None;
+        let mut on_gone = OutputGonePolicy::Stop;
+        let mut tuning = Tuning::default();
+        let mut max_fps: Option<u32> = None;
+        let mut pre_record: Option<u32> = None;
+        let mut write_buffer: Option<u32> = None;
+        let mut skip_late = false;
+        let mut reconnect = None;
+        let mut capture_cores = None;
+        let mut encode_cores = None;
+        let mut max_planes: Option<u32> = None;
+        let mut codec = None;
+        let mut cursor = None;
+        let mut bitrate = None;
+        let mut gop = None;
+        let mut stereo: Option<Stereo> = None;
+        let mut container = None;
+        let mut lossless = None;
+        let mut visually_lossless = false;
+        let mut hw_encode = false;
+        let mut encoder = None;
+        let mut follow_focus = false;
+        let mut fullscreen_on = None;
+        let mut magnifier = Magnifier::default();
+        let mut interactive = false;
+        let mut gamepads = false;
+        let mut view = ViewTuning::default();
+        let mut volume = 100;
+        let mut screenshot = ScreenshotConfig::default();
+        let mut overlays = Overlays::default();
+        let mut scene = None;
+        let mut output = None;
+        let mut sinks = vec![];
+        let mut log_sinks = vec![];
+        let mut env_overrides = vec![];
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--low-latency" => {
+                    tuning = Tuning::low_latency();
+                    view.low_latency = true;
+                }
+                "--archive" => tuning = Tuning::archive(),
+                "--game" => tuning = Tuning::game(60),
+                "--fps" => max_fps = Some(parse_value(&arg, args.next())),
+                "--pre-record" => pre_record = Some(parse_value(&arg, args.next())),
+                "--write-buffer" => write_buffer = Some(parse_value(&arg, args.next())),
+                "--skip-late" => skip_late = true,
+                "--reconnect" => reconnect = Some(parse_value(&arg, args.next())),
+                "--capture-cores" => capture_cores = Some(parse_value(&arg, args.next())),
+                "--encode-cores" => encode_cores = Some(parse_value(&arg, args.next())),
+                "--max-planes" => max_planes = Some(parse_value(&arg, args.next())),
+                "--lossless" => {
+                    lossless = match parse_value::<String>(&arg, args.next()).as_str() {
+                        "ffv1" => Some(LosslessCodec::Ffv1),
+                        "x264" => Some(LosslessCodec::X264),
+                        other => usage_exit(&format!("unknown lossless codec: {other}")),
+                    };
+                }
+                "--visually-lossless" => visually_lossless = true,
+                "--hw-encode" => hw_encode = true,
+                "--encoder" => {
+                    encoder = match parse_value::<String>(&arg, args.next()).as_str() {
+                        "auto" => Some(EncoderBackend::Auto),
+                        "nvenc" => Some(EncoderBackend::Nvenc),
+                        "va" | "vaapi" => Some(EncoderBackend::Va),
+                        "software" => Some(EncoderBackend::Software),
+                        other => usage_exit(&format!("unknown encoder: {other}")),
+                    };
+                }
+                "--codec" => {
+                    codec = match parse_value::<String>(&arg, args.next()).as_str() {
+                        "h264" => Some(VideoCodec::H264),
+                        "hevc" | "h265" => Some(VideoCodec::Hevc),
+                        "vp9" => Some(VideoCodec::Vp9),
+                        other => usage_exit(&format!("unknown codec: {other}")),
+                    };
+                }
+                "--cursor" => {
+                    let mode: String = parse_value(&arg, args.next());
+                    cursor = Some(mode.parse().unwrap_or_else(|e: String| usage_exit(&e)));
+                }
+                "--bitrate" => bitrate = Some(parse_value(&arg, args.next())),
+                "--gop" => gop = Some(parse_value(&arg, args.next())),
+                "--stereo" => {
+                    stereo = Some(
+                        parse_value::<String>(&arg, args.next())
+                            .parse()
+                            .unwrap_or_else(|e: String| usage_exit(&e)),
+                    );
+                }
+                "--container" => {
+                    container = match parse_value::<String>(&arg, args.next()).as_str() {
+                        "mkv" | "matroska" => Some(Container::Matroska),
+                        "mp4" => Some(Container::Mp4),
+                        "lraw" | "raw" => Some(Container::Raw),
+                        other => usage_exit(&format!("unknown container: {other}")),
+                    };
+                }
+                "--follow-focus" => follow_focus = true,
+                "--fullscreen-on" => fullscreen_on = Some(parse_value(&arg, args.next())),
+                "--zoom" => {
+                    let zoom: f64 = parse_value(&arg, args.next());
+                    if !(1.0..=MAX_ZOOM).contains(&zoom) {
+                        usage_exit(&format!("{arg} goes from 1 to {MAX_ZOOM}"));
+                    }
+                    magnifier.set_zoom(zoom);
+                }
+                "--zoom-follow" => magnifier.follow = true,
+                "--zoom-dead-zone" => {
+                    let share: f64 = parse_value(&arg, args.next());
+                    if !(0.0..=MAX_DEAD_ZONE).contains(&share) {
+                        usage_exit(&format!("{arg} goes from 0 to {MAX_DEAD_ZONE}"));
+                    }
+                    magnifier.dead_zone = share;
+                }
+                "--zoom-filter" => {
+                    magnifier.filter = parse_value::<String>(&arg, args.next())
+                        .parse()
+                        .unwrap_or_else(|e: String| usage_exit(&e));
+                }
+                "--interactive" => interactive = true,
+                "--gamepads" => gamepads = true,
+                "--image" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    let parsed = spec.parse().unwrap_or_else(|e: String| usage_exit(&e));
+                    overlays.images.push(parsed);
+                }
+                "--scene" => scene = Some(parse_value(&arg, args.next())),
+                "--text" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    let parsed = spec.parse().unwrap_or_else(|e: String| usage_exit(&e));
+                    overlays.texts.push(parsed);
+                }
+                #[cfg(feature = "html")]
+                "--html" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    let parsed = spec.parse().unwrap_or_else(|e: String| usage_exit(&e));
+                    overlays.pages.push(parsed);
+                }
+                "--background" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    let parsed = spec.parse().unwrap_or_else(|e: String| usage_exit(&e));
+                    overlays.background = Some(parsed);
+                }
+                "--sink" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    sinks.push(spec.parse().unwrap_or_else(|e: String| usage_exit(&e)));
+                }
+                "--log" => {
+                    let spec: String = parse_value(&arg, args.next());
+                    log_sinks.push(spec.parse().unwrap_or_else(|e: String| usage_exit(&e)));
+                }
+                "--wayland-display" => {
+                    env_overrides.push(("WAYLAND_DISPLAY", parse_value(&arg, args.next())));
+                }
+                "--pipewire-remote" => {
+                    env_overrides.push(("PIPEWIRE_REMOTE", parse_value(&arg, args.next())));
+                }
+                "--dbus-address" => {
+                    env_overrides
+                        .push(("DBUS_SESSION_BUS_ADDRESS", parse_value(&arg, args.next())));
+                }
+                "--latency" => view.latency = Duration::from_millis(parse_value(&arg, args.next())),
+                "--volume" => volume = parse_value(&arg, args.next()),
+                "--trigger" => screenshot.trigger = parse_value(&arg, args.next()),
+                "--settle-frames" => {
+                    screenshot.settle = Settle::Updates(parse_value(&arg, args.next()));
+                }
+                "--settle-quiet" => {
+                    let ms: u64 = parse_value(&arg, args.next());
+                    screenshot.settle = Settle::Quiet(Duration::from_millis(ms));
+                }
+                "--settle-timeout" => {
+                    let secs: u64 = parse_value(&arg, args.next());
+                    screenshot.timeout = Duration::from_secs(secs);
+                }
+                "--hw-decode" => view.hw_decode = true,
+                "--follow" => follow = Some(parse_value(&arg, args.next())),
+                "--app-id" => {
+                    target.get_or_insert_with(Default::default).app_id =
+                        Some(parse_value(&arg, args.next()));
+                }
+                "--title" => {
+                    target.get_or_insert_with(Default::default).title =
+                        Some(parse_value(&arg, args.next()));
+                }
+                "--on-output-gone" => {
+                    let value: String = parse_value(&arg, args.next());
+                    on_gone = match value.split_once(':') {
+                        None if value == "stop" => OutputGonePolicy::Stop,
+                        None if value == "wait" => OutputGonePolicy::Wait,
+                        Some(("fallback", output)) => OutputGonePolicy::Fallback(output.into()),
+                        _ => usage_exit(&format!("invalid value for {arg}: {value}")),
+                    };
+                }
+                "--output" => {
+                    output = Some(
+                        parse_value::<String>(&arg, args.next())
+                            .parse()
+                            .unwrap_or_else(|e: String| usage_exit(&e)),
+                    );
+                }
+                "--audio" => {
+                    let source = match parse_value::<String>(&arg, args.next()).as_str() {
+                        "desktop" => AudioSource::Desktop,
+                        "mic" => AudioSource::Microphone,
+                        other => usage_exit(&format!("unknown audio source: {other}")),
+                    };
+                    audio.get_or_insert_with(Default::default).source = source;
+                }
+                "--audio-rate" => {
+                    audio.get_or_insert_with(Default::default).rate =
+                        parse_value(&arg, args.next());
+                }
+                "--audio-channels" => {
+                    audio.get_or_insert_with(Default::default).channels =
+                        parse_value(&arg, args.next());
+                }
+                "--silence-after" => silence_after = Some(parse_value(&arg, args.next())),
+                "--silence-markers" => silence_markers = true,
+                "--input-events" => input_events = true,
+                "--stats" => stats = true,
+                "--json" => json = true,
+                #[cfg(feature = "rnnoise")]
+                "--denoise-gate" => {
+                    audio.get_or_insert_with(Default::default).denoise_gate =
+                        Some(parse_value(&arg, args.next()));
+                }
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                flag if flag.starts_with("--") => usage_exit(&format!("unknown option: {flag}")),
+                _ => positional.push(arg),
+            }
+        }
+
+        // these apply on top of whichever preset was picked
+        if let Some(fps) = max_fps {
+            tuning.max_fps = (fps > 0).then_some(fps);
+        }
+        if capture_cores.is_some() {
+            tuning.capture_cores = capture_cores;
+        }
+        if encode_cores.is_some() {
+            tuning.encode_cores = encode_cores;
+        }
+        if let Some(secs) = silence_after {
+            let Some(audio) = audio.as_mut() else {
+                usage_exit("--silence-after needs --audio");
+            };
+            audio.silence_after = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if silence_markers && audio.as_ref().and_then(|a| a.silence_after).is_none() {
+            usage_exit("--silence-markers needs --audio, with --silence-after not 0");
+        }
+        if silence_markers && !sinks.is_empty() {
+            usage_exit("--silence-markers needs a single recording, not --sink");
+        }
+        if let Some(secs) = pre_record {
+            tuning.pre_record = (secs > 0).then_some(secs);
+        }
+        if skip_late {
+            tuning.deadline = true;
+        }
+        if let Some(mib) = write_buffer {
+            tuning.write_buffer = (mib > 0).then_some(mib);
+        }
+        if let Some(reconnect) = reconnect {
+            tuning.reconnect = reconnect;
+        }
+        if let Some(planes) = max_planes {
+            tuning.max_planes = planes;
+        }
+        if let Some(codec) = codec {
+            tuning.codec = codec;
+        }
+        if let Some(gop) = gop {
+            tuning.gop = (gop > 0).then_some(gop);
+        }
+        if bitrate.is_some() {
+            tuning.bitrate = bitrate;
+        }
+        if stereo.is_some() {
+            tuning.stereo = stereo;
+        }
+        if container.is_some() {
+            tuning.container = container;
+        }
+        if lossless.is_some() {
+            tuning.lossless = lossless;
+        }
+        tuning.visually_lossless |= visually_lossless;
+        tuning.hw_encode |= hw_encode;
+        if let Some(encoder) = encoder {
+            tuning.encoder = encoder;
+        }
+        if let Some(cursor) = cursor {
+            tuning.cursor = cursor;
+        }
+
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            None => Command::ListOutputs { json },
+            Some("windows") => Command::ListWindows,
+            Some("stitch") => Command::Stitch {
+                location: positional.next().unwrap_or_else(|| "desktop.mkv".into()),
+                follow_focus,
+                overlays,
+                scene,
+            },
+            Some("window") => Command::Window {
+                location: positional.next().unwrap_or_else(|| "window.mkv".into()),
+                follow,
+                target,
+            },
+            Some("monitor") => Command::Monitor {
+                location: positional.next().unwrap_or_else(|| "monitor.mkv".into()),
+                on_gone,
+            },
+            Some("record") => Command::Monitor {
+                location: positional.next().unwrap_or_else(|| "recording.mkv".into()),
+                on_gone,
+            },
+            Some("mirror") => Command::Mirror {
+                outputs: positional.collect(),
+                fullscreen_on,
+                interactive,
+                gamepads,
+                magnifier,
+            },
+            Some("stream") => {
+                let target = match positional.next() {
+                    Some(spec) => spec.parse().unwrap_or_else(|e: String| usage_exit(&e)),
+                    None => StreamTarget::default(),
+                };
+                #[cfg(not(feature = "rtsp"))]
+                if matches!(target, StreamTarget::Rtsp { .. }) {
+                    usage_exit("this build can't serve RTSP, stream to rtp://HOST:PORT instead");
+                }
+                Command::Stream { target }
+            }
+            Some("view") => Command::View {
+                target: match positional.next() {
+                    Some(spec) => spec.parse().unwrap_or_else(|e: String| usage_exit(&e)),
+                    None => StreamTarget::default(),
+                },
+                tuning: view,
+                volume,
+            },
+            Some("screenshot") => Command::Screenshot {
+                location: positional.next().unwrap_or_else(|| "screenshot.png".into()),
+                config: screenshot,
+            },
+            Some("transcode") => {
+                let Some(input) = positional.next() else {
+                    usage_exit("transcode needs a .lraw file");
+                };
+                let output = positional.next().unwrap_or_else(|| {
+                    let stem = input
+                        .rsplit_once('.')
+                        .map_or(input.as_str(), |(stem, _)| stem);
+                    format!("{stem}.mkv")
+                });
+                Command::Transcode { input, output }
+            }
+            Some("ctl") => {
+                let request = positional.collect::<Vec<_>>().join(" ");
+                if request.is_empty() {
+                    usage_exit("ctl needs a command");
+                }
+                Command::Ctl { request }
+            }
+            Some(other) => usage_exit(&format!("unknown command: {other}")),
+        };
+
+        Args {
+            command,
+            audio,
+            silence_markers,
+            input_events,
+            stats,
+            tuning,
+            output,
+            sinks,
+            log_sinks,
+            env_overrides,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuning(args: &str) -> Tuning {
+        Args::parse_from(args.split_whitespace().map(String::from)).tuning
+    }
+
+    #[test]
+    fn fps_applies_on_top_of_the_preset() {
+        assert_eq!(tuning("--game").max_fps, Some(60));
+        assert_eq!(tuning("--game --fps 120").max_fps, Some(120));
+        assert_eq!(tuning("--fps 120 --game").max_fps, Some(120));
+        assert_eq!(tuning("--fps 0 --game").max_fps, None);
+    }
+
+    #[test]
+    fn overrides_keep_the_rest_of_the_preset() {
+        let low_latency = tuning("--codec hevc --low-latency");
+        assert_eq!(low_latency.codec, VideoCodec::Hevc);
+        assert_eq!(low_latency.buffers, Tuning::low_latency().buffers);
+    }
+
+    #[test]
+    fn the_last_preset_wins() {
+        let game = tuning("--archive --game");
+        assert_eq!(game.buffers, Tuning::game(60).buffers);
+        assert_eq!(game.max_fps, Some(60));
+    }
+}
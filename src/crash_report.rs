@@ -0,0 +1,148 @@
+use std::{fmt::Write as _, os::fd::AsRawFd, path::PathBuf, sync::Mutex, time::SystemTime};
+
+use wayland_client::Connection;
+
+use crate::{log, token_store};
+
+/// What we know about the capture, e.g. the backend and the negotiated format.
+static CONTEXT: Mutex<Vec<(&'static str, String)>> = Mutex::new(vec![]);
+static COMPOSITOR_PID: Mutex<Option<i32>> = Mutex::new(None);
+
+/// Write a report when we panic. The report has the last lines of output if
+/// [`log::install`] was called.
+pub fn install() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        write(&format!("panic: {info}"));
+    }));
+}
+
+/// Remember something about the capture for the report, replacing an earlier value.
+pub fn note(key: &'static str, value: impl Into<String>) {
+    let mut context = CONTEXT.lock().unwrap();
+    context.retain(|(k, _)| *k != key);
+    context.push((key, value.into()));
+}
+
+/// Everything noted so far. Empty if we may have panicked while holding the lock.
+pub(crate) fn context() -> Vec<(&'static str, String)> {
+    CONTEXT.try_lock().map(|c| c.clone()).unwrap_or_default()
+}
+
+/// Remember which process the compositor is, from the other end of our connection.
+pub fn note_compositor(connection: &Connection) {
+    // dropping the guard right away cancels the read
+    let Ok(guard) = connection.prepare_read() else {
+        return;
+    };
+    let fd = guard.connection_fd().as_raw_fd();
+    drop(guard);
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == 0 && cred.pid > 0 {
+        *COMPOSITOR_PID.lock().unwrap() = Some(cred.pid);
+    }
+}
+
+/// Write a report for `reason` and say where it is. Returns its path.
+pub fn write(reason: &str) -> Option<PathBuf> {
+    let mut report = String::new();
+    let _ = writeln!(report, "lensing {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "reason: {reason}");
+    let _ = writeln!(report, "compositor: {}", compositor());
+    if let Ok(desktop) = std::env::var("XDG_CURRENT_DESKTOP") {
+        let _ = writeln!(report, "desktop: {desktop}");
+    }
+    for gpu in gpus() {
+        let _ = writeln!(report, "gpu: {gpu}");
+    }
+    for (key, value) in context() {
+        let _ = writeln!(report, "{key}: {value}");
+    }
+    let _ = writeln!(report, "\nlast output:");
+    for line in log::recent_lines() {
+        let _ = writeln!(report, "{line}");
+    }
+
+    let dir = token_store::state_dir()?;
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("crash-{time}.txt"));
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(&path, report)) {
+        eprintln!("Could not write a crash report to {}: {e}", path.display());
+        return None;
+    }
+    // stderr, in case stdout is what broke
+    eprintln!(
+        "Wrote a crash report to {}, please attach it when reporting this.",
+        path.display()
+    );
+    Some(path)
+}
+
+/// The compositor's executable and what it says its version is.
+fn compositor() -> String {
+    let Some(pid) = COMPOSITOR_PID.try_lock().ok().and_then(|p| *p) else {
+        return "unknown".into();
+    };
+    let Ok(exe) = std::fs::read_link(format!("/proc/{pid}/exe")) else {
+        return format!("pid {pid}");
+    };
+    // every compositor we know of answers this without side effects
+    let version = std::process::Command::new(&exe)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .and_then(|v| v.lines().next().map(|l| l.trim().to_string()))
+        .unwrap_or_default();
+    format!("{} {version}", exe.display())
+}
+
+/// `card0: amdgpu 0x1002:0x73bf` for every DRM device.
+fn gpus() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/drm") else {
+        return vec![];
+    };
+    let mut gpus: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            // connectors are called card0-DP-1 and the like
+            if !name.starts_with("card") || name.contains('-') {
+                return None;
+            }
+            let device = e.path().join("device");
+            let read = |f: &str| std::fs::read_to_string(device.join(f)).ok();
+            let driver = std::fs::read_link(device.join("driver"))
+                .ok()
+                .and_then(|d| d.file_name()?.to_str().map(String::from))
+                .unwrap_or_else(|| "?".into());
+            let vendor = read("vendor").unwrap_or_default();
+            let model = read("device").unwrap_or_default();
+            Some(format!(
+                "{name}: {driver} {}:{}",
+                vendor.trim(),
+                model.trim()
+            ))
+        })
+        .collect();
+    gpus.sort();
+    gpus
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::preset::Tuning;
+
+use super::LosslessCodec;
+
+/// Frame rate to assume when the tuning doesn't cap it.
+const ASSUMED_FPS: u32 = 60;
+/// How much to write when measuring the disk.
+const PROBE_SIZE: usize = 64 << 20;
+
+/// Rough bytes per second for lossless and visually lossless recordings of desktop
+/// content, or what a bitrate was asked for. `None` for normal lossy encoding, where
+/// bitrates are never a concern.
+pub fn estimate_bitrate(frame_size: (i32, i32), tuning: &Tuning) -> Option<f64> {
+    // 4:2:0 is 12 bits per pixel; the ratios are guesses for typical desktop content
+    let ratio = match (tuning.lossless, tuning.visually_lossless) {
+        (Some(LosslessCodec::Ffv1), _) => 2.5,
+        (Some(LosslessCodec::X264), _) => 4.0,
+        (None, true) => 25.0,
+        (None, false) => return tuning.bitrate.map(|kbps| kbps as f64 * 125.0),
+    };
+    let fps = tuning.max_fps.unwrap_or(ASSUMED_FPS) as f64;
+    let raw = frame_size.0.max(0) as f64 * frame_size.1.max(0) as f64 * 1.5 * fps;
+    Some(raw / ratio)
+}
+
+/// Write a chunk of data next to `location` and time how long it takes to hit the disk.
+fn measure_disk_throughput(location: &str) -> io::Result<f64> {
+    let dir = Path::new(location)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let path = dir.join(".lensing-disk-probe");
+
+    // not zeros, filesystems with compression would make short work of those
+    let mut state = 0x2545f4914f6cdd1du64;
+    let data: Vec<u8> = (0..PROBE_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+
+    let started = Instant::now();
+    let result = File::create(&path).and_then(|mut file| {
+        file.write_all(&data)?;
+        file.sync_all()
+    });
+    let elapsed = started.elapsed();
+    let _ = std::fs::remove_file(&path);
+
+    result?;
+    Ok(PROBE_SIZE as f64 / elapsed.as_secs_f64().max(f64::EPSILON))
+}
+
+/// Warn if a lossless recording is likely to produce data faster than the disk takes it.
+pub fn check_disk_throughput(location: &str, frame_size: (i32, i32), tuning: &Tuning) {
+    let Some(bitrate) = estimate_bitrate(frame_size, tuning) else {
+        return;
+    };
+
+    let throughput = match measure_disk_throughput(location) {
+        Ok(throughput) => throughput,
+        Err(e) => {
+            println!("Could not measure disk throughput: {e}");
+            return;
+        }
+    };
+
+    let mib = |bytes: f64| bytes / (1 << 20) as f64;
+    println!(
+        "Estimated {:.0} MiB/s for {}x{}, disk takes {:.0} MiB/s",
+        mib(bitrate),
+        frame_size.0,
+        frame_size.1,
+        mib(throughput)
+    );
+    if bitrate > throughput {
+        println!(
+            "Warning: the recording will likely be written faster than the disk can keep up. \
+             Frames will queue up in memory and may be dropped."
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use gstreamer::{
+    prelude::*, Element, EventView, PadProbeData, PadProbeReturn, PadProbeType, Pipeline, Structure,
+};
+
+use crate::crash_report;
+
+/// How often a frame is looked at.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Black for less than this is a transition, not blanking.
+const BLANK_AFTER: Duration = Duration::from_secs(3);
+/// Color values up to this count as black.
+const BLACK_LEVEL: u8 = 16;
+/// Pixels looked at per frame.
+const SAMPLES: usize = 4096;
+
+#[derive(Default)]
+struct BlankState {
+    /// Where the alpha byte of a pixel is, `None` for formats we can't look into.
+    alpha: Option<usize>,
+    last_check: Option<Instant>,
+    black_since: Option<Instant>,
+    reported: bool,
+}
+
+/// Watch the capture sources of `pipeline` for frames that stay black.
+///
+/// Compositors and apps blank windows showing protected (DRM/HDCP) video in captures, so
+/// a recording of a movie ends up black without an error anywhere. When that seems to
+/// happen this says why, and posts a `lensing-blank` element message with a `blank`
+/// field on the bus, again with `blank` false once there is something to see.
+pub fn watch(pipeline: &Pipeline) {
+    let sources = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"));
+
+    for source in sources {
+        let Some(pad) = source.static_pad("src") else {
+            continue;
+        };
+
+        let mut state = BlankState::default();
+        let weak_source = source.downgrade();
+        pad.add_probe(
+            PadProbeType::BUFFER | PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                match info.data {
+                    Some(PadProbeData::Event(ref event)) => {
+                        if let EventView::Caps(caps) = event.view() {
+                            state.alpha = caps.caps().structure(0).and_then(|s| {
+                                alpha_index(
+                                    s.get::<&str>("format").ok()?,
+                                    s.get::<&str>("drm-format").ok(),
+                                )
+                            });
+                        }
+                    }
+                    Some(PadProbeData::Buffer(ref buffer)) => {
+                        let Some(alpha) = state.alpha else {
+                            return PadProbeReturn::Ok;
+                        };
+                        let now = Instant::now();
+                        if state
+                            .last_check
+                            .is_some_and(|t| now.duration_since(t) < CHECK_INTERVAL)
+                        {
+                            return PadProbeReturn::Ok;
+                        }
+                        state.last_check = Some(now);
+
+                        let Ok(map) = buffer.map_readable() else {
+                            return PadProbeReturn::Ok;
+                        };
+                        let black = is_black(map.as_slice(), alpha);
+                        drop(map);
+                        let Some(source) = weak_source.upgrade() else {
+                            return PadProbeReturn::Ok;
+                        };
+                        state.update(black, now, &source);
+                    }
+                    _ => {}
+                }
+                PadProbeReturn::Ok
+            },
+        );
+    }
+}
+
+impl BlankState {
+    fn update(&mut self, black: bool, now: Instant, source: &Element) {
+        if !black {
+            self.black_since = None;
+            if self.reported {
+                self.reported = false;
+                println!("The capture shows something again");
+                report(source, false);
+            }
+            return;
+        }
+
+        let since = *self.black_since.get_or_insert(now);
+        if !self.reported && now.duration_since(since) >= BLANK_AFTER {
+            self.reported = true;
+            println!(
+                "Warning: the capture has been black for {} s. Windows showing protected \
+                 (DRM/HDCP) video are blanked in captures, which may be why.",
+                BLANK_AFTER.as_secs()
+            );
+            report(source, true);
+        }
+    }
+}
+
+fn report(source: &Element, blank: bool) {
+    crash_report::note("blank frames", blank.to_string());
+    let structure = Structure::builder("lensing-blank")
+        .field("blank", blank)
+        .build();
+    let _ = source.post_message(
+        gstreamer::message::Element::builder(structure)
+            .src(source)
+            .build(),
+    );
+}
+
+/// The byte of a pixel that is alpha or padding. DRM fourccs name the channels from the
+/// most significant byte, GStreamer formats in memory order.
+fn alpha_index(format: &str, drm_format: Option<&str>) -> Option<usize> {
+    let format = match format {
+        "DMA_DRM" => drm_format?.split(':').next()?,
+        format => format,
+    };
+    match format {
+        "BGRx" | "BGRA" | "RGBx" | "RGBA" | "XR24" | "AR24" | "XB24" | "AB24" => Some(3),
+        "xRGB" | "ARGB" | "xBGR" | "ABGR" => Some(0),
+        _ => None,
+    }
+}
+
+/// Whether an evenly spread sample of 32 bit pixels is all black. Tiling moves pixels
+/// around but keeps black black, so this works for most modifiers too.
+fn is_black(data: &[u8], alpha: usize) -> bool {
+    let pixels = data.len() / 4;
+    if pixels == 0 {
+        return false;
+    }
+    let step = (pixels / SAMPLES).max(1);
+    data.chunks_exact(4).step_by(step).all(|pixel| {
+        pixel
+            .iter()
+            .enumerate()
+            .all(|(i, value)| i == alpha || *value <= BLACK_LEVEL)
+    })
+}
@@ -0,0 +1,114 @@
+use gstreamer::{
+    prelude::*, Caps, EventView, PadProbeData, PadProbeReturn, PadProbeType, Pipeline,
+};
+use gstreamer_video::{
+    VideoColorMatrix, VideoColorPrimaries, VideoColorRange, VideoColorimetry, VideoInfo,
+    VideoTransferFunction,
+};
+
+/// What frames are converted to and tagged as when the capture doesn't say otherwise.
+/// Screens are sRGB, which shares its primaries with BT.709, and players expect limited
+/// range; left alone, videoconvert picks BT.601 for frames under 720 lines, which
+/// players take for BT.709 and show washed out.
+pub const DEFAULT: &str = "bt709";
+
+/// Caps for a filter ahead of an encoder: `media`, e.g.
+/// `video/x-raw(memory:VAMemory),format=NV12`, converted to [`DEFAULT`]. Encoders write
+/// the colorimetry of their input into the VUI, and muxers into the container.
+///
+/// [`tag`] changes it for captures that come with a colorimetry of their own.
+pub fn caps(media: &str) -> String {
+    format!("{media},colorimetry={DEFAULT}")
+}
+
+/// Convert to and tag the colorimetry the capture of `pipeline` actually has, where it
+/// is YUV with one of its own; RGB captures keep [`DEFAULT`]. This rewrites the filters
+/// made with [`caps`] once the capture's caps are known, before the encoders see any.
+///
+/// Encoders that take the capture's dmabufs directly convert and tag them themselves.
+pub fn tag(pipeline: &Pipeline) {
+    let sources = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"));
+
+    for source in sources {
+        let Some(pad) = source.static_pad("src") else {
+            continue;
+        };
+
+        let weak_pipeline = pipeline.downgrade();
+        let mut tagged: Option<VideoColorimetry> = None;
+        pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            let Some(PadProbeData::Event(ref event)) = info.data else {
+                return PadProbeReturn::Ok;
+            };
+            let EventView::Caps(caps) = event.view() else {
+                return PadProbeReturn::Ok;
+            };
+            let Some(colorimetry) = capture_colorimetry(caps.caps()) else {
+                return PadProbeReturn::Ok;
+            };
+            if tagged.as_ref() == Some(&colorimetry) {
+                return PadProbeReturn::Ok;
+            }
+            if let Some(pipeline) = weak_pipeline.upgrade() {
+                println!("The capture is {colorimetry}, tagging the encoding as such");
+                set_filters(&pipeline, &colorimetry);
+            }
+            tagged = Some(colorimetry);
+            PadProbeReturn::Ok
+        });
+    }
+}
+
+/// The colorimetry of a YUV capture, with what it leaves open taken from BT.709.
+/// `None` for RGB, and for caps we can't read, e.g. of dmabufs with modifiers.
+fn capture_colorimetry(caps: &gstreamer::CapsRef) -> Option<VideoColorimetry> {
+    let info = VideoInfo::from_caps(caps).ok()?;
+    if !info.format_info().is_yuv() {
+        return None;
+    }
+    let given = info.colorimetry();
+    let range = match given.range() {
+        VideoColorRange::Unknown => VideoColorRange::Range16_235,
+        range => range,
+    };
+    let matrix = match given.matrix() {
+        VideoColorMatrix::Unknown => VideoColorMatrix::Bt709,
+        matrix => matrix,
+    };
+    let transfer = match given.transfer() {
+        VideoTransferFunction::Unknown => VideoTransferFunction::Bt709,
+        transfer => transfer,
+    };
+    let primaries = match given.primaries() {
+        VideoColorPrimaries::Unknown => VideoColorPrimaries::Bt709,
+        primaries => primaries,
+    };
+    Some(VideoColorimetry::new(range, matrix, transfer, primaries))
+}
+
+/// Set `colorimetry` on every capsfilter of `pipeline` that has one.
+fn set_filters(pipeline: &Pipeline, colorimetry: &VideoColorimetry) {
+    let filters = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "capsfilter"));
+
+    for filter in filters {
+        let mut caps = filter.property::<Caps>("caps");
+        let has_colorimetry = caps.iter().any(|s| s.has_field("colorimetry"));
+        if !has_colorimetry {
+            continue;
+        }
+        for structure in caps.make_mut().iter_mut() {
+            if structure.has_field("colorimetry") {
+                structure.set("colorimetry", colorimetry.to_string());
+            }
+        }
+        filter.set_property("caps", &caps);
+    }
+}
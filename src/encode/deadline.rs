@@ -0,0 +1,148 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use gstreamer::{prelude::*, Element, PadProbeReturn, PadProbeType, Pipeline, Structure};
+
+/// Name of the queue frames wait in for the encoder, see
+/// [`crate::preset::Tuning::encoder_queue_desc`].
+pub const QUEUE_NAME: &str = "encoder_feed";
+
+/// How much each new measurement moves the estimates.
+const SMOOTHING: f64 = 0.2;
+/// Without a skip for this long, the encoder caught up.
+const CAUGHT_UP_AFTER: Duration = Duration::from_secs(2);
+
+fn smooth(average: Option<f64>, sample: f64) -> f64 {
+    match average {
+        Some(average) => average + (sample - average) * SMOOTHING,
+        None => sample,
+    }
+}
+
+#[derive(Default)]
+struct Estimates {
+    last_arrival: Option<Instant>,
+    /// Recent average time between frames coming in, in seconds.
+    interval: Option<f64>,
+    last_push: Option<Instant>,
+    /// Whether another frame was waiting when the last one went to the encoder.
+    backlogged: bool,
+    /// Recent average time the encoder takes per frame, in seconds.
+    service: Option<f64>,
+    last_skip: Option<Instant>,
+    skipping: bool,
+}
+
+/// Skips frames the encoder can't start on before the next one comes in, so an
+/// overloaded encoder lowers the frame rate of the recording instead of falling further
+/// and further behind.
+///
+/// Frames wait for the encoder in the [`QUEUE_NAME`] queue. From how far apart the queue
+/// hands them on while it has more, and how often they arrive, a new frame is skipped if
+/// what the encoder still has to do takes longer than until the next one. Skipping
+/// starts and stops are posted as `lensing-deadline` element messages with `skipping`
+/// and the `skipped` total.
+pub struct Deadlines {
+    skipped: Arc<AtomicU64>,
+}
+
+impl Deadlines {
+    /// `None` if `pipeline` has no encoder queue.
+    pub fn attach(pipeline: &Pipeline) -> Option<Self> {
+        let queue = pipeline.by_name(QUEUE_NAME)?;
+        let (sink, src) = (queue.static_pad("sink")?, queue.static_pad("src")?);
+        let estimates = Arc::new(Mutex::new(Estimates::default()));
+        let skipped = Arc::new(AtomicU64::new(0));
+
+        let src_estimates = estimates.clone();
+        let src_queue = queue.clone();
+        src.add_probe(PadProbeType::BUFFER, move |_, _| {
+            let now = Instant::now();
+            let mut e = src_estimates.lock().unwrap();
+            if let Some(last) = e.last_push {
+                let gap = now.duration_since(last).as_secs_f64();
+                // frames handed on back to back are as far apart as the encoder is slow;
+                // otherwise it was done before, and may have got faster
+                if e.backlogged || e.service.is_some_and(|s| gap < s) {
+                    e.service = Some(smooth(e.service, gap));
+                }
+            }
+            e.last_push = Some(now);
+            e.backlogged = level(&src_queue) > 0;
+            PadProbeReturn::Ok
+        });
+
+        let sink_skipped = skipped.clone();
+        sink.add_probe(PadProbeType::BUFFER, move |_, _| {
+            let now = Instant::now();
+            let mut e = estimates.lock().unwrap();
+            if let Some(last) = e.last_arrival.replace(now) {
+                e.interval = Some(smooth(e.interval, now.duration_since(last).as_secs_f64()));
+            }
+            let (Some(interval), Some(service), Some(last_push)) =
+                (e.interval, e.service, e.last_push)
+            else {
+                return PadProbeReturn::Ok;
+            };
+
+            let current = (service - now.duration_since(last_push).as_secs_f64()).max(0.0);
+            let busy_for = current + level(&queue) as f64 * service;
+            if busy_for >= interval {
+                let skipped = sink_skipped.fetch_add(1, Ordering::Relaxed) + 1;
+                e.last_skip = Some(now);
+                if !e.skipping {
+                    e.skipping = true;
+                    report(&queue, true, skipped, service);
+                }
+                return PadProbeReturn::Drop;
+            }
+            let caught_up = e
+                .last_skip
+                .is_none_or(|t| now.duration_since(t) >= CAUGHT_UP_AFTER);
+            if e.skipping && caught_up {
+                e.skipping = false;
+                report(&queue, false, sink_skipped.load(Ordering::Relaxed), service);
+            }
+            PadProbeReturn::Ok
+        });
+
+        Some(Self { skipped })
+    }
+
+    /// Print how many frames were skipped, if any.
+    pub fn summary(&self) {
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        if skipped > 0 {
+            println!("Skipped {skipped} frames the encoder had no time for");
+        }
+    }
+}
+
+fn level(queue: &Element) -> u32 {
+    queue.property::<u32>("current-level-buffers")
+}
+
+fn report(queue: &Element, skipping: bool, skipped: u64, service: f64) {
+    if skipping {
+        println!(
+            "The encoder can't keep up, skipping frames (about {:.0} fps)",
+            1.0 / service.max(0.001)
+        );
+    } else {
+        println!("The encoder caught up");
+    }
+    let structure = Structure::builder("lensing-deadline")
+        .field("skipping", skipping)
+        .field("skipped", skipped)
+        .build();
+    let _ = queue.post_message(
+        gstreamer::message::Element::builder(structure)
+            .src(queue)
+            .build(),
+    );
+}
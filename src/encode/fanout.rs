@@ -0,0 +1,321 @@
+use std::os::fd::RawFd;
+
+use gstreamer::{
+    glib, prelude::*, Bin, Element, EventType, EventView, GhostPad, Pad, PadProbeData,
+    PadProbeReturn, PadProbeType, Pipeline, Structure,
+};
+use gstreamer_video::VideoInfo;
+
+use crate::{
+    audio::AudioConfig,
+    preset::Tuning,
+    sink::{SinkConfig, SinkKind, SinkSpec},
+    v4l2_loopback,
+};
+
+use super::{hud, mux_desc, video_chain, writer};
+
+/// Node names are for scripts, the description is what apps show.
+fn node_name(description: &str) -> String {
+    description
+        .to_lowercase()
+        .replace(|c: char| !c.is_alphanumeric(), "-")
+}
+
+/// Rate of webcam sinks without `fps=`.
+const WEBCAM_FPS: u32 = 30;
+
+/// Set the right and bottom edges of a sink's videocrop from the size of the frames it
+/// gets, which is only known once the capture has negotiated its caps.
+fn fit_crop(id: u32, crop: &Element, config: SinkConfig) {
+    let pad = crop.static_pad("sink").expect("videocrop sink pad");
+    let crop = crop.downgrade();
+    pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(PadProbeData::Event(ref event)) = info.data else {
+            return PadProbeReturn::Ok;
+        };
+        let EventView::Caps(caps) = event.view() else {
+            return PadProbeReturn::Ok;
+        };
+        let (Some(crop), Ok(video)) = (crop.upgrade(), VideoInfo::from_caps(caps.caps())) else {
+            return PadProbeReturn::Ok;
+        };
+        let size = (video.width(), video.height());
+        let (right, bottom) = config.crop_edges(size).unwrap_or_else(|| {
+            println!(
+                "Sink {id}: the crop doesn't fit the {}x{} capture, cropping to its edges",
+                size.0, size.1
+            );
+            (0, 0)
+        });
+        crop.set_property("right", right as i32);
+        crop.set_property("bottom", bottom as i32);
+        PadProbeReturn::Ok
+    });
+}
+
+/// A sink hanging off the capture tee.
+struct Branch {
+    id: u32,
+    spec: SinkSpec,
+    bin: Bin,
+    /// The tee and the request pad this branch is linked to, for video and possibly audio.
+    tee_pads: Vec<(Element, Pad)>,
+}
+
+/// One capture stream, teed into every sink with its own crop/scale/filters.
+///
+/// Each sink lives in its own bin on a request pad of the tee, so sinks can be
+/// attached and detached while the pipeline is playing without the capture
+/// stream being renegotiated.
+pub struct Fanout {
+    pub pipeline: Pipeline,
+    tuning: Tuning,
+    video: Element,
+    audio: Option<Element>,
+    branches: Vec<Branch>,
+    next_id: u32,
+}
+
+impl Fanout {
+    /// Build the capture side of the pipeline. It has no sinks until [`Fanout::attach`] is called.
+    pub fn new(
+        fd: RawFd,
+        node_id: u32,
+        audio: Option<&AudioConfig>,
+        tuning: &Tuning,
+    ) -> Result<Self, glib::Error> {
+        let mut desc = format!(
+            "{} ! tee name=capture allow-not-linked=true",
+            tuning.pipewiresrc_desc(fd, node_id)
+        );
+        if let Some(audio) = audio {
+            desc.push_str(&format!(
+                " {} ! tee name=audio allow-not-linked=true",
+                audio.encoded_chain()
+            ));
+        }
+
+        let pipeline = gstreamer::parse_launch(&desc)?
+            .downcast::<Pipeline>()
+            .expect("pipeline");
+        super::pin_threads(&pipeline, tuning);
+
+        Ok(Self {
+            video: pipeline.by_name("capture").expect("capture tee"),
+            audio: pipeline.by_name("audio"),
+            pipeline,
+            tuning: *tuning,
+            branches: vec![],
+            next_id: 0,
+        })
+    }
+
+    /// Add a sink, returning its id.
+    pub fn attach(&mut self, spec: SinkSpec) -> Result<u32, String> {
+        let id = self.next_id;
+        let processing = spec.config.branch_desc();
+
+        let desc = match spec.kind {
+            SinkKind::File(ref location) => {
+                let tuning = spec.config.encoding(&self.tuning);
+                let chain = video_chain(false, &tuning);
+                println!("Sink {id} ({location}) encoder path: {:?}", chain.path);
+                let mut desc = format!(
+                    "queue name=video{processing} ! {} ! {} ! {}",
+                    chain.desc,
+                    mux_desc(location, &tuning),
+                    writer::sink_desc(location, &tuning),
+                );
+                if self.audio.is_some() {
+                    desc.push_str(&format!(" {} name=audio ! mux.", self.tuning.queue_desc()));
+                }
+                desc
+            }
+            SinkKind::Mirror => format!(
+                "queue name=video{processing} ! videoconvert{} ! autovideosink name=sink sync=false",
+                hud::desc(spec.config.hud)
+            ),
+            SinkKind::Webcam { ref device, format } => {
+                let device = match device {
+                    Some(device) => device.clone(),
+                    None => v4l2_loopback::find().ok_or(
+                        "no free v4l2loopback device, load one with \
+                         `modprobe v4l2loopback exclusive_caps=1`",
+                    )?,
+                };
+                println!("Sink {id} feeds the webcam {device}");
+                // calls expect a camera to keep a steady rate, so a still screen repeats
+                // frames rather than stalling the video
+                let fps = spec.config.max_fps.unwrap_or(WEBCAM_FPS);
+                format!(
+                    "queue name=video{processing} ! videorate ! video/x-raw,framerate={fps}/1 ! videoconvert ! video/x-raw,format={} ! v4l2sink name=sink device=\"{device}\" sync=false",
+                    format.caps_name()
+                )
+            }
+            SinkKind::Node(ref name) => {
+                println!("Sink {id} publishes the PipeWire source {}", node_name(name));
+                format!(
+                    "queue name=video{processing} ! videoconvert ! pipewiresink name=sink mode=provide sync=false"
+                )
+            }
+        };
+
+        let bin = gstreamer::parse_bin_from_description(&desc, false).map_err(|e| e.to_string())?;
+        if let SinkKind::Node(ref name) = spec.kind {
+            let props = Structure::builder("props")
+                .field("media.class", "Video/Source")
+                .field("node.name", node_name(name))
+                .field("node.description", name.as_str())
+                .build();
+            let sink = bin.by_name("sink").expect("pipewiresink");
+            sink.set_property("stream-properties", props);
+        }
+        if let SinkKind::File(ref location) = spec.kind {
+            let tuning = spec.config.encoding(&self.tuning);
+            writer::attach(&bin, location, &tuning).map_err(|e| e.to_string())?;
+        }
+        self.pipeline.add(&bin).map_err(|e| e.to_string())?;
+
+        let mut tee_pads = vec![];
+        if let Err(e) = self.link(id, &spec, &bin, &mut tee_pads) {
+            // don't leave a half linked bin in the playing pipeline
+            for (tee, tee_pad) in tee_pads {
+                if let Some(peer) = tee_pad.peer() {
+                    let _ = tee_pad.unlink(&peer);
+                }
+                tee.release_request_pad(&tee_pad);
+            }
+            let _ = bin.set_state(gstreamer::State::Null);
+            let _ = self.pipeline.remove(&bin);
+            return Err(e);
+        }
+
+        self.next_id += 1;
+        self.branches.push(Branch {
+            id,
+            spec,
+            bin,
+            tee_pads,
+        });
+        Ok(id)
+    }
+
+    /// Link a sink's bin to the tees and start it. `tee_pads` gets the request pads
+    /// taken so far, also if linking fails.
+    fn link(
+        &self,
+        id: u32,
+        spec: &SinkSpec,
+        bin: &Bin,
+        tee_pads: &mut Vec<(Element, Pad)>,
+    ) -> Result<(), String> {
+        for (name, tee) in [("video", Some(&self.video)), ("audio", self.audio.as_ref())] {
+            let (Some(tee), Some(input)) = (tee, bin.by_name(name)) else {
+                continue;
+            };
+            let target = input.static_pad("sink").expect("queue sink pad");
+            let ghost = GhostPad::with_target(Some(name), &target).map_err(|e| e.to_string())?;
+            bin.add_pad(&ghost).map_err(|e| e.to_string())?;
+
+            let tee_pad = tee.request_pad_simple("src_%u").expect("tee src pad");
+            tee_pads.push((tee.clone(), tee_pad.clone()));
+            tee_pad.link(&ghost).map_err(|e| format!("{e:?}"))?;
+        }
+
+        if let Some(crop) = bin.by_name("crop") {
+            fit_crop(id, &crop, spec.config.clone());
+        }
+
+        if let (Some(overlay), Some(source)) = (bin.by_name("hud"), self.source_pad()) {
+            hud::attach(&overlay, source, "portal");
+        }
+
+        bin.sync_state_with_parent().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Remove a sink. A file sink gets EOS first so that its muxer can finalize the file;
+    /// the bin is torn down once the EOS has reached the end of the branch.
+    pub fn detach(&mut self, id: u32) -> Result<(), String> {
+        let index = self
+            .branches
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or_else(|| format!("no sink {id}"))?;
+        if self.branches.len() == 1 {
+            // without any sinks the pipeline would never see EOS again
+            return Err("cannot detach the last sink, stop the session instead".into());
+        }
+        let branch = self.branches.remove(index);
+
+        let sink_pad = branch
+            .bin
+            .by_name("sink")
+            .or_else(|| branch.bin.by_name(writer::SINK_NAME))
+            .and_then(|s| s.static_pad("sink"))
+            .expect("sink pad");
+        let pipeline = self.pipeline.clone();
+        let bin = branch.bin.clone();
+        sink_pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            let Some(PadProbeData::Event(ref event)) = info.data else {
+                return PadProbeReturn::Ok;
+            };
+            if event.type_() != EventType::Eos {
+                return PadProbeReturn::Ok;
+            }
+
+            // state changes can't happen from the streaming thread; a write buffer is
+            // flushed to the file once the bin is gone
+            let (pipeline, bin) = (pipeline.clone(), bin.clone());
+            std::thread::spawn(move || {
+                let _ = bin.set_state(gstreamer::State::Null);
+                let _ = pipeline.remove(&bin);
+            });
+
+            // the rest of the pipeline is still running, don't let it count this as EOS
+            PadProbeReturn::Drop
+        });
+
+        for (tee, tee_pad) in branch.tee_pads {
+            tee_pad.add_probe(PadProbeType::IDLE, move |pad, _| {
+                if let Some(peer) = pad.peer() {
+                    let _ = pad.unlink(&peer);
+                    peer.send_event(gstreamer::event::Eos::new());
+                }
+                tee.release_request_pad(pad);
+                PadProbeReturn::Remove
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Show or hide the HUD of a preview; returns whether it is shown now.
+    pub fn toggle_hud(&self, id: u32) -> Result<bool, String> {
+        let branch = self
+            .branches
+            .iter()
+            .find(|b| b.id == id)
+            .ok_or_else(|| format!("no sink {id}"))?;
+        let overlay = branch
+            .bin
+            .by_name("hud")
+            .ok_or_else(|| format!("sink {id} is not a preview"))?;
+        Ok(hud::toggle(&overlay))
+    }
+
+    /// Where the capture's caps can be read from.
+    fn source_pad(&self) -> Option<Pad> {
+        self.pipeline
+            .iterate_recurse()
+            .into_iter()
+            .flatten()
+            .find(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"))
+            .and_then(|e| e.static_pad("src"))
+    }
+
+    pub fn sinks(&self) -> impl Iterator<Item = (u32, &SinkSpec)> {
+        self.branches.iter().map(|b| (b.id, &b.spec))
+    }
+}
@@ -0,0 +1,220 @@
+use std::os::fd::AsRawFd;
+
+use gstreamer::{glib, prelude::*, Buffer, Caps, CapsFeatures, Memory, Pipeline};
+use gstreamer_allocators::{prelude::*, DmaBufAllocator};
+use gstreamer_app::AppSrc;
+use gstreamer_video::{VideoFormat, VideoFrameFlags, VideoMeta};
+
+use super::roi::RoiHints;
+use crate::{
+    backend::region::PixelRect,
+    capture_manager::OwnedFrame,
+    pw_capture::{CursorMeta, PipewireFrameFormat},
+};
+
+/// Name of the appsrc in pipelines made by [`GstBridge::launch`].
+pub const APPSRC_NAME: &str = "lensing";
+
+/// Frames appsrc may hold before new ones are dropped, downstream is behind.
+const QUEUED_FRAMES: u64 = 2;
+
+pub(super) const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// The GStreamer format with the byte order of a DRM fourcc.
+fn video_format(fourcc: u32) -> Option<VideoFormat> {
+    match fourcc {
+        // ARGB8888
+        0x34325241 => Some(VideoFormat::Bgra),
+        // ABGR8888
+        0x34324241 => Some(VideoFormat::Rgba),
+        // XRGB8888
+        0x34325258 => Some(VideoFormat::Bgrx),
+        // XBGR8888
+        0x34324258 => Some(VideoFormat::Rgbx),
+        _ => None,
+    }
+}
+
+/// The DRM fourcc with the byte order of a GStreamer format, the other way round from
+/// [`video_format`].
+pub(super) fn drm_fourcc(format: VideoFormat) -> Option<u32> {
+    [0x34325241, 0x34324241, 0x34325258, 0x34324258]
+        .into_iter()
+        .find(|fourcc| video_format(*fourcc) == Some(format))
+}
+
+/// Pushes captured frames into an `appsrc`, so any GStreamer pipeline can take them.
+///
+/// Dmabufs are wrapped with `GstDmaBufAllocator` and described by a `GstVideoMeta`,
+/// so downstream elements that take `memory:DMABuf` get them without a copy. They
+/// share the producer's buffer, see [`OwnedFrame`].
+pub struct GstBridge {
+    appsrc: AppSrc,
+    allocator: DmaBufAllocator,
+    /// What the caps were last set for, and whether the frames were dmabufs.
+    caps_for: Option<(PipewireFrameFormat, bool)>,
+    roi: Option<RoiHints>,
+}
+
+impl GstBridge {
+    /// Push into `appsrc`, which is made live and timestamps frames as they come.
+    pub fn new(appsrc: AppSrc) -> Self {
+        appsrc.set_is_live(true);
+        appsrc.set_format(gstreamer::Format::Time);
+        appsrc.set_do_timestamp(true);
+        Self {
+            appsrc,
+            allocator: DmaBufAllocator::new(),
+            caps_for: None,
+            roi: None,
+        }
+    }
+
+    /// Hint the pointer and damage of frames pushed with [`GstBridge::push_hinted`] to
+    /// the encoder, see [`RoiHints`].
+    pub fn set_roi(&mut self, roi: Option<RoiHints>) {
+        self.roi = roi;
+    }
+
+    /// A pipeline of `desc` in gst-launch syntax fed by a bridge, e.g.
+    /// `vapostproc ! vah264enc ! h264parse ! mp4mux ! filesink location=out.mp4`.
+    pub fn launch(desc: &str) -> Result<(Pipeline, GstBridge), glib::Error> {
+        let pipeline = gstreamer::parse_launch(&format!("appsrc name={APPSRC_NAME} ! {desc}"))?
+            .downcast::<Pipeline>()
+            .expect("pipeline");
+        let appsrc = pipeline
+            .by_name(APPSRC_NAME)
+            .and_then(|e| e.downcast::<AppSrc>().ok())
+            .expect("appsrc");
+        Ok((pipeline, GstBridge::new(appsrc)))
+    }
+
+    /// Push one frame, or drop it if downstream hasn't taken the ones before.
+    pub fn push(&mut self, format: &PipewireFrameFormat, frame: OwnedFrame) -> Result<(), String> {
+        self.push_hinted(format, frame, None, None)
+    }
+
+    /// Like [`GstBridge::push`], with the pointer and damage of the frame as regions of
+    /// interest if [`GstBridge::set_roi`] asked for them, e.g. for a
+    /// [`crate::capture_thread::CapturedFrame`].
+    pub fn push_hinted(
+        &mut self,
+        format: &PipewireFrameFormat,
+        frame: OwnedFrame,
+        cursor: Option<&CursorMeta>,
+        damage: Option<&[PixelRect]>,
+    ) -> Result<(), String> {
+        let frame_size = format.width as u64 * format.height as u64 * 4;
+        if self.appsrc.current_level_bytes() >= QUEUED_FRAMES * frame_size {
+            return Ok(());
+        }
+
+        let dmabuf = matches!(frame, OwnedFrame::Dmabuf { .. });
+        if self.caps_for.is_none_or(|(f, d)| {
+            d != dmabuf
+                || (f.width, f.height, f.format, f.modifier)
+                    != (format.width, format.height, format.format, format.modifier)
+        }) {
+            self.appsrc.set_caps(Some(&caps(format, dmabuf)?));
+            self.caps_for = Some((*format, dmabuf));
+        }
+
+        let mut buffer = match frame {
+            OwnedFrame::Dmabuf { planes } => {
+                let first = planes.first().ok_or("dmabuf without planes")?;
+                // the formats have one color plane, any further ones are the modifier's
+                // business and travel along as memories of their own
+                let (offset, stride) = (first.offset as usize, first.stride);
+                let mut buffer = Buffer::new();
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    for plane in planes {
+                        let size = dmabuf_size(plane.fd.as_raw_fd())
+                            .map_err(|e| format!("dmabuf size: {e}"))?;
+                        let memory: Memory = unsafe { self.allocator.alloc(plane.fd, size) }
+                            .map_err(|e| format!("wrapping the dmabuf: {e}"))?;
+                        buffer.append_memory(memory);
+                    }
+                    add_video_meta(buffer, format, offset, stride)?;
+                }
+                buffer
+            }
+            OwnedFrame::Shm { data, stride } => {
+                let mut buffer = Buffer::from_mut_slice(data);
+                add_video_meta(buffer.get_mut().unwrap(), format, 0, stride)?;
+                buffer
+            }
+        };
+
+        if let Some(roi) = self.roi.as_ref() {
+            roi.add(buffer.get_mut().unwrap(), format, cursor, damage);
+        }
+
+        self.appsrc
+            .push_buffer(buffer)
+            .map(|_| ())
+            .map_err(|e| format!("appsrc: {e:?}"))
+    }
+
+    /// No more frames; downstream gets EOS once it took the queued ones.
+    pub fn end(&self) {
+        let _ = self.appsrc.end_of_stream();
+    }
+}
+
+/// Caps for frames of `format`. Linear dmabufs get the classic `memory:DMABuf` caps
+/// every dmabuf consumer knows, other modifiers need `DMA_DRM` (GStreamer 1.24).
+fn caps(format: &PipewireFrameFormat, dmabuf: bool) -> Result<Caps, String> {
+    let video_format = video_format(format.format)
+        .ok_or_else(|| format!("can't describe format {:#x}", format.format))?;
+    let mut builder = Caps::builder("video/x-raw")
+        .field("width", format.width as i32)
+        .field("height", format.height as i32)
+        .field("framerate", gstreamer::Fraction::new(0, 1));
+    if dmabuf && format.modifier != DRM_FORMAT_MOD_LINEAR {
+        let fourcc = String::from_utf8_lossy(&format.format.to_le_bytes()).into_owned();
+        builder = builder
+            .field("format", "DMA_DRM")
+            .field("drm-format", format!("{fourcc}:{:#018x}", format.modifier));
+    } else {
+        builder = builder.field("format", video_format.to_str());
+    }
+    let mut caps = builder.build();
+    if dmabuf {
+        caps.get_mut()
+            .unwrap()
+            .set_features(0, Some(CapsFeatures::new(&["memory:DMABuf"])));
+    }
+    Ok(caps)
+}
+
+fn add_video_meta(
+    buffer: &mut gstreamer::BufferRef,
+    format: &PipewireFrameFormat,
+    offset: usize,
+    stride: i32,
+) -> Result<(), String> {
+    let video_format = video_format(format.format)
+        .ok_or_else(|| format!("can't describe format {:#x}", format.format))?;
+    VideoMeta::add_full(
+        buffer,
+        VideoFrameFlags::empty(),
+        video_format,
+        format.width,
+        format.height,
+        &[offset],
+        &[stride],
+    )
+    .map(|_| ())
+    .map_err(|e| format!("video meta: {e}"))
+}
+
+/// Dmabufs don't say how big they are other than by seeking to the end.
+fn dmabuf_size(fd: i32) -> std::io::Result<usize> {
+    let size = unsafe { libc::lseek(fd, 0, libc::SEEK_END) };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    unsafe { libc::lseek(fd, 0, libc::SEEK_SET) };
+    Ok(size as usize)
+}
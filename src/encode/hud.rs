@@ -0,0 +1,127 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer::{prelude::*, Element, EventView, Pad, PadProbeData, PadProbeReturn, PadProbeType};
+
+/// How often the numbers on the HUD change.
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// A textoverlay for the stats, to go right before a preview sink.
+pub fn desc(visible: bool) -> String {
+    format!(
+        " ! textoverlay name=hud halignment=left valignment=top font-desc=\"Monospace 10\" shaded-background=true silent={}",
+        !visible
+    )
+}
+
+#[derive(Default)]
+struct Counters {
+    frames: u32,
+    latency: Duration,
+    since: Option<Instant>,
+}
+
+/// Show the backend, the format negotiated with the capture source, and the rate and
+/// latency frames reach the overlay at. `source` is the capture element's src pad.
+///
+/// Pressing `h` in the preview window shows and hides the HUD.
+pub fn attach(overlay: &Element, source: Pad, backend: &'static str) {
+    let Some(sink_pad) = overlay.static_pad("video_sink") else {
+        return;
+    };
+
+    let counters = Arc::new(Mutex::new(Counters::default()));
+    let weak_overlay = overlay.downgrade();
+    sink_pad.add_probe(PadProbeType::BUFFER, move |_, info| {
+        let (Some(overlay), Some(PadProbeData::Buffer(buffer))) =
+            (weak_overlay.upgrade(), &info.data)
+        else {
+            return PadProbeReturn::Ok;
+        };
+
+        // live capture is timestamped with the running time it was taken at
+        let latency = overlay
+            .current_running_time()
+            .zip(buffer.pts())
+            .map(|(now, pts)| Duration::from_nanos(now.nseconds().saturating_sub(pts.nseconds())))
+            .unwrap_or_default();
+
+        let mut counters = counters.lock().unwrap();
+        counters.frames += 1;
+        counters.latency = counters.latency.max(latency);
+        let since = *counters.since.get_or_insert_with(Instant::now);
+        let elapsed = since.elapsed();
+        if elapsed < REFRESH {
+            return PadProbeReturn::Ok;
+        }
+
+        let fps = counters.frames as f64 / elapsed.as_secs_f64();
+        let text = format!(
+            "backend  {backend}\nformat   {}\nfps      {fps:.1}\nlatency  {:.1} ms (max)",
+            describe_caps(&source),
+            counters.latency.as_secs_f64() * 1000.0
+        );
+        *counters = Counters {
+            since: Some(Instant::now()),
+            ..Default::default()
+        };
+        drop(counters);
+
+        overlay.set_property("text", text);
+        PadProbeReturn::Ok
+    });
+
+    let Some(src_pad) = overlay.static_pad("src") else {
+        return;
+    };
+    let weak_overlay = overlay.downgrade();
+    src_pad.add_probe(PadProbeType::EVENT_UPSTREAM, move |_, info| {
+        let (Some(overlay), Some(PadProbeData::Event(event))) =
+            (weak_overlay.upgrade(), &info.data)
+        else {
+            return PadProbeReturn::Ok;
+        };
+        if let EventView::Navigation(nav) = event.view() {
+            let key_press = nav.structure().is_some_and(|s| {
+                s.get::<&str>("event").ok() == Some("key-press")
+                    && s.get::<&str>("key").ok() == Some("h")
+            });
+            if key_press {
+                toggle(&overlay);
+            }
+        }
+        PadProbeReturn::Ok
+    });
+}
+
+/// Show the HUD if it was hidden and the other way round; returns whether it is shown now.
+pub fn toggle(overlay: &Element) -> bool {
+    let silent = overlay.property::<bool>("silent");
+    overlay.set_property("silent", !silent);
+    silent
+}
+
+/// The caps as they matter for a bug report: memory, format and DRM modifier.
+fn describe_caps(pad: &Pad) -> String {
+    let Some(caps) = pad.current_caps() else {
+        return "not negotiated".into();
+    };
+    let (Some(s), Some(features)) = (caps.structure(0), caps.features(0)) else {
+        return caps.to_string();
+    };
+
+    let mut desc = s.get::<&str>("format").unwrap_or("?").to_string();
+    // DMA_DRM caps carry the fourcc and modifier separately
+    if let Ok(drm) = s.get::<&str>("drm-format") {
+        desc.push_str(&format!(" {drm}"));
+    }
+    if let (Ok(w), Ok(h)) = (s.get::<i32>("width"), s.get::<i32>("height")) {
+        desc.push_str(&format!(" {w}x{h}"));
+    }
+    if !features.is_any() && features.size() > 0 {
+        desc.push_str(&format!(" ({features})"));
+    }
+    desc
+}
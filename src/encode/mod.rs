@@ -0,0 +1,814 @@
+use std::{
+    io::BufRead,
+    os::fd::RawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Once,
+    },
+};
+
+use gstreamer::{
+    glib, prelude::*, BusSyncReply, ElementFactory, MessageView, PadDirection, PadProbeReturn,
+    PadProbeType, Pipeline, StreamStatusType,
+};
+
+use crate::{audio::AudioConfig, crash_report, preset::Tuning};
+
+pub mod bitrate;
+pub mod blank;
+pub mod color;
+pub mod deadline;
+pub mod fanout;
+pub mod gst_bridge;
+pub mod hud;
+pub mod pacing;
+pub mod pause;
+pub mod prerecord;
+pub mod raw;
+pub mod roi;
+pub mod scene_cut;
+pub mod screenshot;
+pub mod stereo;
+pub mod stream;
+pub mod view;
+pub mod window;
+pub mod writer;
+
+/// How frames reach the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderPath {
+    /// The encoder imports the captured dmabufs directly.
+    DmaBuf,
+    /// Frames are uploaded into VA surfaces by the VA post-processor.
+    VaSurface,
+    /// The VA post-processor imports the dmabufs and converts them into VA surfaces,
+    /// so frames never leave the GPU.
+    DmaBufVaSurface,
+    /// Frames are uploaded to CUDA memory and converted there, for NVENC.
+    Cuda,
+    /// Frames are uploaded into GL textures and converted by a shader, for NVENC,
+    /// which maps them into CUDA itself.
+    Gl,
+    /// Frames go through system memory and videoconvert.
+    System,
+}
+
+/// Which encoders lossy recordings may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderBackend {
+    /// VA-API, then NVENC, then software.
+    Auto,
+    /// NVENC first, for NVIDIA cards that also expose a VA-API driver.
+    Nvenc,
+    /// VA-API or software, never NVENC.
+    Va,
+    /// Software only, e.g. to rule out driver bugs.
+    Software,
+}
+
+/// A gst-launch style fragment that takes raw video and outputs parsed, encoded video.
+#[derive(Debug, Clone)]
+pub struct EncoderChain {
+    pub path: EncoderPath,
+    pub desc: String,
+}
+
+fn sink_accepts(factory_name: &str, feature: &str) -> bool {
+    let Some(factory) = ElementFactory::find(factory_name) else {
+        return false;
+    };
+
+    factory
+        .static_pad_templates()
+        .iter()
+        .filter(|t| t.direction() == PadDirection::Sink)
+        .any(|t| {
+            t.caps()
+                .iter_with_features()
+                .any(|(_, features)| features.contains(feature))
+        })
+}
+
+pub(crate) fn has_element(factory_name: &str) -> bool {
+    ElementFactory::find(factory_name).is_some()
+}
+
+/// Video codec for lossy recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    /// H.265, for 4K and up where H.264 bitrates get out of hand.
+    Hevc,
+    /// Royalty free, for uploading to sites that prefer it.
+    Vp9,
+}
+
+/// File format of recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// Survives crashes, takes every codec.
+    Matroska,
+    /// Plays in more places. Written fragmented, so a crash loses seconds, not the file.
+    Mp4,
+    /// zstd-compressed raw frames, for when encoding can't keep up; see [`raw`].
+    Raw,
+}
+
+impl Container {
+    /// The container a file name asks for, Matroska unless it ends in `.mp4` or `.lraw`.
+    pub fn from_location(location: &str) -> Self {
+        let ext = location.rsplit_once('.').map(|(_, ext)| ext);
+        match ext {
+            Some(ext) if ext.eq_ignore_ascii_case("mp4") => Container::Mp4,
+            Some(ext) if ext.eq_ignore_ascii_case("lraw") => Container::Raw,
+            _ => Container::Matroska,
+        }
+    }
+
+    /// What a recording to `location` is written as.
+    pub fn of(location: &str, tuning: &Tuning) -> Self {
+        tuning
+            .container
+            .unwrap_or_else(|| Container::from_location(location))
+    }
+}
+
+/// The muxer for a recording to `location`, as a gst-launch fragment with the name
+/// `mux`.
+pub fn mux_desc(location: &str, tuning: &Tuning) -> &'static str {
+    match Container::of(location, tuning) {
+        Container::Mp4 if tuning.lossless == Some(LosslessCodec::Ffv1) => {
+            println!("FFV1 doesn't go into MP4, writing Matroska instead");
+            "matroskamux name=mux"
+        }
+        Container::Mp4 => "mp4mux name=mux fragment-duration=1000",
+        Container::Matroska => "matroskamux name=mux",
+        Container::Raw => {
+            println!("Only single outputs and windows are recorded raw, writing Matroska instead");
+            "matroskamux name=mux"
+        }
+    }
+}
+
+/// Element names for one codec, from cheapest to most expensive to run.
+struct CodecElements {
+    va: &'static str,
+    vaapi: &'static str,
+    /// Empty if NVENC can't do the codec.
+    nvenc: &'static str,
+    software: &'static str,
+    /// Parser plus the caps the muxer needs to tag the track correctly.
+    parse: &'static str,
+}
+
+impl VideoCodec {
+    fn elements(self) -> CodecElements {
+        match self {
+            VideoCodec::H264 => CodecElements {
+                va: "vah264enc",
+                vaapi: "vaapih264enc",
+                nvenc: "nvh264enc",
+                software: "x264enc",
+                parse: "h264parse",
+            },
+            VideoCodec::Hevc => CodecElements {
+                va: "vah265enc",
+                vaapi: "vaapih265enc",
+                nvenc: "nvh265enc",
+                software: "x265enc",
+                parse: "h265parse ! video/x-h265,stream-format=hvc1,alignment=au",
+            },
+            VideoCodec::Vp9 => CodecElements {
+                va: "vavp9enc",
+                vaapi: "vaapivp9enc",
+                nvenc: "",
+                software: "vp9enc",
+                parse: "vp9parse",
+            },
+        }
+    }
+}
+
+/// Encoder for lossless recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LosslessCodec {
+    /// Smaller files, but fewer players understand it.
+    Ffv1,
+    /// H.264 at quantizer 0, plays nearly everywhere.
+    X264,
+}
+
+/// Pick the encoder chain for the given tuning.
+/// `dmabuf_input` tells whether upstream is able to produce `memory:DMABuf` caps, see
+/// [`source_offers_dmabuf`].
+pub fn video_chain(dmabuf_input: bool, tuning: &Tuning) -> EncoderChain {
+    let chain = if let Some(codec) = tuning.lossless {
+        lossless_chain(codec)
+    } else if tuning.visually_lossless {
+        visually_lossless_chain(tuning.codec)
+    } else {
+        lossy_chain(tuning.codec, dmabuf_input, tuning)
+    };
+    crash_report::note("encoder", &chain.desc);
+    chain
+}
+
+/// How long [`source_offers_dmabuf`] waits for the first frame.
+const DMABUF_PROBE_TIMEOUT: gstreamer::ClockTime = gstreamer::ClockTime::from_seconds(2);
+
+/// Whether the capture of `node_id` negotiates `memory:DMABuf` frames for the chain of
+/// `tuning`. That depends on the compositor and the drivers, so the source is briefly
+/// played into a fakesink that only takes dmabufs.
+pub fn source_offers_dmabuf(fd: RawFd, node_id: u32, tuning: &Tuning) -> bool {
+    // packing stereo needs the frames in system memory, and the other chains never import
+    if tuning.stereo.is_some()
+        || tuning.lossless.is_some()
+        || tuning.visually_lossless
+        || tuning.encoder == EncoderBackend::Software
+    {
+        return false;
+    }
+
+    let desc = format!(
+        "{} ! video/x-raw(memory:DMABuf) ! fakesink",
+        tuning.pipewiresrc_desc(fd, node_id),
+    );
+    let Ok(pipeline) = gstreamer::parse_launch(&desc) else {
+        return false;
+    };
+    let bus = pipeline.bus().expect("pipeline bus");
+    let negotiated = pipeline.set_state(gstreamer::State::Playing).is_ok()
+        && matches!(
+            bus.timed_pop_filtered(
+                DMABUF_PROBE_TIMEOUT,
+                &[
+                    gstreamer::MessageType::AsyncDone,
+                    gstreamer::MessageType::Error
+                ],
+            )
+            .as_ref()
+            .map(|m| m.view()),
+            Some(MessageView::AsyncDone(_))
+        );
+    let _ = pipeline.set_state(gstreamer::State::Null);
+
+    if !negotiated {
+        println!("The capture doesn't negotiate dmabufs, encoding from system memory");
+    }
+    negotiated
+}
+
+fn lossless_chain(codec: LosslessCodec) -> EncoderChain {
+    let ffv1 = codec == LosslessCodec::Ffv1 && has_element("avenc_ffv1");
+    if codec == LosslessCodec::Ffv1 && !ffv1 {
+        println!("avenc_ffv1 is not available, using lossless x264 instead");
+    }
+
+    // FFV1 keeps RGB as it is, so only x264 has a conversion to tag
+    let desc = if ffv1 {
+        "videoconvert ! avenc_ffv1".to_string()
+    } else {
+        format!(
+            "videoconvert ! {} ! x264enc pass=quant quantizer=0 speed-preset=ultrafast ! h264parse",
+            color::caps("video/x-raw")
+        )
+    };
+
+    EncoderChain {
+        path: EncoderPath::System,
+        desc,
+    }
+}
+
+/// Low enough quantizers that differences don't show, at a fraction of the lossless size.
+/// Hardware encoders are not consistent enough at this, so this is software only.
+fn visually_lossless_chain(codec: VideoCodec) -> EncoderChain {
+    let encoder = match codec {
+        VideoCodec::H264 => "x264enc pass=qual quantizer=14 speed-preset=veryfast ! h264parse",
+        VideoCodec::Hevc => {
+            "x265enc speed-preset=veryfast option-string=crf=16 ! h265parse ! video/x-h265,stream-format=hvc1,alignment=au"
+        }
+        VideoCodec::Vp9 => {
+            "vp9enc end-usage=q cq-level=12 deadline=1 cpu-used=4 row-mt=true ! vp9parse"
+        }
+    };
+
+    EncoderChain {
+        path: EncoderPath::System,
+        desc: format!("videoconvert ! {} ! {encoder}", color::caps("video/x-raw")),
+    }
+}
+
+/// Pick the cheapest encoder chain for `codec` available on this system.
+fn lossy_chain(codec: VideoCodec, dmabuf_input: bool, tuning: &Tuning) -> EncoderChain {
+    let names = codec.elements();
+    let parse = names.parse;
+
+    // VP9 has no B-frames to turn off
+    let (mut va, mut vaapi, mut nvenc) = if tuning.bframes || codec == VideoCodec::Vp9 {
+        (
+            names.va.to_string(),
+            names.vaapi.to_string(),
+            format!("{} preset=low-latency-hq zerolatency=true", names.nvenc),
+        )
+    } else {
+        (
+            format!("{} b-frames=0", names.va),
+            format!("{} max-bframes=0", names.vaapi),
+            format!(
+                "{} preset=low-latency-hq zerolatency=true bframes=0",
+                names.nvenc
+            ),
+        )
+    };
+    // x265's zerolatency tune already turns B-frames off
+    let mut software = match (codec, tuning.bframes) {
+        (VideoCodec::H264, false) => format!("{} tune=zerolatency bframes=0", names.software),
+        (VideoCodec::Vp9, _) => format!("{} deadline=1 cpu-used=8 row-mt=true", names.software),
+        _ => format!("{} tune=zerolatency", names.software),
+    };
+    if let Some(kbps) = tuning.bitrate {
+        // everything counts in kbit/s, except libvpx
+        let bitrate = format!(" bitrate={kbps}");
+        va.push_str(&bitrate);
+        vaapi.push_str(&bitrate);
+        nvenc.push_str(&bitrate);
+        match codec {
+            VideoCodec::Vp9 => {
+                software.push_str(&format!(" end-usage=cbr target-bitrate={}", kbps * 1000))
+            }
+            _ => software.push_str(&bitrate),
+        }
+    }
+    if let Some(gop) = tuning.gop {
+        va.push_str(&format!(" key-int-max={gop}"));
+        vaapi.push_str(&format!(" keyframe-period={gop}"));
+        nvenc.push_str(&format!(" gop-size={gop}"));
+        match codec {
+            VideoCodec::Vp9 => software.push_str(&format!(" keyframe-max-dist={gop}")),
+            _ => software.push_str(&format!(" key-int-max={gop}")),
+        }
+    }
+
+    let backend = tuning.encoder;
+    if backend == EncoderBackend::Nvenc {
+        if let Some(chain) = nvenc_chain(names.nvenc, &nvenc, parse) {
+            return chain;
+        }
+    }
+    if backend == EncoderBackend::Software {
+        return EncoderChain {
+            path: EncoderPath::System,
+            desc: format!(
+                "videoconvert ! {} ! {software} ! {parse}",
+                color::caps("video/x-raw")
+            ),
+        };
+    }
+
+    if dmabuf_input {
+        if sink_accepts(names.va, "memory:DMABuf") {
+            return EncoderChain {
+                path: EncoderPath::DmaBuf,
+                desc: format!("video/x-raw(memory:DMABuf) ! {va} ! {parse}"),
+            };
+        }
+        if sink_accepts(names.vaapi, "memory:DMABuf") {
+            return EncoderChain {
+                path: EncoderPath::DmaBuf,
+                desc: format!("video/x-raw(memory:DMABuf) ! {vaapi} ! {parse}"),
+            };
+        }
+    }
+
+    // the encoders want NV12, which RGB captures rarely are, so the post-processor
+    // converts on the GPU
+    if tuning.hw_encode && dmabuf_input {
+        if sink_accepts("vapostproc", "memory:DMABuf") && sink_accepts(names.va, "memory:VAMemory")
+        {
+            return EncoderChain {
+                path: EncoderPath::DmaBufVaSurface,
+                desc: format!(
+                    "video/x-raw(memory:DMABuf) ! vapostproc ! {} ! {va} ! {parse}",
+                    color::caps("video/x-raw(memory:VAMemory),format=NV12")
+                ),
+            };
+        }
+        if sink_accepts("vaapipostproc", "memory:DMABuf")
+            && sink_accepts(names.vaapi, "memory:VASurface")
+        {
+            return EncoderChain {
+                path: EncoderPath::DmaBufVaSurface,
+                desc: format!(
+                    "video/x-raw(memory:DMABuf) ! vaapipostproc ! {} ! {vaapi} ! {parse}",
+                    color::caps("video/x-raw(memory:VASurface),format=NV12")
+                ),
+            };
+        }
+    }
+
+    if has_element("vapostproc") && sink_accepts(names.va, "memory:VAMemory") {
+        return EncoderChain {
+            path: EncoderPath::VaSurface,
+            desc: format!(
+                "vapostproc ! {} ! {va} ! {parse}",
+                color::caps("video/x-raw(memory:VAMemory)")
+            ),
+        };
+    }
+
+    if has_element("vaapipostproc") && sink_accepts(names.vaapi, "memory:VASurface") {
+        return EncoderChain {
+            path: EncoderPath::VaSurface,
+            desc: format!(
+                "vaapipostproc ! {} ! {vaapi} ! {parse}",
+                color::caps("video/x-raw(memory:VASurface)")
+            ),
+        };
+    }
+
+    if backend == EncoderBackend::Auto {
+        if let Some(chain) = nvenc_chain(names.nvenc, &nvenc, parse) {
+            return chain;
+        }
+    }
+
+    if tuning.hw_encode || backend == EncoderBackend::Nvenc {
+        println!(
+            "No hardware encoder for {:?} is available, encoding with {} instead",
+            codec, names.software
+        );
+    }
+    EncoderChain {
+        path: EncoderPath::System,
+        desc: format!(
+            "videoconvert ! {} ! {software} ! {parse}",
+            color::caps("video/x-raw")
+        ),
+    }
+}
+
+/// NVENC fed from the GPU. NVIDIA's dmabuf import is too limited to rely on, so
+/// frames come in through system memory, but only the copy happens on the CPU: the
+/// RGB to NV12 conversion runs in CUDA, or in GL with older GStreamer, and the
+/// encoder maps either without another copy.
+fn nvenc_chain(factory: &str, nvenc: &str, parse: &str) -> Option<EncoderChain> {
+    if factory.is_empty() || !has_element(factory) {
+        return None;
+    }
+    if has_element("cudaupload")
+        && has_element("cudaconvert")
+        && sink_accepts(factory, "memory:CUDAMemory")
+    {
+        return Some(EncoderChain {
+            path: EncoderPath::Cuda,
+            desc: format!(
+                "cudaupload ! cudaconvert ! {} ! {nvenc} ! {parse}",
+                color::caps("video/x-raw(memory:CUDAMemory),format=NV12")
+            ),
+        });
+    }
+    if has_element("glupload")
+        && has_element("glcolorconvert")
+        && sink_accepts(factory, "memory:GLMemory")
+    {
+        return Some(EncoderChain {
+            path: EncoderPath::Gl,
+            desc: format!(
+                "glupload ! glcolorconvert ! {} ! {nvenc} ! {parse}",
+                color::caps("video/x-raw(memory:GLMemory),format=NV12")
+            ),
+        });
+    }
+    Some(EncoderChain {
+        path: EncoderPath::System,
+        desc: format!(
+            "videoconvert ! {} ! {nvenc} ! {parse}",
+            color::caps("video/x-raw")
+        ),
+    })
+}
+
+/// Record a single PipeWire node to a file.
+pub fn record_stream_pipeline(
+    fd: RawFd,
+    node_id: u32,
+    location: &str,
+    audio: Option<&AudioConfig>,
+    tuning: &Tuning,
+) -> Result<Pipeline, glib::Error> {
+    if Container::of(location, tuning) == Container::Raw {
+        if audio.is_some() {
+            println!("Raw recordings have no audio, leaving it out");
+        }
+        return raw::record_pipeline(fd, node_id, location, tuning);
+    }
+    let chain = video_chain(source_offers_dmabuf(fd, node_id, tuning), tuning);
+    println!("Encoder path: {:?}", chain.path);
+
+    let mut desc = format!(
+        "{}{} ! {}{} ! {}{}{} ! {} ! {}",
+        tuning.pipewiresrc_desc(fd, node_id),
+        frames_in_tap(tuning),
+        tuning.encoder_queue_desc(),
+        stereo::pack(tuning),
+        chain.desc,
+        frames_out_tap(tuning),
+        prerecord::tap(tuning, "video"),
+        mux_desc(location, tuning),
+        writer::sink_desc(location, tuning),
+    );
+    if let Some(audio) = audio {
+        desc.push_str(&format!(
+            " {} ! {}{} ! mux.",
+            audio.encoded_chain(),
+            tuning.queue_desc(),
+            prerecord::tap(tuning, "audio")
+        ));
+    }
+
+    let pipeline = gstreamer::parse_launch(&desc)?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    pin_threads(&pipeline, tuning);
+    writer::attach(&pipeline, location, tuning)?;
+    stereo::attach(pipeline.upcast_ref(), tuning);
+
+    Ok(pipeline)
+}
+
+/// Pin the streaming threads of `pipeline` as they start, per `tuning`: those of
+/// `pipewiresrc` to the capture cores, all others, which encode, to the encode cores.
+/// Encoders like x264 start their thread pools from there, so those stay put too.
+pub fn pin_threads(pipeline: &Pipeline, tuning: &Tuning) {
+    let (capture, encode) = (tuning.capture_cores, tuning.encode_cores);
+    if capture.is_none() && encode.is_none() {
+        return;
+    }
+    let bus = pipeline.bus().expect("pipeline bus");
+    // entering is posted from the new thread itself
+    bus.set_sync_handler(move |_, msg| {
+        if let MessageView::StreamStatus(status) = msg.view() {
+            let (kind, owner) = status.get();
+            let capturing = owner.factory().is_some_and(|f| f.name() == "pipewiresrc");
+            let cores = if capturing { capture } else { encode };
+            if let (StreamStatusType::Enter, Some(cores)) = (kind, cores) {
+                if let Err(e) = cores.pin_current_thread() {
+                    println!("Could not pin the thread of {}: {e}", owner.name());
+                }
+            }
+        }
+        BusSyncReply::Pass
+    });
+}
+
+const FRAMES_IN: &str = "frames_in";
+const FRAMES_OUT: &str = "frames_out";
+
+/// An identity element the [`FrameCounter`] can hook into, if the tuning asks for it.
+fn frame_tap(tuning: &Tuning, name: &str) -> String {
+    if tuning.verify_frames {
+        format!(" ! identity name={name}")
+    } else {
+        String::new()
+    }
+}
+
+/// `frame_tap` for the point where frames enter the encoder.
+pub fn frames_in_tap(tuning: &Tuning) -> String {
+    frame_tap(tuning, FRAMES_IN)
+}
+
+/// `frame_tap` for the point where encoded frames enter the muxer.
+pub fn frames_out_tap(tuning: &Tuning) -> String {
+    frame_tap(tuning, FRAMES_OUT)
+}
+
+/// Counts buffers on both frame taps of a pipeline.
+pub struct FrameCounter {
+    captured: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl FrameCounter {
+    /// Returns `None` if the pipeline has no frame taps.
+    pub fn attach(pipeline: &Pipeline) -> Option<Self> {
+        let count = |name: &str| -> Option<Arc<AtomicU64>> {
+            let pad = pipeline.by_name(name)?.static_pad("src")?;
+            let counter = Arc::new(AtomicU64::new(0));
+            let counter_clone = counter.clone();
+            pad.add_probe(PadProbeType::BUFFER, move |_, _| {
+                counter_clone.fetch_add(1, Ordering::Relaxed);
+                PadProbeReturn::Ok
+            });
+            Some(counter)
+        };
+
+        Some(Self {
+            captured: count(FRAMES_IN)?,
+            written: count(FRAMES_OUT)?,
+        })
+    }
+
+    /// Print the frame counts; returns false if frames went missing.
+    pub fn verify(&self) -> bool {
+        let captured = self.captured.load(Ordering::Relaxed);
+        let written = self.written.load(Ordering::Relaxed);
+        if captured == written {
+            println!("Frame check passed: {written} frames written");
+            true
+        } else {
+            println!("Frame check FAILED: captured {captured} frames, wrote {written}");
+            false
+        }
+    }
+}
+
+/// `out.mkv` stays as is for the first segment, then becomes `out-1.mkv`, `out-2.mkv`...
+pub fn segment_location(location: &str, segment: u32) -> String {
+    if segment == 0 {
+        return location.to_string();
+    }
+    match location.rsplit_once('.') {
+        Some((stem, ext)) => format!("{stem}-{segment}.{ext}"),
+        None => format!("{location}-{segment}"),
+    }
+}
+
+/// What the capture sources negotiated, for the crash report.
+fn note_source_caps(pipeline: &Pipeline) {
+    let caps: Vec<String> = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"))
+        .filter_map(|e| e.static_pad("src")?.current_caps())
+        .map(|c| c.to_string())
+        .collect();
+    crash_report::note("capture caps", caps.join("; "));
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// How often `p` was entered, each pausing or resuming the recording.
+static PAUSE_TOGGLES: AtomicU32 = AtomicU32::new(0);
+/// Set while a pre-recording waits for Enter to start, see [`prerecord::PreRecord`].
+static START_PENDING: AtomicBool = AtomicBool::new(false);
+/// Only the first recording waits, those after an output came back carry on.
+static START_ARMED: Once = Once::new();
+static STDIN_WATCH: Once = Once::new();
+
+/// Whether the user asked to stop recording.
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::Relaxed)
+}
+
+fn watch_stdin() {
+    STDIN_WATCH.call_once(|| {
+        std::thread::spawn(|| {
+            // p pauses or resumes, any other line starts a pre-recording or stops
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) if line.trim() == "p" => {
+                        PAUSE_TOGGLES.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(_) if START_PENDING.swap(false, Ordering::Relaxed) => {}
+                    _ => break,
+                }
+            }
+            STOP_REQUESTED.store(true, Ordering::Relaxed);
+        });
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The user pressed Enter.
+    User,
+    /// A source ended the stream on its own, e.g. the captured window closed.
+    Eos,
+    Error,
+}
+
+/// Run a pipeline until it reaches EOS or errors out.
+/// Pressing Enter sends EOS so that muxers can finalize the file. Entering `p` pauses
+/// and resumes, see [`pause::Pause`]. A pipeline with [`prerecord::tap`] queues is held
+/// back until Enter is pressed a first time.
+pub fn run_until_eos(pipeline: &Pipeline) -> StopReason {
+    run_until_eos_with(pipeline, |_| {})
+}
+
+/// Like [`run_until_eos`], but lets the caller look at every bus message.
+pub fn run_until_eos_with<F>(pipeline: &Pipeline, mut on_message: F) -> StopReason
+where
+    F: FnMut(&gstreamer::Message),
+{
+    let bus = pipeline.bus().expect("pipeline bus");
+    let counter = FrameCounter::attach(pipeline);
+    pacing::stamp_frame_durations(pipeline);
+    blank::watch(pipeline);
+    scene_cut::watch(pipeline);
+    color::tag(pipeline);
+    let deadlines = deadline::Deadlines::attach(pipeline);
+    let pre_record = prerecord::PreRecord::attach(pipeline);
+    let pause = pause::Pause::attach(pipeline);
+    let prompt = if pause.is_some() {
+        "Press Enter to stop, p and Enter to pause."
+    } else {
+        "Press Enter to stop."
+    };
+
+    // e.g. when the PipeWire node is already gone, which the caller may reconnect after
+    if let Err(e) = pipeline.set_state(gstreamer::State::Playing) {
+        println!("Could not start the pipeline: {e}");
+        let _ = pipeline.set_state(gstreamer::State::Null);
+        return StopReason::Error;
+    }
+
+    if pre_record.is_some() {
+        START_ARMED.call_once(|| START_PENDING.store(true, Ordering::Relaxed));
+    }
+    watch_stdin();
+    let waiting = pre_record.is_some() && START_PENDING.load(Ordering::Relaxed);
+    match pre_record.as_ref() {
+        Some(pre_record) if waiting => println!(
+            "Keeping the last {} s in memory. Press Enter to start recording with them.",
+            pre_record.window().seconds()
+        ),
+        _ => println!("Recording. {prompt}"),
+    }
+
+    let mut eos_sent = false;
+    // presses from a recording before don't count
+    let mut toggles = PAUSE_TOGGLES.load(Ordering::Relaxed);
+    let reason = loop {
+        if let Some(pre_record) = pre_record.as_ref() {
+            // stopping before starting still writes what was kept
+            let stopping = stop_requested();
+            if (stopping || !START_PENDING.load(Ordering::Relaxed))
+                && pre_record.start()
+                && waiting
+                && !stopping
+            {
+                println!(
+                    "Recording from {} s ago. {prompt}",
+                    pre_record.window().seconds()
+                );
+            }
+        }
+        if !eos_sent && stop_requested() {
+            pipeline.send_event(gstreamer::event::Eos::new());
+            eos_sent = true;
+        }
+        let requested = PAUSE_TOGGLES.load(Ordering::Relaxed);
+        if requested != toggles {
+            toggles = requested;
+            match pause.as_ref() {
+                // nothing to pause before a pre-recording started
+                _ if START_PENDING.load(Ordering::Relaxed) => {}
+                Some(pause) if pause.is_paused() => {
+                    if pause.resume() {
+                        println!("Resumed, {} left out so far", pause.paused_for());
+                    }
+                }
+                Some(pause) => {
+                    if pause.pause() {
+                        println!("Paused. p and Enter resumes.");
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let Some(msg) = bus.timed_pop(gstreamer::ClockTime::from_mseconds(100)) else {
+            continue;
+        };
+
+        on_message(&msg);
+        match msg.view() {
+            MessageView::Eos(..) if eos_sent => break StopReason::User,
+            MessageView::Eos(..) => break StopReason::Eos,
+            MessageView::Error(err) => {
+                let error = format!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                println!("{error}");
+                note_source_caps(pipeline);
+                crash_report::write(&error);
+                break StopReason::Error;
+            }
+            _ => {}
+        }
+    };
+
+    let _ = pipeline.set_state(gstreamer::State::Null);
+    if let Some(deadlines) = deadlines {
+        deadlines.summary();
+    }
+    if let Some(counter) = counter {
+        counter.verify();
+    }
+    reason
+}
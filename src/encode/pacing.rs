@@ -0,0 +1,75 @@
+use gstreamer::{
+    prelude::*, ClockTime, EventView, PadProbeData, PadProbeReturn, PadProbeType, Pipeline,
+};
+
+/// How much each new arrival interval moves the estimate.
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Default)]
+struct ArrivalClock {
+    variable: bool,
+    last_pts: Option<ClockTime>,
+    /// Recent average time between frames, in nanoseconds.
+    interval: Option<f64>,
+}
+
+impl ArrivalClock {
+    fn next_duration(&mut self, pts: ClockTime) -> Option<ClockTime> {
+        let last = self.last_pts.replace(pts)?;
+        let dt = pts.nseconds().saturating_sub(last.nseconds()) as f64;
+        let interval = match self.interval {
+            Some(avg) => avg + (dt - avg) * SMOOTHING,
+            None => dt,
+        };
+        self.interval = Some(interval);
+        Some(ClockTime::from_nseconds(interval as u64))
+    }
+}
+
+/// Streams from VRR outputs (or games that present whenever a frame is done) come with a
+/// 0/1 framerate, so there is no nominal frame duration to go by. For those, stamp every
+/// buffer with the recent average arrival interval, so that the encoder and muxer follow
+/// the rate frames actually came in at rather than a fixed refresh rate.
+pub fn stamp_frame_durations(pipeline: &Pipeline) {
+    let sources = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"));
+
+    for source in sources {
+        let Some(pad) = source.static_pad("src") else {
+            continue;
+        };
+
+        let mut clock = ArrivalClock::default();
+        pad.add_probe(
+            PadProbeType::BUFFER | PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                match info.data {
+                    Some(PadProbeData::Event(ref event)) => {
+                        if let EventView::Caps(caps) = event.view() {
+                            let rate = caps
+                                .caps()
+                                .structure(0)
+                                .and_then(|s| s.get::<gstreamer::Fraction>("framerate").ok());
+                            clock.variable = rate.is_some_and(|r| r.numer() == 0);
+                            if clock.variable {
+                                println!("Variable frame rate stream, timing frames by arrival");
+                            }
+                        }
+                    }
+                    Some(PadProbeData::Buffer(ref mut buffer)) if clock.variable => {
+                        if let Some(duration) =
+                            buffer.pts().and_then(|pts| clock.next_duration(pts))
+                        {
+                            buffer.make_mut().set_duration(duration);
+                        }
+                    }
+                    _ => {}
+                }
+                PadProbeReturn::Ok
+            },
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::{Arc, Mutex};
+
+use gstreamer::{
+    prelude::*, BufferFlags, ClockTime, Pad, PadProbeData, PadProbeReturn, PadProbeType, Pipeline,
+};
+use gstreamer_video::UpstreamForceKeyUnitEvent;
+
+/// A stretch of running time left out of the file, in nanoseconds.
+#[derive(Debug, Clone, Copy)]
+struct Gap {
+    start: u64,
+    /// `None` while still paused.
+    end: Option<u64>,
+}
+
+/// How far a buffer at `time` moves back, or `None` if it falls into a gap.
+fn shift(gaps: &[Gap], time: u64) -> Option<u64> {
+    let mut shift = 0;
+    for gap in gaps {
+        match gap.end {
+            _ if time < gap.start => break,
+            Some(end) if time >= end => shift += end - gap.start,
+            _ => return None,
+        }
+    }
+    Some(shift)
+}
+
+/// Pauses a recording so the file plays straight through: what reaches the muxer while
+/// paused is dropped, and what comes after is moved back by the time paused.
+///
+/// Buffers are told apart by their decode timestamps, not by when they reach the muxer,
+/// so frames the encoder still had when pausing make it into the file, and frames it had
+/// been given before resuming don't. Video picks up again at a keyframe, which the
+/// encoder is asked for on resume.
+#[derive(Clone)]
+pub struct Pause {
+    pipeline: Pipeline,
+    gaps: Arc<Mutex<Vec<Gap>>>,
+    /// The muxer's video inputs, which need a keyframe after a gap.
+    video_pads: Vec<Pad>,
+}
+
+impl Pause {
+    /// Hook into the muxer named `mux`; `None` if `pipeline` has none.
+    pub fn attach(pipeline: &Pipeline) -> Option<Self> {
+        let mux = pipeline.by_name("mux")?;
+        let gaps: Arc<Mutex<Vec<Gap>>> = Arc::new(Mutex::new(vec![]));
+        let mut video_pads = vec![];
+
+        for pad in mux.iterate_sink_pads().into_iter().flatten() {
+            let video = pad.name().starts_with("video");
+            if video {
+                video_pads.push(pad.clone());
+            }
+            let gaps = gaps.clone();
+            // frames right after a gap may need ones that were dropped
+            let mut resyncing = false;
+            pad.add_probe(PadProbeType::BUFFER, move |_, info| {
+                let Some(PadProbeData::Buffer(ref mut buffer)) = info.data else {
+                    return PadProbeReturn::Ok;
+                };
+                let Some(time) = buffer.dts_or_pts() else {
+                    return PadProbeReturn::Ok;
+                };
+                let Some(shift) = shift(&gaps.lock().unwrap(), time.nseconds()) else {
+                    resyncing = video;
+                    return PadProbeReturn::Drop;
+                };
+                if resyncing {
+                    if buffer.flags().contains(BufferFlags::DELTA_UNIT) {
+                        return PadProbeReturn::Drop;
+                    }
+                    resyncing = false;
+                }
+                if shift > 0 {
+                    let back =
+                        |t: ClockTime| ClockTime::from_nseconds(t.nseconds().saturating_sub(shift));
+                    let buffer = buffer.make_mut();
+                    if let Some(pts) = buffer.pts() {
+                        buffer.set_pts(back(pts));
+                    }
+                    if let Some(dts) = buffer.dts() {
+                        buffer.set_dts(back(dts));
+                    }
+                }
+                PadProbeReturn::Ok
+            });
+        }
+
+        Some(Self {
+            pipeline: pipeline.clone(),
+            gaps,
+            video_pads,
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.gaps
+            .lock()
+            .unwrap()
+            .last()
+            .is_some_and(|gap| gap.end.is_none())
+    }
+
+    /// Leave everything from now on out of the file. Whether it wasn't paused already
+    /// and the pipeline is running.
+    pub fn pause(&self) -> bool {
+        let Some(now) = self.pipeline.current_running_time() else {
+            return false;
+        };
+        if self.is_paused() {
+            return false;
+        }
+        self.gaps.lock().unwrap().push(Gap {
+            start: now.nseconds(),
+            end: None,
+        });
+        true
+    }
+
+    /// Carry on after the last frame before pausing. Whether it was paused.
+    pub fn resume(&self) -> bool {
+        let Some(now) = self.pipeline.current_running_time() else {
+            return false;
+        };
+        {
+            let mut gaps = self.gaps.lock().unwrap();
+            let Some(gap) = gaps.last_mut().filter(|gap| gap.end.is_none()) else {
+                return false;
+            };
+            gap.end = Some(now.nseconds().max(gap.start));
+        }
+        for pad in &self.video_pads {
+            // sink pads send events upstream, to the encoder
+            pad.push_event(
+                UpstreamForceKeyUnitEvent::builder()
+                    .all_headers(true)
+                    .build(),
+            );
+        }
+        true
+    }
+
+    /// How long the file is shorter than the recording took so far.
+    pub fn paused_for(&self) -> ClockTime {
+        let now = self
+            .pipeline
+            .current_running_time()
+            .map_or(0, |t| t.nseconds());
+        let total = self
+            .gaps
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|gap| gap.end.unwrap_or(now).saturating_sub(gap.start))
+            .sum();
+        ClockTime::from_nseconds(total)
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::{Arc, Mutex};
+
+use gstreamer::{
+    prelude::*, BufferFlags, ClockTime, Pad, PadProbeData, PadProbeId, PadProbeReturn,
+    PadProbeType, Pipeline,
+};
+
+use crate::preset::Tuning;
+
+const QUEUE_PREFIX: &str = "prerecord_";
+/// How much more than asked the queues hold, as the encoder is behind the clock.
+const SLACK: ClockTime = ClockTime::from_seconds(1);
+
+/// A queue that keeps the last `tuning.pre_record` seconds of the encoded `track`
+/// ("video" or "audio") in front of the muxer, for [`PreRecord`]. Empty without
+/// pre-recording.
+pub fn tap(tuning: &Tuning, track: &str) -> String {
+    let Some(secs) = tuning.pre_record else {
+        return String::new();
+    };
+    let size = ClockTime::from_seconds(secs as u64).nseconds() + SLACK.nseconds();
+    format!(
+        " ! queue name={QUEUE_PREFIX}{track} max-size-buffers=0 max-size-bytes=0 max-size-time={size} leaky=downstream"
+    )
+}
+
+/// Holds a recording back until [`PreRecord::start`], which begins the file with the
+/// seconds before it.
+///
+/// The pipeline runs all along: encoded frames pile up in the [`tap`] queues, which
+/// forget the oldest, and the muxer gets nothing until they are let through. The file
+/// starts at the first keyframe of the kept seconds.
+#[derive(Clone)]
+pub struct PreRecord {
+    pipeline: Pipeline,
+    /// How far back the file starts.
+    window: ClockTime,
+    /// The outputs of the queues, blocked until started.
+    blocked: Arc<Mutex<Vec<(Pad, PadProbeId)>>>,
+    /// Running time the file starts at, once started.
+    from: Arc<Mutex<Option<ClockTime>>>,
+}
+
+impl PreRecord {
+    /// Block the [`tap`] queues of `pipeline`; `None` if it has none.
+    pub fn attach(pipeline: &Pipeline) -> Option<Self> {
+        let from = Arc::new(Mutex::new(None));
+        let mut window = None;
+        let mut blocked = vec![];
+
+        for track in ["video", "audio"] {
+            let Some(queue) = pipeline.by_name(&format!("{QUEUE_PREFIX}{track}")) else {
+                continue;
+            };
+            let size = queue.property::<u64>("max-size-time");
+            window = Some(ClockTime::from_nseconds(
+                size.saturating_sub(SLACK.nseconds()),
+            ));
+
+            let src = queue.static_pad("src").expect("queue src pad");
+            if let Some(id) =
+                src.add_probe(PadProbeType::BLOCK_DOWNSTREAM, |_, _| PadProbeReturn::Ok)
+            {
+                blocked.push((src.clone(), id));
+            }
+
+            // the frame the queue was pushing when blocked is as old as they get, and the
+            // other frames may need ones that were forgotten
+            let Some(mux_pad) = src.peer() else {
+                continue;
+            };
+            let from = from.clone();
+            let mut waiting = true;
+            let video = track == "video";
+            mux_pad.add_probe(PadProbeType::BUFFER, move |_, info| {
+                if !waiting {
+                    return PadProbeReturn::Ok;
+                }
+                let Some(PadProbeData::Buffer(ref buffer)) = info.data else {
+                    return PadProbeReturn::Ok;
+                };
+                let Some(from) = *from.lock().unwrap() else {
+                    return PadProbeReturn::Ok;
+                };
+                let early = buffer.dts_or_pts().is_some_and(|t| t < from);
+                if early || (video && buffer.flags().contains(BufferFlags::DELTA_UNIT)) {
+                    return PadProbeReturn::Drop;
+                }
+                waiting = false;
+                PadProbeReturn::Ok
+            });
+        }
+
+        Some(Self {
+            pipeline: pipeline.clone(),
+            window: window?,
+            blocked: Arc::new(Mutex::new(blocked)),
+            from,
+        })
+    }
+
+    pub fn window(&self) -> ClockTime {
+        self.window
+    }
+
+    /// Let the kept frames through and everything after. Whether it wasn't started
+    /// already.
+    pub fn start(&self) -> bool {
+        let mut blocked = self.blocked.lock().unwrap();
+        if blocked.is_empty() {
+            return false;
+        }
+        let now = self
+            .pipeline
+            .current_running_time()
+            .map_or(0, |t| t.nseconds());
+        *self.from.lock().unwrap() = Some(ClockTime::from_nseconds(
+            now.saturating_sub(self.window.nseconds()),
+        ));
+        for (pad, id) in blocked.drain(..) {
+            pad.remove_probe(id);
+        }
+        true
+    }
+}
@@ -0,0 +1,540 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    os::fd::RawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use gstreamer::{glib, prelude::*, ClockTime, FlowError, FlowSuccess, MessageView, Pipeline};
+use gstreamer_app::{AppSink, AppSinkCallbacks, AppSrc};
+
+use super::{mux_desc, pin_threads, stereo, video_chain, writer};
+use crate::preset::Tuning;
+
+// A raw recording is a header, records, and an index, all little endian:
+//
+//   MAGIC
+//   b'C' u32 length, caps        the caps of the frames that follow
+//   b'F' u8 flags, u64 pts ns, u64 size, u64 compressed size, zstd data
+//   ...
+//   per frame: u64 offset, u64 pts ns, u8 flags
+//   u64 frames, u64 index offset, INDEX_MAGIC
+//
+// A frame that isn't a keyframe is stored XORed with the one before, which leaves
+// little but zeroes where the screen stood still. A file cut short has no index, but
+// its records still read.
+
+const MAGIC: &[u8; 8] = b"LENSRAW1";
+const INDEX_MAGIC: &[u8; 8] = b"LRAWIDX1";
+const CAPS: u8 = b'C';
+const FRAME: u8 = b'F';
+const KEYFRAME: u8 = 1;
+
+/// Every this many frames one is stored whole, so that a damaged file loses at most
+/// this many.
+const KEYFRAME_INTERVAL: u64 = 60;
+/// zstd's fastest, it still gets most of what there is to get from screen content.
+const LEVEL: i32 = 1;
+/// Frames compressing at once per thread, before the pipeline waits.
+const IN_FLIGHT: usize = 2;
+
+const SINK_NAME: &str = "raw_writer";
+
+/// Record a single PipeWire node as zstd-compressed raw frames, for when encoding
+/// can't keep up, e.g. 4K at 144 Hz. `lensing transcode` encodes the file later.
+pub fn record_pipeline(
+    fd: RawFd,
+    node_id: u32,
+    location: &str,
+    tuning: &Tuning,
+) -> Result<Pipeline, glib::Error> {
+    let desc = format!(
+        "{} ! videoconvert ! video/x-raw ! {} ! appsink name={SINK_NAME} sync=false enable-last-sample=false",
+        tuning.pipewiresrc_desc(fd, node_id),
+        tuning.queue_desc(),
+    );
+    let pipeline = gstreamer::parse_launch(&desc)?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    pin_threads(&pipeline, tuning);
+
+    let sink = pipeline
+        .by_name(SINK_NAME)
+        .and_then(|e| e.downcast::<AppSink>().ok())
+        .expect("appsink");
+    let file = File::create(location).map_err(|e| {
+        glib::Error::new(
+            gstreamer::ResourceError::OpenWrite,
+            &format!("{location}: {e}"),
+        )
+    })?;
+    let writer = Arc::new(RawWriter::spawn(file));
+    let finishing = writer.clone();
+    sink.set_callbacks(
+        AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| FlowError::Eos)?;
+                let caps = sample.caps().map(|c| c.to_string()).unwrap_or_default();
+                let Some(buffer) = sample.buffer() else {
+                    return Ok(FlowSuccess::Ok);
+                };
+                let pts = buffer.pts().map_or(0, |t| t.nseconds());
+                // a copy, the producer only has so many buffers to go round
+                let data = buffer
+                    .map_readable()
+                    .map_err(|_| FlowError::Error)?
+                    .to_vec();
+                writer.push(caps, pts, data)?;
+                Ok(FlowSuccess::Ok)
+            })
+            .eos(move |_| {
+                if let Err(e) = finishing.finish() {
+                    println!("Could not write the raw recording: {e}");
+                }
+            })
+            .build(),
+    );
+    Ok(pipeline)
+}
+
+struct Job {
+    seq: u64,
+    pts: u64,
+    frame: Arc<Vec<u8>>,
+    /// What a delta frame is XORed with.
+    previous: Option<Arc<Vec<u8>>>,
+}
+
+struct Compressed {
+    seq: u64,
+    key: bool,
+    pts: u64,
+    size: u64,
+    data: io::Result<Vec<u8>>,
+}
+
+enum Record {
+    Caps(String),
+    /// The frame with this sequence number, to be written in order.
+    Frame(u64),
+}
+
+/// Where frames go as they come in and what still has to go; for [`RawWriter::finish`].
+struct Feed {
+    jobs: SyncSender<Job>,
+    records: Sender<Record>,
+    seq: u64,
+    caps: String,
+    previous: Option<Arc<Vec<u8>>>,
+}
+
+/// Compresses frames on a thread per core and writes them, in order, from another.
+struct RawWriter {
+    feed: Mutex<Option<Feed>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    written: Mutex<Option<JoinHandle<io::Result<u64>>>>,
+}
+
+impl RawWriter {
+    fn spawn(file: File) -> Self {
+        let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let (jobs, job_receiver) = mpsc::sync_channel::<Job>(workers * IN_FLIGHT);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (done, done_receiver) = mpsc::channel::<Compressed>();
+        let (records, record_receiver) = mpsc::channel::<Record>();
+
+        let threads = (0..workers)
+            .map(|i| {
+                let (jobs, done) = (job_receiver.clone(), done.clone());
+                std::thread::Builder::new()
+                    .name(format!("raw compress {i}"))
+                    .spawn(move || compress_frames(&jobs, &done))
+                    .expect("compression thread")
+            })
+            .collect();
+        let written = std::thread::Builder::new()
+            .name("raw writer".into())
+            .spawn(move || write_records(file, &record_receiver, &done_receiver))
+            .expect("raw writer thread");
+
+        Self {
+            feed: Mutex::new(Some(Feed {
+                jobs,
+                records,
+                seq: 0,
+                caps: String::new(),
+                previous: None,
+            })),
+            threads: Mutex::new(threads),
+            written: Mutex::new(Some(written)),
+        }
+    }
+
+    /// Blocks while every compression thread is busy.
+    fn push(&self, caps: String, pts: u64, frame: Vec<u8>) -> Result<(), FlowError> {
+        let mut feed = self.feed.lock().unwrap();
+        let Some(feed) = feed.as_mut() else {
+            return Err(FlowError::Eos);
+        };
+        if caps != feed.caps {
+            // a new size or format can't be a delta of the old one
+            feed.previous = None;
+            feed.caps = caps.clone();
+            let _ = feed.records.send(Record::Caps(caps));
+        }
+        let frame = Arc::new(frame);
+        let previous = feed
+            .previous
+            .replace(frame.clone())
+            .filter(|_| feed.seq % KEYFRAME_INTERVAL != 0);
+        let job = Job {
+            seq: feed.seq,
+            pts,
+            frame,
+            previous,
+        };
+        let _ = feed.records.send(Record::Frame(feed.seq));
+        feed.seq += 1;
+        // the writer hung up if it failed, which finish() reports
+        feed.jobs.send(job).map_err(|_| FlowError::Error)
+    }
+
+    /// Wait for every frame to be on disk.
+    fn finish(&self) -> Result<(), String> {
+        // closing the channels ends the threads once they are through
+        drop(self.feed.lock().unwrap().take());
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+        let Some(written) = self.written.lock().unwrap().take() else {
+            return Ok(());
+        };
+        match written.join() {
+            Ok(Ok(frames)) => {
+                println!("Wrote {frames} raw frames");
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("the raw writer panicked".into()),
+        }
+    }
+}
+
+impl Drop for RawWriter {
+    fn drop(&mut self) {
+        // without EOS, e.g. after an error, what made it here is still written
+        let _ = self.finish();
+    }
+}
+
+fn compress_frames(jobs: &Mutex<Receiver<Job>>, done: &Sender<Compressed>) {
+    loop {
+        let Ok(job) = jobs.lock().unwrap().recv() else {
+            return;
+        };
+        let frame = job.frame.as_slice();
+        let previous = job
+            .previous
+            .as_ref()
+            .map(|p| p.as_slice())
+            .filter(|p| p.len() == frame.len());
+        let data = match previous {
+            Some(previous) => {
+                let delta: Vec<u8> = frame.iter().zip(previous).map(|(a, b)| a ^ b).collect();
+                zstd::bulk::compress(&delta, LEVEL)
+            }
+            None => zstd::bulk::compress(frame, LEVEL),
+        };
+        let compressed = Compressed {
+            seq: job.seq,
+            key: previous.is_none(),
+            pts: job.pts,
+            size: frame.len() as u64,
+            data,
+        };
+        if done.send(compressed).is_err() {
+            return;
+        }
+    }
+}
+
+/// Write the records in order as their frames are compressed, then the index. Returns
+/// the number of frames.
+fn write_records(
+    file: File,
+    records: &Receiver<Record>,
+    done: &Receiver<Compressed>,
+) -> io::Result<u64> {
+    let mut out = BufWriter::with_capacity(8 << 20, file);
+    out.write_all(MAGIC)?;
+    let mut offset = MAGIC.len() as u64;
+    let mut index: Vec<(u64, u64, u8)> = vec![];
+    // frames compressed ahead of the one that is next
+    let mut ready: BTreeMap<u64, Compressed> = BTreeMap::new();
+
+    for record in records.iter() {
+        match record {
+            Record::Caps(caps) => {
+                out.write_all(&[CAPS])?;
+                out.write_all(&(caps.len() as u32).to_le_bytes())?;
+                out.write_all(caps.as_bytes())?;
+                offset += 1 + 4 + caps.len() as u64;
+            }
+            Record::Frame(seq) => {
+                let frame = loop {
+                    if let Some(frame) = ready.remove(&seq) {
+                        break frame;
+                    }
+                    let Ok(compressed) = done.recv() else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "a frame got lost in compression",
+                        ));
+                    };
+                    ready.insert(compressed.seq, compressed);
+                };
+                let data = frame.data?;
+                let flags = if frame.key { KEYFRAME } else { 0 };
+                out.write_all(&[FRAME, flags])?;
+                out.write_all(&frame.pts.to_le_bytes())?;
+                out.write_all(&frame.size.to_le_bytes())?;
+                out.write_all(&(data.len() as u64).to_le_bytes())?;
+                out.write_all(&data)?;
+                index.push((offset, frame.pts, flags));
+                offset += 2 + 8 * 3 + data.len() as u64;
+            }
+        }
+    }
+
+    for (frame_offset, pts, flags) in index.iter() {
+        out.write_all(&frame_offset.to_le_bytes())?;
+        out.write_all(&pts.to_le_bytes())?;
+        out.write_all(&[*flags])?;
+    }
+    out.write_all(&(index.len() as u64).to_le_bytes())?;
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(INDEX_MAGIC)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(index.len() as u64)
+}
+
+enum ReadRecord {
+    Caps(String),
+    Frame { pts: u64, data: Vec<u8> },
+}
+
+/// Reads the records of a raw recording back, undoing the deltas.
+struct RawReader {
+    input: BufReader<File>,
+    /// Where the index starts, if the file has one.
+    index: Option<u64>,
+    previous: Option<Vec<u8>>,
+}
+
+impl RawReader {
+    /// Returns the reader and how many frames the index lists, if the file has one.
+    fn open(path: &str) -> io::Result<(Self, Option<u64>)> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a raw lensing recording",
+            ));
+        }
+        let index = read_index(&mut file).ok().flatten();
+        file.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        let reader = Self {
+            input: BufReader::with_capacity(8 << 20, file),
+            index: index.map(|(_, offset)| offset),
+            previous: None,
+        };
+        Ok((reader, index.map(|(frames, _)| frames)))
+    }
+
+    /// `None` at the index or the end of the file.
+    fn next(&mut self) -> io::Result<Option<ReadRecord>> {
+        if let Some(index) = self.index {
+            if self.input.stream_position()? >= index {
+                return Ok(None);
+            }
+        }
+        let mut kind = [0u8; 1];
+        match self.input.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        match kind[0] {
+            CAPS => {
+                let len = read_u32(&mut self.input)? as usize;
+                let mut caps = vec![0u8; len];
+                self.input.read_exact(&mut caps)?;
+                self.previous = None;
+                let caps = String::from_utf8(caps)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Some(ReadRecord::Caps(caps)))
+            }
+            FRAME => {
+                let mut flags = [0u8; 1];
+                self.input.read_exact(&mut flags)?;
+                let pts = read_u64(&mut self.input)?;
+                let size = read_u64(&mut self.input)? as usize;
+                let compressed_size = read_u64(&mut self.input)? as usize;
+                let mut compressed = vec![0u8; compressed_size];
+                match self.input.read_exact(&mut compressed) {
+                    Ok(()) => {}
+                    // the last frame of a file cut short
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                let mut data = zstd::bulk::decompress(&compressed, size)?;
+                if flags[0] & KEYFRAME == 0 {
+                    let Some(previous) = self.previous.as_ref().filter(|p| p.len() == size) else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "a delta frame without the frame before",
+                        ));
+                    };
+                    for (byte, before) in data.iter_mut().zip(previous) {
+                        *byte ^= before;
+                    }
+                }
+                self.previous = Some(data.clone());
+                Ok(Some(ReadRecord::Frame { pts, data }))
+            }
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record {other:#x}"),
+            )),
+        }
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The number of frames and where the index starts.
+fn read_index(file: &mut File) -> io::Result<Option<(u64, u64)>> {
+    file.seek(SeekFrom::End(-24))?;
+    let frames = read_u64(file)?;
+    let offset = read_u64(file)?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    Ok((&magic == INDEX_MAGIC).then_some((frames, offset)))
+}
+
+/// Encode the raw recording at `input` into `output`, with the codec and container
+/// `tuning` and the name of `output` ask for.
+pub fn transcode(input: &str, output: &str, tuning: &Tuning) -> Result<(), String> {
+    let (mut reader, frames) = RawReader::open(input).map_err(|e| format!("{input}: {e}"))?;
+    let Some(ReadRecord::Caps(caps)) = reader.next().map_err(|e| format!("{input}: {e}"))? else {
+        return Err(format!("{input} has no frames"));
+    };
+
+    let chain = video_chain(false, tuning);
+    println!("Encoder path: {:?}", chain.path);
+    let desc = format!(
+        "appsrc name=raw format=time block=true max-bytes=268435456 ! videoconvert{} ! {} ! {} ! {} ! {}",
+        stereo::pack(tuning),
+        tuning.queue_desc(),
+        chain.desc,
+        mux_desc(output, tuning),
+        writer::sink_desc(output, tuning),
+    );
+    let pipeline = gstreamer::parse_launch(&desc)
+        .map_err(|e| e.to_string())?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    pin_threads(&pipeline, tuning);
+    writer::attach(&pipeline, output, tuning).map_err(|e| e.to_string())?;
+    stereo::attach(pipeline.upcast_ref(), tuning);
+    let src = pipeline
+        .by_name("raw")
+        .and_then(|e| e.downcast::<AppSrc>().ok())
+        .expect("appsrc");
+    let set_caps = |caps: &str| match caps.parse::<gstreamer::Caps>() {
+        Ok(caps) => {
+            src.set_caps(Some(&caps));
+            Ok(())
+        }
+        Err(_) => Err(format!("{input} has frames of unknown caps {caps}")),
+    };
+    set_caps(&caps)?;
+
+    pipeline
+        .set_state(gstreamer::State::Playing)
+        .map_err(|e| e.to_string())?;
+
+    let mut pushed = 0u64;
+    let mut first_pts = None;
+    let mut result = Ok(());
+    loop {
+        let record = match reader.next() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                // what was read so far still makes a file
+                println!("Stopped reading {input}: {e}");
+                break;
+            }
+        };
+        let (pts, data) = match record {
+            ReadRecord::Caps(caps) => {
+                if let Err(e) = set_caps(&caps) {
+                    result = Err(e);
+                    break;
+                }
+                continue;
+            }
+            ReadRecord::Frame { pts, data } => (pts, data),
+        };
+        let first = *first_pts.get_or_insert(pts);
+        let mut buffer = gstreamer::Buffer::from_mut_slice(data);
+        buffer
+            .get_mut()
+            .expect("new buffer")
+            .set_pts(ClockTime::from_nseconds(pts.saturating_sub(first)));
+        // the pipeline failed, the bus says why
+        if src.push_buffer(buffer).is_err() {
+            break;
+        }
+        pushed += 1;
+        if let Some(frames) = frames.filter(|f| *f >= 10) {
+            if pushed % (frames / 10) == 0 {
+                println!("{}%", pushed * 100 / frames);
+            }
+        }
+    }
+    let _ = src.end_of_stream();
+
+    let bus = pipeline.bus().expect("pipeline bus");
+    let message = bus.timed_pop_filtered(
+        ClockTime::NONE,
+        &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+    );
+    if let Some(MessageView::Error(err)) = message.as_ref().map(|m| m.view()) {
+        result = Err(format!("{} ({:?})", err.error(), err.debug()));
+    }
+    let _ = pipeline.set_state(gstreamer::State::Null);
+    if result.is_ok() {
+        println!("Transcoded {pushed} frames into {output}");
+    }
+    result
+}
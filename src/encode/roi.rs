@@ -0,0 +1,115 @@
+use gstreamer::{BufferRef, Structure};
+use gstreamer_video::VideoRegionOfInterestMeta;
+
+use crate::{
+    backend::region::PixelRect,
+    pw_capture::{CursorMeta, PipewireFrameFormat},
+};
+
+/// Encoders take a few regions a frame; more damage than this is hinted as the box
+/// around it.
+const MAX_DAMAGE_REGIONS: usize = 4;
+
+/// Regions of interest for the encoder: around the pointer, where the user reads, and
+/// where the frame changed, so text there stays sharp at low bitrates while the rest
+/// gets what bits are left.
+///
+/// They travel as `GstVideoRegionOfInterestMeta`, with parameters for the VA-API
+/// encoders (`vah264enc` and friends, `vaapih264enc`); other encoders leave them be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoiHints {
+    /// Half the side of the square around the pointer, in frame pixels.
+    pub cursor_radius: u32,
+    /// Added to the quantizer around the pointer; negative is sharper.
+    pub cursor_delta_qp: i32,
+    /// Added to the quantizer where the frame changed, `None` doesn't hint damage.
+    pub damage_delta_qp: Option<i32>,
+}
+
+impl Default for RoiHints {
+    fn default() -> Self {
+        Self {
+            cursor_radius: 128,
+            cursor_delta_qp: -10,
+            damage_delta_qp: Some(-4),
+        }
+    }
+}
+
+impl RoiHints {
+    /// Add the regions of a frame of `format` to `buffer`. The pointer is only hinted
+    /// while it is visible.
+    pub fn add(
+        &self,
+        buffer: &mut BufferRef,
+        format: &PipewireFrameFormat,
+        cursor: Option<&CursorMeta>,
+        damage: Option<&[PixelRect]>,
+    ) {
+        let frame = (format.width as i64, format.height as i64);
+        // damage first, so the pointer's region is the one that counts where they overlap
+        if let (Some(delta_qp), Some(damage)) = (self.damage_delta_qp, damage) {
+            let whole = |r: &PixelRect| (r.width as i64, r.height as i64) == frame;
+            if !damage.iter().any(whole) {
+                let rects = if damage.len() > MAX_DAMAGE_REGIONS {
+                    bounding_box(damage).into_iter().collect()
+                } else {
+                    damage.to_vec()
+                };
+                for rect in rects {
+                    let rect = (
+                        rect.x as i64,
+                        rect.y as i64,
+                        rect.width as i64,
+                        rect.height as i64,
+                    );
+                    add_region(buffer, "damage", clip(rect, frame), delta_qp);
+                }
+            }
+        }
+
+        let Some(cursor) = cursor.filter(|c| c.bitmap.is_some()) else {
+            return;
+        };
+        let radius = self.cursor_radius as i64;
+        let (x, y) = (cursor.position.0 as i64, cursor.position.1 as i64);
+        let rect = (x - radius, y - radius, 2 * radius, 2 * radius);
+        add_region(buffer, "cursor", clip(rect, frame), self.cursor_delta_qp);
+    }
+}
+
+fn bounding_box(rects: &[PixelRect]) -> Option<PixelRect> {
+    let x = rects.iter().map(|r| r.x).min()?;
+    let y = rects.iter().map(|r| r.y).min()?;
+    let right = rects.iter().map(|r| r.x + r.width).max()?;
+    let bottom = rects.iter().map(|r| r.y + r.height).max()?;
+    Some(PixelRect {
+        x,
+        y,
+        width: right - x,
+        height: bottom - y,
+    })
+}
+
+/// `rect` as x, y, width and height within a frame of `size`, `None` if it is outside.
+fn clip(rect: (i64, i64, i64, i64), size: (i64, i64)) -> Option<(u32, u32, u32, u32)> {
+    let (x0, y0) = (rect.0.max(0), rect.1.max(0));
+    let (x1, y1) = ((rect.0 + rect.2).min(size.0), (rect.1 + rect.3).min(size.1));
+    (x1 > x0 && y1 > y0).then(|| (x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32))
+}
+
+fn add_region(
+    buffer: &mut BufferRef,
+    roi_type: &str,
+    rect: Option<(u32, u32, u32, u32)>,
+    delta_qp: i32,
+) {
+    let Some(rect) = rect else {
+        return;
+    };
+    let mut meta = VideoRegionOfInterestMeta::add(buffer, roi_type, rect);
+    // the va and vaapi plugins each look for their own
+    for name in ["roi/va", "roi/vaapi"] {
+        meta.add_param(Structure::builder(name).field("delta-qp", delta_qp).build());
+    }
+}
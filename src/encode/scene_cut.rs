@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use gstreamer::{prelude::*, EventView, PadProbeData, PadProbeReturn, PadProbeType, Pipeline};
+use gstreamer_video::DownstreamForceKeyUnitEvent;
+
+/// Pixels looked at per frame.
+const SAMPLES: usize = 4096;
+/// A pixel whose channels changed by more than this in sum changed.
+const PIXEL_CHANGE: u32 = 48;
+/// A frame with this share of its pixels changed is a new scene, e.g. another
+/// workspace.
+const SCENE_CHANGE: f32 = 0.6;
+/// Scene changes closer than this get no keyframe of their own, so an animation that
+/// changes everything doesn't turn every frame into one.
+const MIN_GAP: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct SceneState {
+    /// Whether the frames are in system memory, where we can look at them.
+    mappable: bool,
+    previous: Vec<[u8; 4]>,
+    last_cut: Option<Instant>,
+}
+
+/// Ask the encoders of `pipeline` for a keyframe where the capture changes all at once,
+/// e.g. on a workspace switch, so that seeking there and joining a stream there don't
+/// have to wait for the next regular one. The keyframe interval stays as it is.
+///
+/// Only frames in system memory are looked at; dmabufs go to the encoder unseen.
+pub fn watch(pipeline: &Pipeline) {
+    let sources = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "pipewiresrc"));
+
+    for source in sources {
+        let Some(pad) = source.static_pad("src") else {
+            continue;
+        };
+
+        let mut state = SceneState::default();
+        pad.add_probe(
+            PadProbeType::BUFFER | PadProbeType::EVENT_DOWNSTREAM,
+            move |pad, info| {
+                match info.data {
+                    Some(PadProbeData::Event(ref event)) => {
+                        if let EventView::Caps(caps) = event.view() {
+                            let caps = caps.caps();
+                            state.mappable = !caps
+                                .features(0)
+                                .is_some_and(|f| f.contains("memory:DMABuf"));
+                            state.previous.clear();
+                        }
+                    }
+                    Some(PadProbeData::Buffer(ref buffer)) if state.mappable => {
+                        let Ok(map) = buffer.map_readable() else {
+                            return PadProbeReturn::Ok;
+                        };
+                        let cut = state.frame(map.as_slice());
+                        drop(map);
+                        if cut {
+                            // ahead of the frame, so that it is the one made a keyframe;
+                            // with headers, for viewers joining there
+                            pad.push_event(
+                                DownstreamForceKeyUnitEvent::builder()
+                                    .all_headers(true)
+                                    .build(),
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+                PadProbeReturn::Ok
+            },
+        );
+    }
+}
+
+impl SceneState {
+    /// Whether the frame of 32 bit pixels in `data` should start with a keyframe.
+    fn frame(&mut self, data: &[u8]) -> bool {
+        let pixels = data.len() / 4;
+        if pixels == 0 {
+            return false;
+        }
+        let step = (pixels / SAMPLES).max(1);
+        let samples: Vec<[u8; 4]> = data
+            .chunks_exact(4)
+            .step_by(step)
+            .map(|p| [p[0], p[1], p[2], p[3]])
+            .collect();
+        let previous = std::mem::replace(&mut self.previous, samples);
+        // the first frame, or one of a new size, starts with a keyframe anyway
+        if previous.len() != self.previous.len() {
+            return false;
+        }
+
+        let changed = previous
+            .iter()
+            .zip(self.previous.iter())
+            .filter(|(a, b)| {
+                let diff: u32 = a
+                    .iter()
+                    .zip(b.iter())
+                    .map(|(a, b)| a.abs_diff(*b) as u32)
+                    .sum();
+                diff > PIXEL_CHANGE
+            })
+            .count();
+        if (changed as f32) < SCENE_CHANGE * previous.len() as f32 {
+            return false;
+        }
+        let now = Instant::now();
+        if self
+            .last_cut
+            .is_some_and(|t| now.duration_since(t) < MIN_GAP)
+        {
+            return false;
+        }
+        self.last_cut = Some(now);
+        true
+    }
+}
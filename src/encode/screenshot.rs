@@ -0,0 +1,214 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::Hasher,
+    io::BufRead,
+    os::fd::RawFd,
+    str::FromStr,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use gstreamer::{prelude::*, Bus, ClockTime, MessageView, Pipeline, Sample};
+use gstreamer_app::AppSink;
+
+use crate::preset::Tuning;
+
+const SINK_NAME: &str = "screenshot";
+
+/// What starts the wait for the screen to settle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Trigger {
+    /// The first frame.
+    #[default]
+    Start,
+    /// A line on stdin, for a test harness to send once it has done what it tests.
+    Stdin,
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "start" => Ok(Trigger::Start),
+            "stdin" => Ok(Trigger::Stdin),
+            other => Err(format!("unknown trigger: {other}")),
+        }
+    }
+}
+
+/// When the screenshot is taken after the trigger, so UI tests get the same image
+/// every run rather than one from halfway through an animation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Settle {
+    /// Right away, from the next frame.
+    #[default]
+    Now,
+    /// Once this many frames came in that differ from the one before. Frames that only
+    /// resend the same pixels, e.g. for a moved cursor, don't count.
+    Updates(u32),
+    /// Once the screen didn't change for this long.
+    Quiet(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenshotConfig {
+    pub trigger: Trigger,
+    pub settle: Settle,
+    /// How long to wait for the screen to settle before failing.
+    pub timeout: Duration,
+}
+
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        Self {
+            trigger: Trigger::default(),
+            settle: Settle::default(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Counts the changes of the screen since the trigger.
+struct Settling {
+    settle: Settle,
+    /// Of the pixels of the frame before.
+    hash: Option<u64>,
+    updates: u32,
+    changed: Instant,
+}
+
+impl Settling {
+    fn new(settle: Settle) -> Self {
+        Self {
+            settle,
+            hash: None,
+            updates: 0,
+            changed: Instant::now(),
+        }
+    }
+
+    fn frame(&mut self, sample: &Sample) {
+        let Some(map) = sample.buffer().and_then(|b| b.map_readable().ok()) else {
+            return;
+        };
+        let mut hasher = DefaultHasher::new();
+        hasher.write(map.as_slice());
+        let hash = Some(hasher.finish());
+        // the first frame after the trigger is what the others are compared to
+        if self.hash.is_some() && hash != self.hash {
+            self.updates += 1;
+            self.changed = Instant::now();
+        }
+        self.hash = hash;
+    }
+
+    fn settled(&self) -> bool {
+        match self.settle {
+            Settle::Now => self.hash.is_some(),
+            Settle::Updates(n) => self.updates >= n,
+            Settle::Quiet(quiet) => self.hash.is_some() && self.changed.elapsed() >= quiet,
+        }
+    }
+}
+
+/// Save a PNG of PipeWire node `node_id` to `location` once the screen settled the way
+/// `config` says, e.g. for screenshots in CI. Returns the number of changes waited for.
+pub fn take(
+    fd: RawFd,
+    node_id: u32,
+    location: &str,
+    config: &ScreenshotConfig,
+    tuning: &Tuning,
+) -> Result<u32, String> {
+    let desc = format!(
+        "{} ! videoconvert ! video/x-raw,format=RGBA ! appsink name={SINK_NAME} sync=false max-buffers=2",
+        tuning.pipewiresrc_desc(fd, node_id),
+    );
+    let pipeline = gstreamer::parse_launch(&desc)
+        .map_err(|e| e.to_string())?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    let sink = pipeline
+        .by_name(SINK_NAME)
+        .and_then(|e| e.downcast::<AppSink>().ok())
+        .expect("appsink");
+    pipeline
+        .set_state(gstreamer::State::Playing)
+        .map_err(|e| e.to_string())?;
+    let bus = pipeline.bus().expect("pipeline bus");
+    let result = settle(&sink, &bus, config);
+    let _ = pipeline.set_state(gstreamer::State::Null);
+    let (sample, updates) = result?;
+
+    let png = gstreamer_video::convert_sample(
+        &sample,
+        &gstreamer::Caps::builder("image/png").build(),
+        ClockTime::from_seconds(5),
+    )
+    .map_err(|e| format!("encoding the PNG: {e}"))?;
+    let map = png
+        .buffer()
+        .and_then(|b| b.map_readable().ok())
+        .ok_or("encoding the PNG gave no image")?;
+    std::fs::write(location, map.as_slice()).map_err(|e| format!("{location}: {e}"))?;
+    Ok(updates)
+}
+
+/// The frame to save and the changes there were since the trigger.
+fn settle(sink: &AppSink, bus: &Bus, config: &ScreenshotConfig) -> Result<(Sample, u32), String> {
+    let triggered = match config.trigger {
+        Trigger::Start => None,
+        Trigger::Stdin => {
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = std::io::stdin().lock().lines().next();
+                let _ = sender.send(());
+            });
+            println!("Waiting for a line on stdin to take the screenshot");
+            Some(receiver)
+        }
+    };
+
+    let mut last: Option<Sample> = None;
+    let mut settling = triggered.is_none().then(|| Settling::new(config.settle));
+    let mut deadline = Instant::now() + config.timeout;
+    loop {
+        if settling.is_none() && triggered.as_ref().is_some_and(|r| r.try_recv().is_ok()) {
+            let mut started = Settling::new(config.settle);
+            // the screen may well be settled already, and send nothing new
+            if let Some(sample) = last.as_ref() {
+                started.frame(sample);
+            }
+            settling = Some(started);
+            deadline = Instant::now() + config.timeout;
+        }
+        if let (Some(settling), Some(sample)) = (settling.as_ref(), last.as_ref()) {
+            if settling.settled() {
+                return Ok((sample.clone(), settling.updates));
+            }
+        }
+        // only the wait after the trigger is limited
+        if settling.is_some() && Instant::now() >= deadline {
+            return Err(format!(
+                "the screen didn't settle within {} s",
+                config.timeout.as_secs_f32()
+            ));
+        }
+        if let Some(message) = bus.pop_filtered(&[gstreamer::MessageType::Error]) {
+            if let MessageView::Error(err) = message.view() {
+                return Err(format!("{} ({:?})", err.error(), err.debug()));
+            }
+        }
+        if sink.is_eos() {
+            return Err("the stream ended before the screenshot".into());
+        }
+        let Some(sample) = sink.try_pull_sample(ClockTime::from_mseconds(20)) else {
+            continue;
+        };
+        if let Some(settling) = settling.as_mut() {
+            settling.frame(&sample);
+        }
+        last = Some(sample);
+    }
+}
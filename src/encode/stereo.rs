@@ -0,0 +1,156 @@
+use std::str::FromStr;
+
+use gstreamer::{prelude::*, Element, EventView, PadProbeData, PadProbeReturn, PadProbeType};
+
+use crate::preset::Tuning;
+
+const TEE_NAME: &str = "stereo_eyes";
+const COMPOSITOR_NAME: &str = "stereo";
+const SIZE_NAME: &str = "stereo_size";
+
+/// How the two views sit in the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Left and right next to each other, twice as wide as the capture.
+    SideBySide,
+    /// Left and right squeezed into the width of the capture.
+    HalfSideBySide,
+    /// Left above right, twice as tall as the capture.
+    TopAndBottom,
+    /// Left above right, squeezed into the height of the capture.
+    HalfTopAndBottom,
+}
+
+/// Packs the capture as stereo video, the same picture for either eye, so that VR
+/// video players show it as a big screen in front of the viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stereo {
+    pub layout: StereoLayout,
+    /// Capture pixels the two views are moved towards each other, which brings the
+    /// screen closer; negative moves them apart and the screen further away.
+    pub offset: i32,
+}
+
+/// `sbs`, `hsbs`, `tab` or `htab`, then optionally `:OFFSET`, e.g. `sbs:-20`.
+impl FromStr for Stereo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (layout, offset) = match s.split_once(':') {
+            Some((layout, offset)) => {
+                let offset = offset
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("not an offset in pixels: {offset}"))?;
+                (layout, offset)
+            }
+            None => (s, 0),
+        };
+        let layout = match layout {
+            "sbs" => StereoLayout::SideBySide,
+            "hsbs" => StereoLayout::HalfSideBySide,
+            "tab" => StereoLayout::TopAndBottom,
+            "htab" => StereoLayout::HalfTopAndBottom,
+            other => return Err(format!("unknown stereo layout: {other}")),
+        };
+        Ok(Self { layout, offset })
+    }
+}
+
+/// The links that pack raw video as `tuning` says, starting with ` ! `, for the encoder
+/// to go after; empty without stereo. Frames have to be in system memory for them.
+/// [`attach`] places the views once the capture size is known.
+pub fn pack(tuning: &Tuning) -> String {
+    let Some(stereo) = tuning.stereo else {
+        return String::new();
+    };
+    let [left, right] = [stereo.shift(0), stereo.shift(1)].map(|shift| {
+        // what is moved out of the view on one side stays black on the other
+        if shift >= 0 {
+            format!("right={shift}")
+        } else {
+            format!("left={}", -shift)
+        }
+    });
+    format!(
+        " ! videoconvert ! tee name={TEE_NAME} {TEE_NAME}. ! queue ! videocrop {left} ! {COMPOSITOR_NAME}.sink_0 {TEE_NAME}. ! queue ! videocrop {right} ! {COMPOSITOR_NAME}.sink_1 compositor name={COMPOSITOR_NAME} background=black ! capsfilter name={SIZE_NAME}"
+    )
+}
+
+/// Place the two views of [`pack`] in `bin` once the size of the capture is known, and
+/// again whenever it changes.
+pub fn attach(bin: &gstreamer::Bin, tuning: &Tuning) {
+    let (Some(stereo), Some(tee), Some(compositor), Some(size_filter)) = (
+        tuning.stereo,
+        bin.by_name(TEE_NAME),
+        bin.by_name(COMPOSITOR_NAME),
+        bin.by_name(SIZE_NAME),
+    ) else {
+        return;
+    };
+    let pad = tee.static_pad("sink").expect("tee sink pad");
+    pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(PadProbeData::Event(ref event)) = info.data else {
+            return PadProbeReturn::Ok;
+        };
+        let EventView::Caps(caps) = event.view() else {
+            return PadProbeReturn::Ok;
+        };
+        let Some(size) = caps
+            .caps()
+            .structure(0)
+            .and_then(|s| Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?)))
+        else {
+            return PadProbeReturn::Ok;
+        };
+        stereo.place(&compositor, &size_filter, size);
+        PadProbeReturn::Ok
+    });
+}
+
+impl Stereo {
+    /// How far view `eye` (0 left, 1 right) moves to the right, in capture pixels.
+    fn shift(&self, eye: usize) -> i32 {
+        let half = self.offset / 2;
+        if eye == 0 {
+            half
+        } else {
+            -half
+        }
+    }
+
+    /// Set the sink pads of `compositor`, and the frame size after it, for a capture of
+    /// `size`.
+    fn place(&self, compositor: &Element, size_filter: &Element, (width, height): (i32, i32)) {
+        // the cell of each view, and where the second one starts
+        let (cell_width, cell_height) = match self.layout {
+            StereoLayout::SideBySide | StereoLayout::TopAndBottom => (width, height),
+            StereoLayout::HalfSideBySide => (width / 2, height),
+            StereoLayout::HalfTopAndBottom => (width, height / 2),
+        };
+        let second = match self.layout {
+            StereoLayout::SideBySide | StereoLayout::HalfSideBySide => (cell_width, 0),
+            StereoLayout::TopAndBottom | StereoLayout::HalfTopAndBottom => (0, cell_height),
+        };
+        let scale = cell_width as f64 / width as f64;
+        for eye in 0..2 {
+            let Some(pad) = compositor.static_pad(&format!("sink_{eye}")) else {
+                continue;
+            };
+            let shift = self.shift(eye);
+            let origin = if eye == 0 { (0, 0) } else { second };
+            let x = origin.0 + (shift.max(0) as f64 * scale).round() as i32;
+            let view_width = ((width - shift.abs()) as f64 * scale).round() as i32;
+            pad.set_property("xpos", x);
+            pad.set_property("ypos", origin.1);
+            pad.set_property("width", view_width.max(1));
+            pad.set_property("height", cell_height);
+        }
+        // the compositor would leave out the edges the views were moved away from
+        let caps = gstreamer::Caps::builder("video/x-raw")
+            .field("width", second.0 + cell_width)
+            .field("height", second.1 + cell_height)
+            .build();
+        size_filter.set_property("caps", &caps);
+    }
+}
@@ -0,0 +1,219 @@
+use std::os::fd::RawFd;
+
+use gstreamer::{glib, prelude::*, Pipeline};
+
+use crate::preset::Tuning;
+
+use super::{source_offers_dmabuf, stereo, video_chain, VideoCodec};
+
+const DEFAULT_RTSP_PORT: u16 = 8554;
+const DEFAULT_RTSP_PATH: &str = "/lensing";
+const DEFAULT_RTP_PORT: u16 = 5000;
+/// The first dynamic payload type, what receivers usually assume.
+pub(super) const PAYLOAD_TYPE: u32 = 96;
+/// The next one, for the audio next to it.
+#[cfg(feature = "rtsp")]
+const AUDIO_PAYLOAD_TYPE: u32 = 97;
+
+/// Where `stream` sends the capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamTarget {
+    /// Serve it to RTSP clients, listening on `address`.
+    Rtsp {
+        address: String,
+        port: u16,
+        path: String,
+    },
+    /// Push RTP over UDP to one receiver, or a multicast group.
+    Rtp { host: String, port: u16 },
+}
+
+impl Default for StreamTarget {
+    fn default() -> Self {
+        StreamTarget::Rtsp {
+            address: "0.0.0.0".into(),
+            port: DEFAULT_RTSP_PORT,
+            path: DEFAULT_RTSP_PATH.into(),
+        }
+    }
+}
+
+/// `rtsp://[ADDRESS][:PORT][/PATH]` or `rtp://HOST[:PORT]`
+impl std::str::FromStr for StreamTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid stream target: {s}");
+        let split_port = |authority: &str, default: u16| -> Result<(String, u16), String> {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => Ok((host.into(), port.parse().map_err(|_| invalid())?)),
+                None => Ok((authority.into(), default)),
+            }
+        };
+
+        if let Some(rest) = s.strip_prefix("rtsp://") {
+            let (authority, path) = match rest.find('/') {
+                Some(i) => rest.split_at(i),
+                None => (rest, DEFAULT_RTSP_PATH),
+            };
+            let (address, port) = split_port(authority, DEFAULT_RTSP_PORT)?;
+            let address = if address.is_empty() {
+                "0.0.0.0".into()
+            } else {
+                address
+            };
+            let path = if path == "/" { DEFAULT_RTSP_PATH } else { path };
+            return Ok(StreamTarget::Rtsp {
+                address,
+                port,
+                path: path.into(),
+            });
+        }
+        if let Some(rest) = s.strip_prefix("rtp://") {
+            let (host, port) = split_port(rest.trim_end_matches('/'), DEFAULT_RTP_PORT)?;
+            if host.is_empty() {
+                return Err(format!("rtp:// needs the receiver's address: {s}"));
+            }
+            return Ok(StreamTarget::Rtp { host, port });
+        }
+        Err(invalid())
+    }
+}
+
+/// The RTP payloader for a codec, and the encoding name receivers have to set in their
+/// caps.
+pub(super) fn payloader(codec: VideoCodec) -> (&'static str, &'static str) {
+    match codec {
+        // parameter sets with every keyframe, so receivers can join at any time
+        VideoCodec::H264 => ("rtph264pay config-interval=-1", "H264"),
+        VideoCodec::Hevc => ("rtph265pay config-interval=-1", "H265"),
+        VideoCodec::Vp9 => ("rtpvp9pay", "VP9"),
+    }
+}
+
+/// Lossy and without B-frames whatever the preset says: nothing lossless fits into RTP,
+/// and B-frames make every receiver wait for the frames after.
+fn stream_tuning(tuning: &Tuning) -> Tuning {
+    if tuning.lossless.is_some() || tuning.visually_lossless {
+        println!("Streams are encoded lossy, ignoring the lossless options");
+    }
+    Tuning {
+        bframes: false,
+        lossless: None,
+        visually_lossless: false,
+        ..*tuning
+    }
+}
+
+/// Capture, encode and payload a PipeWire node, ending with the payloader `pay0`.
+fn payloaded_desc(fd: RawFd, node_id: u32, tuning: &Tuning) -> String {
+    let chain = video_chain(source_offers_dmabuf(fd, node_id, tuning), tuning);
+    println!("Encoder path: {:?}", chain.path);
+    let (pay, _) = payloader(tuning.codec);
+    format!(
+        "{} ! {}{} ! {} ! {pay} name=pay0 pt={PAYLOAD_TYPE}",
+        tuning.pipewiresrc_desc(fd, node_id),
+        tuning.encoder_queue_desc(),
+        stereo::pack(tuning),
+        chain.desc,
+    )
+}
+
+/// Push a single PipeWire node as RTP over UDP to `host`. Run it with
+/// [`super::run_until_eos`].
+pub fn rtp_pipeline(
+    fd: RawFd,
+    node_id: u32,
+    host: &str,
+    port: u16,
+    tuning: &Tuning,
+) -> Result<Pipeline, glib::Error> {
+    let tuning = stream_tuning(tuning);
+    let desc = format!(
+        "{} ! udpsink host={host} port={port} sync=false async=false",
+        payloaded_desc(fd, node_id, &tuning)
+    );
+    let pipeline = gstreamer::parse_launch(&desc)?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    super::pin_threads(&pipeline, &tuning);
+    stereo::attach(pipeline.upcast_ref(), &tuning);
+
+    let (pay, encoding) = payloader(tuning.codec);
+    let depay = pay.split(' ').next().unwrap_or(pay).replace("pay", "depay");
+    println!("Streaming RTP to {host}:{port}, receive it with e.g.");
+    println!(
+        "  gst-launch-1.0 udpsrc port={port} caps=\"application/x-rtp,media=video,clock-rate=90000,encoding-name={encoding},payload={PAYLOAD_TYPE}\" ! rtpjitterbuffer latency=50 ! {depay} ! decodebin ! autovideosink sync=false"
+    );
+    Ok(pipeline)
+}
+
+/// Serve a single PipeWire node over RTSP until the user presses Enter, with `audio` as
+/// a second, Opus stream if given. All clients share one capture and encoder, started
+/// when the first one connects.
+#[cfg(feature = "rtsp")]
+pub fn serve_rtsp(
+    fd: RawFd,
+    node_id: u32,
+    address: &str,
+    port: u16,
+    path: &str,
+    tuning: &Tuning,
+    audio: Option<&crate::audio::AudioConfig>,
+) -> Result<(), String> {
+    use gstreamer_rtsp_server::{prelude::*, RTSPMediaFactory, RTSPServer};
+
+    let tuning = stream_tuning(tuning);
+    let server = RTSPServer::new();
+    server.set_address(address);
+    server.set_service(&port.to_string());
+    let mounts = server
+        .mount_points()
+        .ok_or("RTSP server without mount points")?;
+    let factory = RTSPMediaFactory::new();
+    let audio_desc = audio
+        .map(|audio| {
+            // Opus only takes some rates, and 48000 is the one every receiver plays
+            format!(
+                " {} ! audioresample ! audio/x-raw,rate=48000 ! opusenc ! rtpopuspay name=pay1 pt={AUDIO_PAYLOAD_TYPE}",
+                audio.raw_chain()
+            )
+        })
+        .unwrap_or_default();
+    factory.set_launch(&format!(
+        "( {}{audio_desc} )",
+        payloaded_desc(fd, node_id, &tuning)
+    ));
+    factory.set_shared(true);
+    factory.connect_media_configure(move |_, media| {
+        println!("An RTSP client connected, capturing");
+        if let Some(bin) = media.element().downcast_ref::<gstreamer::Bin>() {
+            stereo::attach(bin, &tuning);
+        }
+    });
+    mounts.add_factory(path, factory);
+    let source = server
+        .attach(None)
+        .map_err(|e| format!("RTSP server on {address}:{port}: {e}"))?;
+
+    let main_loop = glib::MainLoop::new(None, false);
+    let quit = main_loop.clone();
+    super::watch_stdin();
+    glib::timeout_add(std::time::Duration::from_millis(100), move || {
+        if super::stop_requested() {
+            quit.quit();
+            glib::Continue(false)
+        } else {
+            glib::Continue(true)
+        }
+    });
+    let host = if address == "0.0.0.0" {
+        "localhost"
+    } else {
+        address
+    };
+    println!("Serving rtsp://{host}:{port}{path}. Press Enter to stop.");
+    main_loop.run();
+    source.remove();
+    Ok(())
+}
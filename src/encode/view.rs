@@ -0,0 +1,541 @@
+use std::{
+    cell::Cell,
+    os::fd::BorrowedFd,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use gstreamer::{
+    glib::{self, translate::IntoGlib},
+    prelude::*,
+    Bin, CapsFeaturesRef, Element, ElementFactory, MessageView, Pipeline, Rank, Sample,
+};
+use gstreamer_allocators::DmaBufMemory;
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use gstreamer_video::{VideoInfo, VideoMeta};
+
+use crate::{
+    capture_manager::{OwnedDmabufPlane, OwnedFrame},
+    mirror::{FrameFeed, Mirror},
+    preset::Tuning,
+    pw_capture::PipewireFrameFormat,
+    wl_client_desktop::WlClientDesktopState,
+};
+
+use super::{
+    gst_bridge::{drm_fourcc, DRM_FORMAT_MOD_LINEAR},
+    stream::{payloader, StreamTarget, PAYLOAD_TYPE},
+};
+
+/// How long packets wait for late ones, and audio and video for each other, by default.
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(200);
+
+/// Changes the volume and latency of a running viewer, see [`crate::ipc`].
+#[derive(Clone)]
+pub struct ViewControl {
+    volume: Element,
+    pipeline: Pipeline,
+    stop: Arc<AtomicBool>,
+}
+
+impl ViewControl {
+    /// `arg` is a volume in percent, `mute` or `unmute`, or empty to only ask. Replies
+    /// the volume, e.g. `80%`, or `muted`.
+    pub fn volume(&self, arg: &str) -> Result<String, String> {
+        match arg {
+            "" => {}
+            "mute" => self.volume.set_property("mute", true),
+            "unmute" => self.volume.set_property("mute", false),
+            percent => {
+                let percent: u32 = percent
+                    .trim_end_matches('%')
+                    .parse()
+                    .map_err(|_| format!("invalid volume: {percent}"))?;
+                self.volume.set_property("volume", percent as f64 / 100.0);
+                self.volume.set_property("mute", false);
+            }
+        }
+        if self.volume.property::<bool>("mute") {
+            return Ok("muted".into());
+        }
+        let volume = self.volume.property::<f64>("volume");
+        Ok(format!("{}%", (volume * 100.0).round()))
+    }
+
+    /// `arg` is the jitter buffer in milliseconds, or empty to only ask. Replies the
+    /// latency the viewer adds, e.g. `120ms`.
+    pub fn latency(&self, arg: &str) -> Result<String, String> {
+        if !arg.is_empty() {
+            let ms: u32 = arg
+                .trim_end_matches("ms")
+                .parse()
+                .map_err(|_| format!("invalid latency: {arg}"))?;
+            let buffers = jitter_buffers(&self.pipeline);
+            if buffers.is_empty() {
+                return Err("no jitter buffer yet, the stream hasn't started".into());
+            }
+            for buffer in buffers {
+                buffer.set_property("latency", ms);
+            }
+            // the sinks pick the new latency up once it is distributed again
+            let _ = self.pipeline.recalculate_latency();
+        }
+        Ok(format!(
+            "{}ms",
+            pipeline_latency(&self.pipeline).map_or(0, |l| l.as_millis())
+        ))
+    }
+
+    /// End [`Viewer::run`], as Enter does.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// How the viewer buffers and decodes.
+#[derive(Debug, Clone, Copy)]
+pub struct ViewTuning {
+    /// The jitter buffer, which also gives audio and video the time to line up.
+    pub latency: Duration,
+    /// Drop packets that come later than `latency` instead of waiting for them, and
+    /// decode frame by frame, not several at once on as many threads.
+    pub low_latency: bool,
+    /// Decode with VA-API, NVDEC or V4L2 where a decoder is installed, and show the
+    /// frames in a window of our own that takes them as dmabufs, rather than in a
+    /// GStreamer sink, so the pixels never pass through the CPU. See
+    /// [`Viewer::run_in_window`].
+    pub hw_decode: bool,
+}
+
+impl Default for ViewTuning {
+    fn default() -> Self {
+        Self {
+            latency: DEFAULT_LATENCY,
+            low_latency: false,
+            hw_decode: false,
+        }
+    }
+}
+
+/// Decoders off the CPU for `codec`, in order of preference: VA-API, the older
+/// gstreamer-vaapi, NVDEC, and V4L2 as on the Raspberry Pi, stateless and stateful.
+fn hw_decoders(codec: &str) -> [String; 5] {
+    ["va", "vaapi", "nv", "v4l2sl", "v4l2"].map(|api| format!("{api}{codec}dec"))
+}
+
+/// Rank the installed [`hw_decoders`] above the software ones, which decodebin would
+/// mostly pick otherwise. Whether there was any.
+fn prefer_hw_decoders() -> bool {
+    let mut found = false;
+    for codec in ["h264", "h265", "vp9", "av1"] {
+        // gstreamer-rs 0.20 has no arithmetic on ranks
+        let mut rank = Rank::Primary.into_glib() + 5;
+        for name in hw_decoders(codec) {
+            if let Some(factory) = ElementFactory::find(&name) {
+                factory.set_rank(Rank::__Unknown(rank));
+                found = true;
+            }
+            rank -= 1;
+        }
+    }
+    found
+}
+
+/// Where the video goes.
+enum VideoOutput {
+    /// A window of GStreamer's choosing.
+    Sink,
+    /// The window of [`Viewer::run_in_window`], once it is up.
+    Feed(Arc<Mutex<Option<FrameFeed>>>),
+}
+
+/// What turns decoded frames into ones the mirror renderer takes, by where the
+/// decoder leaves them. Frames in VA surfaces are converted on the GPU and handed on as
+/// dmabufs, where the post-processor can export them; the others are converted on the
+/// CPU.
+fn convert_desc(features: Option<&CapsFeaturesRef>) -> &'static str {
+    let has = |feature| features.is_some_and(|f| f.contains(feature));
+    if has("memory:VAMemory") {
+        "vapostproc ! capsfilter caps=\"video/x-raw(memory:DMABuf),format=BGRx;video/x-raw,format=BGRx\""
+    } else if has("memory:VASurface") {
+        "vaapipostproc ! capsfilter caps=\"video/x-raw(memory:DMABuf),format=BGRx;video/x-raw,format=BGRx\""
+    } else if has("memory:CUDAMemory") {
+        "cudadownload ! videoconvert ! video/x-raw,format=BGRx"
+    } else {
+        "videoconvert ! video/x-raw,format=BGRx"
+    }
+}
+
+/// The branch that shows decoded frames of `features`.
+fn video_branch(output: &VideoOutput, features: Option<&CapsFeaturesRef>) -> Result<Bin, String> {
+    let VideoOutput::Feed(feed) = output else {
+        return gstreamer::parse_bin_from_description("queue ! videoconvert ! autovideosink", true)
+            .map_err(|e| e.to_string());
+    };
+    let desc = format!(
+        "queue ! {} ! appsink name=present max-buffers=1 drop=true",
+        convert_desc(features)
+    );
+    let bin = gstreamer::parse_bin_from_description(&desc, true).map_err(|e| e.to_string())?;
+    let sink = bin
+        .by_name("present")
+        .and_then(|e| e.downcast::<AppSink>().ok())
+        .expect("appsink");
+    let feed = feed.clone();
+    sink.set_callbacks(
+        AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                let Some(feed) = feed.lock().unwrap().clone() else {
+                    return Ok(gstreamer::FlowSuccess::Ok);
+                };
+                if feed.is_hidden() {
+                    return Ok(gstreamer::FlowSuccess::Ok);
+                }
+                match owned_frame(&sample) {
+                    Ok((format, frame)) => feed.push(&format, frame),
+                    Err(e) => println!("Dropping a frame: {e}"),
+                }
+                Ok(gstreamer::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    Ok(bin)
+}
+
+/// The frame of `sample` as the mirror renderer takes it. A dmabuf is shared, not
+/// copied, so the decoder may write the next frame into it once its pool comes round
+/// to it again, like a capture's producer.
+fn owned_frame(sample: &Sample) -> Result<(PipewireFrameFormat, OwnedFrame), String> {
+    let caps = sample.caps().ok_or("a sample without caps")?;
+    let info = VideoInfo::from_caps(caps).map_err(|e| format!("caps: {e}"))?;
+    let buffer = sample.buffer().ok_or("a sample without a buffer")?;
+    let format = PipewireFrameFormat {
+        width: info.width(),
+        height: info.height(),
+        format: drm_fourcc(info.format())
+            .ok_or_else(|| format!("can't show {:?}", info.format()))?,
+        // BGRx caps are linear, other layouts say DMA_DRM
+        modifier: DRM_FORMAT_MOD_LINEAR,
+    };
+    // the meta has the layout the decoder picked, the caps only the default one
+    let (offset, stride) = match buffer.meta::<VideoMeta>() {
+        Some(meta) => (meta.offset()[0], meta.stride()[0]),
+        None => (info.offset()[0], info.stride()[0]),
+    };
+
+    if buffer.n_memory() == 1 {
+        let memory = buffer.peek_memory(0);
+        if let Some(dmabuf) = memory.downcast_memory_ref::<DmaBufMemory>() {
+            let fd = unsafe { BorrowedFd::borrow_raw(dmabuf.fd()) }
+                .try_clone_to_owned()
+                .map_err(|e| format!("dmabuf: {e}"))?;
+            let plane = OwnedDmabufPlane {
+                fd,
+                offset: (memory.offset() + offset) as u32,
+                stride,
+            };
+            return Ok((
+                format,
+                OwnedFrame::Dmabuf {
+                    planes: vec![plane],
+                },
+            ));
+        }
+    }
+    let map = buffer
+        .map_readable()
+        .map_err(|e| format!("mapping the frame: {e}"))?;
+    let size = stride.unsigned_abs() as usize * info.height() as usize;
+    let data = map
+        .get(offset..offset + size)
+        .ok_or("a frame smaller than its caps")?;
+    Ok((
+        format,
+        OwnedFrame::Shm {
+            data: data.to_vec(),
+            stride,
+        },
+    ))
+}
+
+/// The elements in `pipeline` that reorder packets and wait for late ones.
+fn jitter_buffers(pipeline: &Pipeline) -> Vec<Element> {
+    pipeline
+        .iterate_recurse()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.factory().is_some_and(|f| f.name() == "rtpjitterbuffer"))
+        .collect()
+}
+
+/// What the viewer adds to the delay of the sender and the network: jitter buffer,
+/// decoder and sinks. `None` before the stream is up.
+fn pipeline_latency(pipeline: &Pipeline) -> Option<Duration> {
+    let mut query = gstreamer::query::Latency::new();
+    if !pipeline.query(&mut query) {
+        return None;
+    }
+    let (_, min, _) = query.result();
+    Some(Duration::from_nanos(min.nseconds()))
+}
+
+/// Plays what `lensing stream` sends: the video in a window and, from RTSP servers that
+/// have it, the audio through PipeWire.
+pub struct Viewer {
+    pipeline: Pipeline,
+    control: ViewControl,
+    uri: String,
+    /// Where the frames go with [`ViewTuning::hw_decode`].
+    feed: Arc<Mutex<Option<FrameFeed>>>,
+}
+
+impl Viewer {
+    /// `volume` is in percent. RTP streams are taken as `tuning.codec`, as they don't
+    /// say what they carry.
+    pub fn new(
+        target: &StreamTarget,
+        view: &ViewTuning,
+        volume: u32,
+        tuning: &Tuning,
+    ) -> Result<Self, String> {
+        let latency = view.latency;
+        let latency_ms = latency.as_millis() as u32;
+        let low_latency = view.low_latency;
+        let audio = gstreamer::parse_bin_from_description(
+            &format!(
+                "queue max-size-time={} ! audioconvert ! audioresample ! volume name=volume volume={} ! pipewiresink",
+                latency.as_nanos() * 2,
+                volume as f64 / 100.0
+            ),
+            true,
+        )
+        .map_err(|e| format!("audio output: {e}"))?;
+        let volume = audio.by_name("volume").expect("volume element");
+
+        let feed = Arc::new(Mutex::new(None));
+        let video = if view.hw_decode {
+            if !prefer_hw_decoders() {
+                println!("No hardware decoder is installed, decoding on the CPU");
+            }
+            VideoOutput::Feed(feed.clone())
+        } else {
+            VideoOutput::Sink
+        };
+        let (pipeline, uri) = match target {
+            StreamTarget::Rtsp {
+                address,
+                port,
+                path,
+            } => {
+                let host = if address == "0.0.0.0" {
+                    "localhost"
+                } else {
+                    address
+                };
+                let uri = format!("rtsp://{host}:{port}{path}");
+                let pipeline = rtsp_pipeline(&uri, latency_ms, low_latency, video, audio)
+                    .map_err(|e| format!("{uri}: {e}"))?;
+                (pipeline, uri)
+            }
+            StreamTarget::Rtp { host, port } => {
+                let uri = format!("rtp://{host}:{port}");
+                let (pay, encoding) = payloader(tuning.codec);
+                let depay = pay.split(' ').next().unwrap_or(pay).replace("pay", "depay");
+                // a multicast group has to be joined, any other address is where we listen
+                let desc = format!(
+                    "udpsrc address={host} port={port} caps=\"application/x-rtp,media=video,clock-rate=90000,encoding-name={encoding},payload={PAYLOAD_TYPE}\" ! rtpjitterbuffer latency={latency_ms} drop-on-latency={low_latency} ! {depay} ! decodebin name=decode"
+                );
+                let pipeline = gstreamer::parse_launch(&desc)
+                    .map_err(|e| format!("{uri}: {e}"))?
+                    .downcast::<Pipeline>()
+                    .expect("pipeline");
+                let decode = pipeline.by_name("decode").expect("decodebin");
+                link_outputs(&pipeline, &decode, video, None);
+                (pipeline, uri)
+            }
+        };
+        if low_latency {
+            pipeline.connect_deep_element_added(|_, _, element| {
+                // libav decodes as many frames at once as there are threads, and holds
+                // them back until the last is done
+                if element.has_property("thread-type", None) {
+                    element.set_property_from_str("thread-type", "slice");
+                }
+            });
+        }
+        let control = ViewControl {
+            volume,
+            pipeline: pipeline.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        Ok(Self {
+            pipeline,
+            control,
+            uri,
+            feed,
+        })
+    }
+
+    pub fn control(&self) -> ViewControl {
+        self.control.clone()
+    }
+
+    /// Play until the stream ends, the window is closed or the user presses Enter.
+    pub fn run(&self) -> Result<(), String> {
+        let bus = self.pipeline.bus().expect("pipeline bus");
+        self.pipeline
+            .set_state(gstreamer::State::Playing)
+            .map_err(|e| format!("can't start playing: {e}"))?;
+        super::watch_stdin();
+        println!("Playing. Press Enter to stop.");
+
+        let mut shown_latency = None;
+        let result = loop {
+            if super::stop_requested() || self.control.stop.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            let Some(msg) = bus.timed_pop(gstreamer::ClockTime::from_mseconds(100)) else {
+                continue;
+            };
+            match msg.view() {
+                MessageView::Eos(..) => break Ok(()),
+                // an element's latency changed, e.g. once the decoder is known
+                MessageView::Latency(..) => {
+                    let _ = self.pipeline.recalculate_latency();
+                    let latency = pipeline_latency(&self.pipeline);
+                    if let Some(latency) = latency.filter(|l| Some(*l) != shown_latency) {
+                        println!(
+                            "Latency: {} ms on top of the network's",
+                            latency.as_millis()
+                        );
+                        shown_latency = Some(latency);
+                    }
+                }
+                MessageView::Error(err) => {
+                    break Err(format!(
+                        "Error from {:?}: {} ({:?})",
+                        err.src().map(|s| s.path_string()),
+                        err.error(),
+                        err.debug()
+                    ))
+                }
+                _ => {}
+            }
+        };
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+        result
+    }
+
+    /// [`Viewer::run`] with [`ViewTuning::hw_decode`]: the video shows in a window like
+    /// a mirror's, which takes the decoder's dmabufs as they are, and closing it ends
+    /// the viewer.
+    pub fn run_in_window(
+        self,
+        desktop: &WlClientDesktopState,
+        tuning: &Tuning,
+    ) -> Result<(), String> {
+        let control = self.control();
+        let uri = self.uri.clone();
+        let thread = Rc::new(Cell::new(None));
+        let thread_slot = thread.clone();
+        let result = Mirror::present(desktop, &uri, tuning, move |feed| {
+            *self.feed.lock().unwrap() = Some(feed.clone());
+            let handle = std::thread::Builder::new()
+                .name("view".into())
+                .spawn(move || {
+                    let result = self.run();
+                    feed.end(result.err());
+                })
+                .map_err(|e| format!("viewer thread: {e}"))?;
+            thread_slot.set(Some(handle));
+            Ok(())
+        });
+        // the window is gone, whoever ended first
+        control.stop();
+        if let Some(handle) = thread.take() {
+            let _ = handle.join();
+        }
+        result
+    }
+}
+
+/// RTSP servers say what they send, so decodebin can pick the depayloaders and decoders.
+/// The first video and audio stream are played, the audio through `audio`.
+fn rtsp_pipeline(
+    uri: &str,
+    latency_ms: u32,
+    low_latency: bool,
+    video: VideoOutput,
+    audio: Bin,
+) -> Result<Pipeline, glib::BoolError> {
+    let pipeline = Pipeline::new(None);
+    let decode = ElementFactory::make("uridecodebin")
+        .property("uri", uri)
+        .build()?;
+    pipeline.add(&decode)?;
+    decode.connect("source-setup", false, move |values| {
+        if let Ok(source) = values[1].get::<Element>() {
+            if source.has_property("latency", None) {
+                source.set_property("latency", latency_ms);
+            }
+            if source.has_property("drop-on-latency", None) {
+                source.set_property("drop-on-latency", low_latency);
+            }
+        }
+        None
+    });
+
+    link_outputs(&pipeline, &decode, video, Some(audio));
+    Ok(pipeline)
+}
+
+/// Show the first video stream `decode` comes up with and play the first audio stream
+/// through `audio`, each once it is there.
+fn link_outputs(pipeline: &Pipeline, decode: &Element, video: VideoOutput, audio: Option<Bin>) {
+    let outputs = Arc::new(Mutex::new((Some(video), audio)));
+    let weak_pipeline = pipeline.downgrade();
+    decode.connect_pad_added(move |_, pad| {
+        let Some(pipeline) = weak_pipeline.upgrade() else {
+            return;
+        };
+        let Some(caps) = pad.current_caps() else {
+            return;
+        };
+        let Some(structure) = caps.structure(0) else {
+            return;
+        };
+        let mut outputs = outputs.lock().unwrap();
+        let branch = if structure.name().starts_with("video/") {
+            outputs
+                .0
+                .take()
+                .map(|video| video_branch(&video, caps.features(0)))
+        } else if structure.name().starts_with("audio/") {
+            outputs.1.take().map(Ok)
+        } else {
+            None
+        };
+        let linked = match branch {
+            None => return,
+            Some(Ok(branch)) => {
+                pipeline.add(&branch).is_ok()
+                    && branch
+                        .static_pad("sink")
+                        .is_some_and(|sink| pad.link(&sink).is_ok())
+                    && branch.sync_state_with_parent().is_ok()
+            }
+            Some(Err(e)) => {
+                println!("{e}");
+                false
+            }
+        };
+        if !linked {
+            println!("Could not play the {} of the stream", structure.name());
+        }
+    });
+}
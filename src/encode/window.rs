@@ -0,0 +1,131 @@
+use std::os::fd::RawFd;
+
+use gstreamer::{
+    glib, prelude::*, Element, EventView, PadProbeData, PadProbeReturn, PadProbeType, Pipeline,
+};
+
+use crate::{audio::AudioConfig, portal::PortalStream, preset::Tuning};
+
+use super::{
+    frames_in_tap, frames_out_tap, mux_desc, prerecord, record_stream_pipeline, stereo,
+    video_chain, writer, Container,
+};
+
+/// Record a single window.
+///
+/// Some compositors include the frame of X11 windows (XWayland clients) in the stream
+/// while reporting the size of the client area, so when the portal reports a size the
+/// frames are cropped down to it. `scales` are those of the outputs the window may be
+/// on, see [`crate::wl_client_desktop::OutputState::scale`], since HiDPI windows
+/// stream at a multiple of the size. Otherwise this is [`record_stream_pipeline`].
+pub fn record_window_pipeline(
+    fd: RawFd,
+    stream: &PortalStream,
+    location: &str,
+    audio: Option<&AudioConfig>,
+    tuning: &Tuning,
+    scales: &[f64],
+) -> Result<Pipeline, glib::Error> {
+    // raw frames are kept as they come, decorations and all
+    let Some(content_size) = stream
+        .size
+        .filter(|_| Container::of(location, tuning) != Container::Raw)
+    else {
+        return record_stream_pipeline(fd, stream.node_id, location, audio, tuning);
+    };
+
+    // cropping needs the frames in system memory
+    let chain = video_chain(false, tuning);
+    println!("Encoder path: {:?}", chain.path);
+
+    let mut desc = format!(
+        "{} ! {} ! videoconvert ! videocrop name=decorations{}{} ! {}{}{} ! {} ! {}",
+        tuning.pipewiresrc_desc(fd, stream.node_id),
+        tuning.encoder_queue_desc(),
+        frames_in_tap(tuning),
+        stereo::pack(tuning),
+        chain.desc,
+        frames_out_tap(tuning),
+        prerecord::tap(tuning, "video"),
+        mux_desc(location, tuning),
+        writer::sink_desc(location, tuning),
+    );
+    if let Some(audio) = audio {
+        desc.push_str(&format!(
+            " {} ! {}{} ! mux.",
+            audio.encoded_chain(),
+            tuning.queue_desc(),
+            prerecord::tap(tuning, "audio")
+        ));
+    }
+
+    let pipeline = gstreamer::parse_launch(&desc)?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+
+    super::pin_threads(&pipeline, tuning);
+    writer::attach(&pipeline, location, tuning)?;
+    stereo::attach(pipeline.upcast_ref(), tuning);
+
+    let crop = pipeline.by_name("decorations").expect("decorations crop");
+    trim_decorations(&crop, content_size, scales.to_vec());
+
+    Ok(pipeline)
+}
+
+/// Once the stream size is known, crop whatever is around the `content_size` client area,
+/// scaled by the largest of `scales` it fits at. X11 frames have a title bar at the top
+/// and borders of equal width on the other sides.
+fn trim_decorations(crop: &Element, content_size: (i32, i32), scales: Vec<f64>) {
+    let pad = crop.static_pad("sink").expect("videocrop sink pad");
+    let crop = crop.clone();
+
+    pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(PadProbeData::Event(ref event)) = info.data else {
+            return PadProbeReturn::Ok;
+        };
+        let EventView::Caps(caps) = event.view() else {
+            return PadProbeReturn::Ok;
+        };
+        let Some(s) = caps.caps().structure(0) else {
+            return PadProbeReturn::Ok;
+        };
+        let (Ok(width), Ok(height)) = (s.get::<i32>("width"), s.get::<i32>("height")) else {
+            return PadProbeReturn::Ok;
+        };
+
+        // a HiDPI window streams at its logical size times the output's scale, and
+        // only what is around that is decorations
+        let scaled = |scale: f64| {
+            (
+                (content_size.0 as f64 * scale).round() as i32,
+                (content_size.1 as f64 * scale).round() as i32,
+            )
+        };
+        let content = scales
+            .iter()
+            .chain([1.0].iter())
+            .map(|&scale| scaled(scale))
+            .filter(|&(w, h)| w <= width && h <= height)
+            .max()
+            .unwrap_or(content_size);
+        let extra_w = (width - content.0).max(0);
+        let extra_h = (height - content.1).max(0);
+        // a pixel off is rounding of the scale
+        if extra_w <= 1 && extra_h <= 1 {
+            return PadProbeReturn::Ok;
+        }
+
+        println!(
+            "Window stream is {width}x{height}, cropping decorations down to {}x{}",
+            content.0, content.1
+        );
+        let border = extra_w / 2;
+        let bottom = border.min(extra_h);
+        crop.set_property("left", border);
+        crop.set_property("right", extra_w - border);
+        crop.set_property("top", extra_h - bottom);
+        crop.set_property("bottom", bottom);
+        PadProbeReturn::Ok
+    });
+}
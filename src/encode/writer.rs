@@ -0,0 +1,361 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::JoinHandle,
+};
+
+use gstreamer::{
+    buffer::{MappedBuffer, Readable},
+    format::Bytes,
+    glib,
+    prelude::*,
+    Bin, FlowError, FlowSuccess, Format, PadProbeData, PadProbeReturn, PadProbeType, QueryViewMut,
+    Sample,
+};
+use gstreamer_app::{AppSink, AppSinkCallbacks};
+use io_uring::{opcode, types, IoUring};
+
+use crate::preset::Tuning;
+
+/// Name of the sink [`sink_desc`] puts at the end of a recording.
+pub const SINK_NAME: &str = "file_writer";
+
+/// Writes in flight at once, and the most the ring is asked to hold.
+const QUEUE_DEPTH: u32 = 32;
+
+/// The end of a recording pipeline that writes to `location`: a `filesink`, or with a
+/// write buffer an `appsink` that [`attach`] hands the data to a writer thread from.
+pub fn sink_desc(location: &str, tuning: &Tuning) -> String {
+    match tuning.write_buffer {
+        Some(_) => format!("appsink name={SINK_NAME} sync=false enable-last-sample=false"),
+        None => format!("filesink name={SINK_NAME} location=\"{location}\""),
+    }
+}
+
+/// Write what reaches the [`sink_desc`] sink of `bin` to `location` from a thread
+/// of its own, through io_uring where the kernel has it, so a slow disk (an SD card,
+/// NFS) only holds up the pipeline once the write buffer is full. EOS waits for the
+/// file to be written out. Does nothing for a `filesink`.
+pub fn attach(bin: &impl IsA<Bin>, location: &str, tuning: &Tuning) -> Result<(), glib::Error> {
+    let (Some(capacity), Some(sink)) = (tuning.write_buffer, bin.by_name(SINK_NAME)) else {
+        return Ok(());
+    };
+    let sink = sink.downcast::<AppSink>().expect("appsink");
+    let file = File::create(location).map_err(|e| {
+        glib::Error::new(
+            gstreamer::ResourceError::OpenWrite,
+            &format!("{location}: {e}"),
+        )
+    })?;
+    let writer = Arc::new(Writer::spawn(file, capacity as usize * 1024 * 1024));
+
+    // muxers only go back to fill in headers if they can seek
+    let pad = sink.static_pad("sink").expect("appsink sink pad");
+    pad.add_probe(PadProbeType::QUERY_DOWNSTREAM, |_, info| {
+        let Some(PadProbeData::Query(query)) = info.data.as_mut() else {
+            return PadProbeReturn::Ok;
+        };
+        match query.view_mut() {
+            QueryViewMut::Seeking(q) if q.format() == Format::Bytes => {
+                q.set(true, Some(Bytes::ZERO), Bytes::NONE);
+                PadProbeReturn::Handled
+            }
+            _ => PadProbeReturn::Ok,
+        }
+    });
+
+    let position = Mutex::new(Position::default());
+    let finishing = writer.clone();
+    sink.set_callbacks(
+        AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| FlowError::Eos)?;
+                let size = sample.buffer().map_or(0, |b| b.size() as u64);
+                let offset = position
+                    .lock()
+                    .unwrap()
+                    .advance(segment_start(&sample), size);
+                let Some(buffer) = sample.buffer_owned() else {
+                    return Ok(FlowSuccess::Ok);
+                };
+                let data = buffer
+                    .into_mapped_buffer_readable()
+                    .map_err(|_| FlowError::Error)?;
+                if let Err(e) = writer.push(Chunk { offset, data }) {
+                    gstreamer::element_error!(sink, gstreamer::ResourceError::Write, ["{}", e]);
+                    return Err(FlowError::Error);
+                }
+                Ok(FlowSuccess::Ok)
+            })
+            .eos(move |_| {
+                if let Err(e) = finishing.finish() {
+                    println!("Could not write the recording: {e}");
+                }
+            })
+            .build(),
+    );
+    Ok(())
+}
+
+/// Where the next buffer goes. A new segment in bytes is a muxer seeking back.
+#[derive(Default)]
+struct Position {
+    next: u64,
+    segment_start: Option<u64>,
+}
+
+impl Position {
+    /// The offset of a buffer of `size` bytes in a segment from `start`, see
+    /// [`segment_start`].
+    fn advance(&mut self, start: Option<u64>, size: u64) -> u64 {
+        if start.is_some() && start != self.segment_start {
+            self.segment_start = start;
+            self.next = start.unwrap_or_default();
+        }
+        let offset = self.next;
+        self.next += size;
+        offset
+    }
+}
+
+/// Where the byte segment of `sample` starts, if it has one.
+fn segment_start(sample: &Sample) -> Option<u64> {
+    sample
+        .segment()
+        .and_then(|s| s.downcast_ref::<Bytes>())
+        .and_then(|s| s.start())
+        .map(|start| *start)
+}
+
+struct Chunk {
+    offset: u64,
+    data: MappedBuffer<Readable>,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+#[derive(Default)]
+struct Backlog {
+    chunks: VecDeque<Chunk>,
+    /// Waiting and being written.
+    bytes: usize,
+    finished: bool,
+    error: Option<String>,
+    warned: bool,
+}
+
+struct Shared {
+    backlog: Mutex<Backlog>,
+    changed: Condvar,
+    capacity: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Backlog> {
+        self.backlog.lock().unwrap()
+    }
+
+    /// Chunks that follow each other in the file, so that none overwrite each other in
+    /// flight. Empty once finished.
+    fn next_batch(&self) -> Vec<Chunk> {
+        let mut backlog = self.lock();
+        while backlog.chunks.is_empty() && !backlog.finished {
+            backlog = self.changed.wait(backlog).unwrap();
+        }
+        let mut batch: Vec<Chunk> = vec![];
+        while batch.len() < QUEUE_DEPTH as usize {
+            let follows = match (batch.last(), backlog.chunks.front()) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(last), Some(next)) => next.offset == last.end(),
+            };
+            if !follows {
+                break;
+            }
+            batch.extend(backlog.chunks.pop_front());
+        }
+        batch
+    }
+
+    fn written(&self, bytes: usize) {
+        self.lock().bytes -= bytes;
+        self.changed.notify_all();
+    }
+
+    fn fail(&self, error: String) {
+        let mut backlog = self.lock();
+        backlog.error = Some(error);
+        backlog.chunks.clear();
+        backlog.bytes = 0;
+        self.changed.notify_all();
+    }
+}
+
+/// A file written from a thread of its own, with up to `capacity` bytes waiting.
+struct Writer {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Writer {
+    fn spawn(file: File, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            backlog: Mutex::new(Backlog::default()),
+            changed: Condvar::new(),
+            capacity,
+        });
+        let writing = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("file writer".into())
+            .spawn(move || {
+                if let Err(e) = write_out(&file, &writing) {
+                    writing.fail(e.to_string());
+                }
+            })
+            .expect("file writer thread");
+        Self {
+            shared,
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    /// Blocks only while the buffer is full.
+    fn push(&self, chunk: Chunk) -> Result<(), String> {
+        let len = chunk.data.len();
+        let mut backlog = self.shared.lock();
+        let full = |backlog: &Backlog| {
+            backlog.error.is_none()
+                && backlog.bytes > 0
+                && backlog.bytes + len > self.shared.capacity
+        };
+        if full(&backlog) && !backlog.warned {
+            backlog.warned = true;
+            println!(
+                "The disk can't keep up, the {} MiB write buffer is full",
+                self.shared.capacity / (1024 * 1024)
+            );
+        }
+        while full(&backlog) {
+            backlog = self.shared.changed.wait(backlog).unwrap();
+        }
+        if let Some(e) = &backlog.error {
+            return Err(e.clone());
+        }
+        backlog.bytes += len;
+        backlog.chunks.push_back(chunk);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Wait for everything to be on disk.
+    fn finish(&self) -> Result<(), String> {
+        self.shared.lock().finished = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+        match &self.shared.lock().error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // without EOS, e.g. after an error, what made it here is still written
+        let _ = self.finish();
+    }
+}
+
+fn write_out(file: &File, shared: &Shared) -> io::Result<()> {
+    let mut ring = match IoUring::new(QUEUE_DEPTH) {
+        Ok(ring) => Some(ring),
+        Err(e) => {
+            println!("No io_uring ({e}), writing the recording with plain writes");
+            None
+        }
+    };
+    loop {
+        let batch = shared.next_batch();
+        if batch.is_empty() {
+            return file.sync_all();
+        }
+        match ring.as_mut() {
+            Some(ring) => write_batch(ring, file, &batch)?,
+            None => {
+                for chunk in &batch {
+                    file.write_all_at(chunk.data.as_slice(), chunk.offset)?;
+                }
+            }
+        }
+        shared.written(batch.iter().map(|c| c.data.len()).sum());
+    }
+}
+
+fn write_batch(ring: &mut IoUring, file: &File, batch: &[Chunk]) -> io::Result<()> {
+    let fd = types::Fd(file.as_raw_fd());
+    for (i, chunk) in batch.iter().enumerate() {
+        let data = chunk.data.as_slice();
+        let write = opcode::Write::new(fd, data.as_ptr(), data.len() as u32)
+            .offset(chunk.offset)
+            .build()
+            .user_data(i as u64);
+        // the batch fits the ring, and the data outlives the writes
+        unsafe { ring.submission().push(&write) }
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "io_uring submission queue full"))?;
+    }
+    ring.submit_and_wait(batch.len())?;
+
+    let completions: Vec<(usize, i32)> = ring
+        .completion()
+        .map(|c| (c.user_data() as usize, c.result()))
+        .collect();
+    for (i, result) in completions {
+        if result < 0 {
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        // a short write is finished the plain way
+        let chunk = &batch[i];
+        let written = result as usize;
+        if written < chunk.data.len() {
+            file.write_all_at(
+                &chunk.data.as_slice()[written..],
+                chunk.offset + written as u64,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_buffers_in_one_segment() {
+        let mut position = Position::default();
+        assert_eq!(position.advance(Some(0), 100), 0);
+        assert_eq!(position.advance(Some(0), 50), 100);
+        assert_eq!(position.advance(None, 10), 150);
+        assert_eq!(position.advance(Some(0), 1), 160);
+    }
+
+    #[test]
+    fn seeks_to_new_segments() {
+        let mut position = Position::default();
+        position.advance(Some(0), 1000);
+        // the muxer going back to fill in a header
+        assert_eq!(position.advance(Some(24), 8), 24);
+        assert_eq!(position.advance(Some(24), 4), 32);
+        // and back to the end
+        assert_eq!(position.advance(Some(1000), 10), 1000);
+    }
+}
@@ -0,0 +1,40 @@
+use thiserror::Error;
+
+/// Why there are no frames, for programs embedding lensing to tell the user rather than
+/// crash, e.g. on a headless session or without a screencast portal.
+#[derive(Debug, Error)]
+pub enum LensingError {
+    /// No screencast portal, or the user cancelled its dialog.
+    #[error("screencast portal: {0}")]
+    Portal(#[from] ashpd::Error),
+    /// No compositor to connect to, or it lacks a global we can't do without.
+    #[error("wayland: {0}")]
+    Wayland(String),
+    #[error("pipewire: {0}")]
+    PipeWire(#[from] pipewire::Error),
+    /// The producer and we have no format in common.
+    #[error("no format to agree on with the producer: {0}")]
+    Negotiation(String),
+    /// The stream failed after it was negotiated.
+    #[error("stream: {0}")]
+    Stream(String),
+    /// The portal closed the session, e.g. because it was restarted.
+    #[error("the portal closed the session")]
+    SessionClosed,
+    /// The portal handed out no stream, e.g. because nothing was selected.
+    #[error("the portal handed out no stream")]
+    NoStream,
+    /// A capture thread could not be started, or panicked.
+    #[error("capture thread: {0}")]
+    Thread(String),
+    /// A GStreamer pipeline could not be built, e.g. for a missing plugin or a bad
+    /// `filter=` element, or a sink could not be attached to it.
+    #[error("pipeline: {0}")]
+    Pipeline(String),
+}
+
+impl From<gstreamer::glib::Error> for LensingError {
+    fn from(e: gstreamer::glib::Error) -> Self {
+        LensingError::Pipeline(e.to_string())
+    }
+}
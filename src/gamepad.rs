@@ -0,0 +1,340 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// from linux/input.h, linux/input-event-codes.h and linux/uinput.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+/// Also BTN_GAMEPAD: every gamepad has it, and nothing else does.
+const BTN_SOUTH: usize = 0x130;
+const KEY_MAX: usize = 0x2ff;
+const ABS_MAX: usize = 0x3f;
+
+const EVIOCGID: libc::c_ulong = 0x80084502;
+const EVIOCGRAB: libc::c_ulong = 0x40044590;
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_SETUP: libc::c_ulong = 0x405c5503;
+const UI_ABS_SETUP: libc::c_ulong = 0x401c5504;
+const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
+const UI_SET_ABSBIT: libc::c_ulong = 0x40045567;
+
+/// The name our copies go by, so they aren't copied again.
+const COPY_PREFIX: &str = "lensing ";
+
+const fn eviocgname(len: usize) -> libc::c_ulong {
+    (2 << 30) | ((len as libc::c_ulong) << 16) | (0x45 << 8) | 0x06
+}
+
+const fn eviocgbit(ev: u16, len: usize) -> libc::c_ulong {
+    (2 << 30) | ((len as libc::c_ulong) << 16) | (0x45 << 8) | (0x20 + ev as libc::c_ulong)
+}
+
+const fn eviocgabs(axis: usize) -> libc::c_ulong {
+    (2 << 30)
+        | ((std::mem::size_of::<AbsInfo>() as libc::c_ulong) << 16)
+        | (0x45 << 8)
+        | (0x40 + axis as libc::c_ulong)
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct AbsInfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+#[repr(C)]
+struct UinputSetup {
+    id: InputId,
+    name: [u8; 80],
+    ff_effects_max: u32,
+}
+
+#[repr(C)]
+struct UinputAbsSetup {
+    code: u16,
+    absinfo: AbsInfo,
+}
+
+#[repr(C)]
+struct EvdevEvent {
+    time: libc::timeval,
+    type_: u16,
+    code: u16,
+    value: i32,
+}
+
+impl EvdevEvent {
+    fn new(type_: u16, code: u16, value: i32) -> Self {
+        Self {
+            // uinput stamps them itself
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The codes of type `ev` that `device` has, up to `max`.
+fn bits(device: &File, ev: u16, max: usize) -> Vec<usize> {
+    let mut bytes = vec![0u8; max / 8 + 1];
+    let result = unsafe {
+        libc::ioctl(
+            device.as_raw_fd(),
+            eviocgbit(ev, bytes.len()),
+            bytes.as_mut_ptr(),
+        )
+    };
+    if result < 0 {
+        return vec![];
+    }
+    (0..=max)
+        .filter(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0)
+        .collect()
+}
+
+fn device_name(device: &File) -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe {
+        libc::ioctl(
+            device.as_raw_fd(),
+            eviocgname(name.len()),
+            name.as_mut_ptr(),
+        )
+    };
+    if result < 0 {
+        return "gamepad".into();
+    }
+    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// `/dev/input/event*`, in the order the kernel numbered them.
+fn event_devices() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return vec![];
+    };
+    let mut devices: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("event")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    devices.sort_unstable();
+    devices
+        .into_iter()
+        .map(|n| format!("/dev/input/event{n}"))
+        .collect()
+}
+
+/// A uinput device with the buttons, axes and ids of `source`.
+fn create_copy(source: &File, name: &str) -> std::io::Result<File> {
+    let copy = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/uinput")?;
+    let (fd, source_fd) = (copy.as_raw_fd(), source.as_raw_fd());
+
+    check(unsafe { libc::ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_int) })?;
+    for key in bits(source, EV_KEY, KEY_MAX) {
+        check(unsafe { libc::ioctl(fd, UI_SET_KEYBIT, key as libc::c_int) })?;
+    }
+    let axes = bits(source, EV_ABS, ABS_MAX);
+    if !axes.is_empty() {
+        check(unsafe { libc::ioctl(fd, UI_SET_EVBIT, EV_ABS as libc::c_int) })?;
+    }
+    for axis in axes {
+        let mut absinfo = AbsInfo::default();
+        check(unsafe { libc::ioctl(source_fd, eviocgabs(axis), &mut absinfo) })?;
+        check(unsafe { libc::ioctl(fd, UI_SET_ABSBIT, axis as libc::c_int) })?;
+        let setup = UinputAbsSetup {
+            code: axis as u16,
+            absinfo,
+        };
+        check(unsafe { libc::ioctl(fd, UI_ABS_SETUP, &setup) })?;
+    }
+
+    // the same ids, so games pick the same button mapping
+    let mut setup = UinputSetup {
+        id: InputId::default(),
+        name: [0; 80],
+        ff_effects_max: 0,
+    };
+    check(unsafe { libc::ioctl(source_fd, EVIOCGID, &mut setup.id) })?;
+    let name = format!("{COPY_PREFIX}{name}");
+    let len = name.len().min(setup.name.len() - 1);
+    setup.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    check(unsafe { libc::ioctl(fd, UI_DEV_SETUP, &setup) })?;
+    check(unsafe { libc::ioctl(fd, UI_DEV_CREATE) })?;
+    Ok(copy)
+}
+
+struct Gamepad {
+    name: String,
+    source: File,
+    copy: File,
+    /// Buttons that are down on the copy, to let go of when forwarding stops.
+    held: Mutex<Vec<u16>>,
+}
+
+impl Gamepad {
+    /// Pass the events of the gamepad on while `forwarding` is set, until it goes away.
+    fn forward(&self, forwarding: &AtomicBool) {
+        let mut bytes = [0u8; std::mem::size_of::<EvdevEvent>()];
+        loop {
+            if let Err(e) = (&self.source).read_exact(&mut bytes) {
+                println!("Stopped passing on {}: {e}", self.name);
+                return;
+            }
+            let event = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const EvdevEvent) };
+
+            // held while writing, so a release can't come before the press it releases
+            let mut held = self.held.lock().unwrap();
+            if !forwarding.load(Ordering::Relaxed) {
+                continue;
+            }
+            if event.type_ == EV_KEY {
+                held.retain(|code| *code != event.code);
+                if event.value != 0 {
+                    held.push(event.code);
+                }
+            }
+            // a full uinput queue drops events, like a slow reader of the gamepad would
+            let _ = (&self.copy).write_all(event.as_bytes());
+        }
+    }
+
+    fn release(&self) {
+        let mut held = self.held.lock().unwrap();
+        if held.is_empty() {
+            return;
+        }
+        for code in held.drain(..) {
+            let _ = (&self.copy).write_all(EvdevEvent::new(EV_KEY, code, 0).as_bytes());
+        }
+        let _ = (&self.copy).write_all(EvdevEvent::new(EV_SYN, SYN_REPORT, 0).as_bytes());
+    }
+}
+
+/// Passes local gamepads on as uinput copies, for sessions that don't see them, e.g.
+/// in a container or on another seat. The RemoteDesktop portal has no gamepads and
+/// libei doesn't do them yet, so they take this way next to pointer and touch input.
+///
+/// While forwarding, the gamepads are grabbed, so only the copies see them.
+pub struct Gamepads {
+    pads: Vec<Arc<Gamepad>>,
+    forwarding: Arc<AtomicBool>,
+}
+
+impl Gamepads {
+    /// Copy the gamepads that are plugged in, which takes read access to their
+    /// `/dev/input/event*` and write access to `/dev/uinput`.
+    pub fn open() -> Result<Self, String> {
+        let forwarding = Arc::new(AtomicBool::new(false));
+        let mut pads = vec![];
+        for path in event_devices() {
+            // most devices aren't for everyone to read, and we only need the gamepads
+            let Ok(source) = File::open(&path) else {
+                continue;
+            };
+            if !bits(&source, EV_KEY, KEY_MAX).contains(&BTN_SOUTH) {
+                continue;
+            }
+            let name = device_name(&source);
+            if name.starts_with(COPY_PREFIX) {
+                continue;
+            }
+            let copy = create_copy(&source, &name)
+                .map_err(|e| format!("copying {name} with /dev/uinput: {e}"))?;
+            let pad = Arc::new(Gamepad {
+                name: name.clone(),
+                source,
+                copy,
+                held: Mutex::new(vec![]),
+            });
+
+            let (thread_pad, thread_forwarding) = (pad.clone(), forwarding.clone());
+            std::thread::Builder::new()
+                .name(format!("gamepad {path}"))
+                .spawn(move || thread_pad.forward(&thread_forwarding))
+                .map_err(|e| format!("gamepad thread: {e}"))?;
+            println!("Passing on {name} ({path}) while a mirror window has focus");
+            pads.push(pad);
+        }
+        if pads.is_empty() {
+            return Err("no gamepads, or none this user may read from /dev/input".into());
+        }
+        Ok(Self { pads, forwarding })
+    }
+
+    /// Start or stop passing the gamepads on. Stopping lets go of the buttons held on
+    /// the copies.
+    pub fn set_forwarding(&self, on: bool) {
+        if self.forwarding.swap(on, Ordering::Relaxed) == on {
+            return;
+        }
+        for pad in self.pads.iter() {
+            let grab = unsafe { libc::ioctl(pad.source.as_raw_fd(), EVIOCGRAB, on as libc::c_int) };
+            if let Err(e) = check(grab) {
+                println!("Could not grab {}: {e}", pad.name);
+            }
+            if !on {
+                pad.release();
+            }
+        }
+    }
+}
+
+impl Drop for Gamepads {
+    fn drop(&mut self) {
+        self.set_forwarding(false);
+    }
+}
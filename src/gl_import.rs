@@ -0,0 +1,297 @@
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    ptr,
+};
+
+use crate::pw_capture::{DrmFormat, PipewireDmabufPlane, PipewireFrameFormat};
+
+type EglDisplay = *mut c_void;
+type EglImage = *mut c_void;
+type EglBoolean = u32;
+
+// from EGL/egl.h and EGL/eglext.h
+const EGL_NONE: i32 = 0x3038;
+const EGL_EXTENSIONS: i32 = 0x3055;
+const EGL_HEIGHT: i32 = 0x3056;
+const EGL_WIDTH: i32 = 0x3057;
+const EGL_LINUX_DMA_BUF_EXT: u32 = 0x3270;
+const EGL_LINUX_DRM_FOURCC_EXT: i32 = 0x3271;
+/// fd, offset, pitch, modifier lo and hi of each plane.
+const EGL_DMA_BUF_PLANE_ATTRIBS: [[i32; 5]; 4] = [
+    [0x3272, 0x3273, 0x3274, 0x3443, 0x3444],
+    [0x3275, 0x3276, 0x3277, 0x3445, 0x3446],
+    [0x3278, 0x3279, 0x327a, 0x3447, 0x3448],
+    [0x3440, 0x3441, 0x3442, 0x3449, 0x344a],
+];
+
+// from GLES2/gl2.h and GLES2/gl2ext.h
+pub const GL_TEXTURE_2D: u32 = 0x0de1;
+/// For modifiers the driver can only sample through `samplerExternalOES`.
+pub const GL_TEXTURE_EXTERNAL_OES: u32 = 0x8d65;
+const GL_TEXTURE_MAG_FILTER: u32 = 0x2800;
+const GL_TEXTURE_MIN_FILTER: u32 = 0x2801;
+const GL_LINEAR: i32 = 0x2601;
+
+/// The modifier of buffers whose layout the driver knows without being told.
+const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+#[link(name = "EGL")]
+extern "C" {
+    fn eglGetProcAddress(name: *const c_char) -> *mut c_void;
+    fn eglQueryString(display: EglDisplay, name: i32) -> *const c_char;
+    fn eglGetError() -> i32;
+}
+
+/// Entry points of the extensions, which have to be looked up.
+struct Fns {
+    create_image:
+        unsafe extern "C" fn(EglDisplay, *mut c_void, u32, *mut c_void, *const i32) -> EglImage,
+    destroy_image: unsafe extern "C" fn(EglDisplay, EglImage) -> EglBoolean,
+    query_formats: unsafe extern "C" fn(EglDisplay, i32, *mut i32, *mut i32) -> EglBoolean,
+    query_modifiers: unsafe extern "C" fn(
+        EglDisplay,
+        i32,
+        i32,
+        *mut u64,
+        *mut EglBoolean,
+        *mut i32,
+    ) -> EglBoolean,
+    image_target_texture: unsafe extern "C" fn(u32, EglImage),
+    gen_textures: unsafe extern "C" fn(i32, *mut u32),
+    delete_textures: unsafe extern "C" fn(i32, *const u32),
+    bind_texture: unsafe extern "C" fn(u32, u32),
+    tex_parameter: unsafe extern "C" fn(u32, u32, i32),
+}
+
+unsafe fn proc_address<T>(name: &str) -> Result<T, String> {
+    let c_name = CString::new(name).unwrap();
+    let address = eglGetProcAddress(c_name.as_ptr());
+    if address.is_null() {
+        return Err(format!("{name} is missing"));
+    }
+    Ok(std::mem::transmute_copy(&address))
+}
+
+/// Turns dmabuf frames into EGLImages and GL textures, for consumers that render with
+/// OpenGL. Needs `EGL_EXT_image_dma_buf_import_modifiers`.
+///
+/// The display is the caller's, and textures are made in whichever context is current.
+pub struct GlImporter {
+    display: EglDisplay,
+    fns: Fns,
+}
+
+impl GlImporter {
+    /// # Safety
+    ///
+    /// `display` has to be an initialized `EGLDisplay` that outlives the importer.
+    pub unsafe fn new(display: *mut c_void) -> Result<Self, String> {
+        let extensions = eglQueryString(display, EGL_EXTENSIONS);
+        if extensions.is_null() {
+            return Err(format!("not an EGL display: {:#x}", eglGetError()));
+        }
+        let extensions = CStr::from_ptr(extensions).to_string_lossy();
+        for wanted in [
+            "EGL_EXT_image_dma_buf_import",
+            "EGL_EXT_image_dma_buf_import_modifiers",
+        ] {
+            if !extensions.split(' ').any(|e| e == wanted) {
+                return Err(format!("the EGL display lacks {wanted}"));
+            }
+        }
+
+        let fns = Fns {
+            create_image: proc_address("eglCreateImageKHR")?,
+            destroy_image: proc_address("eglDestroyImageKHR")?,
+            query_formats: proc_address("eglQueryDmaBufFormatsEXT")?,
+            query_modifiers: proc_address("eglQueryDmaBufModifiersEXT")?,
+            image_target_texture: proc_address("glEGLImageTargetTexture2DOES")?,
+            gen_textures: proc_address("glGenTextures")?,
+            delete_textures: proc_address("glDeleteTextures")?,
+            bind_texture: proc_address("glBindTexture")?,
+            tex_parameter: proc_address("glTexParameteri")?,
+        };
+        Ok(Self { display, fns })
+    }
+
+    /// The formats and modifiers the display imports, for [`crate::Capture::run`].
+    /// `external` includes modifiers that only work with [`GL_TEXTURE_EXTERNAL_OES`].
+    pub fn formats(&self, external: bool) -> Vec<DrmFormat> {
+        let mut formats = vec![];
+        for code in self.query(|max, out, count| unsafe {
+            (self.fns.query_formats)(self.display, max, out, count)
+        }) {
+            let mut count = 0;
+            unsafe {
+                (self.fns.query_modifiers)(
+                    self.display,
+                    code,
+                    0,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut count,
+                )
+            };
+            let mut modifiers = vec![0u64; count.max(0) as usize];
+            let mut external_only = vec![0; modifiers.len()];
+            unsafe {
+                (self.fns.query_modifiers)(
+                    self.display,
+                    code,
+                    count,
+                    modifiers.as_mut_ptr(),
+                    external_only.as_mut_ptr(),
+                    &mut count,
+                )
+            };
+            modifiers.truncate(count.max(0) as usize);
+
+            for (modifier, external_only) in modifiers.into_iter().zip(external_only) {
+                if external || external_only == 0 {
+                    formats.push(DrmFormat {
+                        code: code as u32,
+                        modifier,
+                    });
+                }
+            }
+        }
+        formats
+    }
+
+    /// The two call dance of the EGL queries: count, then fill.
+    fn query(&self, f: impl Fn(i32, *mut i32, *mut i32) -> EglBoolean) -> Vec<i32> {
+        let mut count = 0;
+        if f(0, ptr::null_mut(), &mut count) == 0 {
+            return vec![];
+        }
+        let mut values = vec![0; count.max(0) as usize];
+        f(count, values.as_mut_ptr(), &mut count);
+        values.truncate(count.max(0) as usize);
+        values
+    }
+
+    /// Import the planes of one frame. The image shares the buffer, so it shows whatever
+    /// the producer writes to it later.
+    pub fn import(
+        &self,
+        format: &PipewireFrameFormat,
+        planes: &[PipewireDmabufPlane],
+    ) -> Result<DmabufImage<'_>, String> {
+        if planes.is_empty() || planes.len() > EGL_DMA_BUF_PLANE_ATTRIBS.len() {
+            return Err(format!("can't import {} planes", planes.len()));
+        }
+
+        let mut attribs = vec![
+            EGL_WIDTH,
+            format.width as i32,
+            EGL_HEIGHT,
+            format.height as i32,
+            EGL_LINUX_DRM_FOURCC_EXT,
+            format.format as i32,
+        ];
+        for (plane, names) in planes.iter().zip(EGL_DMA_BUF_PLANE_ATTRIBS) {
+            attribs.extend([
+                names[0],
+                plane.fd,
+                names[1],
+                plane.offset as i32,
+                names[2],
+                plane.stride,
+            ]);
+            // leaving the modifier out means the implicit one
+            if format.modifier != DRM_FORMAT_MOD_INVALID {
+                attribs.extend([
+                    names[3],
+                    format.modifier as u32 as i32,
+                    names[4],
+                    (format.modifier >> 32) as u32 as i32,
+                ]);
+            }
+        }
+        attribs.push(EGL_NONE);
+
+        let image = unsafe {
+            (self.fns.create_image)(
+                self.display,
+                ptr::null_mut(),
+                EGL_LINUX_DMA_BUF_EXT,
+                ptr::null_mut(),
+                attribs.as_ptr(),
+            )
+        };
+        if image.is_null() {
+            return Err(format!(
+                "eglCreateImage failed for {:#x} with modifier {:#x}: {:#x}",
+                format.format,
+                format.modifier,
+                unsafe { eglGetError() }
+            ));
+        }
+        Ok(DmabufImage {
+            importer: self,
+            image,
+        })
+    }
+}
+
+/// An EGLImage of a dmabuf frame; destroyed on drop.
+pub struct DmabufImage<'a> {
+    importer: &'a GlImporter,
+    image: EglImage,
+}
+
+impl DmabufImage<'_> {
+    /// The `EGLImage`, for APIs that take one.
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.image
+    }
+
+    /// Make this image the storage of the texture bound to `target` in the current
+    /// context, [`GL_TEXTURE_2D`] or [`GL_TEXTURE_EXTERNAL_OES`].
+    pub fn bind(&self, target: u32) {
+        unsafe { (self.importer.fns.image_target_texture)(target, self.image) };
+    }
+
+    /// A new texture in the current context with this image as storage. Its contents
+    /// stay valid after the image is dropped.
+    pub fn texture(&self, target: u32) -> GlTexture<'_> {
+        let fns = &self.importer.fns;
+        let mut name = 0;
+        unsafe {
+            (fns.gen_textures)(1, &mut name);
+            (fns.bind_texture)(target, name);
+            (fns.tex_parameter)(target, GL_TEXTURE_MIN_FILTER, GL_LINEAR);
+            (fns.tex_parameter)(target, GL_TEXTURE_MAG_FILTER, GL_LINEAR);
+        }
+        self.bind(target);
+        GlTexture {
+            importer: self.importer,
+            name,
+        }
+    }
+}
+
+impl Drop for DmabufImage<'_> {
+    fn drop(&mut self) {
+        unsafe { (self.importer.fns.destroy_image)(self.importer.display, self.image) };
+    }
+}
+
+/// A GL texture made by [`DmabufImage::texture`]; deleted on drop, which needs its
+/// context to be current.
+pub struct GlTexture<'a> {
+    importer: &'a GlImporter,
+    name: u32,
+}
+
+impl GlTexture<'_> {
+    pub fn name(&self) -> u32 {
+        self.name
+    }
+}
+
+impl Drop for GlTexture<'_> {
+    fn drop(&mut self) {
+        unsafe { (self.importer.fns.delete_textures)(1, &self.name) };
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use gstreamer::{prelude::*, Pipeline};
+
+/// Pointer motion closer together than this is logged once.
+const MOTION_INTERVAL: Duration = Duration::from_millis(10);
+
+// from linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const BTN_MISC: u16 = 0x100;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+/// `struct input_event` on 64 bit: a timeval, then type, code and value.
+const EVENT_SIZE: usize = 24;
+
+#[derive(Default)]
+struct Shared {
+    /// Wall clock time at running time zero, once the pipeline is playing.
+    origin: Option<SystemTime>,
+    events: Vec<(SystemTime, usize, &'static str)>,
+}
+
+/// Records when keys were pressed, buttons clicked and the pointer moved, so that
+/// glitches in a recording can be matched up with what the user did.
+///
+/// The RemoteDesktop portal only lets clients send input, not watch it, so this reads
+/// the evdev devices directly, which needs read access to `/dev/input` (usually the
+/// `input` group). Only the kind of event is kept, never which key it was.
+pub struct InputLog {
+    devices: Vec<String>,
+    shared: Arc<Mutex<Shared>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl InputLog {
+    /// Start reading every input device. `pipeline` is the recording the times are
+    /// relative to, like the video timestamps.
+    pub fn start(pipeline: &Pipeline) -> Result<Self, String> {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let mut paths: Vec<_> = std::fs::read_dir("/dev/input")
+            .map_err(|e| format!("/dev/input: {e}"))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("event"))
+            })
+            .collect();
+        paths.sort();
+
+        let mut devices = vec![];
+        for path in paths {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("Not logging input from {}: {e}", path.display());
+                    continue;
+                }
+            };
+            devices.push(device_name(&path));
+
+            let device = devices.len() - 1;
+            let shared = shared.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || read_events(file, device, &shared, &stopped));
+        }
+        if devices.is_empty() {
+            return Err("no readable input devices, is the user in the input group?".into());
+        }
+
+        // the running time is the clock's time since the pipeline started playing
+        let weak_pipeline = pipeline.downgrade();
+        let origin_shared = shared.clone();
+        std::thread::spawn(move || loop {
+            let Some(pipeline) = weak_pipeline.upgrade() else {
+                return;
+            };
+            if let Some(running) = pipeline.current_running_time() {
+                let origin = SystemTime::now() - Duration::from_nanos(running.nseconds());
+                origin_shared.lock().unwrap().origin = Some(origin);
+                return;
+            }
+            drop(pipeline);
+            std::thread::sleep(Duration::from_millis(10));
+        });
+
+        Ok(Self {
+            devices,
+            shared,
+            stopped,
+        })
+    }
+
+    /// Stop logging and write one `<seconds> <kind> <device>` line per event, in
+    /// recording time. Events from before the recording started are left out.
+    pub fn finish(self, path: &Path) -> std::io::Result<()> {
+        self.stopped.store(true, Ordering::Relaxed);
+        let shared = self.shared.lock().unwrap();
+        let Some(origin) = shared.origin else {
+            return Ok(());
+        };
+
+        let mut out = String::new();
+        for (time, device, kind) in shared.events.iter() {
+            let Ok(offset) = time.duration_since(origin) else {
+                continue;
+            };
+            let name = &self.devices[*device];
+            let _ = writeln!(out, "{:.3} {kind} {name}", offset.as_secs_f64());
+        }
+        std::fs::write(path, out)
+    }
+}
+
+/// The kernel's name for the device, e.g. `Logitech USB Receiver`.
+fn device_name(path: &Path) -> String {
+    let node = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    std::fs::read_to_string(format!("/sys/class/input/{node}/device/name"))
+        .map(|n| n.trim().to_string())
+        .unwrap_or_else(|_| node.to_string())
+}
+
+fn read_events(mut file: File, device: usize, shared: &Mutex<Shared>, stopped: &AtomicBool) {
+    let mut last_motion: Option<SystemTime> = None;
+    let mut event = [0u8; EVENT_SIZE];
+    while !stopped.load(Ordering::Relaxed) {
+        if file.read_exact(&mut event).is_err() {
+            // unplugged
+            return;
+        }
+        let secs = i64::from_ne_bytes(event[0..8].try_into().unwrap());
+        let usecs = i64::from_ne_bytes(event[8..16].try_into().unwrap());
+        let type_ = u16::from_ne_bytes([event[16], event[17]]);
+        let code = u16::from_ne_bytes([event[18], event[19]]);
+        let value = i32::from_ne_bytes(event[20..24].try_into().unwrap());
+
+        // evdev stamps events with the wall clock unless told otherwise
+        let time = SystemTime::UNIX_EPOCH
+            + Duration::from_secs(secs as u64)
+            + Duration::from_micros(usecs as u64);
+
+        let kind = match (type_, value) {
+            // 2 is a held key repeating
+            (EV_KEY, 2) => continue,
+            (EV_KEY, 1) if code >= BTN_MISC => "button-press",
+            (EV_KEY, 0) if code >= BTN_MISC => "button-release",
+            (EV_KEY, 1) => "key-press",
+            (EV_KEY, 0) => "key-release",
+            // high resolution wheels send the classic events too
+            (EV_REL, _) if code == REL_WHEEL || code == REL_HWHEEL => "scroll",
+            (EV_REL, _) if code > REL_WHEEL => continue,
+            (EV_REL | EV_ABS, _) => {
+                if last_motion
+                    .is_some_and(|t| time.duration_since(t).unwrap_or_default() < MOTION_INTERVAL)
+                {
+                    continue;
+                }
+                last_motion = Some(time);
+                "motion"
+            }
+            _ => continue,
+        };
+        shared.lock().unwrap().events.push((time, device, kind));
+    }
+}
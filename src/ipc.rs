@@ -0,0 +1,235 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    encode::{fanout::Fanout, view::ViewControl},
+    mirror::MirrorControl,
+    stitch::Scenes,
+};
+
+/// What a running session lets commands change.
+pub enum Session {
+    /// A monitor recording with sinks that can come and go.
+    Monitor(Fanout),
+    /// A stitched desktop with image scenes.
+    Stitch(Scenes),
+    /// Mirror windows that can be brought to the front.
+    Mirror(MirrorControl),
+    /// A viewer playing a stream.
+    View(ViewControl),
+}
+
+/// The session currently accepting commands, if any.
+pub type SessionSlot = Arc<Mutex<Option<Session>>>;
+
+/// Whether this process owns the socket file, so that [`cleanup`] leaves the one of
+/// another session alone.
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+pub fn socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("lensing.sock")
+}
+
+/// Accept control commands for whatever session is in `slot`, one per line:
+///
+/// - `attach SPEC` adds a sink (same syntax as `--sink`) and replies `ok ID`
+/// - `detach ID` removes it again and replies `ok`
+/// - `list` replies `ok ID:SINK ...`
+/// - `hud ID` shows or hides the stats on a preview and replies `ok on` or `ok off`
+/// - `scene NAME` switches a stitch session to another scene and replies `ok`
+/// - `scenes` replies `ok NAME ...`, starting with the current scene if there is one
+/// - `raise [OUTPUT] [token=TOKEN]` focuses a mirror window, with the xdg-activation
+///   token of whoever asked if given, and replies `ok`
+/// - `cursor [hidden|embedded|metadata]` switches the cursor of a mirror, between
+///   hidden and embedded if no mode is given, and replies `ok MODE`
+/// - `volume [PERCENT|mute|unmute]` changes the volume of a viewer and replies e.g.
+///   `ok 80%` or `ok muted`
+/// - `latency [MS]` changes the jitter buffer of a viewer and replies the latency it
+///   adds, e.g. `ok 120ms`
+///
+/// Failures reply `error MESSAGE`.
+pub fn serve(slot: SessionSlot) -> io::Result<()> {
+    let path = socket_path();
+    if UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another session is listening on {}", path.display()),
+        ));
+    }
+    // left over from a session that didn't exit cleanly
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    SERVING.store(true, Ordering::Relaxed);
+    println!("Control socket: {}", path.display());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let slot = slot.clone();
+            std::thread::spawn(move || handle_client(stream, &slot));
+        }
+    });
+    Ok(())
+}
+
+/// Remove the socket file, so that later `ctl` calls fail instead of hanging.
+pub fn cleanup() {
+    if SERVING.swap(false, Ordering::Relaxed) {
+        let _ = std::fs::remove_file(socket_path());
+    }
+}
+
+fn handle_client(stream: UnixStream, slot: &Mutex<Option<Session>>) {
+    let Ok(reader) = stream.try_clone() else {
+        return;
+    };
+    let mut writer = stream;
+
+    for line in BufReader::new(reader).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let reply = match slot.lock().unwrap().as_mut() {
+            Some(session) => handle_command(line.trim(), session),
+            None => Err("not recording".into()),
+        };
+        let reply = match reply {
+            Ok(reply) if reply.is_empty() => "ok".to_string(),
+            Ok(reply) => format!("ok {reply}"),
+            Err(e) => format!("error {e}"),
+        };
+        if writeln!(writer, "{reply}").is_err() {
+            break;
+        }
+    }
+}
+
+fn handle_command(line: &str, session: &mut Session) -> Result<String, String> {
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    match session {
+        Session::Monitor(fanout) => handle_monitor_command(command, arg, fanout),
+        Session::Stitch(scenes) => handle_stitch_command(command, arg, scenes),
+        Session::Mirror(control) => handle_mirror_command(command, arg, control),
+        Session::View(control) => handle_view_command(command, arg, control),
+    }
+}
+
+fn handle_monitor_command(command: &str, arg: &str, fanout: &mut Fanout) -> Result<String, String> {
+    match command {
+        "attach" => {
+            let id = fanout.attach(arg.trim().parse()?)?;
+            Ok(id.to_string())
+        }
+        "detach" => {
+            let id = arg
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid sink id: {arg}"))?;
+            fanout.detach(id)?;
+            Ok(String::new())
+        }
+        "list" => Ok(fanout
+            .sinks()
+            .map(|(id, spec)| format!("{id}:{}", spec.kind))
+            .collect::<Vec<_>>()
+            .join(" ")),
+        "hud" => {
+            let id = arg
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid sink id: {arg}"))?;
+            let shown = fanout.toggle_hud(id)?;
+            Ok(if shown { "on" } else { "off" }.to_string())
+        }
+        "scene" | "scenes" => Err("only stitch sessions have scenes".into()),
+        "raise" => Err("only mirror sessions can be raised".into()),
+        "cursor" => Err("only mirrors switch the cursor while running".into()),
+        "volume" | "latency" => Err("only viewers have a volume and latency".into()),
+        _ => Err(format!("unknown command: {command}")),
+    }
+}
+
+fn handle_stitch_command(command: &str, arg: &str, scenes: &mut Scenes) -> Result<String, String> {
+    match command {
+        "scene" => {
+            scenes.switch(arg.trim())?;
+            Ok(String::new())
+        }
+        "scenes" => {
+            let current = scenes.current();
+            let others = scenes.names().into_iter().filter(|&s| Some(s) != current);
+            Ok(current
+                .into_iter()
+                .chain(others)
+                .collect::<Vec<_>>()
+                .join(" "))
+        }
+        "attach" | "detach" | "list" | "hud" => Err("only monitor sessions have sinks".into()),
+        "raise" => Err("only mirror sessions can be raised".into()),
+        "cursor" => Err("only mirrors switch the cursor while running".into()),
+        "volume" | "latency" => Err("only viewers have a volume and latency".into()),
+        _ => Err(format!("unknown command: {command}")),
+    }
+}
+
+fn handle_mirror_command(
+    command: &str,
+    arg: &str,
+    control: &MirrorControl,
+) -> Result<String, String> {
+    match command {
+        "raise" => {
+            let mut output = None;
+            let mut token = None;
+            for word in arg.split_whitespace() {
+                match word.strip_prefix("token=") {
+                    Some(value) => token = Some(value),
+                    None => output = Some(word),
+                }
+            }
+            control.raise(output, token)?;
+            Ok(String::new())
+        }
+        "cursor" => {
+            let mode = match arg.trim() {
+                "" => None,
+                mode => Some(mode.parse()?),
+            };
+            Ok(control.set_cursor(mode).to_string())
+        }
+        "attach" | "detach" | "list" | "hud" => Err("only monitor sessions have sinks".into()),
+        "scene" | "scenes" => Err("only stitch sessions have scenes".into()),
+        "volume" | "latency" => Err("only viewers have a volume and latency".into()),
+        _ => Err(format!("unknown command: {command}")),
+    }
+}
+
+fn handle_view_command(command: &str, arg: &str, control: &ViewControl) -> Result<String, String> {
+    match command {
+        "volume" => control.volume(arg.trim()),
+        "latency" => control.latency(arg.trim()),
+        "attach" | "detach" | "list" | "hud" => Err("only monitor sessions have sinks".into()),
+        "scene" | "scenes" => Err("only stitch sessions have scenes".into()),
+        "raise" | "cursor" => Err("only mirror sessions have windows of their own".into()),
+        _ => Err(format!("unknown command: {command}")),
+    }
+}
+
+/// Send one command to a running session and return its reply.
+pub fn send(command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    writeln!(stream, "{command}")?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
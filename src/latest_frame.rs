@@ -0,0 +1,124 @@
+use std::{
+    cell::{Cell, UnsafeCell},
+    os::fd::RawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    capture_thread::{CapturedFrame, StreamThread},
+    error::LensingError,
+    portal::PortalSession,
+    preset::Tuning,
+    pw_capture::DrmFormat,
+};
+
+/// Set on the middle slot index while it holds a frame the reader hasn't had.
+const NEW: u8 = 0b100;
+const INDEX: u8 = 0b011;
+
+/// Three slots: the writer fills its back slot and swaps it for the middle, the reader
+/// swaps the middle for its front slot if it is new. Neither ever waits for the other.
+struct TripleBuffer {
+    slots: [UnsafeCell<Option<CapturedFrame>>; 3],
+    middle: AtomicU8,
+    ended: AtomicBool,
+}
+
+// a slot is only touched by the side whose index it is, the swap hands it over
+unsafe impl Sync for TripleBuffer {}
+
+impl TripleBuffer {
+    /// Only the writer calls this, with the back index it got from the last call.
+    fn publish(&self, back: u8, frame: CapturedFrame) -> u8 {
+        unsafe { *self.slots[back as usize].get() = Some(frame) };
+        // an older frame the reader never took is dropped when it is written over
+        self.middle.swap(back | NEW, Ordering::AcqRel) & INDEX
+    }
+
+    /// Only the reader calls this, with the front index it got from the last call.
+    fn take(&self, front: &mut u8) -> Option<CapturedFrame> {
+        if self.middle.load(Ordering::Relaxed) & NEW == 0 {
+            return None;
+        }
+        *front = self.middle.swap(*front, Ordering::AcqRel) & INDEX;
+        unsafe { (*self.slots[*front as usize].get()).take() }
+    }
+}
+
+/// The newest frame of a PipeWire stream running on a thread of its own, for render
+/// loops that poll once per frame of theirs, e.g. at 90 Hz in VR, and must never wait on
+/// the capture. Frames that arrive between two polls are skipped.
+/// Dropping it ends the stream without waiting for the thread.
+pub struct LatestFrame {
+    buffer: Arc<TripleBuffer>,
+    front: u8,
+    stream: StreamThread,
+    /// The portal session of the stream, closed after it.
+    _session: Option<PortalSession>,
+}
+
+impl LatestFrame {
+    /// Connect to `node_id` like [`crate::pw_capture::pipewire_run_stream`] does.
+    ///
+    /// The stream takes ownership of `remote_fd`.
+    pub fn spawn(
+        remote_fd: Option<RawFd>,
+        node_id: u32,
+        fps: u32,
+        tuning: &Tuning,
+        formats: Vec<DrmFormat>,
+    ) -> Result<Self, LensingError> {
+        let buffer = Arc::new(TripleBuffer {
+            slots: Default::default(),
+            middle: AtomicU8::new(1),
+            ended: AtomicBool::new(false),
+        });
+        let (writing, ending) = (buffer.clone(), buffer.clone());
+        let back = Cell::new(2);
+        let stream = StreamThread::spawn(
+            remote_fd,
+            node_id,
+            fps,
+            tuning,
+            formats,
+            move |frame| back.set(writing.publish(back.get(), frame)),
+            move || ending.ended.store(true, Ordering::Release),
+        )?;
+        Ok(Self {
+            buffer,
+            front: 0,
+            stream,
+            _session: None,
+        })
+    }
+
+    /// Keep `session` open as long as the stream runs.
+    pub(crate) fn with_session(mut self, session: PortalSession) -> Self {
+        self._session = Some(session);
+        self
+    }
+
+    /// The frame that arrived last, if it did since the last call. Never blocks.
+    pub fn try_latest(&mut self) -> Option<CapturedFrame> {
+        self.buffer.take(&mut self.front)
+    }
+
+    /// Whether the stream is over; a last frame may still be waiting.
+    pub fn ended(&self) -> bool {
+        self.buffer.ended.load(Ordering::Acquire)
+    }
+
+    /// See [`crate::CaptureManager::set_fps`].
+    pub fn set_fps(&self, fps: u32) {
+        self.stream.set_fps(fps);
+    }
+
+    /// End the stream and wait for its thread. The error it ended with, if it did
+    /// before.
+    pub fn stop(self) -> Result<(), LensingError> {
+        self.stream.stop()
+    }
+}
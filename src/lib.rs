@@ -0,0 +1,38 @@
+pub mod affinity;
+pub mod audio;
+pub mod backend;
+pub mod capture_manager;
+pub mod capture_thread;
+pub mod crash_report;
+pub mod encode;
+pub mod error;
+pub mod gamepad;
+#[cfg(feature = "gl")]
+pub mod gl_import;
+pub mod input_log;
+pub mod ipc;
+pub mod latest_frame;
+pub mod log;
+pub mod mirror;
+pub mod portal;
+pub mod preset;
+pub mod pw_capture;
+pub mod reconnect;
+pub mod sink;
+pub mod stats;
+pub mod stitch;
+pub mod token_store;
+pub mod v4l2_loopback;
+#[cfg(feature = "vulkan")]
+pub mod vk_import;
+pub mod wgpu_import;
+pub mod wl_client_desktop;
+pub mod zoom;
+
+mod capture;
+
+pub use capture::Capture;
+pub use capture_manager::CaptureManager;
+pub use capture_thread::CaptureThread;
+pub use error::LensingError;
+pub use latest_frame::LatestFrame;
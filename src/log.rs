@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::{
+        fd::{AsFd, FromRawFd},
+        unix::net::UnixDatagram,
+    },
+    path::PathBuf,
+    sync::{mpsc, Mutex},
+    time::Duration,
+};
+
+use crate::crash_report;
+
+/// Log lines kept for crash reports.
+const RECENT_LINES: usize = 200;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Where our output goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogSink {
+    Stdout,
+    Stderr,
+    /// Appended to.
+    File(PathBuf),
+    /// The systemd journal, with the capture context as structured fields.
+    Journald,
+}
+
+/// `stdout`, `stderr`, `journald` or `file=PATH`
+impl std::str::FromStr for LogSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "stdout" => Ok(LogSink::Stdout),
+            None if s == "stderr" => Ok(LogSink::Stderr),
+            None if s == "journald" => Ok(LogSink::Journald),
+            Some(("file", path)) if !path.is_empty() => Ok(LogSink::File(path.into())),
+            _ => Err(format!("invalid log sink: {s}")),
+        }
+    }
+}
+
+enum Output {
+    File(File),
+    Journald(UnixDatagram),
+}
+
+impl Output {
+    fn open(sink: &LogSink, stdout: &File) -> std::io::Result<Self> {
+        Ok(match sink {
+            LogSink::Stdout => Output::File(stdout.try_clone()?),
+            LogSink::Stderr => {
+                let stderr = std::io::stderr().as_fd().try_clone_to_owned()?;
+                Output::File(stderr.into())
+            }
+            LogSink::File(path) => {
+                Output::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            LogSink::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNALD_SOCKET)?;
+                Output::Journald(socket)
+            }
+        })
+    }
+
+    fn write(&mut self, line: &str) {
+        match self {
+            Output::File(file) => {
+                let _ = writeln!(file, "{line}");
+            }
+            Output::Journald(socket) => {
+                let _ = socket.send(journal_entry(line).as_bytes());
+            }
+        }
+    }
+}
+
+/// A journal entry in the native protocol, one `FIELD=value` per line.
+fn journal_entry(line: &str) -> String {
+    let priority = if line.starts_with("Error") {
+        3
+    } else if line.starts_with("Warning") || line.starts_with("Could not") {
+        4
+    } else {
+        6
+    };
+    let mut entry = format!("MESSAGE={line}\nPRIORITY={priority}\nSYSLOG_IDENTIFIER=lensing\n");
+    for (key, value) in crash_report::context() {
+        // multi line values need the binary form, which nothing we note needs
+        if value.contains('\n') {
+            continue;
+        }
+        let field: String = key
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                _ => '_',
+            })
+            .collect();
+        entry.push_str(&format!("LENSING_{field}={value}\n"));
+    }
+    entry
+}
+
+/// Keeps the output going to the sinks, see [`install`].
+pub struct LogGuard {
+    stdout: i32,
+    drained: mpsc::Receiver<()>,
+}
+
+/// Send everything printed to stdout to `sinks` (just stdout if empty) and keep the
+/// last lines for crash reports. Output is only passed on while the guard lives.
+pub fn install(sinks: &[LogSink]) -> Option<LogGuard> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let (read_end, write_end) = (fds[0], fds[1]);
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if stdout < 0 || unsafe { libc::dup2(write_end, libc::STDOUT_FILENO) } < 0 {
+        unsafe {
+            libc::close(read_end);
+            libc::close(write_end);
+        }
+        return None;
+    }
+    unsafe { libc::close(write_end) };
+
+    let terminal = unsafe { File::from_raw_fd(libc::dup(stdout)) };
+    let default = [LogSink::Stdout];
+    let sinks = if sinks.is_empty() {
+        &default[..]
+    } else {
+        sinks
+    };
+    let mut outputs = vec![];
+    for sink in sinks {
+        match Output::open(sink, &terminal) {
+            Ok(output) => outputs.push(output),
+            // stderr, this is about stdout not working out
+            Err(e) => eprintln!("Could not log to {sink:?}: {e}"),
+        }
+    }
+
+    let (done, drained) = mpsc::channel();
+    let pipe = unsafe { File::from_raw_fd(read_end) };
+    std::thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            for output in outputs.iter_mut() {
+                output.write(&line);
+            }
+            let mut recent = RECENT.lock().unwrap();
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line);
+        }
+        let _ = done.send(());
+    });
+
+    Some(LogGuard { stdout, drained })
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        // put stdout back, and let the sinks have what is still in the pipe
+        let _ = std::io::stdout().flush();
+        unsafe {
+            libc::dup2(self.stdout, libc::STDOUT_FILENO);
+            libc::close(self.stdout);
+        }
+        let _ = self.drained.recv_timeout(Duration::from_secs(1));
+    }
+}
+
+/// The last lines printed, oldest first. Empty if we may have panicked while holding
+/// the lock.
+pub(crate) fn recent_lines() -> Vec<String> {
+    RECENT
+        .try_lock()
+        .map(|recent| recent.iter().cloned().collect())
+        .unwrap_or_default()
+}
@@ -1,46 +1,171 @@
 use std::{
     os::{fd::FromRawFd, unix::net::UnixStream},
+    path::Path,
     sync::{Arc, Mutex},
 };
 
-use ashpd::{
-    desktop::screencast::{CursorMode, PersistMode, Screencast, SourceType},
-    WindowIdentifier,
-};
-use futures::executor::block_on;
+use cli::{Args, Command, OutputGonePolicy};
 use gstreamer::{
     element_error,
-    prelude::{Cast, GstBinExtManual},
+    prelude::{Cast, GstBinExt, GstBinExtManual},
     Element, ElementFactory, Pipeline,
 };
-use smithay_client_toolkit::{
-    compositor::{CompositorHandler, CompositorState},
-    delegate_compositor, delegate_output, delegate_xdg_window,
-    output::{OutputHandler, OutputState},
-    reexports::{
-        calloop::EventLoop,
-        client::{
-            globals::registry_queue_init,
-            protocol::{wl_output::WlOutput, wl_surface::WlSurface},
-            Connection, QueueHandle, WaylandSource,
-        },
+use lensing::{
+    audio::{
+        self,
+        silence::{SilenceDetector, SilenceEvent},
     },
-    shell::xdg::{
-        window::{Window, WindowConfigure, WindowHandler},
-        XdgShell,
+    crash_report,
+    encode::{
+        self,
+        screenshot::ScreenshotConfig,
+        stream::StreamTarget,
+        view::{ViewTuning, Viewer},
+        StopReason,
     },
+    gamepad::Gamepads,
+    input_log::InputLog,
+    ipc, log,
+    mirror::{magnifier::Magnifier, Mirror},
+    portal,
+    reconnect::Backoff,
+    sink::SinkKind,
+    stats, stitch,
+    token_store::TokenStore,
+    wl_client_desktop::{OutputState, WindowFilter, WlClientDesktopState},
+    zoom, LensingError,
 };
-use wl_client_desktop::WlClientDesktopState;
+use wayland_client::protocol::wl_output::Transform;
 
-mod pw_capture;
-mod wl_client_desktop;
+mod cli;
 
 fn main() {
-    let wl_desktop = WlClientDesktopState::new();
+    let args = Args::parse();
+
+    // before any thread is up, so that every connection (and GStreamer) agrees on them
+    for (key, value) in args.env_overrides.iter() {
+        std::env::set_var(key, value);
+    }
+
+    if let Command::Ctl { ref request } = args.command {
+        return ctl(request);
+    }
+    // a file in, a file out, no compositor needed
+    if let Command::Transcode {
+        ref input,
+        ref output,
+    } = args.command
+    {
+        gstreamer::init().expect("gstreamer init");
+        if let Err(e) = encode::raw::transcode(input, output, &args.tuning) {
+            println!("Could not transcode {input}: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let _log = log::install(&args.log_sinks);
+    crash_report::install();
+    crash_report::note("command", std::env::args().collect::<Vec<_>>().join(" "));
+    if args.stats {
+        stats::print_every(std::time::Duration::from_secs(5));
+    }
 
+    let mut wl_desktop = or_exit(WlClientDesktopState::new());
+    crash_report::note_compositor(&wl_desktop.connection);
+    if let Some(backend) = wl_desktop.nested_backend() {
+        println!(
+            "This compositor is nested inside a {backend} session. The screencast portal captures \
+             the session it was started for, so make sure a portal backend runs for this display."
+        );
+    }
+
+    match args.command {
+        Command::ListOutputs { json } => list_outputs(&wl_desktop, json),
+        Command::ListWindows => list_windows(&mut wl_desktop),
+        Command::Stitch {
+            ref location,
+            follow_focus,
+            ref overlays,
+            ref scene,
+        } => stitch_desktop(
+            &wl_desktop,
+            &args,
+            location,
+            follow_focus,
+            overlays,
+            scene.as_deref(),
+        ),
+        Command::Window {
+            ref location,
+            ref follow,
+            ref target,
+        } => record_window(
+            &mut wl_desktop,
+            &args,
+            location,
+            follow.as_deref(),
+            target.as_ref(),
+        ),
+        Command::Monitor {
+            ref location,
+            ref on_gone,
+        } => record_monitor(&mut wl_desktop, &args, location, on_gone),
+        Command::Mirror {
+            ref outputs,
+            ref fullscreen_on,
+            interactive,
+            gamepads,
+            magnifier,
+        } => mirror_outputs(
+            &mut wl_desktop,
+            &args,
+            outputs,
+            fullscreen_on.as_deref(),
+            magnifier,
+            interactive,
+            gamepads,
+        ),
+        Command::Stream { ref target } => stream_monitor(&wl_desktop, &args, target),
+        Command::Screenshot {
+            ref location,
+            ref config,
+        } => screenshot(&wl_desktop, &args, location, config),
+        Command::View {
+            ref target,
+            tuning,
+            volume,
+        } => view_stream(&wl_desktop, &args, target, &tuning, volume),
+        Command::Ctl { .. } | Command::Transcode { .. } => unreachable!(),
+    }
+}
+
+fn ctl(request: &str) {
+    // launchers and hotkey daemons hand down a token that lets the mirror take focus
+    let request = match std::env::var("XDG_ACTIVATION_TOKEN") {
+        Ok(token) if request.split_whitespace().next() == Some("raise") => {
+            format!("{request} token={token}")
+        }
+        _ => request.to_string(),
+    };
+    match ipc::send(&request) {
+        Ok(reply) => println!("{reply}"),
+        Err(e) => {
+            println!("No session at {}: {e}", ipc::socket_path().display());
+            std::process::exit(1);
+        }
+    }
+}
+
+fn list_outputs(wl_desktop: &WlClientDesktopState, json: bool) {
+    if json {
+        let outputs: Vec<String> = wl_desktop.outputs.iter().map(output_json).collect();
+        println!("[{}]", outputs.join(","));
+        return;
+    }
     for o in wl_desktop.outputs.iter() {
         println!(
-            "{}: {} @ {}x{}, offset {}x{}, pixels {}x{}",
+            "{}: {} @ {}x{}, offset {}x{}, pixels {}x{}, scale {}",
             o.name,
             o.model,
             o.logical_size.0,
@@ -48,65 +173,727 @@ fn main() {
             o.logical_pos.0,
             o.logical_pos.1,
             o.size.0,
-            o.size.1
+            o.size.1,
+            o.scale()
         );
     }
 }
 
-// fn wayland() {
-//     let connection = Connection::connect_to_env().expect("Unable to connect to wayland");
-//     let (globals, event_queue) = registry_queue_init(&connection).unwrap();
-//     let qh = event_queue.handle();
-//     let mut event_loop: EventLoop<MirrorWindow> =
-//         EventLoop::try_new().expect("Failed to initialize the event loop!");
-//     let loop_handle = event_loop.handle();
-//     WaylandSource::new(event_queue)
-//         .unwrap()
-//         .insert(loop_handle)
-//         .unwrap();
-
-//     let compositor = CompositorState::bind(&globals, &qh).expect("wl_compositor not available");
-//     let xdg_shell = XdgShell::bind(&globals, &qh).expect("xdg shell is not available");
-// }
-
-// struct MirrorWindow {}
-// impl CompositorHandler for MirrorWindow {
-//     fn scale_factor_changed(
-//         &mut self,
-//         conn: &Connection,
-//         qh: &QueueHandle<Self>,
-//         surface: &WlSurface,
-//         new_factor: i32,
-//     ) {
-//     }
-
-//     fn frame(&mut self, conn: &Connection, qh: &QueueHandle<Self>, surface: &WlSurface, time: u32) {
-//     }
-// }
-// delegate_compositor!(MirrorWindow);
-
-// impl OutputHandler for MirrorWindow {
-//     fn output_state(&mut self) -> &mut OutputState {}
-
-//     fn new_output(&mut self, conn: &Connection, qh: &QueueHandle<Self>, output: WlOutput) {}
-
-//     fn update_output(&mut self, conn: &Connection, qh: &QueueHandle<Self>, output: WlOutput) {}
-
-//     fn output_destroyed(&mut self, conn: &Connection, qh: &QueueHandle<Self>, output: WlOutput) {}
-// }
-// delegate_output!(MirrorWindow);
-
-// impl WindowHandler for MirrorWindow {
-//     fn request_close(&mut self, conn: &Connection, qh: &QueueHandle<Self>, window: &Window) {}
-
-//     fn configure(
-//         &mut self,
-//         conn: &Connection,
-//         qh: &QueueHandle<Self>,
-//         window: &Window,
-//         configure: WindowConfigure,
-//         serial: u32,
-//     ) {
-//     }
-// }
-// delegate_xdg_window!(MirrorWindow);
+fn output_json(o: &OutputState) -> String {
+    let transform = match o.transform() {
+        Transform::Normal => "normal",
+        Transform::_90 => "90",
+        Transform::_180 => "180",
+        Transform::_270 => "270",
+        Transform::Flipped => "flipped",
+        Transform::Flipped90 => "flipped-90",
+        Transform::Flipped180 => "flipped-180",
+        Transform::Flipped270 => "flipped-270",
+        _ => "normal",
+    };
+    format!(
+        "{{\"name\":{},\"make\":{},\"model\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"pixel_width\":{},\"pixel_height\":{},\"scale\":{},\"transform\":\"{transform}\"}}",
+        json_string(&o.name),
+        json_string(&o.make),
+        json_string(&o.model),
+        o.logical_pos.0,
+        o.logical_pos.1,
+        o.logical_size.0,
+        o.logical_size.1,
+        o.size.0,
+        o.size.1,
+        o.scale(),
+    )
+}
+
+/// `s` as a JSON string, quotes included.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn mirror_outputs(
+    wl_desktop: &mut WlClientDesktopState,
+    args: &Args,
+    names: &[String],
+    fullscreen_on: Option<&str>,
+    magnifier: Magnifier,
+    interactive: bool,
+    gamepads: bool,
+) {
+    // the toplevels tell which output is focused
+    or_exit(wl_desktop.roundtrip());
+    let outputs: Vec<&OutputState> = if names.is_empty() {
+        wl_desktop
+            .focused_output()
+            .or_else(|| wl_desktop.outputs.first())
+            .into_iter()
+            .collect()
+    } else {
+        let mut outputs: Vec<&OutputState> = vec![];
+        for name in names {
+            let Some(output) = wl_desktop.outputs.iter().find(|o| &o.name == name) else {
+                println!("No output {name}, see `lensing` for the list");
+                std::process::exit(1);
+            };
+            // windows are told apart by output
+            if !outputs.iter().any(|o| o.name == *name) {
+                outputs.push(output);
+            }
+        }
+        outputs
+    };
+    if outputs.is_empty() {
+        println!("No outputs to mirror");
+        std::process::exit(1);
+    }
+    let fullscreen_on = fullscreen_on.map(|name| {
+        wl_desktop
+            .outputs
+            .iter()
+            .find(|o| o.name == name)
+            .unwrap_or_else(|| {
+                println!("No output {name} to go fullscreen on, see `lensing` for the list");
+                std::process::exit(1);
+            })
+    });
+
+    let input = interactive.then(|| {
+        portal::remote_input().unwrap_or_else(|e| {
+            println!("Could not get input for the outputs: {e}");
+            std::process::exit(1);
+        })
+    });
+    let gamepads = gamepads.then(|| {
+        Gamepads::open().unwrap_or_else(|e| {
+            println!("Could not pass on gamepads: {e}");
+            std::process::exit(1);
+        })
+    });
+
+    // for `ctl raise`, e.g. from a hotkey
+    let sessions = ipc::SessionSlot::default();
+    if let Err(e) = ipc::serve(sessions.clone()) {
+        println!("Could not open control socket: {e}");
+    }
+    let result = Mirror::run(
+        wl_desktop,
+        &outputs,
+        fullscreen_on,
+        magnifier,
+        &args.tuning,
+        input,
+        gamepads,
+        &sessions,
+    );
+    ipc::cleanup();
+    if let Err(e) = result {
+        println!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn list_windows(wl_desktop: &mut WlClientDesktopState) {
+    if wl_desktop.maybe_toplevel_mgr.is_none() {
+        println!("Compositor does not list toplevels");
+        std::process::exit(1);
+    }
+    // the toplevels come in after the manager is bound
+    or_exit(wl_desktop.roundtrip());
+
+    for t in wl_desktop.windows() {
+        let outputs: Vec<&str> = t
+            .outputs
+            .iter()
+            .filter_map(|w| wl_desktop.outputs.iter().find(|o| &o.wl_output == w))
+            .map(|o| o.name.as_str())
+            .collect();
+        println!(
+            "{}: \"{}\" on {}{}",
+            t.app_id,
+            t.title,
+            outputs.join(", "),
+            if t.activated { " (focused)" } else { "" }
+        );
+    }
+}
+
+fn stitch_desktop(
+    wl_desktop: &WlClientDesktopState,
+    args: &Args,
+    location: &str,
+    follow_focus: bool,
+    overlays: &stitch::Overlays,
+    scene: Option<&str>,
+) {
+    gstreamer::init().expect("gstreamer init");
+
+    let session = or_exit(portal::select_monitors(true, args.tuning.cursor));
+    let mut canvas = stitch::Canvas::from_desktop(wl_desktop, &session.streams);
+    canvas.overlays = overlays.clone();
+
+    println!(
+        "Stitching {} outputs into {}x{} canvas",
+        canvas.tiles.len(),
+        canvas.width,
+        canvas.height
+    );
+
+    encode::bitrate::check_disk_throughput(location, (canvas.width, canvas.height), &args.tuning);
+
+    let pipeline = or_exit(stitch::stitch_pipeline(
+        session.fd,
+        &canvas,
+        location,
+        args.audio.as_ref(),
+        &args.tuning,
+        follow_focus,
+    ));
+
+    if follow_focus {
+        let crop = pipeline.by_name("zoom").expect("zoom element");
+        zoom::follow_focus(crop, (canvas.width, canvas.height));
+    }
+
+    // only images can change during a stitch session
+    let sessions = ipc::SessionSlot::default();
+    if !canvas.overlays.images.is_empty() {
+        let scenes = stitch::Scenes::new(&pipeline, &canvas, scene);
+        *sessions.lock().unwrap() = Some(ipc::Session::Stitch(scenes));
+        if let Err(e) = ipc::serve(sessions.clone()) {
+            println!("Could not open control socket: {e}");
+        }
+    }
+
+    let input_log = start_input_log(args, &pipeline);
+    watch_silence(args, &pipeline, Some(location));
+    finish_input_log(input_log, location);
+    if !canvas.overlays.images.is_empty() {
+        ipc::cleanup();
+    }
+}
+
+fn record_window(
+    wl_desktop: &mut WlClientDesktopState,
+    args: &Args,
+    location: &str,
+    follow: Option<&str>,
+    target: Option<&WindowFilter>,
+) {
+    gstreamer::init().expect("gstreamer init");
+
+    let mut restore_token: Option<String> = None;
+    let mut segment = 0;
+
+    loop {
+        // the portal always lets the user pick, but we can make sure the window is there
+        // and say which one to pick
+        if let (Some(filter), None) = (target, restore_token.as_ref()) {
+            if !wl_desktop.windows().any(|t| filter.matches(t)) {
+                println!("Waiting for {filter} to open");
+            }
+            match or_exit(wl_desktop.wait_for_window(filter)) {
+                Some(window) => println!(
+                    "Select \"{}\" ({}) in the dialog",
+                    window.title, window.app_id
+                ),
+                None => {
+                    println!("Compositor does not list toplevels, select {filter} in the dialog")
+                }
+            }
+        }
+
+        let session = or_exit(portal::select_window(
+            restore_token.as_deref(),
+            args.tuning.cursor,
+        ));
+        restore_token = session.restore_token.clone();
+
+        let Some(stream) = session.streams.first() else {
+            println!("No window selected");
+            return;
+        };
+
+        let path = encode::segment_location(location, segment);
+        if let Some(size) = stream.size {
+            encode::bitrate::check_disk_throughput(&path, size, &args.tuning);
+        }
+        // the portal doesn't say which output the window is on
+        let scales: Vec<f64> = wl_desktop.outputs.iter().map(|o| o.scale()).collect();
+        let pipeline = or_exit(encode::window::record_window_pipeline(
+            session.fd,
+            stream,
+            &path,
+            args.audio.as_ref(),
+            &args.tuning,
+            &scales,
+        ));
+
+        let input_log = start_input_log(args, &pipeline);
+        let reason = watch_silence(args, &pipeline, Some(&path));
+        finish_input_log(input_log, &path);
+
+        let Some(app_id) = follow else {
+            return;
+        };
+        if reason == StopReason::User || encode::stop_requested() {
+            return;
+        }
+
+        println!("Window is gone, waiting for {app_id} to come back");
+        if !or_exit(wl_desktop.wait_for_app(app_id)) {
+            println!("Compositor does not list toplevels, cannot follow {app_id}");
+            return;
+        }
+        segment += 1;
+    }
+}
+
+fn record_monitor(
+    wl_desktop: &mut WlClientDesktopState,
+    args: &Args,
+    location: &str,
+    on_gone: &OutputGonePolicy,
+) {
+    gstreamer::init().expect("gstreamer init");
+
+    // sinks can only be attached to a fanout session
+    let sessions = ipc::SessionSlot::default();
+    if !args.sinks.is_empty() {
+        if let Err(e) = ipc::serve(sessions.clone()) {
+            println!("Could not open control socket: {e}");
+        }
+    }
+
+    record_monitor_segments(wl_desktop, args, location, on_gone, &sessions);
+
+    if !args.sinks.is_empty() {
+        ipc::cleanup();
+    }
+}
+
+/// The name of the output `--output` picks, exiting if there is none like it.
+fn target_output(wl_desktop: &WlClientDesktopState, args: &Args) -> Option<String> {
+    let selector = args.output.as_ref()?;
+    match wl_desktop.find_output(selector) {
+        Some(output) => Some(output.name.clone()),
+        None => {
+            println!("No output {selector}, run lensing to list them");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The restore token to start with: the one of `target`, so the portal can pick it
+/// without asking, or without a target the one of whichever output was captured last.
+fn initial_token(tokens: &TokenStore, target: Option<&str>) -> Option<String> {
+    let Some(name) = target else {
+        return tokens.latest().map(|(_, t)| t.to_string());
+    };
+    let token = tokens.get(name).map(String::from);
+    if token.is_none() {
+        println!("Please select {name}");
+    }
+    token
+}
+
+/// Ask the portal for the monitor `target`, or the one of `restore_token` without one.
+fn select_monitor(
+    target: Option<&str>,
+    restore_token: Option<&str>,
+    cursor: portal::CursorMode,
+) -> Result<portal::PortalSession, LensingError> {
+    match target {
+        Some(_) => portal::select_output(restore_token, cursor),
+        None => portal::select_monitor(restore_token, cursor),
+    }
+}
+
+/// The stream of `session` to capture: the one of `target` among those the user
+/// picked, or the only one without a target. Says why if there is none.
+fn selected_stream<'a>(
+    wl_desktop: &WlClientDesktopState,
+    session: &'a portal::PortalSession,
+    target: Option<&str>,
+) -> Option<&'a portal::PortalStream> {
+    let Some(first) = session.streams.first() else {
+        println!("No output selected");
+        return None;
+    };
+    let Some(name) = target else {
+        return Some(first);
+    };
+    let stream = wl_desktop
+        .outputs
+        .iter()
+        .find(|o| o.name == name)
+        .and_then(|o| session.stream_at(o.logical_pos));
+    if stream.is_none() {
+        println!("{name} was not among the selected outputs");
+    }
+    stream
+}
+
+fn record_monitor_segments(
+    wl_desktop: &mut WlClientDesktopState,
+    args: &Args,
+    location: &str,
+    on_gone: &OutputGonePolicy,
+    sessions: &ipc::SessionSlot,
+) {
+    let mut tokens = TokenStore::load();
+    let mut target = target_output(wl_desktop, args);
+    let mut restore_token = initial_token(&tokens, target.as_deref());
+    let mut segment = 0;
+    let mut backoff = Backoff::new(args.tuning.reconnect);
+    let mut reconnecting = false;
+
+    loop {
+        let selected = select_monitor(
+            target.as_deref(),
+            restore_token.as_deref(),
+            args.tuning.cursor,
+        );
+        let session = match selected {
+            Ok(session) => session,
+            // a portal that was restarted may take a moment to come back
+            Err(e) if reconnecting => match backoff.failed() {
+                Some(delay) => {
+                    println!(
+                        "Could not reconnect ({e}), trying again in {} ms",
+                        delay.as_millis()
+                    );
+                    std::thread::sleep(delay);
+                    continue;
+                }
+                None => or_exit(Err(e)),
+            },
+            Err(e) => or_exit(Err(e)),
+        };
+        reconnecting = false;
+        restore_token = session.restore_token.clone();
+
+        let Some(stream) = selected_stream(wl_desktop, &session, target.as_deref()) else {
+            return;
+        };
+
+        let output = wl_desktop
+            .outputs
+            .iter()
+            .find(|o| Some(o.logical_pos) == stream.position);
+        // by its global too, as a monitor plugged out and back in between two looks
+        // keeps its name but not its global
+        let captured = output.map(|o| (o.id, o.identity()));
+        if let (Some(output), Some(token)) = (output, restore_token.as_deref()) {
+            tokens.set(&output.name, token);
+        }
+        let frame_size = output.map(|o| o.size).or(stream.size).unwrap_or_default();
+
+        let path = encode::segment_location(location, segment);
+        let pipeline = if args.sinks.is_empty() {
+            encode::bitrate::check_disk_throughput(&path, frame_size, &args.tuning);
+            or_exit(encode::record_stream_pipeline(
+                session.fd,
+                stream.node_id,
+                &path,
+                args.audio.as_ref(),
+                &args.tuning,
+            ))
+        } else {
+            let mut fanout = or_exit(encode::fanout::Fanout::new(
+                session.fd,
+                stream.node_id,
+                args.audio.as_ref(),
+                &args.tuning,
+            ));
+            for sink in args.sinks.iter() {
+                let mut sink = sink.clone();
+                if let SinkKind::File(ref mut path) = sink.kind {
+                    *path = encode::segment_location(path, segment);
+                    let size = sink
+                        .config
+                        .scale
+                        .map_or(frame_size, |(w, h)| (w as i32, h as i32));
+                    encode::bitrate::check_disk_throughput(path, size, &args.tuning);
+                }
+                or_exit(fanout.attach(sink).map_err(LensingError::Pipeline));
+            }
+
+            let pipeline = fanout.pipeline.clone();
+            *sessions.lock().unwrap() = Some(ipc::Session::Monitor(fanout));
+            pipeline
+        };
+
+        // with sinks there is no single recording to line the input and silences up with
+        let input_log = if args.sinks.is_empty() {
+            start_input_log(args, &pipeline)
+        } else {
+            None
+        };
+        backoff.started();
+        let markers = args.sinks.is_empty().then_some(path.as_str());
+        let reason = watch_silence(args, &pipeline, markers);
+        finish_input_log(input_log, &path);
+        sessions.lock().unwrap().take();
+        if reason == StopReason::User || encode::stop_requested() {
+            return;
+        }
+
+        or_exit(wl_desktop.roundtrip());
+        let gone = captured.filter(|(id, _)| !wl_desktop.outputs.iter().any(|o| o.id == *id));
+        let Some((_, lost)) = gone else {
+            // the output is still there, so the stream failed or the portal went away
+            let Some(delay) = backoff.failed() else {
+                println!("Capture lost, stopping");
+                return;
+            };
+            let cause = if session.closed() {
+                "The portal closed the session"
+            } else {
+                "The stream ended"
+            };
+            println!(
+                "{cause}, reconnecting in {} ms (try {} of {})",
+                delay.as_millis(),
+                backoff.attempt(),
+                args.tuning.reconnect.attempts
+            );
+            std::thread::sleep(delay);
+            if encode::stop_requested() {
+                return;
+            }
+            reconnecting = true;
+            segment += 1;
+            continue;
+        };
+
+        match on_gone {
+            OutputGonePolicy::Stop => {
+                println!("Output {lost} is gone, stopping");
+                return;
+            }
+            OutputGonePolicy::Wait => {
+                println!("Output {lost} is gone, waiting for it to come back");
+                or_exit(wl_desktop.wait_for_monitor(&lost));
+            }
+            OutputGonePolicy::Fallback(fallback) => {
+                if !wl_desktop.outputs.iter().any(|o| &o.name == fallback) {
+                    println!("Output {lost} is gone and {fallback} is not connected, stopping");
+                    return;
+                }
+                restore_token = tokens.get(fallback).map(String::from);
+                if restore_token.is_none() {
+                    println!("Output {lost} is gone, please select {fallback} to continue");
+                } else {
+                    println!("Output {lost} is gone, continuing on {fallback}");
+                }
+                target = Some(fallback.clone());
+            }
+        }
+        segment += 1;
+    }
+}
+
+fn stream_monitor(wl_desktop: &WlClientDesktopState, args: &Args, target: &StreamTarget) {
+    gstreamer::init().expect("gstreamer init");
+
+    let mut tokens = TokenStore::load();
+    let target = target_output(wl_desktop, args);
+    let restore_token = initial_token(&tokens, target.as_deref());
+    let session = or_exit(select_monitor(
+        target.as_deref(),
+        restore_token.as_deref(),
+        args.tuning.cursor,
+    ));
+    let Some(stream) = selected_stream(wl_desktop, &session, target.as_deref()) else {
+        return;
+    };
+    let output = wl_desktop
+        .outputs
+        .iter()
+        .find(|o| Some(o.logical_pos) == stream.position);
+    if let (Some(output), Some(token)) = (output, session.restore_token.as_deref()) {
+        tokens.set(&output.name, token);
+    }
+
+    match target {
+        StreamTarget::Rtp { host, port } => {
+            if args.audio.is_some() {
+                println!("RTP streams carry no audio, serve RTSP for that");
+            }
+            let pipeline = or_exit(encode::stream::rtp_pipeline(
+                session.fd,
+                stream.node_id,
+                host,
+                *port,
+                &args.tuning,
+            ));
+            encode::run_until_eos(&pipeline);
+        }
+        #[cfg(feature = "rtsp")]
+        StreamTarget::Rtsp {
+            address,
+            port,
+            path,
+        } => {
+            if let Err(e) = encode::stream::serve_rtsp(
+                session.fd,
+                stream.node_id,
+                address,
+                *port,
+                path,
+                &args.tuning,
+                args.audio.as_ref(),
+            ) {
+                println!("{e}");
+                std::process::exit(1);
+            }
+        }
+        // the command line turns these down
+        #[cfg(not(feature = "rtsp"))]
+        StreamTarget::Rtsp { .. } => unreachable!(),
+    }
+}
+
+fn screenshot(
+    wl_desktop: &WlClientDesktopState,
+    args: &Args,
+    location: &str,
+    config: &ScreenshotConfig,
+) {
+    gstreamer::init().expect("gstreamer init");
+
+    // CI runs can't click through a dialog, so the token of an earlier run is a must there
+    let mut tokens = TokenStore::load();
+    let target = target_output(wl_desktop, args);
+    let restore_token = initial_token(&tokens, target.as_deref());
+    let session = or_exit(select_monitor(
+        target.as_deref(),
+        restore_token.as_deref(),
+        args.tuning.cursor,
+    ));
+    let Some(stream) = selected_stream(wl_desktop, &session, target.as_deref()) else {
+        return;
+    };
+    let output = wl_desktop
+        .outputs
+        .iter()
+        .find(|o| Some(o.logical_pos) == stream.position);
+    if let (Some(output), Some(token)) = (output, session.restore_token.as_deref()) {
+        tokens.set(&output.name, token);
+    }
+
+    match encode::screenshot::take(session.fd, stream.node_id, location, config, &args.tuning) {
+        Ok(updates) => println!("Saved {location} after {updates} screen updates"),
+        Err(e) => {
+            println!("Could not take the screenshot: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn view_stream(
+    wl_desktop: &WlClientDesktopState,
+    args: &Args,
+    target: &StreamTarget,
+    tuning: &ViewTuning,
+    volume: u32,
+) {
+    gstreamer::init().expect("gstreamer init");
+
+    let viewer = Viewer::new(target, tuning, volume, &args.tuning).unwrap_or_else(|e| {
+        println!("Could not play the stream: {e}");
+        std::process::exit(1);
+    });
+    // for `ctl volume`
+    let sessions = ipc::SessionSlot::default();
+    *sessions.lock().unwrap() = Some(ipc::Session::View(viewer.control()));
+    if let Err(e) = ipc::serve(sessions.clone()) {
+        println!("Could not open control socket: {e}");
+    }
+    let result = if tuning.hw_decode {
+        viewer.run_in_window(wl_desktop, &args.tuning)
+    } else {
+        viewer.run()
+    };
+    ipc::cleanup();
+    if let Err(e) = result {
+        println!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Run `pipeline` until EOS, warning when the audio goes silent, and for
+/// `--silence-markers` write the silences next to the recording at `location`.
+fn watch_silence(args: &Args, pipeline: &Pipeline, location: Option<&str>) -> StopReason {
+    let mut silence = args
+        .audio
+        .and_then(|a| a.silence_after)
+        .map(SilenceDetector::new);
+
+    let reason = encode::run_until_eos_with(pipeline, |msg| {
+        let Some(detector) = silence.as_mut() else {
+            return;
+        };
+        let Some((time, rms)) = audio::level_rms_db(msg) else {
+            return;
+        };
+        match detector.update(time, rms) {
+            Some(SilenceEvent::Started(_)) => {
+                println!("Warning: audio has been silent for a while. Is the source muted?")
+            }
+            Some(SilenceEvent::Ended(_)) => println!("Audio is back."),
+            None => {}
+        }
+    });
+
+    if let (true, Some(detector), Some(location)) = (args.silence_markers, silence, location) {
+        let path = format!("{location}.markers");
+        if let Err(e) = detector.write_markers(Path::new(&path)) {
+            println!("Could not write {path}: {e}");
+        }
+    }
+    reason
+}
+
+/// For `--input-events`, see [`finish_input_log`].
+fn start_input_log(args: &Args, pipeline: &Pipeline) -> Option<InputLog> {
+    if !args.input_events {
+        return None;
+    }
+    InputLog::start(pipeline)
+        .map_err(|e| println!("Not logging input: {e}"))
+        .ok()
+}
+
+/// Write the input events next to the recording at `location`.
+fn finish_input_log(input_log: Option<InputLog>, location: &str) {
+    let Some(input_log) = input_log else {
+        return;
+    };
+    let path = format!("{location}.input");
+    if let Err(e) = input_log.finish(Path::new(&path)) {
+        println!("Could not write {path}: {e}");
+    }
+}
+
+/// What `result` holds, or the error and exit, e.g. without a compositor or a portal,
+/// or for a pipeline that could not be built.
+fn or_exit<T, E: Into<LensingError>>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        let e: LensingError = e.into();
+        println!("Error: {e}");
+        std::process::exit(1);
+    })
+}
@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+pub const MAX_ZOOM: f64 = 16.0;
+/// Arrow keys move the view by this share of it.
+const PAN_STEP: f64 = 0.125;
+/// The dead zone can't reach the edges of the view, or the pointer could leave it.
+pub const MAX_DEAD_ZONE: f64 = 0.45;
+
+/// How frame pixels are stretched over window pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    /// Smooth, best for photos and video.
+    #[default]
+    Linear,
+    /// Every frame pixel a sharp square, best for text and pixel art when magnified.
+    Nearest,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" => Ok(Filter::Linear),
+            "nearest" => Ok(Filter::Nearest),
+            other => Err(format!("unknown filter: {other}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::Linear => write!(f, "linear"),
+            Filter::Nearest => write!(f, "nearest"),
+        }
+    }
+}
+
+/// Shows a part of the frames magnified, like a screen magnifier: + and - double and
+/// halve the zoom, 0 resets it, the arrow keys pan, n toggles the filter and p following
+/// the pointer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Magnifier {
+    /// From 1, the whole frame, to [`MAX_ZOOM`].
+    pub zoom: f64,
+    pub filter: Filter,
+    /// The middle of the view, in frame coordinates from 0 to 1.
+    pub center: (f64, f64),
+    /// Pan to keep the pointer in the middle of the view, see [`Magnifier::follow`].
+    pub follow: bool,
+    /// How far the pointer may get from the middle before the view follows, as a share
+    /// of the view, so that small movements don't shake it.
+    pub dead_zone: f64,
+}
+
+impl Default for Magnifier {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            filter: Filter::default(),
+            center: (0.5, 0.5),
+            follow: false,
+            dead_zone: 0.1,
+        }
+    }
+}
+
+impl Magnifier {
+    pub fn set_zoom(&mut self, zoom: f64) {
+        self.zoom = zoom.clamp(1.0, MAX_ZOOM);
+    }
+
+    pub fn zoom_in(&mut self) {
+        self.set_zoom(self.zoom * 2.0);
+    }
+
+    pub fn zoom_out(&mut self) {
+        self.set_zoom(self.zoom / 2.0);
+    }
+
+    /// Move the view by `steps` of [`PAN_STEP`] in either direction.
+    pub fn pan(&mut self, steps: (f64, f64)) {
+        let step = PAN_STEP / self.zoom;
+        self.center = (
+            self.center.0 + steps.0 * step,
+            self.center.1 + steps.1 * step,
+        );
+        self.center = self.view_center();
+    }
+
+    /// Pan so that `pointer`, in frame coordinates from 0 to 1, is within the dead zone
+    /// again. The view still stops at the edges, where the pointer may leave the middle.
+    pub fn follow(&mut self, pointer: (f64, f64)) {
+        if !self.follow {
+            return;
+        }
+        let reach = self.dead_zone.clamp(0.0, MAX_DEAD_ZONE) / self.zoom;
+        let follow = |center: f64, pointer: f64| center.clamp(pointer - reach, pointer + reach);
+        // from where the view is, not where it was panned past the edge
+        let (x, y) = self.view_center();
+        self.center = (follow(x, pointer.0), follow(y, pointer.1));
+        self.center = self.view_center();
+    }
+
+    pub fn toggle_filter(&mut self) {
+        self.filter = match self.filter {
+            Filter::Linear => Filter::Nearest,
+            Filter::Nearest => Filter::Linear,
+        };
+    }
+
+    /// The part of the frame shown, in frame coordinates from 0 to 1: x, y, width and
+    /// height. It stays within the frame, so the view stops at the edges.
+    pub fn view(&self) -> [f64; 4] {
+        let size = 1.0 / self.zoom;
+        let (x, y) = self.view_center();
+        [x - size / 2.0, y - size / 2.0, size, size]
+    }
+
+    /// Where `position`, from 0 to 1 in the window, is in the frame.
+    pub fn frame_position(&self, position: (f64, f64)) -> (f64, f64) {
+        let [x, y, width, height] = self.view();
+        (x + position.0 * width, y + position.1 * height)
+    }
+
+    /// The center, held back from the edges by half the view.
+    fn view_center(&self) -> (f64, f64) {
+        let half = 0.5 / self.zoom;
+        (
+            self.center.0.clamp(half, 1.0 - half),
+            self.center.1.clamp(half, 1.0 - half),
+        )
+    }
+}
@@ -0,0 +1,2094 @@
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use smithay_client_toolkit::{
+    compositor::{CompositorHandler, CompositorState},
+    delegate_compositor, delegate_keyboard, delegate_output, delegate_pointer,
+    delegate_pointer_constraints, delegate_registry, delegate_relative_pointer, delegate_seat,
+    delegate_shm, delegate_subcompositor, delegate_touch, delegate_xdg_shell, delegate_xdg_window,
+    output::{OutputHandler, OutputState},
+    reexports::{
+        calloop::{
+            channel::{self, Event},
+            ping::{make_ping, Ping},
+            timer::{TimeoutAction, Timer},
+            EventLoop,
+        },
+        client::{
+            event_created_child,
+            globals::registry_queue_init,
+            protocol::{
+                wl_keyboard::WlKeyboard, wl_output::WlOutput, wl_pointer::WlPointer,
+                wl_seat::WlSeat, wl_surface::WlSurface, wl_touch::WlTouch,
+            },
+            Connection, Dispatch, Proxy, QueueHandle, WEnum, WaylandSource,
+        },
+        protocols::wp::{
+            fractional_scale::v1::client::{
+                wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+                wp_fractional_scale_v1::{self, WpFractionalScaleV1},
+            },
+            pointer_constraints::zv1::client::{
+                zwp_confined_pointer_v1::ZwpConfinedPointerV1,
+                zwp_locked_pointer_v1::ZwpLockedPointerV1, zwp_pointer_constraints_v1::Lifetime,
+            },
+            relative_pointer::zv1::client::zwp_relative_pointer_v1::ZwpRelativePointerV1,
+            tablet::zv2::client::{
+                zwp_tablet_manager_v2::ZwpTabletManagerV2,
+                zwp_tablet_pad_group_v2::{self, ZwpTabletPadGroupV2},
+                zwp_tablet_pad_ring_v2::ZwpTabletPadRingV2,
+                zwp_tablet_pad_strip_v2::ZwpTabletPadStripV2,
+                zwp_tablet_pad_v2::{self, ZwpTabletPadV2},
+                zwp_tablet_seat_v2::{self, ZwpTabletSeatV2},
+                zwp_tablet_tool_v2::{self, ButtonState, ZwpTabletToolV2},
+                zwp_tablet_v2::ZwpTabletV2,
+            },
+            viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+        },
+        protocols::xdg::activation::v1::client::{
+            xdg_activation_token_v1::{self, XdgActivationTokenV1},
+            xdg_activation_v1::XdgActivationV1,
+        },
+    },
+    registry::{ProvidesRegistryState, RegistryState},
+    registry_handlers,
+    seat::{
+        keyboard::{keysyms, KeyEvent, KeyboardHandler, Modifiers},
+        pointer::{
+            PointerData, PointerEvent, PointerEventKind, PointerHandler, BTN_LEFT, BTN_MIDDLE,
+            BTN_RIGHT,
+        },
+        pointer_constraints::{PointerConstraintsHandler, PointerConstraintsState},
+        relative_pointer::{RelativeMotionEvent, RelativePointerHandler, RelativePointerState},
+        touch::TouchHandler,
+        Capability, SeatHandler, SeatState,
+    },
+    shell::{
+        xdg::{
+            frame::{fallback_frame::FallbackFrame, DecorationsFrame, FrameAction, FrameClick},
+            window::{DecorationMode, Window, WindowConfigure, WindowDecorations, WindowHandler},
+            XdgShell, XdgSurface,
+        },
+        WaylandSurface,
+    },
+    shm::{Shm, ShmHandler},
+    subcompositor::SubcompositorState,
+};
+use wayland_client::protocol::wl_output::Transform;
+
+use crate::{
+    backend::{self, region::PixelRect, CursorSwitch},
+    capture_manager::OwnedFrame,
+    gamepad::Gamepads,
+    ipc::{Session, SessionSlot},
+    portal::{CursorMode, InputEvent, RemoteInput},
+    preset::Tuning,
+    pw_capture::{CursorMeta, PipewireFrameData, PipewireFrameFormat},
+    wgpu_import,
+    wl_client_desktop::{OutputState as DesktopOutput, WlClientDesktopState},
+};
+
+use self::{magnifier::Magnifier, renderer::Renderer};
+
+pub mod magnifier;
+mod renderer;
+
+/// A frame callback that takes longer than this means the window can't be seen.
+const HIDDEN_AFTER: Duration = Duration::from_secs(1);
+/// The window doesn't get narrower or lower than this, in logical pixels.
+const MIN_SIZE: u32 = 128;
+/// Scales are kept in 120ths, as fractional-scale-v1 sends them.
+const SCALE_DENOMINATOR: u32 = 120;
+
+// from linux/input-event-codes.h, the pen's side buttons
+const BTN_STYLUS: u32 = 0x14b;
+const BTN_STYLUS2: u32 = 0x14c;
+
+struct Frame {
+    format: PipewireFrameFormat,
+    transform: Transform,
+    /// `None` if only the cursor changed.
+    frame: Option<OwnedFrame>,
+    cursor: Option<CursorMeta>,
+    /// What changed since the frame drawn before, see
+    /// [`crate::pw_capture::PipewireFrame::damage`].
+    damage: Option<Vec<PixelRect>>,
+}
+
+/// The newest frame of the capture thread, with the one being captured and the one on
+/// screen the third buffer. A new frame replaces one that wasn't drawn yet, so the
+/// capture never waits for a throttled window and the window never shows stale frames.
+#[derive(Default)]
+struct FrameSlot {
+    frame: Mutex<Option<Frame>>,
+    /// Nobody would see the frames, so the capture thread doesn't copy them.
+    hidden: AtomicBool,
+    /// Frames were left out, so the damage of the next one doesn't say what changed
+    /// since the frame drawn.
+    resync: AtomicBool,
+}
+
+impl FrameSlot {
+    fn put(&self, mut frame: Frame) {
+        let mut slot = self.frame.lock().unwrap();
+        // a cursor that moved on doesn't replace the pixels of a frame
+        if let (None, Some(pending)) = (&frame.frame, slot.as_mut()) {
+            pending.cursor = frame.cursor;
+            return;
+        }
+        if frame.frame.is_some() && self.resync.swap(false, Ordering::Relaxed) {
+            frame.damage = None;
+        }
+        // what changed in a frame that wasn't drawn still has to be
+        if let Some(pending) = slot.as_ref() {
+            frame.damage = match (frame.damage.take(), &pending.damage) {
+                (Some(mut damage), Some(more)) => {
+                    damage.extend_from_slice(more);
+                    Some(damage)
+                }
+                _ => None,
+            };
+        }
+        let old = slot.replace(frame);
+        // closing the replaced fds is no reason to hold the lock
+        drop(slot);
+        drop(old);
+    }
+
+    fn take(&self) -> Option<Frame> {
+        self.frame.lock().unwrap().take()
+    }
+
+    fn is_full(&self) -> bool {
+        self.frame.lock().unwrap().is_some()
+    }
+
+    /// A frame didn't make it into the slot.
+    fn skipped(&self) {
+        self.resync.store(true, Ordering::Relaxed);
+    }
+
+    fn is_hidden(&self) -> bool {
+        self.hidden.load(Ordering::Relaxed)
+    }
+
+    fn set_hidden(&self, hidden: bool) {
+        self.hidden.store(hidden, Ordering::Relaxed);
+        if hidden {
+            self.skipped();
+            // the window would show it once visible again, older than what comes next
+            drop(self.take());
+        }
+    }
+}
+
+/// Hands frames to the window of [`Mirror::present`], from any thread.
+#[derive(Clone)]
+pub struct FrameFeed {
+    pending: Arc<FrameSlot>,
+    new_frame: Ping,
+    ended: channel::Sender<Option<String>>,
+}
+
+impl FrameFeed {
+    /// Show `frame` next, in place of one that wasn't drawn yet.
+    pub fn push(&self, format: &PipewireFrameFormat, frame: OwnedFrame) {
+        if self.pending.is_hidden() {
+            return;
+        }
+        self.pending.put(Frame {
+            format: *format,
+            transform: Transform::Normal,
+            frame: Some(frame),
+            cursor: None,
+            damage: None,
+        });
+        self.new_frame.ping();
+    }
+
+    /// Nobody would see the frames, so they needn't be made.
+    pub fn is_hidden(&self) -> bool {
+        self.pending.is_hidden()
+    }
+
+    /// There are no more frames, because of `error` if it failed. The window closes.
+    pub fn end(&self, error: Option<String>) {
+        let _ = self.ended.send(error);
+    }
+}
+
+/// What a window shows, and where its frames come from.
+struct WindowSpec {
+    name: String,
+    /// Logical size of what is shown, what the first size has to fit.
+    screen: (i32, i32),
+    node_id: Option<u32>,
+    fullscreen_on: Option<WlOutput>,
+    magnifier: Magnifier,
+    start: StartFrames,
+}
+
+/// Starts putting frames into the slot, with a ping for each, and says on the channel
+/// when they ended.
+type StartFrames =
+    Box<dyn FnOnce(Arc<FrameSlot>, Ping, channel::Sender<Option<String>>) -> Result<(), String>>;
+
+/// What other processes can ask of a running mirror, see [`crate::ipc`].
+enum MirrorRequest {
+    /// Bring a window to the front, the first one without an output. The token comes
+    /// from whoever launched the request, e.g. a hotkey daemon.
+    Raise {
+        output: Option<String>,
+        token: Option<String>,
+    },
+}
+
+/// Passes control commands on to the mirror's event loop.
+pub struct MirrorControl {
+    sender: channel::Sender<MirrorRequest>,
+    outputs: Vec<String>,
+    cursor: CursorSwitch,
+}
+
+impl MirrorControl {
+    pub fn raise(&self, output: Option<&str>, token: Option<&str>) -> Result<(), String> {
+        if let Some(output) = output {
+            if !self.outputs.iter().any(|o| o == output) {
+                return Err(format!("{output} is not mirrored"));
+            }
+        }
+        self.sender
+            .send(MirrorRequest::Raise {
+                output: output.map(String::from),
+                token: token.map(String::from),
+            })
+            .map_err(|_| "the mirror is closing".to_string())
+    }
+
+    /// Switch the cursor of the windows to `mode`, or between hidden and embedded. A
+    /// cursor sent as metadata is drawn or not right away; screencopy captures start a
+    /// new session that paints it or not. Portal sessions that embed the cursor keep it.
+    pub fn set_cursor(&self, mode: Option<CursorMode>) -> CursorMode {
+        let mode = mode.unwrap_or_else(|| toggled(self.cursor.mode()));
+        self.cursor.set(mode);
+        mode
+    }
+}
+
+fn toggled(mode: CursorMode) -> CursorMode {
+    match mode {
+        CursorMode::Hidden => CursorMode::Embedded,
+        CursorMode::Embedded | CursorMode::Metadata => CursorMode::Hidden,
+    }
+}
+
+/// A live view of another output in a window of its own.
+struct MirrorWindow {
+    output: String,
+    // the renderer draws to the window's surface and the decorations are subsurfaces of
+    // it, so they go first
+    renderer: Renderer,
+    /// A title bar with a close button and borders, for compositors that leave
+    /// decorations to the client.
+    decorations: Option<FallbackFrame<Mirror>>,
+    window: Window,
+    /// Logical size of the window.
+    size: (u32, u32),
+    /// The compositor or the user picked the size, so the frames don't.
+    size_chosen: bool,
+    /// Not fullscreen, maximized or tiled, so the size may follow the frames.
+    floating: bool,
+    /// Logical size of the mirrored output, what the first size has to fit.
+    screen: (u32, u32),
+    /// The remote desktop stream of the output, if input is passed through.
+    node_id: Option<u32>,
+    /// Pixel size of the frames in their logical orientation, once there are any.
+    frame_size: Option<(u32, u32)>,
+    /// In [`SCALE_DENOMINATOR`]ths.
+    scale: u32,
+    /// With these the compositor tells fractional scales, and buffers of any pixel size
+    /// are shown at the logical size. Without them the scale is whole.
+    fractional: Option<(WpFractionalScaleV1, WpViewport)>,
+    configured: bool,
+    fullscreen: bool,
+    /// Where fullscreen goes, left to the compositor if `None`.
+    fullscreen_on: Option<WlOutput>,
+    /// The newest frame, if it wasn't drawn yet.
+    pending: Arc<FrameSlot>,
+    /// A frame callback is outstanding, the compositor isn't ready for another frame.
+    waiting: bool,
+    /// Draw again without a new frame, e.g. for another part of it magnified.
+    redraw: bool,
+    magnifier: Magnifier,
+    /// When the frame that is waited for was committed.
+    committed_at: Instant,
+}
+
+/// Mirror windows, one per output, on one connection and event loop.
+pub struct Mirror {
+    registry_state: RegistryState,
+    output_state: OutputState,
+    seat_state: SeatState,
+    keyboard: Option<WlKeyboard>,
+    /// The window with keyboard focus.
+    focused: Option<WlSurface>,
+    shm: Shm,
+    /// Client-side decorations need it, none without.
+    subcompositor: Option<Arc<SubcompositorState>>,
+    pointer: Option<WlPointer>,
+    /// The window whose decorations the pointer is over.
+    hovered: Option<WlSurface>,
+    /// Lets windows be raised on request, rather than the compositor only flashing them.
+    activation: Option<XdgActivationV1>,
+    /// The cursor in the frames of all windows.
+    cursor: CursorSwitch,
+    /// Where pointer, touch and pen input on the windows goes, if anywhere.
+    input: Option<RemoteInput>,
+    pointer_constraints: PointerConstraintsState,
+    relative_pointer_state: RelativePointerState,
+    /// Movement of the pointer while it's locked, when it has no position.
+    relative_pointer: Option<ZwpRelativePointerV1>,
+    /// Asked for with g, until the compositor unlocks the pointer.
+    lock: Option<ZwpLockedPointerV1>,
+    /// The window the pointer is locked to, once the compositor did.
+    locked_to: Option<WlSurface>,
+    /// Passed on while a window has keyboard focus.
+    gamepads: Option<Gamepads>,
+    touch: Option<WlTouch>,
+    /// The windows touched, by touch point.
+    touches: Vec<(i32, WlSurface)>,
+    /// The touch point that moves the pointer, if the session has no touchscreen.
+    emulated_touch: Option<i32>,
+    tablet_manager: Option<ZwpTabletManagerV2>,
+    tablet_seat: Option<ZwpTabletSeatV2>,
+    /// The window a pen is over.
+    pen: Option<WlSurface>,
+    windows: Vec<MirrorWindow>,
+    /// Why the last window went away, if that was an error.
+    error: Option<String>,
+}
+
+impl Mirror {
+    /// Capture each of `outputs` and show it in a window until the windows are closed or
+    /// the outputs go away. Frames that come in faster than a window is drawn are
+    /// dropped. F11 or f toggles fullscreen, Escape leaves it, c shows or hides the
+    /// cursor and q closes a window. The windows start out as `magnifier` says, and its
+    /// keys zoom them, see [`Magnifier`].
+    ///
+    /// `fullscreen_on` puts the first window fullscreen on that output right away, e.g.
+    /// a projector. While the windows are up, `control` takes the commands for them.
+    ///
+    /// With `input`, pointer, touch and pen input on a window goes to the output it
+    /// shows, so a touchscreen or pen display can drive it. The keyboard stays with the
+    /// shortcuts. Pens arrive as a pointer, the portal has no tablets. g locks the
+    /// pointer to a window and sends its movement on as it is, for mouse-look in games,
+    /// until g is pressed again or the window loses focus. `gamepads` are passed on
+    /// while a window has focus.
+    #[allow(clippy::too_many_arguments)]
+    pub fn run(
+        desktop: &WlClientDesktopState,
+        outputs: &[&DesktopOutput],
+        fullscreen_on: Option<&DesktopOutput>,
+        magnifier: Magnifier,
+        tuning: &Tuning,
+        input: Option<RemoteInput>,
+        gamepads: Option<Gamepads>,
+        control: &SessionSlot,
+    ) -> Result<(), String> {
+        // the windows draw a cursor sent as metadata themselves, which looks the same, can
+        // be hidden without a new session, and tells --zoom-follow where the pointer is
+        let mode = match tuning.cursor {
+            CursorMode::Embedded => CursorMode::Metadata,
+            mode => mode,
+        };
+        let cursor = CursorSwitch::new(mode);
+        let fps = tuning.max_fps.unwrap_or(60);
+        let windows = outputs
+            .iter()
+            .enumerate()
+            .map(|(i, output)| {
+                let node_id = input.as_ref().and_then(|i| i.stream_at(output.logical_pos));
+                if input.is_some() && node_id.is_none() {
+                    println!(
+                        "{} wasn't shared for input, its mirror only shows it",
+                        output.name
+                    );
+                }
+                let name = output.name.clone();
+                let cursor = cursor.clone();
+                WindowSpec {
+                    name: output.name.clone(),
+                    screen: output.logical_size,
+                    node_id,
+                    // the desktop's outputs are on the same connection, so they do for
+                    // requests
+                    fullscreen_on: fullscreen_on
+                        .filter(|_| i == 0)
+                        .map(|target| target.wl_output.clone()),
+                    magnifier,
+                    start: Box::new(move |pending, new_frame, ended| {
+                        spawn_capture(&name, fps, cursor, pending, new_frame, ended)
+                    }),
+                }
+            })
+            .collect();
+        Self::run_windows(
+            desktop,
+            windows,
+            tuning,
+            cursor,
+            input,
+            gamepads,
+            Some(control),
+        )
+    }
+
+    /// Show what [`FrameFeed`] is given in a window titled after `name`, e.g. a decoded
+    /// stream, until the window is closed or the feed ends. `start` gets the feed once
+    /// the window is up. The keys are those of [`Mirror::run`].
+    pub fn present(
+        desktop: &WlClientDesktopState,
+        name: &str,
+        tuning: &Tuning,
+        start: impl FnOnce(FrameFeed) -> Result<(), String> + 'static,
+    ) -> Result<(), String> {
+        // where the window most likely opens, until the first frame says better
+        let screen = desktop
+            .outputs
+            .first()
+            .map_or((1920, 1080), |o| o.logical_size);
+        let window = WindowSpec {
+            name: name.to_string(),
+            screen,
+            node_id: None,
+            fullscreen_on: None,
+            magnifier: Magnifier::default(),
+            start: Box::new(move |pending, new_frame, ended| {
+                start(FrameFeed {
+                    pending,
+                    new_frame,
+                    ended,
+                })
+            }),
+        };
+        let cursor = CursorSwitch::new(tuning.cursor);
+        Self::run_windows(desktop, vec![window], tuning, cursor, None, None, None)
+    }
+
+    fn run_windows(
+        desktop: &WlClientDesktopState,
+        specs: Vec<WindowSpec>,
+        tuning: &Tuning,
+        cursor: CursorSwitch,
+        input: Option<RemoteInput>,
+        gamepads: Option<Gamepads>,
+        control: Option<&SessionSlot>,
+    ) -> Result<(), String> {
+        let connection = desktop.connection.clone();
+        let (globals, event_queue) =
+            registry_queue_init(&connection).map_err(|e| format!("wayland registry: {e}"))?;
+        let qh = event_queue.handle();
+        let mut event_loop: EventLoop<Mirror> =
+            EventLoop::try_new().map_err(|e| format!("event loop: {e}"))?;
+        let loop_handle = event_loop.handle();
+        WaylandSource::new(event_queue)
+            .map_err(|e| format!("wayland source: {e}"))?
+            .insert(loop_handle.clone())
+            .map_err(|e| format!("wayland source: {e}"))?;
+
+        let compositor =
+            CompositorState::bind(&globals, &qh).map_err(|_| "wl_compositor not available")?;
+        let xdg_shell = XdgShell::bind(&globals, &qh).map_err(|_| "xdg shell not available")?;
+        let shm = Shm::bind(&globals, &qh).map_err(|_| "wl_shm not available")?;
+        let subcompositor =
+            SubcompositorState::bind(compositor.wl_compositor().clone(), &globals, &qh)
+                .ok()
+                .map(Arc::new);
+        let activation = globals.bind::<XdgActivationV1, _, _>(&qh, 1..=1, ()).ok();
+        // unless pens are passed through, the compositor turns them into a pointer
+        let tablet_manager = input.as_ref().and_then(|_| {
+            globals
+                .bind::<ZwpTabletManagerV2, _, _>(&qh, 1..=1, ())
+                .ok()
+        });
+
+        let (sender, requests) = channel::channel();
+        let request_qh = qh.clone();
+        loop_handle
+            .insert_source(requests, move |event, _, mirror: &mut Mirror| {
+                if let Event::Msg(MirrorRequest::Raise { output, token }) = event {
+                    mirror.raise(output.as_deref(), token, &request_qh);
+                }
+            })
+            .map_err(|e| format!("control channel: {e}"))?;
+        // both or neither, a fractional scale is no use without a viewport to show it
+        let fractional_scale = globals
+            .bind::<WpFractionalScaleManagerV1, _, _>(&qh, 1..=1, ())
+            .ok()
+            .zip(globals.bind::<WpViewporter, _, _>(&qh, 1..=1, ()).ok());
+
+        let names: Vec<String> = specs.iter().map(|spec| spec.name.clone()).collect();
+        let mut windows = vec![];
+        for spec in specs {
+            let surface = compositor.create_surface(&qh);
+            let fractional = fractional_scale.as_ref().map(|(manager, viewporter)| {
+                (
+                    manager.get_fractional_scale(&surface, &qh, surface.clone()),
+                    viewporter.get_viewport(&surface, &qh, ()),
+                )
+            });
+            let window = xdg_shell.create_window(surface, WindowDecorations::RequestServer, &qh);
+            window.set_title(format!("lensing: {}", spec.name));
+            window.set_app_id("lensing");
+            window.set_min_size(Some((MIN_SIZE, MIN_SIZE)));
+            if let Some(target) = spec.fullscreen_on.as_ref() {
+                window.set_fullscreen(Some(target));
+            }
+            window.commit();
+
+            // until the first frame says better
+            let screen = (spec.screen.0.max(1) as u32, spec.screen.1.max(1) as u32);
+            let size = ((screen.0 / 2).max(MIN_SIZE), (screen.1 / 2).max(MIN_SIZE));
+            let mut renderer = Renderer::new(
+                &connection,
+                window.wl_surface(),
+                size,
+                tuning.immediate_present,
+            )?;
+            renderer.set_view(spec.magnifier.view());
+            renderer.set_filter(spec.magnifier.filter);
+
+            let pending = Arc::new(FrameSlot::default());
+            let (new_frame, new_frame_source) =
+                make_ping().map_err(|e| format!("frame ping: {e}"))?;
+            let (ended_sender, ended) = channel::channel();
+            (spec.start)(pending.clone(), new_frame, ended_sender)?;
+            let frame_qh = qh.clone();
+            let name = spec.name.clone();
+            loop_handle
+                .insert_source(new_frame_source, move |_, _, mirror: &mut Mirror| {
+                    mirror.draw_if_ready(&name, &frame_qh);
+                })
+                .map_err(|e| format!("frame ping: {e}"))?;
+            let name = spec.name.clone();
+            loop_handle
+                .insert_source(ended, move |event, _, mirror: &mut Mirror| {
+                    let error = match event {
+                        Event::Msg(error) => error,
+                        Event::Closed => None,
+                    };
+                    mirror.close(&name, error);
+                })
+                .map_err(|e| format!("capture channel: {e}"))?;
+
+            windows.push(MirrorWindow {
+                output: spec.name,
+                renderer,
+                decorations: None,
+                window,
+                size,
+                size_chosen: false,
+                floating: true,
+                screen,
+                node_id: spec.node_id,
+                frame_size: None,
+                scale: SCALE_DENOMINATOR,
+                fractional,
+                configured: false,
+                fullscreen: false,
+                fullscreen_on: spec.fullscreen_on,
+                pending,
+                waiting: false,
+                redraw: false,
+                magnifier: spec.magnifier,
+                committed_at: Instant::now(),
+            });
+        }
+
+        // compositors stop sending frame callbacks to windows that are minimized or
+        // covered, the only hint sctk 0.17 passes on, since xdg_toplevel's suspended state
+        // is newer than it
+        loop_handle
+            .insert_source(
+                Timer::from_duration(HIDDEN_AFTER),
+                |_, _, mirror: &mut Mirror| {
+                    for window in &mirror.windows {
+                        if window.waiting
+                            && !window.pending.is_hidden()
+                            && window.committed_at.elapsed() >= HIDDEN_AFTER
+                        {
+                            println!(
+                                "The mirror window of {} is hidden, pausing its preview",
+                                window.output
+                            );
+                            window.pending.set_hidden(true);
+                        }
+                    }
+                    TimeoutAction::ToDuration(HIDDEN_AFTER)
+                },
+            )
+            .map_err(|e| format!("visibility timer: {e}"))?;
+
+        let mut mirror = Mirror {
+            registry_state: RegistryState::new(&globals),
+            output_state: OutputState::new(&globals, &qh),
+            seat_state: SeatState::new(&globals, &qh),
+            keyboard: None,
+            focused: None,
+            shm,
+            subcompositor,
+            pointer: None,
+            hovered: None,
+            activation,
+            cursor: cursor.clone(),
+            input,
+            pointer_constraints: PointerConstraintsState::bind(&globals, &qh),
+            relative_pointer_state: RelativePointerState::bind(&globals, &qh),
+            relative_pointer: None,
+            lock: None,
+            locked_to: None,
+            gamepads,
+            touch: None,
+            touches: vec![],
+            emulated_touch: None,
+            tablet_manager,
+            tablet_seat: None,
+            pen: None,
+            windows,
+            error: None,
+        };
+        if let Some(control) = control {
+            *control.lock().unwrap() = Some(Session::Mirror(MirrorControl {
+                sender,
+                outputs: names,
+                cursor,
+            }));
+        }
+
+        let result = loop {
+            if mirror.windows.is_empty() {
+                break mirror.error.take().map_or(Ok(()), Err);
+            }
+            if let Err(e) = event_loop.dispatch(None, &mut mirror) {
+                break Err(format!("event loop: {e}"));
+            }
+        };
+        if let Some(control) = control {
+            control.lock().unwrap().take();
+        }
+        result
+    }
+
+    fn window_mut(&mut self, surface: &WlSurface) -> Option<&mut MirrorWindow> {
+        self.windows
+            .iter_mut()
+            .find(|w| w.window.wl_surface() == surface)
+    }
+
+    /// Take down the window of `output`. Its capture thread can't be stopped, but it
+    /// stops copying frames.
+    fn close(&mut self, output: &str, error: Option<String>) {
+        let Some(index) = self.windows.iter().position(|w| w.output == output) else {
+            return;
+        };
+        let window = self.windows.remove(index);
+        window.pending.set_hidden(true);
+        // before the surface they belong to goes with the window
+        if let Some((fractional, viewport)) = &window.fractional {
+            fractional.destroy();
+            viewport.destroy();
+        }
+        if let Some(e) = error {
+            println!("Mirror of {output} ended: {e}");
+            self.error = Some(e);
+        }
+        if self.focused.as_ref() == Some(window.window.wl_surface()) {
+            self.focused = None;
+        }
+        if self.hovered.as_ref() == Some(window.window.wl_surface()) {
+            self.hovered = None;
+        }
+        if self.pen.as_ref() == Some(window.window.wl_surface()) {
+            self.pen = None;
+        }
+        if self.locked_to.as_ref() == Some(window.window.wl_surface()) {
+            self.unlock_pointer();
+        }
+        self.touches
+            .retain(|(_, s)| s != window.window.wl_surface());
+    }
+
+    /// Pass input on `surface` at `position`, in logical pixels of the window, on to the
+    /// output the window shows. Whether it went anywhere: decorations, windows of
+    /// outputs without input, and the bars around a frame that doesn't fill its window
+    /// keep theirs.
+    fn forward(
+        &self,
+        surface: &WlSurface,
+        position: (f64, f64),
+        event: impl FnOnce(f64, f64) -> InputEvent,
+    ) -> bool {
+        let Some((input, window, node_id)) = self.input_target(surface) else {
+            return false;
+        };
+        let Some((x, y)) = window.to_output(position) else {
+            return false;
+        };
+        input.send(node_id, event(x, y));
+        true
+    }
+
+    /// Like [`Mirror::forward`], for input without a position, e.g. a button release,
+    /// which goes to wherever the pointer is on the output.
+    fn forward_event(&self, surface: &WlSurface, event: InputEvent) -> bool {
+        let Some((input, _, node_id)) = self.input_target(surface) else {
+            return false;
+        };
+        input.send(node_id, event);
+        true
+    }
+
+    /// The input, window and stream of `surface`, if the window passes input on.
+    fn input_target(&self, surface: &WlSurface) -> Option<(&RemoteInput, &MirrorWindow, u32)> {
+        let input = self.input.as_ref()?;
+        let window = self
+            .windows
+            .iter()
+            .find(|w| w.window.wl_surface() == surface)?;
+        Some((input, window, window.node_id?))
+    }
+
+    /// Lock the pointer to the window of `surface`, or let it go if it is locked. Only
+    /// windows that pass input on lock it, the others would only trap it.
+    fn toggle_lock(&mut self, surface: &WlSurface, qh: &QueueHandle<Self>) {
+        if self.lock.is_some() {
+            self.unlock_pointer();
+            return;
+        }
+        let Some(pointer) = self.pointer.as_ref() else {
+            return;
+        };
+        if !self
+            .windows
+            .iter()
+            .any(|w| w.window.wl_surface() == surface && w.node_id.is_some())
+        {
+            return;
+        }
+        // the lock ends when the window loses focus, rather than coming back with it
+        match self
+            .pointer_constraints
+            .lock_pointer(surface, pointer, None, Lifetime::Oneshot, qh)
+        {
+            Ok(lock) => self.lock = Some(lock),
+            Err(_) => println!("The compositor can't lock the pointer"),
+        }
+    }
+
+    fn unlock_pointer(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            lock.destroy();
+        }
+        if self.locked_to.take().is_some() {
+            println!("Released the pointer");
+        }
+    }
+
+    /// Pass the touch up of `id` on, as a pointer release if it moved the pointer.
+    fn touch_up(&mut self, id: i32) {
+        let Some(index) = self.touches.iter().position(|(t, _)| *t == id) else {
+            return;
+        };
+        let (_, surface) = self.touches.remove(index);
+        if self.emulated_touch == Some(id) {
+            self.emulated_touch = None;
+            self.forward_event(
+                &surface,
+                InputEvent::PointerButton {
+                    button: BTN_LEFT,
+                    pressed: false,
+                },
+            );
+        } else {
+            self.forward_event(&surface, InputEvent::TouchUp { slot: id as u32 });
+        }
+    }
+
+    /// Ask the compositor to focus the window of `output`. Without a token from the
+    /// launcher this asks for one of its own, which compositors may only honor by marking
+    /// the window urgent.
+    fn raise(&mut self, output: Option<&str>, token: Option<String>, qh: &QueueHandle<Self>) {
+        let window = match output {
+            Some(output) => self.windows.iter().find(|w| w.output == output),
+            None => self.windows.first(),
+        };
+        let Some(window) = window else {
+            return;
+        };
+        let Some(activation) = self.activation.as_ref() else {
+            println!("The compositor has no xdg-activation, the mirror can't raise itself");
+            return;
+        };
+        let surface = window.window.wl_surface();
+        match token {
+            Some(token) => activation.activate(token, surface),
+            None => {
+                let request = activation.get_activation_token(qh, surface.clone());
+                request.set_surface(surface);
+                request.set_app_id("lensing".into());
+                request.commit();
+            }
+        }
+    }
+
+    /// Do what a click on the decorations of `surface`'s window asks for.
+    fn frame_action(
+        &mut self,
+        surface: &WlSurface,
+        pointer: &WlPointer,
+        serial: u32,
+        action: FrameAction,
+    ) {
+        let Some(window) = self.window_mut(surface) else {
+            return;
+        };
+        let Some(seat) = pointer
+            .data::<PointerData>()
+            .map(|data| data.seat().clone())
+        else {
+            return;
+        };
+        let xdg = &window.window;
+        match action {
+            FrameAction::Close => {
+                let output = window.output.clone();
+                self.close(&output, None);
+            }
+            FrameAction::Minimize => xdg.set_minimized(),
+            FrameAction::Maximize => xdg.set_maximized(),
+            FrameAction::UnMaximize => xdg.unset_maximized(),
+            FrameAction::ShowMenu(x, y) => xdg.show_window_menu(&seat, serial, (x, y)),
+            FrameAction::Resize(edge) => xdg.resize(&seat, serial, edge),
+            FrameAction::Move => xdg.move_(&seat, serial),
+        }
+    }
+
+    /// Render the window of `surface` at `scale`, in [`SCALE_DENOMINATOR`]ths, and redraw
+    /// it right away unless a frame is on its way anyway.
+    fn rescale(&mut self, surface: &WlSurface, scale: u32, qh: &QueueHandle<Self>) {
+        let Some(window) = self.window_mut(surface) else {
+            return;
+        };
+        if window.set_scale(scale) && window.configured && !window.waiting {
+            let output = window.output.clone();
+            self.draw(&output, qh);
+        }
+    }
+
+    /// Draw the pending frame of `output`, unless the compositor still has to ask for one.
+    fn draw_if_ready(&mut self, output: &str, qh: &QueueHandle<Self>) {
+        let Some(window) = self.windows.iter_mut().find(|w| w.output == output) else {
+            return;
+        };
+        if !window.configured || window.waiting || !(window.pending.is_full() || window.redraw) {
+            return;
+        }
+        self.draw(output, qh);
+    }
+
+    fn draw(&mut self, output: &str, qh: &QueueHandle<Self>) {
+        let Some(window) = self.windows.iter_mut().find(|w| w.output == output) else {
+            return;
+        };
+        if let Err(e) = window.draw(qh) {
+            self.close(output, Some(e));
+        }
+    }
+}
+
+impl MirrorWindow {
+    fn draw(&mut self, qh: &QueueHandle<Mirror>) -> Result<(), String> {
+        self.redraw = false;
+        if let Some(Frame {
+            format,
+            transform,
+            frame,
+            cursor,
+            damage,
+        }) = self.pending.take()
+        {
+            if let Some(frame) = frame {
+                let result = self
+                    .renderer
+                    .upload(&format, transform, &frame, damage.as_deref());
+                if let Err(e) = result {
+                    println!("Dropping a frame: {e}");
+                    self.pending.skipped();
+                }
+                let (width, height) = (format.width, format.height);
+                if renderer::is_rotated(transform) {
+                    self.fit_to_frames((height, width));
+                } else {
+                    self.fit_to_frames((width, height));
+                }
+            }
+            self.renderer.set_cursor(cursor.as_ref());
+            self.follow_pointer(&format, transform, cursor.as_ref());
+        }
+
+        self.draw_decorations();
+        // presenting commits the surface, which the callback has to be part of
+        let surface = self.window.wl_surface();
+        surface.frame(qh, surface.clone());
+        self.waiting = true;
+        self.committed_at = Instant::now();
+        self.renderer.draw().inspect_err(|_| {
+            // nothing was committed, so there is no callback to wait for
+            self.waiting = false;
+        })
+    }
+
+    /// Keep the window to the shape of the frames: no smaller than [`MIN_SIZE`], no
+    /// larger than one frame pixel per pixel, and until something else picked a size, at
+    /// the frame size or a half or quarter of it, whatever fits on the screen.
+    fn fit_to_frames(&mut self, frame_size: (u32, u32)) {
+        if self.frame_size == Some(frame_size) || frame_size.0 == 0 || frame_size.1 == 0 {
+            return;
+        }
+        self.frame_size = Some(frame_size);
+
+        let scale = self.scale.max(1);
+        let logical = (
+            (frame_size.0 * SCALE_DENOMINATOR / scale).max(1),
+            (frame_size.1 * SCALE_DENOMINATOR / scale).max(1),
+        );
+        let short_side = logical.0.min(logical.1);
+        let min = (
+            logical.0 * MIN_SIZE / short_side,
+            logical.1 * MIN_SIZE / short_side,
+        );
+        // these count the decorations in
+        self.window.set_min_size(Some(self.outer_size(min)));
+        self.window.set_max_size(Some(
+            self.outer_size((logical.0.max(min.0), logical.1.max(min.1))),
+        ));
+
+        if !self.size_chosen {
+            let bounds = (self.screen.0 * 3 / 4, self.screen.1 * 3 / 4);
+            let mut size = logical;
+            for _ in 0..2 {
+                if size.0 <= bounds.0 && size.1 <= bounds.1 {
+                    break;
+                }
+                size = (size.0 / 2, size.1 / 2);
+            }
+            self.size = (size.0.max(min.0), size.1.max(min.1));
+        } else if self.floating {
+            self.size = fit_aspect(self.size, logical);
+        }
+        self.resize();
+    }
+
+    /// Where `position`, in logical pixels of the window, is on the output. `None` on
+    /// the bars around a frame that doesn't fill the window, e.g. fullscreen or tiled.
+    fn to_output(&self, position: (f64, f64)) -> Option<(f64, f64)> {
+        let (width, height) = (self.size.0.max(1) as f64, self.size.1.max(1) as f64);
+        let [left, top, area_width, area_height] = self.renderer.frame_area();
+        let inside = (
+            (position.0 / width - left) / area_width,
+            (position.1 / height - top) / area_height,
+        );
+        if !(0.0..=1.0).contains(&inside.0) || !(0.0..=1.0).contains(&inside.1) {
+            return None;
+        }
+        let (x, y) = self.magnifier.frame_position(inside);
+        Some((x * self.screen.0 as f64, y * self.screen.1 as f64))
+    }
+
+    /// Pan the magnified view along with a visible pointer, if it follows it. Only the
+    /// portal and KWin send where the pointer is, and they turn the frames themselves.
+    fn follow_pointer(
+        &mut self,
+        format: &PipewireFrameFormat,
+        transform: Transform,
+        cursor: Option<&CursorMeta>,
+    ) {
+        let Some(cursor) = cursor.filter(|c| c.bitmap.is_some()) else {
+            return;
+        };
+        if !self.magnifier.follow || transform != Transform::Normal || format.width == 0 {
+            return;
+        }
+        let pointer = (
+            cursor.position.0 as f64 / format.width as f64,
+            cursor.position.1 as f64 / format.height.max(1) as f64,
+        );
+        self.magnifier.follow(pointer);
+        self.renderer.set_view(self.magnifier.view());
+    }
+
+    /// Zoom, pan or change the filter if `key` is one of the [`Magnifier`]'s. Whether
+    /// the window has to be drawn again.
+    fn magnify(&mut self, key: u32) -> bool {
+        let before = self.magnifier;
+        match key {
+            keysyms::XKB_KEY_plus | keysyms::XKB_KEY_equal | keysyms::XKB_KEY_KP_Add => {
+                self.magnifier.zoom_in()
+            }
+            keysyms::XKB_KEY_minus | keysyms::XKB_KEY_KP_Subtract => self.magnifier.zoom_out(),
+            keysyms::XKB_KEY_0 | keysyms::XKB_KEY_KP_0 => self.magnifier.set_zoom(1.0),
+            keysyms::XKB_KEY_Left => self.magnifier.pan((-1.0, 0.0)),
+            keysyms::XKB_KEY_Right => self.magnifier.pan((1.0, 0.0)),
+            keysyms::XKB_KEY_Up => self.magnifier.pan((0.0, -1.0)),
+            keysyms::XKB_KEY_Down => self.magnifier.pan((0.0, 1.0)),
+            keysyms::XKB_KEY_n => {
+                self.magnifier.toggle_filter();
+                println!("Filter {}", self.magnifier.filter);
+            }
+            keysyms::XKB_KEY_p => {
+                self.magnifier.follow = !self.magnifier.follow;
+                let what = if self.magnifier.follow {
+                    "Following"
+                } else {
+                    "Not following"
+                };
+                println!("{what} the pointer");
+            }
+            _ => return false,
+        }
+        if self.magnifier == before {
+            return false;
+        }
+        if self.magnifier.zoom != before.zoom {
+            println!("Zoom {}x", self.magnifier.zoom);
+        }
+        self.renderer.set_view(self.magnifier.view());
+        self.renderer.set_filter(self.magnifier.filter);
+        self.redraw = true;
+        true
+    }
+
+    /// The surface's true size, which buffers have to have to be sharp.
+    fn pixel_size(&self) -> (u32, u32) {
+        // rounded half up, as the protocol asks
+        let scale =
+            |logical: u32| (logical * self.scale + SCALE_DENOMINATOR / 2) / SCALE_DENOMINATOR;
+        (scale(self.size.0), scale(self.size.1))
+    }
+
+    /// Render at the pixel size for the current size and scale. The viewport shows the
+    /// buffers at the logical size, with the next commit like the buffers themselves.
+    fn resize(&mut self) {
+        if let Some((_, viewport)) = &self.fractional {
+            viewport.set_destination(self.size.0 as i32, self.size.1 as i32);
+        }
+        // the window geometry leaves out the shadow around the output, but not the
+        // decorations
+        let (width, height) = self.size;
+        let (x, y, outer) = match self.decorations.as_mut() {
+            Some(frame) if !frame.is_hidden() => {
+                frame.resize(
+                    NonZeroU32::new(width.max(1)).unwrap(),
+                    NonZeroU32::new(height.max(1)).unwrap(),
+                );
+                let (x, y) = frame.location();
+                (x, y, frame.add_borders(width, height))
+            }
+            _ => (0, 0, self.size),
+        };
+        self.window
+            .xdg_surface()
+            .set_window_geometry(x, y, outer.0 as i32, outer.1 as i32);
+        self.renderer.resize(self.pixel_size());
+    }
+
+    /// `size` of the content with the decorations around it, if there are any.
+    fn outer_size(&self, size: (u32, u32)) -> (u32, u32) {
+        match &self.decorations {
+            Some(frame) if !frame.is_hidden() => frame.add_borders(size.0, size.1),
+            _ => size,
+        }
+    }
+
+    /// Redraw the decorations if the pointer or the window state changed them. They go
+    /// on screen with the next commit of the window.
+    fn draw_decorations(&mut self) -> bool {
+        match self.decorations.as_mut() {
+            Some(frame) if frame.is_dirty() && !frame.is_hidden() => {
+                frame.draw();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the scale changed.
+    fn set_scale(&mut self, scale: u32) -> bool {
+        if scale == self.scale {
+            return false;
+        }
+        self.scale = scale;
+        // the frames cover a different logical size now
+        if let Some(frame_size) = self.frame_size.take() {
+            self.fit_to_frames(frame_size);
+        } else {
+            self.resize();
+        }
+        true
+    }
+
+    fn toggle_fullscreen(&self) {
+        if self.fullscreen {
+            self.window.unset_fullscreen();
+        } else {
+            self.window.set_fullscreen(self.fullscreen_on.as_ref());
+        }
+    }
+}
+
+/// The largest size within `bounds` with the aspect ratio of `shape`.
+fn fit_aspect(bounds: (u32, u32), shape: (u32, u32)) -> (u32, u32) {
+    let (width, height) = (shape.0.max(1) as u64, shape.1.max(1) as u64);
+    let (bound_width, bound_height) = (bounds.0 as u64, bounds.1 as u64);
+    let size = if bound_width * height <= bound_height * width {
+        (bound_width, bound_width * height / width)
+    } else {
+        (bound_height * width / height, bound_height)
+    };
+    (size.0.max(1) as u32, size.1.max(1) as u32)
+}
+
+/// Capture `output` on a thread of its own, with its own connection, since backends
+/// block while they deliver frames. Frames go to `pending`, with a ping for each;
+/// `ended` says when the capture ended, and why if it failed.
+fn spawn_capture(
+    output: &str,
+    fps: u32,
+    cursor: CursorSwitch,
+    pending: Arc<FrameSlot>,
+    new_frame: Ping,
+    ended: channel::Sender<Option<String>>,
+) -> Result<(), String> {
+    let name = output.to_string();
+    std::thread::Builder::new()
+        .name(format!("capture {output}"))
+        .spawn(move || {
+            let desktop = WlClientDesktopState::new().and_then(|mut desktop| {
+                desktop.wait_for_output(&name)?;
+                Ok(desktop)
+            });
+            let desktop = match desktop {
+                Ok(desktop) => desktop,
+                Err(e) => {
+                    let _ = ended.send(Some(e.to_string()));
+                    return;
+                }
+            };
+            let Some(output) = desktop.outputs.iter().find(|o| o.name == name) else {
+                return;
+            };
+
+            let result =
+                backend::detect(&desktop.connection, output, &cursor).and_then(|backend| {
+                    println!("Mirroring {name} with {}", backend.name());
+                    let transform = backend.transform();
+                    backend.run(
+                        fps,
+                        wgpu_import::formats(),
+                        Box::new(move |format, frame| {
+                            if pending.is_hidden() {
+                                return;
+                            }
+                            // the frame before looks better than one the producer says is
+                            // broken, but the next one has to be uploaded whole
+                            if frame.header.is_some_and(|h| h.is_corrupted()) {
+                                pending.skipped();
+                                return;
+                            }
+                            let pixels = match frame.data {
+                                PipewireFrameData::Unchanged => None,
+                                _ => match OwnedFrame::copy(frame) {
+                                    Ok(pixels) => Some(pixels),
+                                    Err(e) => {
+                                        println!("Dropping a frame: {e}");
+                                        pending.skipped();
+                                        return;
+                                    }
+                                },
+                            };
+                            // a hidden cursor sent as metadata just isn't drawn
+                            let shown = cursor.mode() != CursorMode::Hidden;
+                            pending.put(Frame {
+                                format: *format,
+                                transform,
+                                frame: pixels,
+                                cursor: frame.cursor.clone().filter(|_| shown),
+                                damage: frame.damage.clone(),
+                            });
+                            new_frame.ping();
+                        }),
+                    )
+                });
+            let _ = ended.send(result.err());
+        })
+        .map(|_| ())
+        .map_err(|e| format!("capture thread: {e}"))
+}
+
+impl CompositorHandler for Mirror {
+    fn scale_factor_changed(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        surface: &WlSurface,
+        new_factor: i32,
+    ) {
+        let Some(window) = self.window_mut(surface) else {
+            return;
+        };
+        // the fractional scale is the precise one, the whole one rounds it up
+        if window.fractional.is_some() {
+            return;
+        }
+        surface.set_buffer_scale(new_factor);
+        self.rescale(surface, new_factor.max(1) as u32 * SCALE_DENOMINATOR, qh);
+    }
+
+    fn frame(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        surface: &WlSurface,
+        _time: u32,
+    ) {
+        let Some(window) = self.window_mut(surface) else {
+            return;
+        };
+        window.waiting = false;
+        if window.pending.is_hidden() {
+            println!(
+                "The mirror window of {} is visible, resuming its preview",
+                window.output
+            );
+            window.pending.set_hidden(false);
+        }
+        let output = window.output.clone();
+        self.draw_if_ready(&output, qh);
+    }
+}
+delegate_compositor!(Mirror);
+
+impl OutputHandler for Mirror {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _output: WlOutput) {}
+
+    fn update_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _output: WlOutput) {}
+
+    fn output_destroyed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _output: WlOutput) {
+    }
+}
+delegate_output!(Mirror);
+
+impl WindowHandler for Mirror {
+    fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, window: &Window) {
+        if let Some(window) = self.window_mut(window.wl_surface()) {
+            let output = window.output.clone();
+            self.close(&output, None);
+        }
+    }
+
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        window: &Window,
+        configure: WindowConfigure,
+        _serial: u32,
+    ) {
+        let Some(window) = self
+            .windows
+            .iter_mut()
+            .find(|w| w.window.wl_surface() == window.wl_surface())
+        else {
+            return;
+        };
+        // floating windows may be smaller than asked, the others have to fill it
+        window.floating =
+            !(configure.is_fullscreen() || configure.is_maximized() || configure.is_tiled());
+
+        // no xdg-decoration, or the compositor wants the client to draw them
+        let client_side = configure.decoration_mode == DecorationMode::Client;
+        if client_side && window.decorations.is_none() {
+            if let Some(subcompositor) = self.subcompositor.clone() {
+                match FallbackFrame::new(&window.window, &self.shm, subcompositor, qh.clone()) {
+                    Ok(mut frame) => {
+                        frame.set_title(format!("lensing: {}", window.output));
+                        window.decorations = Some(frame);
+                    }
+                    Err(e) => println!("No decorations for the mirror of {}: {e}", window.output),
+                }
+            }
+        }
+        if let Some(frame) = window.decorations.as_mut() {
+            frame.set_hidden(!client_side);
+            if client_side {
+                frame.update_state(configure.state);
+                frame.update_wm_capabilities(configure.capabilities);
+            }
+        }
+
+        // a size left to us keeps the one we had
+        if let (Some(width), Some(height)) = configure.new_size {
+            let (width, height) = match window.decorations.as_ref() {
+                Some(frame) if !frame.is_hidden() => frame.subtract_borders(width, height),
+                _ => (Some(width), Some(height)),
+            };
+            let size = (
+                width.map_or(1, NonZeroU32::get),
+                height.map_or(1, NonZeroU32::get),
+            );
+            window.size = match window.frame_size {
+                Some(frame_size) if window.floating => fit_aspect(size, frame_size),
+                _ => size,
+            };
+            window.size_chosen = true;
+        }
+        window.fullscreen = configure.is_fullscreen();
+        window.resize();
+        window.configured = true;
+        // the first configure has to be answered with a buffer
+        if !window.waiting {
+            let output = window.output.clone();
+            self.draw(&output, qh);
+        }
+    }
+}
+delegate_xdg_shell!(Mirror);
+delegate_xdg_window!(Mirror);
+
+impl SeatHandler for Mirror {
+    fn seat_state(&mut self) -> &mut SeatState {
+        &mut self.seat_state
+    }
+
+    fn new_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
+
+    fn new_capability(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        seat: WlSeat,
+        capability: Capability,
+    ) {
+        if self.tablet_seat.is_none() {
+            if let Some(manager) = self.tablet_manager.as_ref() {
+                self.tablet_seat = Some(manager.get_tablet_seat(&seat, qh, ()));
+            }
+        }
+        if capability == Capability::Keyboard && self.keyboard.is_none() {
+            match self.seat_state.get_keyboard(qh, &seat, None) {
+                Ok(keyboard) => self.keyboard = Some(keyboard),
+                Err(e) => println!("No keyboard shortcuts for the mirror: {e}"),
+            }
+        }
+        if capability == Capability::Pointer && self.pointer.is_none() {
+            match self.seat_state.get_pointer(qh, &seat) {
+                Ok(pointer) => {
+                    if self.input.is_some() {
+                        self.relative_pointer = self
+                            .relative_pointer_state
+                            .get_relative_pointer(&pointer, qh)
+                            .ok();
+                    }
+                    self.pointer = Some(pointer);
+                }
+                Err(e) => println!("The mirror's decorations won't take clicks: {e}"),
+            }
+        }
+        if capability == Capability::Touch && self.touch.is_none() && self.input.is_some() {
+            match self.seat_state.get_touch(qh, &seat) {
+                Ok(touch) => self.touch = Some(touch),
+                Err(e) => println!("Touches on the mirror won't be passed on: {e}"),
+            }
+        }
+    }
+
+    fn remove_capability(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _seat: WlSeat,
+        capability: Capability,
+    ) {
+        if capability == Capability::Keyboard {
+            if let Some(keyboard) = self.keyboard.take() {
+                keyboard.release();
+            }
+        }
+        if capability == Capability::Pointer {
+            self.unlock_pointer();
+            if let Some(relative_pointer) = self.relative_pointer.take() {
+                relative_pointer.destroy();
+            }
+            if let Some(pointer) = self.pointer.take() {
+                pointer.release();
+            }
+            self.hovered = None;
+        }
+        if capability == Capability::Touch {
+            if let Some(touch) = self.touch.take() {
+                touch.release();
+            }
+            for id in self.touches.iter().map(|(id, _)| *id).collect::<Vec<_>>() {
+                self.touch_up(id);
+            }
+        }
+    }
+
+    fn remove_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
+}
+delegate_seat!(Mirror);
+
+impl KeyboardHandler for Mirror {
+    fn enter(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        surface: &WlSurface,
+        _serial: u32,
+        _raw: &[u32],
+        _keysyms: &[u32],
+    ) {
+        self.focused = Some(surface.clone());
+        if let Some(gamepads) = self.gamepads.as_ref() {
+            gamepads.set_forwarding(true);
+        }
+    }
+
+    fn leave(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        surface: &WlSurface,
+        _serial: u32,
+    ) {
+        if self.focused.as_ref() == Some(surface) {
+            self.focused = None;
+            if let Some(gamepads) = self.gamepads.as_ref() {
+                gamepads.set_forwarding(false);
+            }
+        }
+    }
+
+    fn press_key(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        event: KeyEvent,
+    ) {
+        let Some(surface) = self.focused.clone() else {
+            return;
+        };
+        let interactive = self.input.is_some();
+        let Some(window) = self.window_mut(&surface) else {
+            return;
+        };
+        match event.keysym {
+            keysyms::XKB_KEY_F11 | keysyms::XKB_KEY_f => window.toggle_fullscreen(),
+            keysyms::XKB_KEY_Escape if window.fullscreen => window.window.unset_fullscreen(),
+            keysyms::XKB_KEY_q => {
+                let output = window.output.clone();
+                self.close(&output, None);
+            }
+            keysyms::XKB_KEY_g if interactive => self.toggle_lock(&surface, qh),
+            keysyms::XKB_KEY_c => {
+                let mode = toggled(self.cursor.mode());
+                self.cursor.set(mode);
+                println!("Cursor {mode}");
+            }
+            key => {
+                if window.magnify(key) {
+                    let output = window.output.clone();
+                    self.draw_if_ready(&output, qh);
+                }
+            }
+        }
+    }
+
+    fn release_key(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        _event: KeyEvent,
+    ) {
+    }
+
+    fn update_modifiers(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        _modifiers: Modifiers,
+    ) {
+    }
+}
+delegate_keyboard!(Mirror);
+
+/// Over the decorations the pointer works them, over the output it's passed on if
+/// input is.
+impl PointerHandler for Mirror {
+    fn pointer_frame(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        pointer: &WlPointer,
+        events: &[PointerEvent],
+    ) {
+        for event in events {
+            let (x, y) = event.position;
+            match event.kind {
+                PointerEventKind::Enter { .. } | PointerEventKind::Motion { .. } => {
+                    self.hovered = self.windows.iter_mut().find_map(|w| {
+                        let frame = w.decorations.as_mut()?;
+                        frame.click_point_moved(&event.surface, x, y)?;
+                        Some(w.window.wl_surface().clone())
+                    });
+                    self.forward(&event.surface, event.position, |x, y| {
+                        InputEvent::PointerMotion { x, y }
+                    });
+                }
+                PointerEventKind::Leave { .. } => {
+                    let Some(surface) = self.hovered.take() else {
+                        continue;
+                    };
+                    if let Some(frame) = self
+                        .window_mut(&surface)
+                        .and_then(|w| w.decorations.as_mut())
+                    {
+                        frame.click_point_left();
+                    }
+                }
+                PointerEventKind::Press { button, serial, .. }
+                | PointerEventKind::Release { button, serial, .. } => {
+                    let pressed = matches!(event.kind, PointerEventKind::Press { .. });
+                    let input = InputEvent::PointerButton { button, pressed };
+                    // presses on the bars around the frame stay, releases always go, so
+                    // that no button is left held
+                    let forwarded = if pressed {
+                        self.forward(&event.surface, event.position, |_, _| input)
+                    } else {
+                        self.forward_event(&event.surface, input)
+                    };
+                    if forwarded {
+                        continue;
+                    }
+                    let click = match button {
+                        BTN_LEFT => FrameClick::Normal,
+                        BTN_RIGHT => FrameClick::Alternate,
+                        _ => continue,
+                    };
+                    let Some(surface) = self.hovered.clone() else {
+                        continue;
+                    };
+                    let action = self
+                        .window_mut(&surface)
+                        .and_then(|w| w.decorations.as_mut())
+                        .and_then(|frame| frame.on_click(click, pressed));
+                    if let Some(action) = action {
+                        self.frame_action(&surface, pointer, serial, action);
+                    }
+                }
+                PointerEventKind::Axis {
+                    horizontal,
+                    vertical,
+                    ..
+                } => {
+                    self.forward(&event.surface, event.position, |_, _| {
+                        InputEvent::PointerAxis {
+                            dx: horizontal.absolute,
+                            dy: vertical.absolute,
+                            finish: horizontal.stop || vertical.stop,
+                        }
+                    });
+                }
+            }
+        }
+
+        // buttons light up under the pointer, without waiting for the next frame
+        for window in self.windows.iter_mut() {
+            if window.draw_decorations() {
+                window.window.wl_surface().commit();
+            }
+        }
+    }
+}
+delegate_pointer!(Mirror);
+
+impl PointerConstraintsHandler for Mirror {
+    fn confined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+    }
+
+    fn unconfined(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _confined_pointer: &ZwpConfinedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+    }
+
+    fn locked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _locked_pointer: &ZwpLockedPointerV1,
+        surface: &WlSurface,
+        pointer: &WlPointer,
+    ) {
+        // a cursor frozen on top of the mirrored one would only be in the way
+        if let Some(serial) = pointer
+            .data::<PointerData>()
+            .and_then(|data| data.latest_enter_serial())
+        {
+            pointer.set_cursor(serial, None, 0, 0);
+        }
+        if let Some(window) = self
+            .windows
+            .iter()
+            .find(|w| w.window.wl_surface() == surface)
+        {
+            println!(
+                "Locked the pointer to the mirror of {}, g releases it",
+                window.output
+            );
+        }
+        self.locked_to = Some(surface.clone());
+    }
+
+    fn unlocked(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _locked_pointer: &ZwpLockedPointerV1,
+        _surface: &WlSurface,
+        _pointer: &WlPointer,
+    ) {
+        // a oneshot lock doesn't come back
+        self.unlock_pointer();
+    }
+}
+delegate_pointer_constraints!(Mirror);
+
+impl RelativePointerHandler for Mirror {
+    fn relative_pointer_motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _relative_pointer: &ZwpRelativePointerV1,
+        _pointer: &WlPointer,
+        event: RelativeMotionEvent,
+    ) {
+        // unlocked, the pointer's position says it all
+        let Some(surface) = self.locked_to.as_ref() else {
+            return;
+        };
+        // games want the mouse's own movement, the other side accelerates it if it likes
+        let (dx, dy) = event.delta_unaccel;
+        self.forward_event(surface, InputEvent::PointerMotionRelative { dx, dy });
+    }
+}
+delegate_relative_pointer!(Mirror);
+
+/// Touches are only taken while input is passed on. Without a touchscreen in the
+/// session, the first finger down moves the pointer and holds its left button.
+impl TouchHandler for Mirror {
+    fn down(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _serial: u32,
+        _time: u32,
+        surface: WlSurface,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        // touches that start on the bars around the frame aren't passed on at all
+        if self.input.as_ref().is_some_and(|i| i.touch) {
+            if self.forward(&surface, position, |x, y| InputEvent::TouchDown {
+                slot: id as u32,
+                x,
+                y,
+            }) {
+                self.touches.push((id, surface));
+            }
+        } else if self.emulated_touch.is_none()
+            && self.forward(&surface, position, |x, y| InputEvent::PointerMotion {
+                x,
+                y,
+            })
+        {
+            self.emulated_touch = Some(id);
+            self.forward_event(
+                &surface,
+                InputEvent::PointerButton {
+                    button: BTN_LEFT,
+                    pressed: true,
+                },
+            );
+            self.touches.push((id, surface));
+        }
+    }
+
+    fn up(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _serial: u32,
+        _time: u32,
+        id: i32,
+    ) {
+        self.touch_up(id);
+    }
+
+    fn motion(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _time: u32,
+        id: i32,
+        position: (f64, f64),
+    ) {
+        let Some((_, surface)) = self.touches.iter().find(|(t, _)| *t == id) else {
+            return;
+        };
+        if self.emulated_touch == Some(id) {
+            self.forward(surface, position, |x, y| InputEvent::PointerMotion { x, y });
+        } else {
+            self.forward(surface, position, |x, y| InputEvent::TouchMotion {
+                slot: id as u32,
+                x,
+                y,
+            });
+        }
+    }
+
+    fn shape(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _major: f64,
+        _minor: f64,
+    ) {
+    }
+
+    fn orientation(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _touch: &WlTouch,
+        _id: i32,
+        _orientation: f64,
+    ) {
+    }
+
+    /// The portal can't cancel touches, so they end where they are.
+    fn cancel(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _touch: &WlTouch) {
+        for id in self.touches.iter().map(|(id, _)| *id).collect::<Vec<_>>() {
+            self.touch_up(id);
+        }
+    }
+}
+delegate_touch!(Mirror);
+
+impl ShmHandler for Mirror {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+delegate_shm!(Mirror);
+delegate_subcompositor!(Mirror);
+
+impl ProvidesRegistryState for Mirror {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![OutputState, SeatState];
+}
+delegate_registry!(Mirror);
+
+impl Dispatch<WpFractionalScaleV1, WlSurface> for Mirror {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpFractionalScaleV1,
+        event: <WpFractionalScaleV1 as Proxy>::Event,
+        surface: &WlSurface,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event {
+            state.rescale(surface, scale.max(1), qh);
+        }
+    }
+}
+
+impl Dispatch<XdgActivationTokenV1, WlSurface> for Mirror {
+    fn event(
+        state: &mut Self,
+        proxy: &XdgActivationTokenV1,
+        event: <XdgActivationTokenV1 as Proxy>::Event,
+        surface: &WlSurface,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let xdg_activation_token_v1::Event::Done { token } = event {
+            if let Some(activation) = state.activation.as_ref() {
+                activation.activate(token, surface);
+            }
+            proxy.destroy();
+        }
+    }
+}
+
+impl Dispatch<XdgActivationV1, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &XdgActivationV1,
+        _event: <XdgActivationV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpFractionalScaleManagerV1, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpFractionalScaleManagerV1,
+        _event: <WpFractionalScaleManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpViewporter, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewporter,
+        _event: <WpViewporter as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpViewport, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewport,
+        _event: <WpViewport as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpTabletManagerV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletManagerV2,
+        _event: <ZwpTabletManagerV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpTabletSeatV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletSeatV2,
+        _event: <ZwpTabletSeatV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(Mirror, ZwpTabletSeatV2, [
+        zwp_tablet_seat_v2::EVT_TABLET_ADDED_OPCODE => (ZwpTabletV2, ()),
+        zwp_tablet_seat_v2::EVT_TOOL_ADDED_OPCODE => (ZwpTabletToolV2, ()),
+        zwp_tablet_seat_v2::EVT_PAD_ADDED_OPCODE => (ZwpTabletPadV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletV2,
+        _event: <ZwpTabletV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+/// A pen on a window is a pointer on the output: the tip the left button, the side
+/// buttons the right and middle ones.
+impl Dispatch<ZwpTabletToolV2, ()> for Mirror {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwpTabletToolV2,
+        event: <ZwpTabletToolV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let button = |button, pressed| InputEvent::PointerButton { button, pressed };
+        match event {
+            zwp_tablet_tool_v2::Event::ProximityIn { surface, .. } => state.pen = Some(surface),
+            zwp_tablet_tool_v2::Event::ProximityOut => state.pen = None,
+            zwp_tablet_tool_v2::Event::Motion { x, y } => {
+                if let Some(surface) = state.pen.as_ref() {
+                    state.forward(surface, (x, y), |x, y| InputEvent::PointerMotion { x, y });
+                }
+            }
+            zwp_tablet_tool_v2::Event::Down { .. } | zwp_tablet_tool_v2::Event::Up => {
+                let pressed = matches!(event, zwp_tablet_tool_v2::Event::Down { .. });
+                if let Some(surface) = state.pen.as_ref() {
+                    state.forward_event(surface, button(BTN_LEFT, pressed));
+                }
+            }
+            zwp_tablet_tool_v2::Event::Button {
+                button: code,
+                state: pressed,
+                ..
+            } => {
+                let code = match code {
+                    BTN_STYLUS => BTN_RIGHT,
+                    BTN_STYLUS2 => BTN_MIDDLE,
+                    other => other,
+                };
+                let pressed = pressed == WEnum::Value(ButtonState::Pressed);
+                if let Some(surface) = state.pen.as_ref() {
+                    state.forward_event(surface, button(code, pressed));
+                }
+            }
+            zwp_tablet_tool_v2::Event::Removed => proxy.destroy(),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletPadV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwpTabletPadV2,
+        event: <ZwpTabletPadV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let zwp_tablet_pad_v2::Event::Removed = event {
+            proxy.destroy();
+        }
+    }
+
+    event_created_child!(Mirror, ZwpTabletPadV2, [
+        zwp_tablet_pad_v2::EVT_GROUP_OPCODE => (ZwpTabletPadGroupV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletPadGroupV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletPadGroupV2,
+        _event: <ZwpTabletPadGroupV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(Mirror, ZwpTabletPadGroupV2, [
+        zwp_tablet_pad_group_v2::EVT_RING_OPCODE => (ZwpTabletPadRingV2, ()),
+        zwp_tablet_pad_group_v2::EVT_STRIP_OPCODE => (ZwpTabletPadStripV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletPadRingV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletPadRingV2,
+        _event: <ZwpTabletPadRingV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpTabletPadStripV2, ()> for Mirror {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwpTabletPadStripV2,
+        _event: <ZwpTabletPadStripV2 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_the_shape_into_the_bounds() {
+        assert_eq!(fit_aspect((1000, 1000), (1920, 1080)), (1000, 562));
+        assert_eq!(fit_aspect((1920, 1080), (1080, 1920)), (607, 1080));
+        assert_eq!(fit_aspect((1920, 1080), (3840, 2160)), (1920, 1080));
+    }
+
+    #[test]
+    fn never_fits_to_nothing() {
+        assert_eq!(fit_aspect((800, 600), (0, 0)), (600, 600));
+        assert_eq!(fit_aspect((1, 1000), (1000, 1)), (1, 1));
+    }
+}
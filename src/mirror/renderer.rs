@@ -0,0 +1,529 @@
+use std::sync::Arc;
+
+use raw_window_handle::{
+    HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle,
+    WaylandDisplayHandle, WaylandWindowHandle,
+};
+use wayland_client::{
+    protocol::{wl_output::Transform, wl_surface::WlSurface},
+    Connection, Proxy,
+};
+
+use super::magnifier::Filter;
+use crate::{
+    backend::region::PixelRect,
+    capture_manager::OwnedFrame,
+    pw_capture::{CursorBitmap, CursorMeta, PipewireFrameFormat},
+    wgpu_import::{FrameTexture, WgpuImporter},
+};
+
+/// Where the cursor rectangle goes in the uniform, after the texture coordinates.
+const CURSOR_OFFSET: u64 = 32;
+/// Where the part of the frame that is shown goes, after the cursor.
+const VIEW_OFFSET: u64 = 48;
+
+/// https://github.com/rust-windowing/raw-window-handle/issues/49
+struct WaylandHandle(RawDisplayHandle, RawWindowHandle);
+
+unsafe impl HasRawDisplayHandle for WaylandHandle {
+    fn raw_display_handle(&self) -> RawDisplayHandle {
+        self.0
+    }
+}
+
+unsafe impl HasRawWindowHandle for WaylandHandle {
+    fn raw_window_handle(&self) -> RawWindowHandle {
+        self.1
+    }
+}
+
+/// Draws captured frames onto a Wayland surface, scaled to fit and turned to the
+/// logical orientation.
+pub struct Renderer {
+    surface: wgpu::Surface,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    config: wgpu::SurfaceConfiguration,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    linear: wgpu::Sampler,
+    nearest: wgpu::Sampler,
+    filter: Filter,
+    uv: wgpu::Buffer,
+    importer: WgpuImporter,
+    /// The frame that is drawn.
+    frame: Option<(FrameTexture, wgpu::BindGroup)>,
+    /// The pointer, drawn over the frame where the stream says it is.
+    cursor: Option<(Arc<CursorBitmap>, wgpu::TextureView)>,
+    /// Bound instead of a cursor while there is none.
+    no_cursor: wgpu::TextureView,
+    transform: Transform,
+}
+
+impl Renderer {
+    /// Render to `surface`, which has to outlive the renderer. `size` is in pixels.
+    pub fn new(
+        connection: &Connection,
+        surface: &WlSurface,
+        size: (u32, u32),
+        immediate_present: bool,
+    ) -> Result<Self, String> {
+        let mut display = WaylandDisplayHandle::empty();
+        display.display = connection.backend().display_ptr() as *mut _;
+        let mut window = WaylandWindowHandle::empty();
+        window.surface = surface.id().as_ptr() as *mut _;
+        let handle = WaylandHandle(
+            RawDisplayHandle::Wayland(display),
+            RawWindowHandle::Wayland(window),
+        );
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let surface = unsafe { instance.create_surface(&handle) }
+            .map_err(|e| format!("wgpu surface: {e}"))?;
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            compatible_surface: Some(&surface),
+            ..Default::default()
+        }))
+        .ok_or("no GPU can draw to the window")?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&Default::default(), None))
+            .map_err(|e| format!("wgpu device: {e}"))?;
+
+        let caps = surface.get_capabilities(&adapter);
+        // the frames are already sRGB encoded, an sRGB surface would encode them again
+        let format = caps
+            .formats
+            .iter()
+            .copied()
+            .find(|f| !f.is_srgb())
+            .or_else(|| caps.formats.first().copied())
+            .ok_or("the window surface has no formats")?;
+        let wanted = if immediate_present {
+            wgpu::PresentMode::Immediate
+        } else {
+            wgpu::PresentMode::Mailbox
+        };
+        let present_mode = if caps.present_modes.contains(&wanted) {
+            wanted
+        } else {
+            wgpu::PresentMode::Fifo
+        };
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width: size.0.max(1),
+            height: size.1.max(1),
+            present_mode,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+        surface.configure(&device, &config);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("mirror"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("mirror frame"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("mirror"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("mirror"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            multiview: None,
+        });
+        let linear = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mirror"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        // still smooth where the window is smaller than the frames
+        let nearest = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mirror nearest"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uv = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("mirror uv"),
+            size: VIEW_OFFSET + 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut renderer = Self {
+            surface,
+            device,
+            queue,
+            config,
+            pipeline,
+            bind_group_layout,
+            linear,
+            nearest,
+            filter: Filter::default(),
+            uv,
+            importer: WgpuImporter::new(),
+            frame: None,
+            cursor: None,
+            no_cursor: cursor_texture(
+                &device,
+                &queue,
+                &CursorBitmap {
+                    width: 1,
+                    height: 1,
+                    pixels: vec![0; 4],
+                },
+            ),
+            transform: Transform::Normal,
+        };
+        renderer.set_transform(Transform::Normal);
+        renderer.set_cursor(None);
+        renderer.set_view([0.0, 0.0, 1.0, 1.0]);
+        Ok(renderer)
+    }
+
+    /// The surface is `size` pixels now.
+    pub fn resize(&mut self, size: (u32, u32)) {
+        let (width, height) = (size.0.max(1), size.1.max(1));
+        if (width, height) == (self.config.width, self.config.height) {
+            return;
+        }
+        self.config.width = width;
+        self.config.height = height;
+        self.surface.configure(&self.device, &self.config);
+    }
+
+    /// Replace the frame that is drawn. `transform` is what still has to be applied to
+    /// it, see [`crate::backend::CaptureBackend::transform`]. With `damage`, what changed
+    /// since the frame drawn, only that is uploaded if the format stayed the same.
+    pub fn upload(
+        &mut self,
+        format: &PipewireFrameFormat,
+        transform: Transform,
+        frame: &OwnedFrame,
+        damage: Option<&[PixelRect]>,
+    ) -> Result<(), String> {
+        if let (Some(damage), Some((texture, _))) = (damage, self.frame.as_ref()) {
+            let drawn = texture.frame_format();
+            let same = (drawn.width, drawn.height, drawn.format, drawn.modifier)
+                == (format.width, format.height, format.format, format.modifier);
+            if same && transform == self.transform {
+                return texture.update(&self.queue, frame, damage);
+            }
+        }
+        let texture = self
+            .importer
+            .import(&self.device, &self.queue, format, frame, None)?;
+        if transform != self.transform {
+            self.set_transform(transform);
+        }
+        let bind_group = self.bind_group(&texture);
+        // the draws of the old one are submitted already
+        if let Some((old, _)) = self.frame.replace((texture, bind_group)) {
+            old.release(&self.queue);
+        }
+        Ok(())
+    }
+
+    /// Draw `cursor` over the frame, for streams that send it as metadata. `None` draws
+    /// none, which leaves whatever cursor the frames have in them.
+    pub fn set_cursor(&mut self, cursor: Option<&CursorMeta>) {
+        let bitmap = cursor.and_then(|c| c.bitmap.as_ref());
+        let unchanged = match (bitmap, &self.cursor) {
+            (Some(new), Some((old, _))) => Arc::ptr_eq(new, old),
+            (None, None) => true,
+            _ => false,
+        };
+        if !unchanged {
+            self.cursor = bitmap.map(|b| (b.clone(), cursor_texture(&self.device, &self.queue, b)));
+            if let Some((texture, _)) = self.frame.take() {
+                let bind_group = self.bind_group(&texture);
+                self.frame = Some((texture, bind_group));
+            }
+        }
+
+        // the bitmap's rectangle in texture coordinates, empty to draw none
+        let rect = match (cursor, bitmap, self.frame.as_ref()) {
+            (Some(cursor), Some(bitmap), Some((texture, _))) => {
+                let (width, height) = texture.extent();
+                let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+                [
+                    (cursor.position.0 - cursor.hotspot.0) as f32 / width,
+                    (cursor.position.1 - cursor.hotspot.1) as f32 / height,
+                    bitmap.width as f32 / width,
+                    bitmap.height as f32 / height,
+                ]
+            }
+            _ => [0.0; 4],
+        };
+        let bytes: Vec<u8> = rect.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        self.queue.write_buffer(&self.uv, CURSOR_OFFSET, &bytes);
+    }
+
+    /// Show only `view` of the frame, x, y, width and height from 0 to 1 in its logical
+    /// orientation, stretched over the window.
+    pub fn set_view(&mut self, view: [f64; 4]) {
+        let bytes: Vec<u8> = view
+            .iter()
+            .flat_map(|f| (*f as f32).to_ne_bytes())
+            .collect();
+        self.queue.write_buffer(&self.uv, VIEW_OFFSET, &bytes);
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        if filter == self.filter {
+            return;
+        }
+        self.filter = filter;
+        if let Some((texture, _)) = self.frame.take() {
+            let bind_group = self.bind_group(&texture);
+            self.frame = Some((texture, bind_group));
+        }
+    }
+
+    /// Draw the last uploaded frame, black until there is one, and present it.
+    pub fn draw(&mut self) -> Result<(), String> {
+        let target = match self.surface.get_current_texture() {
+            Ok(target) => target,
+            // the compositor resized or otherwise changed the surface, once is enough
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                self.surface.configure(&self.device, &self.config);
+                self.surface
+                    .get_current_texture()
+                    .map_err(|e| format!("window surface: {e}"))?
+            }
+            Err(e) => return Err(format!("window surface: {e}")),
+        };
+        let view = target
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("mirror"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            if let Some((texture, bind_group)) = self.frame.as_ref() {
+                let (x, y, width, height) = self.viewport(texture.extent());
+                pass.set_viewport(x, y, width, height, 0.0, 1.0);
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+        }
+        self.queue.submit(Some(encoder.finish()));
+        target.present();
+        Ok(())
+    }
+
+    /// Where the last frame is drawn, centered with its aspect ratio: x, y, width and
+    /// height from 0 to 1 of the surface. All of it until there is a frame.
+    pub fn frame_area(&self) -> [f64; 4] {
+        let Some((texture, _)) = self.frame.as_ref() else {
+            return [0.0, 0.0, 1.0, 1.0];
+        };
+        let (x, y, width, height) = self.viewport(texture.extent());
+        let surface = (
+            self.config.width.max(1) as f64,
+            self.config.height.max(1) as f64,
+        );
+        [
+            x as f64 / surface.0,
+            y as f64 / surface.1,
+            width as f64 / surface.0,
+            height as f64 / surface.1,
+        ]
+    }
+
+    /// The largest rectangle of the surface with the aspect ratio of the frame, centered.
+    fn viewport(&self, size: (u32, u32)) -> (f32, f32, f32, f32) {
+        let (mut width, mut height) = (size.0 as f32, size.1 as f32);
+        if is_rotated(self.transform) {
+            (width, height) = (height, width);
+        }
+        let (surface_width, surface_height) = (self.config.width as f32, self.config.height as f32);
+        let scale = (surface_width / width).min(surface_height / height);
+        let (width, height) = (width * scale, height * scale);
+        (
+            ((surface_width - width) / 2.0).floor(),
+            ((surface_height - height) / 2.0).floor(),
+            width.round().max(1.0),
+            height.round().max(1.0),
+        )
+    }
+
+    fn bind_group(&self, texture: &FrameTexture) -> wgpu::BindGroup {
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("mirror frame"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(texture.view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(match self.filter {
+                        Filter::Linear => &self.linear,
+                        Filter::Nearest => &self.nearest,
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.uv.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(
+                        self.cursor
+                            .as_ref()
+                            .map_or(&self.no_cursor, |(_, view)| view),
+                    ),
+                },
+            ],
+        })
+    }
+
+    fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+        let bytes: Vec<u8> = uv_transform(transform)
+            .iter()
+            .flat_map(|f| f.to_ne_bytes())
+            .collect();
+        self.queue.write_buffer(&self.uv, 0, &bytes);
+    }
+}
+
+/// Like the frames, cursors are sRGB encoded already, so they go into a linear format.
+fn cursor_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bitmap: &CursorBitmap,
+) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width: bitmap.width,
+        height: bitmap.height,
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("mirror cursor"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        &bitmap.pixels,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(bitmap.width * 4),
+            rows_per_image: None,
+        },
+        size,
+    );
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+pub(super) fn is_rotated(transform: Transform) -> bool {
+    matches!(
+        transform,
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+    )
+}
+
+/// Texture coordinates as a function of (u, v, 1) in the viewport, two rows padded to
+/// vec4s. The inverse of what `transform` does, like [`crate::backend::region::PixelRect::untransformed`].
+fn uv_transform(transform: Transform) -> [f32; 8] {
+    let (mut u, v) = match transform {
+        Transform::_90 | Transform::Flipped90 => ([0.0, -1.0, 1.0], [1.0, 0.0, 0.0]),
+        Transform::_180 | Transform::Flipped180 => ([-1.0, 0.0, 1.0], [0.0, -1.0, 1.0]),
+        Transform::_270 | Transform::Flipped270 => ([0.0, 1.0, 0.0], [-1.0, 0.0, 1.0]),
+        _ => ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    };
+    if matches!(
+        transform,
+        Transform::Flipped | Transform::Flipped90 | Transform::Flipped180 | Transform::Flipped270
+    ) {
+        u = [-u[0], -u[1], 1.0 - u[2]];
+    }
+    [u[0], u[1], u[2], 0.0, v[0], v[1], v[2], 0.0]
+}
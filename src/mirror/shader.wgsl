@@ -0,0 +1,50 @@
+// Draws the frame, or the part of it that is magnified, over the whole viewport, turned
+// to the logical orientation, with the cursor over it if the stream sends it on its own.
+
+struct Uv {
+    // texture coordinates as an affine function of the viewport coordinates
+    u: vec4<f32>,
+    v: vec4<f32>,
+    // where the cursor bitmap goes, in texture coordinates: x, y, width, height
+    cursor: vec4<f32>,
+    // the part of the frame shown, in viewport coordinates: x, y, width, height
+    view: vec4<f32>,
+};
+
+@group(0) @binding(0) var frame: texture_2d<f32>;
+@group(0) @binding(1) var frame_sampler: sampler;
+@group(0) @binding(2) var<uniform> uv: Uv;
+@group(0) @binding(3) var cursor: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // one triangle covering the viewport
+    let pos = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(pos * 2.0 - 1.0, 0.0, 1.0);
+    out.coords = vec2<f32>(pos.x, 1.0 - pos.y);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let p = vec3<f32>(uv.view.xy + in.coords * uv.view.zw, 1.0);
+    let coords = vec2<f32>(dot(uv.u.xyz, p), dot(uv.v.xyz, p));
+    // X formats leave the alpha byte undefined
+    let color = textureSample(frame, frame_sampler, coords).rgb;
+
+    // sampled everywhere, as textureSample has to be in uniform control flow
+    let cursor_coords = (coords - uv.cursor.xy) / max(uv.cursor.zw, vec2<f32>(1e-6));
+    let pointer = textureSample(cursor, frame_sampler, cursor_coords);
+    let inside = uv.cursor.z > 0.0
+        && all(cursor_coords >= vec2<f32>(0.0))
+        && all(cursor_coords <= vec2<f32>(1.0));
+    // the bitmap comes premultiplied
+    let over = select(vec4<f32>(0.0), pointer, inside);
+    return vec4<f32>(color * (1.0 - over.a) + over.rgb, 1.0);
+}
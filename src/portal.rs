@@ -0,0 +1,452 @@
+use std::{
+    os::fd::{BorrowedFd, IntoRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::JoinHandle,
+};
+
+use ashpd::{
+    desktop::{
+        remote_desktop::{DeviceType, KeyState, RemoteDesktop},
+        screencast::{CursorMode as PortalCursorMode, PersistMode, Screencast, SourceType},
+        Session,
+    },
+    enumflags2::BitFlags,
+    WindowIdentifier,
+};
+use futures::{
+    channel::oneshot,
+    executor::block_on,
+    future::{self, Either},
+    pin_mut,
+};
+
+use crate::error::LensingError;
+
+/// How the cursor comes with the frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CursorMode {
+    /// Not at all.
+    Hidden,
+    /// Drawn into the frames.
+    #[default]
+    Embedded,
+    /// Left out of the frames and sent along with them, for the consumer to draw.
+    Metadata,
+}
+
+impl std::str::FromStr for CursorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hidden" => Ok(CursorMode::Hidden),
+            "embedded" => Ok(CursorMode::Embedded),
+            "metadata" => Ok(CursorMode::Metadata),
+            other => Err(format!("unknown cursor mode: {other}")),
+        }
+    }
+}
+
+impl std::fmt::Display for CursorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CursorMode::Hidden => "hidden",
+            CursorMode::Embedded => "embedded",
+            CursorMode::Metadata => "metadata",
+        })
+    }
+}
+
+impl CursorMode {
+    fn portal(self) -> PortalCursorMode {
+        match self {
+            CursorMode::Hidden => PortalCursorMode::Hidden,
+            CursorMode::Embedded => PortalCursorMode::Embedded,
+            CursorMode::Metadata => PortalCursorMode::Metadata,
+        }
+    }
+
+    /// This, if the portal offers it, or the closest it does: a cursor in the frames
+    /// rather than none at all.
+    fn supported(self, available: BitFlags<PortalCursorMode>) -> CursorMode {
+        let fallbacks = match self {
+            CursorMode::Hidden => [
+                CursorMode::Hidden,
+                CursorMode::Metadata,
+                CursorMode::Embedded,
+            ],
+            CursorMode::Embedded => [
+                CursorMode::Embedded,
+                CursorMode::Metadata,
+                CursorMode::Hidden,
+            ],
+            CursorMode::Metadata => [
+                CursorMode::Metadata,
+                CursorMode::Embedded,
+                CursorMode::Hidden,
+            ],
+        };
+        fallbacks
+            .into_iter()
+            .find(|mode| available.contains(mode.portal()))
+            // old portals don't tell, and all of them embed it
+            .unwrap_or(CursorMode::Embedded)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PortalStream {
+    pub node_id: u32,
+    pub position: Option<(i32, i32)>,
+    pub size: Option<(i32, i32)>,
+}
+
+/// A screencast session, closed along with its PipeWire remote when dropped.
+pub struct PortalSession {
+    /// The PipeWire remote, for GStreamer's `pipewiresrc fd=`, which makes a copy of
+    /// its own. Streams that take ownership of theirs get a [`PortalSession::pipewire_fd`].
+    pub fd: RawFd,
+    pub streams: Vec<PortalStream>,
+    pub restore_token: Option<String>,
+    /// What the portal could do of the cursor mode asked for. It stays for the
+    /// session, the portal takes no new one once the streams run.
+    pub cursor: CursorMode,
+    /// Set once the portal sends `Closed` for the session.
+    closed: Arc<AtomicBool>,
+    /// Dropped to have the session thread close the session.
+    close: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for PortalSession {
+    fn drop(&mut self) {
+        // asking for another session, e.g. to reconnect, would leave this one open with
+        // its sharing indicator on, and its thread parked
+        self.close.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl PortalSession {
+    /// Whether the portal closed the session, e.g. because the user revoked it or the
+    /// portal was restarted. Its streams are gone then, or about to be.
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// A copy of [`PortalSession::fd`] for a stream to take ownership of.
+    pub fn pipewire_fd(&self) -> Result<RawFd, LensingError> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+            .try_clone_to_owned()
+            .map(IntoRawFd::into_raw_fd)
+            .map_err(|e| LensingError::Stream(format!("duplicating the PipeWire fd: {e}")))
+    }
+
+    /// The stream of the monitor at `position`, in the logical desktop, or the only
+    /// stream if the portal doesn't tell positions.
+    pub fn stream_at(&self, position: (i32, i32)) -> Option<&PortalStream> {
+        self.streams
+            .iter()
+            .find(|s| s.position == Some(position))
+            .or_else(|| match self.streams.as_slice() {
+                [only] if only.position.is_none() => Some(only),
+                _ => None,
+            })
+    }
+}
+
+/// Ask the portal for one or more monitors. Blocks until the user has made a selection.
+pub fn select_monitors(multiple: bool, cursor: CursorMode) -> Result<PortalSession, LensingError> {
+    select_sources(SourceType::Monitor.into(), multiple, None, cursor)
+}
+
+/// Ask the portal for a single monitor, reusing an earlier selection if possible.
+pub fn select_monitor(
+    restore_token: Option<&str>,
+    cursor: CursorMode,
+) -> Result<PortalSession, LensingError> {
+    select_sources(SourceType::Monitor.into(), false, restore_token, cursor)
+}
+
+/// Ask the portal for monitors to find a certain one among: the user may pick several,
+/// so that picking the one asked for isn't the only way through the dialog.
+pub fn select_output(
+    restore_token: Option<&str>,
+    cursor: CursorMode,
+) -> Result<PortalSession, LensingError> {
+    select_sources(SourceType::Monitor.into(), true, restore_token, cursor)
+}
+
+/// Ask the portal for a single window. With a restore token from an earlier session,
+/// the portal may hand back the same application's window without asking again.
+pub fn select_window(
+    restore_token: Option<&str>,
+    cursor: CursorMode,
+) -> Result<PortalSession, LensingError> {
+    select_sources(SourceType::Window.into(), false, restore_token, cursor)
+}
+
+fn select_sources(
+    types: BitFlags<SourceType>,
+    multiple: bool,
+    restore_token: Option<&str>,
+    cursor: CursorMode,
+) -> Result<PortalSession, LensingError> {
+    let (session, mut portal_session) = block_on(async {
+        let proxy = Screencast::new().await?;
+        let session = proxy.create_session().await?;
+
+        let available = proxy.available_cursor_modes().await.unwrap_or_default();
+        let granted = cursor.supported(available);
+        if granted != cursor {
+            println!("The portal has no {cursor} cursor mode, using {granted}");
+        }
+
+        proxy
+            .select_sources(
+                &session,
+                granted.portal(),
+                types,
+                multiple,
+                restore_token,
+                // so that tokens stay valid across runs, see token_store::TokenStore
+                PersistMode::ExplicitlyRevoked,
+            )
+            .await?;
+
+        let response = proxy
+            .start(&session, &WindowIdentifier::default())
+            .await?
+            .response()?;
+
+        let streams = response
+            .streams()
+            .iter()
+            .map(|s| PortalStream {
+                node_id: s.pipe_wire_node_id(),
+                position: s.position(),
+                size: s.size(),
+            })
+            .collect();
+
+        let fd = proxy.open_pipe_wire_remote(&session).await?;
+
+        let portal_session = PortalSession {
+            fd,
+            streams,
+            restore_token: response.restore_token().map(String::from),
+            cursor: granted,
+            closed: Arc::default(),
+            close: None,
+            thread: None,
+        };
+        Ok::<_, ashpd::Error>((session, portal_session))
+    })?;
+
+    // the thread waits as long as the session lives, for the portal closing it or the
+    // PortalSession being dropped, which closes it
+    let closed = portal_session.closed.clone();
+    let (close, close_requested) = oneshot::channel::<()>();
+    let thread = std::thread::Builder::new()
+        .name("portal session".into())
+        .spawn(move || {
+            block_on(async {
+                let closed_by_portal = session.receive_closed();
+                pin_mut!(closed_by_portal);
+                match future::select(closed_by_portal, close_requested).await {
+                    Either::Left((Ok(_), _)) => {
+                        println!("The portal closed the screencast session");
+                        closed.store(true, Ordering::Release);
+                    }
+                    Either::Left((Err(_), _)) => {}
+                    Either::Right(_) => {
+                        let _ = session.close().await;
+                    }
+                }
+            })
+        })
+        .map_err(|e| LensingError::Thread(format!("portal session: {e}")))?;
+    portal_session.close = Some(close);
+    portal_session.thread = Some(thread);
+    Ok(portal_session)
+}
+
+/// Input for a remote desktop session, in the logical coordinates of one of its
+/// streams.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    PointerMotion {
+        x: f64,
+        y: f64,
+    },
+    /// Movement of a locked pointer, relative to wherever it is on the output, e.g.
+    /// for mouse-look in games.
+    PointerMotionRelative {
+        dx: f64,
+        dy: f64,
+    },
+    /// An evdev button code, e.g. `BTN_LEFT`.
+    PointerButton {
+        button: u32,
+        pressed: bool,
+    },
+    /// Smooth scrolling, in logical pixels. `finish` ends a scroll, e.g. a finger
+    /// lifted off the touchpad.
+    PointerAxis {
+        dx: f64,
+        dy: f64,
+        finish: bool,
+    },
+    TouchDown {
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchMotion {
+        slot: u32,
+        x: f64,
+        y: f64,
+    },
+    TouchUp {
+        slot: u32,
+    },
+}
+
+/// A RemoteDesktop session to send input through, with a stream per monitor for the
+/// input's coordinates to be relative to. The streams are never opened, the input
+/// is for monitors captured otherwise, e.g. by a [`crate::mirror::Mirror`].
+pub struct RemoteInput {
+    pub streams: Vec<PortalStream>,
+    /// The portal lets the session drive a touchscreen. Without one, touches have to
+    /// be sent as pointer events.
+    pub touch: bool,
+    sender: mpsc::Sender<(u32, InputEvent)>,
+}
+
+impl RemoteInput {
+    /// The stream of the monitor at `position`, in the logical desktop, or the only
+    /// stream if the portal doesn't tell positions.
+    pub fn stream_at(&self, position: (i32, i32)) -> Option<u32> {
+        self.streams
+            .iter()
+            .find(|s| s.position == Some(position))
+            .or_else(|| match self.streams.as_slice() {
+                [only] if only.position.is_none() => Some(only),
+                _ => None,
+            })
+            .map(|s| s.node_id)
+    }
+
+    /// Queue `event` for the monitor of the stream `node_id`. The portal calls are made
+    /// on a thread of their own, so this never waits for D-Bus.
+    pub fn send(&self, node_id: u32, event: InputEvent) {
+        // the thread only ends with the session, and then there is no one to tell
+        let _ = self.sender.send((node_id, event));
+    }
+}
+
+/// Ask the portal to let this process control the pointer and, if it can, the
+/// touchscreen on one or more monitors. Blocks until the user has agreed.
+pub fn remote_input() -> Result<RemoteInput, LensingError> {
+    let (proxy, session, streams, devices) = block_on(async {
+        let proxy = RemoteDesktop::new().await?;
+        let session = proxy.create_session().await?;
+        let wanted = DeviceType::Pointer | DeviceType::Touchscreen;
+        let available = proxy.available_device_types().await?;
+        proxy.select_devices(&session, available & wanted).await?;
+
+        // remote desktop sessions need streams to place absolute input on
+        let screencast = Screencast::new().await?;
+        screencast
+            .select_sources(
+                &session,
+                PortalCursorMode::Embedded,
+                SourceType::Monitor.into(),
+                true,
+                None,
+                PersistMode::DoNot,
+            )
+            .await?;
+
+        let response = proxy
+            .start(&session, &WindowIdentifier::default())
+            .await?
+            .response()?;
+        let streams: Vec<PortalStream> = response
+            .streams()
+            .unwrap_or_default()
+            .iter()
+            .map(|s| PortalStream {
+                node_id: s.pipe_wire_node_id(),
+                position: s.position(),
+                size: s.size(),
+            })
+            .collect();
+        Ok::<_, ashpd::Error>((proxy, session, streams, response.devices()))
+    })?;
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("remote input".into())
+        .spawn(move || {
+            for (node_id, event) in receiver {
+                if let Err(e) = block_on(forward(&proxy, &session, node_id, event)) {
+                    println!("Could not forward {event:?}: {e}");
+                }
+            }
+            let _ = block_on(session.close());
+        })
+        .map_err(|e| LensingError::Thread(format!("remote input: {e}")))?;
+
+    Ok(RemoteInput {
+        streams,
+        touch: devices.contains(DeviceType::Touchscreen),
+        sender,
+    })
+}
+
+async fn forward(
+    proxy: &RemoteDesktop<'_>,
+    session: &Session<'_>,
+    node_id: u32,
+    event: InputEvent,
+) -> ashpd::Result<()> {
+    match event {
+        InputEvent::PointerMotion { x, y } => {
+            proxy
+                .notify_pointer_motion_absolute(session, node_id, x, y)
+                .await
+        }
+        InputEvent::PointerMotionRelative { dx, dy } => {
+            proxy.notify_pointer_motion(session, dx, dy).await
+        }
+        InputEvent::PointerButton { button, pressed } => {
+            let state = if pressed {
+                KeyState::Pressed
+            } else {
+                KeyState::Released
+            };
+            proxy
+                .notify_pointer_button(session, button as i32, state)
+                .await
+        }
+        InputEvent::PointerAxis { dx, dy, finish } => {
+            proxy.notify_pointer_axis(session, dx, dy, finish).await
+        }
+        InputEvent::TouchDown { slot, x, y } => {
+            proxy.notify_touch_down(session, node_id, slot, x, y).await
+        }
+        InputEvent::TouchMotion { slot, x, y } => {
+            proxy
+                .notify_touch_motion(session, node_id, slot, x, y)
+                .await
+        }
+        InputEvent::TouchUp { slot } => proxy.notify_touch_up(session, slot).await,
+    }
+}
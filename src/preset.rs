@@ -0,0 +1,178 @@
+use std::os::fd::RawFd;
+
+use crate::{
+    affinity::CoreSet,
+    encode::{deadline, stereo::Stereo, Container, EncoderBackend, LosslessCodec, VideoCodec},
+    portal::CursorMode,
+    reconnect::Reconnect,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMode {
+    /// GStreamer defaults.
+    Default,
+    /// Hold at most this many buffers, dropping the oldest.
+    Leaky(u32),
+    /// Buffer several seconds so that nothing is ever dropped.
+    Deep,
+}
+
+/// Knobs shared by the capture, encode and mirror paths.
+#[derive(Debug, Clone, Copy)]
+pub struct Tuning {
+    /// Number of PipeWire buffers to negotiate, `None` leaves it to the producer.
+    pub buffers: Option<u32>,
+    /// Dmabufs with more planes than this are rejected, and the producer is told so.
+    pub max_planes: u32,
+    /// Allow the encoder to use B-frames.
+    pub bframes: bool,
+    pub queue: QueueMode,
+    /// Present mirror frames immediately, even if that tears.
+    pub immediate_present: bool,
+    /// Use a lossless encoder.
+    pub lossless: Option<LosslessCodec>,
+    /// Use an encoder setting that is lossy, but not visibly so.
+    pub visually_lossless: bool,
+    /// Count frames going into the encoder and into the file, and compare at the end.
+    pub verify_frames: bool,
+    /// Drop frames beyond this rate. Frames are never duplicated, so variable rates stay as they are.
+    pub max_fps: Option<u32>,
+    /// Which encoders lossy recordings may use.
+    pub encoder: EncoderBackend,
+    /// Keep dmabuf frames on the GPU all the way into a VA-API encoder.
+    pub hw_encode: bool,
+    pub codec: VideoCodec,
+    /// Target bitrate in kbit/s for lossy encoding, `None` leaves it to the encoder.
+    pub bitrate: Option<u32>,
+    /// Frames from one keyframe to the next in lossy encoding, `None` leaves it to the
+    /// encoder. Short for streams that viewers join, long for files.
+    pub gop: Option<u32>,
+    /// `None` picks the container by file extension.
+    pub container: Option<Container>,
+    /// Pack the frames as stereo video for VR video players.
+    pub stereo: Option<Stereo>,
+    /// How the cursor comes with the frames.
+    pub cursor: CursorMode,
+    /// Skip frames the encoder can't start on before the next one comes in, so that
+    /// overload lowers the frame rate rather than adding latency.
+    pub deadline: bool,
+    /// Cores the threads taking frames from PipeWire run on, `None` leaves it to the
+    /// scheduler.
+    pub capture_cores: Option<CoreSet>,
+    /// Cores the encoding runs on, including the threads encoders start themselves.
+    pub encode_cores: Option<CoreSet>,
+    /// Keep this many seconds of encoded video around before the recording is started,
+    /// and begin the file with them.
+    pub pre_record: Option<u32>,
+    /// MiB of encoded data that may wait for the disk, written behind the pipeline;
+    /// `None` writes from the pipeline with a `filesink`.
+    pub write_buffer: Option<u32>,
+    /// What a capture that failed or lost its portal session does.
+    pub reconnect: Reconnect,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            buffers: None,
+            max_planes: 4,
+            bframes: true,
+            queue: QueueMode::Default,
+            immediate_present: false,
+            lossless: None,
+            visually_lossless: false,
+            verify_frames: false,
+            max_fps: None,
+            encoder: EncoderBackend::Auto,
+            hw_encode: false,
+            codec: VideoCodec::H264,
+            bitrate: None,
+            gop: None,
+            container: None,
+            stereo: None,
+            cursor: CursorMode::Embedded,
+            deadline: false,
+            capture_cores: None,
+            encode_cores: None,
+            pre_record: None,
+            write_buffer: Some(64),
+            reconnect: Reconnect::default(),
+        }
+    }
+}
+
+impl Tuning {
+    /// Aims for under 50 ms from capture to display, e.g. for the VR mirror.
+    pub fn low_latency() -> Self {
+        Self {
+            buffers: Some(2),
+            bframes: false,
+            queue: QueueMode::Leaky(1),
+            immediate_present: true,
+            deadline: true,
+            ..Default::default()
+        }
+    }
+
+    /// Low latency with hardware encoding, capped at `fps`. Frames are passed on as the
+    /// game presents them rather than paced to a fixed rate, so VRR stays smooth.
+    pub fn game(fps: u32) -> Self {
+        Self {
+            buffers: Some(3),
+            bframes: false,
+            queue: QueueMode::Leaky(2),
+            immediate_present: true,
+            max_fps: Some(fps),
+            encoder: EncoderBackend::Nvenc,
+            deadline: true,
+            ..Default::default()
+        }
+    }
+
+    /// Every frame, losslessly, for reference footage of compositor bugs.
+    pub fn archive() -> Self {
+        Self {
+            buffers: Some(8),
+            queue: QueueMode::Deep,
+            lossless: Some(LosslessCodec::Ffv1),
+            verify_frames: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn queue_desc(&self) -> String {
+        match self.queue {
+            QueueMode::Default => "queue".into(),
+            QueueMode::Leaky(n) => format!(
+                "queue max-size-buffers={n} max-size-bytes=0 max-size-time=0 leaky=downstream"
+            ),
+            QueueMode::Deep => {
+                "queue max-size-buffers=0 max-size-bytes=0 max-size-time=5000000000".into()
+            }
+        }
+    }
+
+    /// The queue frames wait in for the encoder, named for [`deadline::Deadlines`] if
+    /// frames may be skipped.
+    pub fn encoder_queue_desc(&self) -> String {
+        if self.deadline {
+            format!("{} name={}", self.queue_desc(), deadline::QUEUE_NAME)
+        } else {
+            self.queue_desc()
+        }
+    }
+
+    /// The capture source, rate limited if `max_fps` is set.
+    pub fn pipewiresrc_desc(&self, fd: RawFd, node_id: u32) -> String {
+        let mut desc = match self.buffers {
+            Some(n) => {
+                format!("pipewiresrc fd={fd} path={node_id} min-buffers={n} max-buffers={n}")
+            }
+            None => format!("pipewiresrc fd={fd} path={node_id}"),
+        };
+        if let Some(fps) = self.max_fps {
+            desc.push_str(&format!(" ! videorate drop-only=true max-rate={fps}"));
+        }
+        desc
+    }
+}
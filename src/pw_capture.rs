@@ -1,22 +1,34 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io::Cursor;
 use std::mem::MaybeUninit;
+use std::os::fd::RawFd;
+use std::ptr::NonNull;
 use std::rc::Rc;
+use std::sync::Arc;
 
-use libspa_sys::{spa_pod, spa_video_info_raw};
+use libspa_sys::{
+    spa_buffer, spa_meta, spa_meta_bitmap, spa_meta_cursor, spa_meta_header, spa_meta_region,
+    spa_pod, spa_region, spa_video_info_raw,
+};
 use pipewire::prelude::*;
 use pipewire::properties;
+use pipewire::spa::data::{Data, DataType};
 use pipewire::spa::pod::serialize::PodSerializer;
 use pipewire::spa::pod::{ChoiceValue, Object, Property, PropertyFlags, Value};
 use pipewire::spa::utils::{Choice, ChoiceFlags, Fraction, Rectangle};
 use pipewire::spa::utils::{ChoiceEnum, Id};
-use pipewire::stream::{Stream, StreamFlags};
-use pipewire::{Context, Error, MainLoop};
+use pipewire::stream::{Stream, StreamFlags, StreamState};
+use pipewire::{Context, MainLoop};
 
-#[derive(Debug, Clone, Copy)]
+use crate::{
+    backend::region::PixelRect, error::LensingError, preset::Tuning, stats::StreamTracker,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PipewireFrameFormat {
     pub width: u32,
     pub height: u32,
+    /// DRM fourcc, like [`DrmFormat::code`].
     pub format: u32,
     pub modifier: u64,
 }
@@ -37,6 +49,93 @@ pub struct PipewireDmabufPlane {
     pub stride: i32,
 }
 
+/// The pixels of a [`PipewireFrame`].
+#[derive(Debug)]
+pub enum PipewireFrameData {
+    Dmabuf {
+        planes: Vec<PipewireDmabufPlane>,
+    },
+    /// The producer could not share dmabufs, the pixels are mapped into our memory.
+    Shm {
+        ptr: *const u8,
+        size: usize,
+        stride: i32,
+    },
+    /// Only the metadata changed, e.g. the cursor moved; the pixels are those of the
+    /// frame before.
+    Unchanged,
+}
+
+/// A captured frame. Only valid for the duration of the `on_frame` call.
+#[derive(Debug)]
+pub struct PipewireFrame {
+    pub data: PipewireFrameData,
+    /// Where the pointer is, if the stream was started with
+    /// [`crate::portal::CursorMode::Metadata`] and the producer sends it.
+    pub cursor: Option<CursorMeta>,
+    /// The parts of the frame that changed since the frame before, within the frame.
+    /// `None` if the producer doesn't say, and all of it may have; empty for
+    /// [`PipewireFrameData::Unchanged`].
+    pub damage: Option<Vec<PixelRect>>,
+    /// When the producer made the frame and which one it is, if it says.
+    pub header: Option<FrameHeader>,
+}
+
+/// What the producer says about a frame besides its pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Presentation time in nanoseconds, on the producer's clock. Compositors use
+    /// `CLOCK_MONOTONIC`, the clock GStreamer and PipeWire run on too.
+    pub pts: i64,
+    /// Counts the buffers of the stream. A gap to the frame before is frames that were
+    /// dropped, by the producer or because we were behind.
+    pub seq: u64,
+    /// `SPA_META_HEADER_FLAG_*`, see [`FrameHeader::is_discont`] and
+    /// [`FrameHeader::is_corrupted`].
+    pub flags: u32,
+}
+
+impl FrameHeader {
+    /// The stream starts over here, e.g. after the producer paused it.
+    pub fn is_discont(&self) -> bool {
+        self.flags & libspa_sys::SPA_META_HEADER_FLAG_DISCONT != 0
+    }
+
+    /// The producer knows the pixels are broken.
+    pub fn is_corrupted(&self) -> bool {
+        self.flags & libspa_sys::SPA_META_HEADER_FLAG_CORRUPTED != 0
+    }
+
+    /// How many frames were left out between `before` and this one.
+    pub fn dropped_since(&self, before: &FrameHeader) -> u64 {
+        self.seq.wrapping_sub(before.seq).saturating_sub(1)
+    }
+}
+
+/// The pointer as the producer describes it, for consumers that draw it themselves.
+#[derive(Debug, Clone)]
+pub struct CursorMeta {
+    /// Of the hotspot, in frame pixels. May be outside of the frame.
+    pub position: (i32, i32),
+    /// The pixel of the bitmap that is at `position`.
+    pub hotspot: (i32, i32),
+    /// `None` while the pointer is invisible. Producers only send the bitmap when it
+    /// changes, this is the last one they sent.
+    pub bitmap: Option<Arc<CursorBitmap>>,
+}
+
+/// The image of the pointer.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CursorBitmap {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA with premultiplied alpha, as producers draw it, and rows without padding.
+    pub pixels: Vec<u8>,
+}
+
+/// Every format we negotiate has 32 bit pixels.
+const BYTES_PER_PIXEL: u64 = 4;
+
 #[derive(Debug, Clone, Copy)]
 pub struct DrmFormat {
     pub code: u32,
@@ -57,101 +156,480 @@ fn fourcc_to_spa_video_format(fourcc: u32) -> Option<u32> {
     }
 }
 
-fn format_dmabuf_params() -> Vec<u8> {
+fn spa_video_format_to_fourcc(format: u32) -> Option<u32> {
+    match format {
+        libspa_sys::SPA_VIDEO_FORMAT_BGRA => Some(0x34325241),
+        libspa_sys::SPA_VIDEO_FORMAT_RGBA => Some(0x34324241),
+        libspa_sys::SPA_VIDEO_FORMAT_BGRx => Some(0x34325258),
+        libspa_sys::SPA_VIDEO_FORMAT_RGBx => Some(0x34324258),
+        _ => None,
+    }
+}
+
+/// Why the planes of a buffer can't hold a frame of `format`, if they can't.
+fn validate_buffer(
+    format: &PipewireFrameFormat,
+    datas: &[Data],
+    max_planes: u32,
+) -> Result<(), String> {
+    if datas.is_empty() || datas.len() > max_planes as usize {
+        return Err(format!(
+            "{} planes, expected 1 to {max_planes}",
+            datas.len()
+        ));
+    }
+    let row = format.width as u64 * BYTES_PER_PIXEL;
+    let first = &datas[0];
+    let stride = first.chunk().stride();
+    // for tiled modifiers the stride is a row of tiles, which isn't any shorter
+    if stride <= 0 || (stride as u64) < row {
+        return Err(format!("stride {stride} for {} pixel rows", format.width));
+    }
+    let needed = stride as u64 * format.height.saturating_sub(1) as u64 + row;
+
+    match first.type_() {
+        DataType::DmaBuf => {
+            for (i, plane) in datas.iter().enumerate() {
+                if plane.type_() != DataType::DmaBuf || plane.as_raw().fd < 0 {
+                    return Err(format!("plane {i} is not a dmabuf"));
+                }
+            }
+            // producers that don't say how big the buffer is leave it at 0
+            let size = first.as_raw().maxsize as u64;
+            let offset = first.chunk().offset() as u64;
+            if size != 0 && offset + needed > size {
+                return Err(format!(
+                    "{size} byte dmabuf, {} needed for {}x{} at stride {stride}",
+                    offset + needed,
+                    format.width,
+                    format.height
+                ));
+            }
+        }
+        DataType::MemFd | DataType::MemPtr => {
+            let size = first.chunk().size() as u64;
+            if size < needed {
+                return Err(format!(
+                    "{size} bytes, {needed} needed for {}x{} at stride {stride}",
+                    format.width, format.height
+                ));
+            }
+        }
+        _ => return Err("unexpected data type".into()),
+    }
+    Ok(())
+}
+
+/// `dmabuf` picks the data types to accept: dmabufs if a modifier was negotiated,
+/// otherwise memory we can map.
+fn format_buffer_params(dmabuf: bool, buffers: Option<u32>, max_planes: u32) -> Vec<u8> {
+    let data_types = if dmabuf {
+        1 << libspa_sys::SPA_DATA_DmaBuf
+    } else {
+        (1 << libspa_sys::SPA_DATA_MemFd) | (1 << libspa_sys::SPA_DATA_MemPtr)
+    };
+    let mut properties = vec![Property {
+        key: libspa_sys::SPA_PARAM_BUFFERS_dataType,
+        flags: PropertyFlags::empty(),
+        value: Value::Choice(ChoiceValue::Int(Choice(
+            ChoiceFlags::from_bits_truncate(0),
+            ChoiceEnum::Flags {
+                default: data_types as i32,
+                flags: vec![],
+            },
+        ))),
+    }];
+    // a modifier may need planes of its own, e.g. for compression metadata
+    properties.push(Property {
+        key: libspa_sys::SPA_PARAM_BUFFERS_blocks,
+        flags: PropertyFlags::empty(),
+        value: Value::Choice(ChoiceValue::Int(Choice(
+            ChoiceFlags::from_bits_truncate(0),
+            ChoiceEnum::Range {
+                default: 1,
+                min: 1,
+                max: max_planes.max(1) as i32,
+            },
+        ))),
+    });
+    if let Some(buffers) = buffers {
+        properties.push(Property {
+            key: libspa_sys::SPA_PARAM_BUFFERS_buffers,
+            flags: PropertyFlags::empty(),
+            value: Value::Int(buffers as _),
+        });
+    }
+
     let pod = Value::Object(Object {
         type_: libspa_sys::SPA_TYPE_OBJECT_ParamBuffers,
         id: libspa_sys::SPA_PARAM_Buffers,
-        properties: vec![Property {
-            key: libspa_sys::SPA_PARAM_BUFFERS_dataType,
-            flags: PropertyFlags::empty(),
-            value: Value::Id(Id(libspa_sys::SPA_DATA_DmaBuf)),
-        }],
+        properties,
     });
     let (c, _) = PodSerializer::serialize(Cursor::new(Vec::new()), &pod).unwrap();
     c.into_inner()
 }
 
-fn format_get_params(format: u32, modifier: u64, fps: u32) -> Vec<u8> {
+/// The largest cursor we make room for, in pixels to a side.
+const MAX_CURSOR_SIZE: usize = 256;
+
+/// The bytes of a cursor meta with a `size` by `size` bitmap.
+fn cursor_meta_size(size: usize) -> i32 {
+    (std::mem::size_of::<spa_meta_cursor>()
+        + std::mem::size_of::<spa_meta_bitmap>()
+        + size * size * BYTES_PER_PIXEL as usize) as i32
+}
+
+/// The most damaged rectangles we make room for. Producers with more send fewer,
+/// bigger ones.
+const MAX_DAMAGE_RECTS: usize = 16;
+
+/// Asks for room for a meta of `type_` in the buffers, `sizes` bytes of it: the default,
+/// the least and the most. Producers only fill the cursor in if the stream was started
+/// with [`crate::portal::CursorMode::Metadata`].
+fn format_meta_params(type_: u32, sizes: (i32, i32, i32)) -> Vec<u8> {
     let pod = Value::Object(Object {
-        type_: libspa_sys::SPA_TYPE_OBJECT_Format,
-        id: libspa_sys::SPA_PARAM_EnumFormat,
+        type_: libspa_sys::SPA_TYPE_OBJECT_ParamMeta,
+        id: libspa_sys::SPA_PARAM_Meta,
         properties: vec![
             Property {
-                key: libspa_sys::SPA_FORMAT_mediaType,
+                key: libspa_sys::SPA_PARAM_META_type,
                 flags: PropertyFlags::empty(),
-                value: Value::Id(Id(libspa_sys::SPA_MEDIA_TYPE_video)),
+                value: Value::Id(Id(type_)),
             },
             Property {
-                key: libspa_sys::SPA_FORMAT_mediaSubtype,
+                key: libspa_sys::SPA_PARAM_META_size,
                 flags: PropertyFlags::empty(),
-                value: Value::Id(Id(libspa_sys::SPA_MEDIA_SUBTYPE_raw)),
-            },
-            Property {
-                key: libspa_sys::SPA_FORMAT_VIDEO_format,
-                flags: PropertyFlags::empty(),
-                value: Value::Id(Id(format)),
-            },
-            Property {
-                key: libspa_sys::SPA_FORMAT_VIDEO_modifier,
-                flags: PropertyFlags::MANDATORY | PropertyFlags::DONT_FIXATE,
-                value: Value::Id(Id(modifier as _)),
-            },
-            Property {
-                key: libspa_sys::SPA_FORMAT_VIDEO_size,
-                flags: PropertyFlags::empty(),
-                value: Value::Choice(ChoiceValue::Rectangle(Choice(
+                value: Value::Choice(ChoiceValue::Int(Choice(
                     ChoiceFlags::from_bits_truncate(0),
                     ChoiceEnum::Range {
-                        default: Rectangle {
-                            width: 256,
-                            height: 256,
-                        },
-                        min: Rectangle {
-                            width: 1,
-                            height: 1,
-                        },
-                        max: Rectangle {
-                            width: 8192,
-                            height: 8192,
-                        },
-                    },
-                ))),
-            },
-            Property {
-                key: libspa_sys::SPA_FORMAT_VIDEO_framerate,
-                flags: PropertyFlags::empty(),
-                value: Value::Choice(ChoiceValue::Fraction(Choice(
-                    ChoiceFlags::from_bits_truncate(0),
-                    ChoiceEnum::Range {
-                        default: Fraction { num: fps, denom: 1 },
-                        min: Fraction { num: 0, denom: 1 },
-                        max: Fraction {
-                            num: 1000,
-                            denom: 1,
-                        },
+                        default: sizes.0,
+                        min: sizes.1,
+                        max: sizes.2,
                     },
                 ))),
             },
         ],
     });
+    let (c, _) = PodSerializer::serialize(Cursor::new(Vec::new()), &pod).unwrap();
+    c.into_inner()
+}
+
+/// The EnumFormat params for `dmabuf` formats and modifiers, then the `shm` formats
+/// as a fallback, all at `fps`.
+fn enum_format_pods(dmabuf: &[(u32, u64)], shm: &[u32], fps: u32) -> Vec<Vec<u8>> {
+    dmabuf
+        .iter()
+        .map(|(format, modifier)| format_get_params(*format, Some(*modifier), fps))
+        .chain(
+            shm.iter()
+                .map(|format| format_get_params(*format, None, fps)),
+        )
+        .collect()
+}
+
+/// Without a modifier, this offers the format in shared memory.
+fn format_get_params(format: u32, modifier: Option<u64>, fps: u32) -> Vec<u8> {
+    let mut properties = vec![
+        Property {
+            key: libspa_sys::SPA_FORMAT_mediaType,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(libspa_sys::SPA_MEDIA_TYPE_video)),
+        },
+        Property {
+            key: libspa_sys::SPA_FORMAT_mediaSubtype,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(libspa_sys::SPA_MEDIA_SUBTYPE_raw)),
+        },
+        Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_format,
+            flags: PropertyFlags::empty(),
+            value: Value::Id(Id(format)),
+        },
+        Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_size,
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Rectangle(Choice(
+                ChoiceFlags::from_bits_truncate(0),
+                ChoiceEnum::Range {
+                    default: Rectangle {
+                        width: 256,
+                        height: 256,
+                    },
+                    min: Rectangle {
+                        width: 1,
+                        height: 1,
+                    },
+                    max: Rectangle {
+                        width: 8192,
+                        height: 8192,
+                    },
+                },
+            ))),
+        },
+        Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_framerate,
+            flags: PropertyFlags::empty(),
+            value: Value::Choice(ChoiceValue::Fraction(Choice(
+                ChoiceFlags::from_bits_truncate(0),
+                ChoiceEnum::Range {
+                    default: Fraction { num: fps, denom: 1 },
+                    min: Fraction { num: 0, denom: 1 },
+                    max: Fraction {
+                        num: 1000,
+                        denom: 1,
+                    },
+                },
+            ))),
+        },
+    ];
+    if let Some(modifier) = modifier {
+        properties.push(Property {
+            key: libspa_sys::SPA_FORMAT_VIDEO_modifier,
+            flags: PropertyFlags::MANDATORY | PropertyFlags::DONT_FIXATE,
+            value: Value::Id(Id(modifier as _)),
+        });
+    }
+
+    let pod = Value::Object(Object {
+        type_: libspa_sys::SPA_TYPE_OBJECT_Format,
+        id: libspa_sys::SPA_PARAM_EnumFormat,
+        properties,
+    });
 
     let (c, _) = PodSerializer::serialize(Cursor::new(Vec::new()), &pod).unwrap();
     c.into_inner()
 }
 
+/// A buffer taken from a stream, and queued back when dropped. Unlike
+/// `pipewire::buffer::Buffer`, it lets us at the metadata.
+struct DequeuedBuffer<'s> {
+    stream: &'s Stream<i32>,
+    buffer: NonNull<pipewire::sys::pw_buffer>,
+}
+
+impl<'s> DequeuedBuffer<'s> {
+    fn dequeue(stream: &'s Stream<i32>) -> Option<Self> {
+        let buffer = NonNull::new(unsafe { stream.dequeue_raw_buffer() })?;
+        Some(Self { stream, buffer })
+    }
+
+    fn spa_buffer(&self) -> Option<&spa_buffer> {
+        unsafe { self.buffer.as_ref().buffer.as_ref() }
+    }
+
+    fn datas_mut(&mut self) -> &mut [Data] {
+        let Some(buffer) = self.spa_buffer() else {
+            return &mut [];
+        };
+        if buffer.n_datas == 0 || buffer.datas.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(buffer.datas as *mut Data, buffer.n_datas as _) }
+    }
+
+    /// The meta of `type_`, if the producer attached one of at least `min_size` bytes.
+    fn meta(&self, type_: u32, min_size: usize) -> Option<&spa_meta> {
+        let buffer = self.spa_buffer()?;
+        if buffer.metas.is_null() {
+            return None;
+        }
+        unsafe { std::slice::from_raw_parts(buffer.metas, buffer.n_metas as _) }
+            .iter()
+            .find(|m| m.type_ == type_ && m.size as usize >= min_size && !m.data.is_null())
+    }
+
+    /// Whether the buffer holds a frame, and not just new metadata.
+    fn has_pixels(&mut self) -> bool {
+        self.datas_mut()
+            .first()
+            .is_some_and(|data| data.chunk().size() != 0)
+    }
+}
+
+impl Drop for DequeuedBuffer<'_> {
+    fn drop(&mut self) {
+        unsafe { self.stream.queue_raw_buffer(self.buffer.as_ptr()) };
+    }
+}
+
+/// Carry the cursor of `buffer` over into `cursor`. Producers only fill the meta in when
+/// the pointer changed, and the bitmap only when its image did. Whether there was a
+/// cursor meta at all.
+fn update_cursor(buffer: &DequeuedBuffer, cursor: &mut Option<CursorMeta>) -> bool {
+    let header = std::mem::size_of::<spa_meta_cursor>();
+    let Some(meta) = buffer.meta(libspa_sys::SPA_META_Cursor, header) else {
+        return false;
+    };
+    let bytes = unsafe { std::slice::from_raw_parts(meta.data as *const u8, meta.size as _) };
+    let spa_cursor = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const spa_meta_cursor) };
+    // an id of 0 says nothing changed
+    if spa_cursor.id == 0 {
+        return true;
+    }
+
+    let mut bitmap = cursor.take().and_then(|c| c.bitmap);
+    let offset = spa_cursor.bitmap_offset as usize;
+    if offset >= header && offset + std::mem::size_of::<spa_meta_bitmap>() <= bytes.len() {
+        let bytes = &bytes[offset..];
+        let spa_bitmap =
+            unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const spa_meta_bitmap) };
+        if spa_bitmap.size.width == 0 || spa_bitmap.size.height == 0 {
+            bitmap = None;
+        } else if let Some(new) = read_bitmap(&spa_bitmap, bytes) {
+            bitmap = Some(Arc::new(new));
+        }
+    }
+    *cursor = Some(CursorMeta {
+        position: (spa_cursor.position.x, spa_cursor.position.y),
+        hotspot: (spa_cursor.hotspot.x, spa_cursor.hotspot.y),
+        bitmap,
+    });
+    true
+}
+
+/// The pixels of a cursor bitmap as RGBA. `bytes` start with the bitmap meta; `None` if
+/// the pixels don't fit into them, or are in a format cursors aren't drawn in.
+fn read_bitmap(bitmap: &spa_meta_bitmap, bytes: &[u8]) -> Option<CursorBitmap> {
+    let (swap, opaque) = match bitmap.format {
+        libspa_sys::SPA_VIDEO_FORMAT_RGBA => (false, false),
+        libspa_sys::SPA_VIDEO_FORMAT_BGRA => (true, false),
+        libspa_sys::SPA_VIDEO_FORMAT_RGBx => (false, true),
+        libspa_sys::SPA_VIDEO_FORMAT_BGRx => (true, true),
+        _ => return None,
+    };
+    let (width, height) = (bitmap.size.width as usize, bitmap.size.height as usize);
+    let row = width * BYTES_PER_PIXEL as usize;
+    if bitmap.stride < 0 || (bitmap.stride as usize) < row {
+        return None;
+    }
+
+    let mut pixels = Vec::with_capacity(row * height);
+    for y in 0..height {
+        let start = bitmap.offset as usize + y * bitmap.stride as usize;
+        for p in bytes.get(start..start + row)?.chunks_exact(4) {
+            let (r, b) = if swap { (p[2], p[0]) } else { (p[0], p[2]) };
+            pixels.extend_from_slice(&[r, p[1], b, if opaque { 255 } else { p[3] }]);
+        }
+    }
+    Some(CursorBitmap {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+fn read_header(buffer: &DequeuedBuffer) -> Option<FrameHeader> {
+    let meta = buffer.meta(
+        libspa_sys::SPA_META_Header,
+        std::mem::size_of::<spa_meta_header>(),
+    )?;
+    let header = unsafe { std::ptr::read_unaligned(meta.data as *const spa_meta_header) };
+    Some(FrameHeader {
+        pts: header.pts,
+        seq: header.seq,
+        flags: header.flags,
+    })
+}
+
+/// The damaged regions of `buffer`, `None` if it has no damage meta. The regions end
+/// with the first empty one. Producers that attach the meta without filling it in
+/// leave it empty, which says nothing, so that counts as no meta either.
+fn read_damage(buffer: &DequeuedBuffer) -> Option<Vec<spa_region>> {
+    let region = std::mem::size_of::<spa_meta_region>();
+    let meta = buffer.meta(libspa_sys::SPA_META_VideoDamage, region)?;
+    let bytes = unsafe { std::slice::from_raw_parts(meta.data as *const u8, meta.size as _) };
+    let regions: Vec<spa_region> = bytes
+        .chunks_exact(region)
+        .map(|r| unsafe { std::ptr::read_unaligned(r.as_ptr() as *const spa_meta_region) }.region)
+        .take_while(|r| r.size.width != 0 && r.size.height != 0)
+        .collect();
+    (!regions.is_empty()).then_some(regions)
+}
+
+/// `regions` cut down to the frame, leaving out those outside of it.
+fn clip_damage(regions: &[spa_region], format: &PipewireFrameFormat) -> Vec<PixelRect> {
+    let (width, height) = (format.width as i64, format.height as i64);
+    regions
+        .iter()
+        .filter_map(|r| {
+            let (x0, y0) = (r.position.x as i64, r.position.y as i64);
+            let (x1, y1) = (x0 + r.size.width as i64, y0 + r.size.height as i64);
+            let (x0, y0) = (x0.clamp(0, width), y0.clamp(0, height));
+            let (x1, y1) = (x1.clamp(0, width), y1.clamp(0, height));
+            (x1 > x0 && y1 > y0).then(|| PixelRect {
+                x: x0 as u32,
+                y: y0 as u32,
+                width: (x1 - x0) as u32,
+                height: (y1 - y0) as u32,
+            })
+        })
+        .collect()
+}
+
+/// Connect to `node_id` and call `on_frame` for every frame until the stream ends.
+/// `remote_fd` is the PipeWire fd handed out by the portal; without it, the default
+/// PipeWire daemon is used.
+///
+/// This blocks; [`crate::CaptureThread`] runs the stream on a thread of its own.
+/// PipeWire stays initialized afterwards: it can only be deinitialized once per
+/// process, after every PipeWire thread is gone, which a library can't know.
 pub fn pipewire_init_stream<F>(
     name: &str,
+    remote_fd: Option<RawFd>,
+    node_id: u32,
+    fps: u32,
+    tuning: &Tuning,
+    formats: Vec<DrmFormat>,
+    on_frame: F,
+) -> Result<(), LensingError>
+where
+    F: Fn(&PipewireFrameFormat, &PipewireFrame) + 'static,
+{
+    pipewire_run_stream(
+        name, remote_fd, node_id, fps, tuning, formats, None, on_frame,
+    )
+}
+
+/// What a running [`pipewire_run_stream`] can be asked to do.
+#[derive(Debug, Clone, Copy)]
+pub enum StreamRequest {
+    /// End the stream.
+    Stop,
+    /// Offer the formats again at this framerate, so the producer renegotiates, e.g. to
+    /// drop to a few frames per second while nothing happens on screen.
+    Framerate(u32),
+}
+
+/// Like [`pipewire_init_stream`], but `requests` end the stream early or change its
+/// framerate.
+///
+/// The stream takes ownership of `remote_fd`.
+#[allow(clippy::too_many_arguments)]
+pub fn pipewire_run_stream<F>(
+    name: &str,
+    remote_fd: Option<RawFd>,
     node_id: u32,
     fps: u32,
+    tuning: &Tuning,
     formats: Vec<DrmFormat>,
+    requests: Option<pipewire::channel::Receiver<StreamRequest>>,
     on_frame: F,
-) -> Result<(), Error>
+) -> Result<(), LensingError>
 where
-    F: Fn(&PipewireFrameFormat, &Vec<PipewireDmabufPlane>) + 'static,
+    F: Fn(&PipewireFrameFormat, &PipewireFrame) + 'static,
 {
+    let (buffers, max_planes) = (tuning.buffers, tuning.max_planes);
+    if let Some(cores) = tuning.capture_cores {
+        if let Err(e) = cores.pin_current_thread() {
+            println!("Could not pin the capture thread: {e}");
+        }
+    }
     let main_loop = MainLoop::new()?;
     let context = Context::new(&main_loop)?;
-    let _core = context.connect(None)?;
+    let _core = match remote_fd {
+        Some(fd) => context.connect_fd(fd, None)?,
+        None => context.connect(None)?,
+    };
 
     let stream: Rc<RefCell<Option<Stream<i32>>>> = Rc::new(RefCell::new(None));
     let stream_clone = stream.clone();
@@ -159,6 +637,38 @@ where
     let format: Rc<RefCell<Option<PipewireFrameFormat>>> = Rc::new(RefCell::new(None));
     let format_clone = format.clone();
 
+    // dmabufs first, then the same formats again in shared memory as a fallback
+    let spa_formats: Vec<(u32, u64)> = formats
+        .iter()
+        .filter_map(|f| Some((fourcc_to_spa_video_format(f.code)?, f.modifier)))
+        .collect();
+    let mut shm_formats: Vec<u32> = vec![];
+    for (format, _) in spa_formats.iter() {
+        if !shm_formats.contains(format) {
+            shm_formats.push(*format);
+        }
+    }
+    if spa_formats.is_empty() {
+        return Err(LensingError::Negotiation(
+            "none of the formats asked for can be captured".into(),
+        ));
+    }
+    let (resize_formats, resize_shm_formats) = (spa_formats.clone(), shm_formats.clone());
+    // the framerate asked for last, for formats offered again
+    let fps = Rc::new(Cell::new(fps));
+    let resize_fps = fps.clone();
+
+    let last_rejection: RefCell<Option<String>> = RefCell::new(None);
+    let cursor: RefCell<Option<CursorMeta>> = RefCell::new(None);
+    let stats = StreamTracker::register(node_id);
+    let stats_clone = stats.clone();
+
+    // why the stream ended, if it failed
+    let failure: Rc<RefCell<Option<LensingError>>> = Rc::new(RefCell::new(None));
+    let failure_clone = failure.clone();
+    let negotiated = format.clone();
+
+    let weak_loop = main_loop.downgrade();
     let stream_inner = Stream::<i32>::with_user_data(
         &main_loop,
         name,
@@ -169,70 +679,211 @@ where
         },
         0,
     )
-    .param_changed(move |_, id, param| {
-        if param.is_null() || *id != libspa_sys::SPA_PARAM_Format as _ {
+    .param_changed(move |id, _, param| {
+        if param.is_null() || id != libspa_sys::SPA_PARAM_Format {
             return;
         }
         let mut maybe_info = MaybeUninit::<spa_video_info_raw>::zeroed();
 
-        unsafe {
-            libspa_sys::spa_format_video_raw_parse(param, maybe_info.as_mut_ptr());
+        let res = unsafe { libspa_sys::spa_format_video_raw_parse(param, maybe_info.as_mut_ptr()) };
+        if res < 0 {
+            println!("Could not parse the stream format: {res}");
+            return;
         }
 
         let info = unsafe { maybe_info.assume_init() };
+        let Some(fourcc) = spa_video_format_to_fourcc(info.format) else {
+            println!("Stream negotiated an unexpected format: {}", info.format);
+            return;
+        };
 
         let format = PipewireFrameFormat {
             width: info.size.width,
             height: info.size.height,
-            format: info.format,
+            format: fourcc,
             modifier: info.modifier,
         };
-        format_clone.replace(Some(format));
+        println!("Stream format: {format:?}");
+        crate::crash_report::note("stream format", format!("{format:?}"));
+        let resized = format_clone
+            .replace(Some(format))
+            .filter(|previous| (previous.width, previous.height) != (format.width, format.height));
+        stats_clone.format(format);
+        if let Some(previous) = resized {
+            println!(
+                "Stream resized from {}x{} to {}x{}",
+                previous.width, previous.height, format.width, format.height
+            );
+        }
 
-        let params = format_dmabuf_params();
+        let dmabuf = info.flags & libspa_sys::SPA_VIDEO_FLAG_MODIFIER != 0;
+        if !dmabuf {
+            println!("No dmabuf modifier negotiated, falling back to shared memory");
+        }
+        let params = format_buffer_params(dmabuf, buffers, max_planes);
+        let cursor_params = format_meta_params(
+            libspa_sys::SPA_META_Cursor,
+            (
+                cursor_meta_size(64),
+                cursor_meta_size(1),
+                cursor_meta_size(MAX_CURSOR_SIZE),
+            ),
+        );
+        let region = std::mem::size_of::<spa_meta_region>() as i32;
+        let damage_size = region * MAX_DAMAGE_RECTS as i32;
+        let damage_params = format_meta_params(
+            libspa_sys::SPA_META_VideoDamage,
+            (damage_size, region, damage_size),
+        );
+        let header_size = std::mem::size_of::<spa_meta_header>() as i32;
+        let header_params = format_meta_params(
+            libspa_sys::SPA_META_Header,
+            (header_size, header_size, header_size),
+        );
 
+        // a modifier the old size was fixated with may not do for the new one, so a
+        // resize offers all the formats again
+        let format_pods = match resized {
+            Some(_) => enum_format_pods(&resize_formats, &resize_shm_formats, resize_fps.get()),
+            None => vec![],
+        };
+        let mut all_params: Vec<*const spa_pod> =
+            [&params, &cursor_params, &damage_params, &header_params]
+                .into_iter()
+                .chain(format_pods.iter())
+                .map(|p| p.as_ptr() as _)
+                .collect();
         if let Some(ref stream) = *stream_clone.borrow() {
-            let _ = stream.update_params(&mut [params.as_ptr() as _]);
+            let _ = stream.update_params(&mut all_params);
         }
     })
-    .state_changed(|old, new| {
+    .state_changed(move |old, new| {
         println!("Stream state changed: {:?} -> {:?}", old, new);
+        if let StreamState::Error(ref message) = new {
+            // failing before a format was agreed on is failing to agree on one
+            let error = match *negotiated.borrow() {
+                Some(_) => LensingError::Stream(message.clone()),
+                None => LensingError::Negotiation(message.clone()),
+            };
+            failure_clone.replace(Some(error));
+        }
+        // the producer went away, or the stream can't continue
+        if matches!(new, StreamState::Error(_) | StreamState::Unconnected) {
+            if let Some(main_loop) = weak_loop.upgrade() {
+                main_loop.quit();
+            }
+        }
     })
     .process(move |stream, _| {
-        let mut maybe_buffer = None;
-        // discard all but the freshest ingredients
-        while let Some(buffer) = stream.dequeue_buffer() {
-            maybe_buffer = Some(buffer);
+        let mut cursor = cursor.borrow_mut();
+        let mut maybe_buffer: Option<DequeuedBuffer> = None;
+        let mut unchanged = false;
+        let mut damage = Some(vec![]);
+        let mut frames = 0;
+        // discard all but the freshest ingredients, though not for a newer cursor; the
+        // cursor of every buffer counts, its bitmap may only be in one of them
+        while let Some(mut buffer) = DequeuedBuffer::dequeue(stream) {
+            let has_cursor = update_cursor(&buffer, &mut cursor);
+            let has_pixels = buffer.has_pixels();
+            if has_pixels {
+                frames += 1;
+                stats.received(read_header(&buffer));
+                // what changed in the frames we skip is still changed in the next one
+                damage = match (damage, read_damage(&buffer)) {
+                    (Some(mut all), Some(regions)) => {
+                        all.extend(regions);
+                        Some(all)
+                    }
+                    _ => None,
+                };
+            }
+            // a producer that sends the cursor on its own sends it in empty buffers
+            let cursor_only = has_cursor && !has_pixels;
+            if !cursor_only || maybe_buffer.is_none() {
+                maybe_buffer = Some(buffer);
+                unchanged = cursor_only;
+            }
+        }
+        if frames > 1 {
+            stats.skipped(frames - 1);
         }
 
-        if let Some(mut buffer) = maybe_buffer {
-            let datas = buffer.datas_mut();
-            if datas.len() < 1 {
-                return;
+        let Some(mut buffer) = maybe_buffer else {
+            return;
+        };
+        let Some(format) = *format.borrow() else {
+            return;
+        };
+        let damage = damage.map(|regions| clip_damage(&regions, &format));
+        let header = read_header(&buffer);
+        if unchanged {
+            let frame = PipewireFrame {
+                data: PipewireFrameData::Unchanged,
+                cursor: cursor.clone(),
+                damage: Some(vec![]),
+                header,
+            };
+            on_frame(&format, &frame);
+            return;
+        }
+        let datas = buffer.datas_mut();
+        if let Err(e) = validate_buffer(&format, datas, max_planes) {
+            // a broken producer tends to send the same broken buffer over and over
+            if last_rejection.borrow().as_ref() != Some(&e) {
+                println!("Dropping buffers that don't hold a frame: {e}");
+                last_rejection.replace(Some(e));
             }
-            let planes: Vec<PipewireDmabufPlane> = datas
-                .iter()
-                .map(|p| PipewireDmabufPlane {
-                    fd: p.as_raw().fd as _,
-                    offset: p.chunk().offset(),
-                    stride: p.chunk().stride(),
-                })
-                .collect();
+            return;
+        }
+        last_rejection.replace(None);
 
-            if let Some(ref format) = *format.borrow() {
-                on_frame(format, &planes);
+        let data = match datas[0].type_() {
+            DataType::DmaBuf => PipewireFrameData::Dmabuf {
+                planes: datas
+                    .iter()
+                    .map(|p| PipewireDmabufPlane {
+                        fd: p.as_raw().fd as _,
+                        offset: p.chunk().offset(),
+                        stride: p.chunk().stride(),
+                    })
+                    .collect(),
+            },
+            DataType::MemFd | DataType::MemPtr => {
+                let data = &mut datas[0];
+                let offset = data.chunk().offset() as usize;
+                let size = data.chunk().size() as usize;
+                let stride = data.chunk().stride();
+                // MemFd is mapped for us, see StreamFlags::MAP_BUFFERS
+                let Some(mem) = data.data() else {
+                    return;
+                };
+                let Some(pixels) = mem.get(offset..offset + size) else {
+                    return;
+                };
+                PipewireFrameData::Shm {
+                    ptr: pixels.as_ptr(),
+                    size,
+                    stride,
+                }
             }
-        }
+            _ => return,
+        };
+
+        let frame = PipewireFrame {
+            data,
+            cursor: cursor.clone(),
+            damage,
+            header,
+        };
+        stats.delivered(header);
+        on_frame(&format, &frame);
     })
     .create()?;
 
-    let mut format_params: Vec<*const spa_pod> = formats
-        .iter()
-        .filter_map(|f| {
-            let spa_video_format = fourcc_to_spa_video_format(f.code)?;
-            Some(format_get_params(spa_video_format, f.modifier, fps).as_ptr() as _)
-        })
-        .collect();
+    // the pods have to outlive the pointers handed to connect()
+    let format_pods = enum_format_pods(&spa_formats, &shm_formats, fps.get());
+    let mut format_params: Vec<*const spa_pod> =
+        format_pods.iter().map(|p| p.as_ptr() as _).collect();
 
     stream.replace(Some(stream_inner));
 
@@ -245,8 +896,35 @@ where
         )?;
     }
 
+    let weak_loop = main_loop.downgrade();
+    let stream_requests = stream.clone();
+    let _requests = requests.map(|requests| {
+        requests.attach(&main_loop, move |request| match request {
+            StreamRequest::Stop => {
+                if let Some(main_loop) = weak_loop.upgrade() {
+                    main_loop.quit();
+                }
+            }
+            StreamRequest::Framerate(new_fps) => {
+                println!("Asking the stream for {new_fps} fps");
+                fps.set(new_fps);
+                // only the EnumFormat params are replaced, the buffer and meta params stay
+                let pods = enum_format_pods(&spa_formats, &shm_formats, new_fps);
+                let mut params: Vec<*const spa_pod> =
+                    pods.iter().map(|p| p.as_ptr() as _).collect();
+                if let Some(ref stream) = *stream_requests.borrow() {
+                    if let Err(e) = stream.update_params(&mut params) {
+                        println!("Could not change the framerate: {e}");
+                    }
+                }
+            }
+        })
+    });
+
     main_loop.run();
-    unsafe { pipewire::deinit() };
 
-    Ok(())
+    match failure.take() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
 }
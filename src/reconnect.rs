@@ -0,0 +1,118 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// A capture that ran this long before it failed counts as recovered, and the next
+/// failure starts over with the first delay.
+const STABLE: Duration = Duration::from_secs(30);
+
+/// How often and how soon a capture that failed, or whose portal session was closed,
+/// is started again, asking the portal with the restore token of the session before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconnect {
+    /// Tries in a row before giving up; 0 never reconnects.
+    pub attempts: u32,
+    /// The wait before the first try, doubled for every one after.
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Reconnect {
+    pub const OFF: Self = Self {
+        attempts: 0,
+        delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+}
+
+/// `off`, or `ATTEMPTS[:DELAY_MS[:MAX_DELAY_MS]]`, e.g. `10:500:60000`.
+impl FromStr for Reconnect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "off" {
+            return Ok(Self::OFF);
+        }
+        let mut parts = s.split(':');
+        let mut next = |what: &str| {
+            parts
+                .next()
+                .map(|part| {
+                    part.trim()
+                        .parse::<u32>()
+                        .map_err(|_| format!("not a number of {what}: {part}"))
+                })
+                .transpose()
+        };
+        let defaults = Self::default();
+        let attempts = next("attempts")?.unwrap_or(defaults.attempts);
+        let delay =
+            next("milliseconds")?.map_or(defaults.delay, |ms| Duration::from_millis(ms.into()));
+        let max_delay = next("milliseconds")?.map_or(defaults.max_delay.max(delay), |ms| {
+            Duration::from_millis(ms.into())
+        });
+        if max_delay < delay {
+            return Err(format!("the longest delay is shorter than the first: {s}"));
+        }
+        Ok(Self {
+            attempts,
+            delay,
+            max_delay,
+        })
+    }
+}
+
+/// Counts the tries of a [`Reconnect`] policy.
+pub struct Backoff {
+    policy: Reconnect,
+    failures: u32,
+    started: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: Reconnect) -> Self {
+        Self {
+            policy,
+            failures: 0,
+            started: None,
+        }
+    }
+
+    /// Call when the capture is running again.
+    pub fn started(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    /// How long to wait before the next try, `None` once they are used up.
+    pub fn failed(&mut self) -> Option<Duration> {
+        if self.started.take().is_some_and(|t| t.elapsed() >= STABLE) {
+            self.failures = 0;
+        }
+        if self.failures >= self.policy.attempts {
+            return None;
+        }
+        let delay = self
+            .policy
+            .delay
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.policy.max_delay);
+        self.failures += 1;
+        Some(delay)
+    }
+
+    /// The try [`Backoff::failed`] last allowed, counting from 1.
+    pub fn attempt(&self) -> u32 {
+        self.failures
+    }
+}
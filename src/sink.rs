@@ -0,0 +1,301 @@
+use crate::preset::Tuning;
+
+/// Per-sink processing of the shared capture.
+#[derive(Debug, Clone, Default)]
+pub struct SinkConfig {
+    /// Rate of this sink. Frames over it are dropped in its branch, not at the capture.
+    pub max_fps: Option<u32>,
+    /// x, y, width, height in source pixels.
+    pub crop: Option<(u32, u32, u32, u32)>,
+    /// Output size in pixels.
+    pub scale: Option<(u32, u32)>,
+    /// Extra GStreamer elements in gst-launch syntax, applied after crop/scale.
+    pub filters: Vec<String>,
+    /// Start previews with the stats HUD shown.
+    pub hud: bool,
+    /// Keyframe interval of file sinks, instead of the one of the recording.
+    pub gop: Option<u32>,
+    /// B-frames in file sinks or not, instead of what the recording does.
+    pub bframes: Option<bool>,
+}
+
+impl SinkConfig {
+    /// crop, scale, rate limit and filters as gst-launch links on raw video, each
+    /// starting with ` ! `, so the result can be appended to the branch's queue.
+    /// The crop is a videocrop named `crop` with only its left and top edges set;
+    /// the right and bottom ones depend on the size of the frames, see [`SinkConfig::crop_edges`].
+    pub fn branch_desc(&self) -> String {
+        let mut desc = String::new();
+
+        if let Some(fps) = self.max_fps {
+            desc.push_str(&format!(" ! videorate drop-only=true max-rate={fps}"));
+        }
+
+        if let Some((x, y, ..)) = self.crop {
+            desc.push_str(&format!(
+                " ! videoconvert ! videocrop name=crop left={x} top={y}"
+            ));
+        }
+
+        if let Some((w, h)) = self.scale {
+            desc.push_str(&format!(
+                " ! videoconvert ! videoscale ! video/x-raw,width={w},height={h}"
+            ));
+        }
+
+        for filter in self.filters.iter() {
+            desc.push_str(&format!(" ! {filter}"));
+        }
+
+        desc
+    }
+
+    /// Right and bottom edges of the videocrop for frames of `size`, or `None` without
+    /// a crop or if it doesn't fit the frames.
+    pub fn crop_edges(&self, size: (u32, u32)) -> Option<(u32, u32)> {
+        let (x, y, w, h) = self.crop?;
+        let right = size.0.checked_sub(x + w)?;
+        let bottom = size.1.checked_sub(y + h)?;
+        Some((right, bottom))
+    }
+
+    /// `tuning` with the encoder settings of this sink, e.g. a short GOP without
+    /// B-frames for a file that is streamed while it is written.
+    pub fn encoding(&self, tuning: &Tuning) -> Tuning {
+        Tuning {
+            gop: self.gop.or(tuning.gop),
+            bframes: self.bframes.unwrap_or(tuning.bframes),
+            ..*tuning
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkKind {
+    /// Record to a file.
+    File(String),
+    /// Show a preview window.
+    Mirror,
+    /// Feed a v4l2loopback device, for apps that only take webcams. `None` finds one.
+    Webcam {
+        device: Option<String>,
+        format: WebcamFormat,
+    },
+    /// Publish a PipeWire video source with this description, which OBS and other apps
+    /// list next to cameras.
+    Node(String),
+}
+
+/// Description of node sinks without a name.
+pub const DEFAULT_NODE_NAME: &str = "lensing output";
+
+/// What webcam sinks convert frames to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebcamFormat {
+    /// What every app takes.
+    #[default]
+    Yuy2,
+    /// Smaller, where apps take it.
+    Nv12,
+}
+
+impl WebcamFormat {
+    /// The format in GStreamer caps.
+    pub fn caps_name(self) -> &'static str {
+        match self {
+            WebcamFormat::Yuy2 => "YUY2",
+            WebcamFormat::Nv12 => "NV12",
+        }
+    }
+}
+
+impl std::fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SinkKind::File(location) => write!(f, "file={location}"),
+            SinkKind::Mirror => write!(f, "mirror"),
+            SinkKind::Webcam { device, format } => {
+                write!(f, "v4l2")?;
+                if let Some(device) = device {
+                    write!(f, "={device}")?;
+                }
+                if *format != WebcamFormat::default() {
+                    write!(f, ",format={}", format.caps_name().to_lowercase())?;
+                }
+                Ok(())
+            }
+            SinkKind::Node(name) => write!(f, "pipewire={name}"),
+        }
+    }
+}
+
+/// A sink as given on the command line, e.g.
+/// `file=out.mkv,fps=60,gop=60,bframes=off`, `mirror,fps=30,crop=0:0:1920:1080,scale=960x540` or
+/// `v4l2=/dev/video4,scale=1280x720,format=nv12` or `pipewire=Slides,crop=0:0:1280:720`.
+/// A value with commas, like a `filter=` with caps, goes in double quotes:
+/// `mirror,filter="videoscale ! video/x-raw,width=640"`.
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    pub kind: SinkKind,
+    pub config: SinkConfig,
+}
+
+fn parse_numbers<const N: usize>(value: &str, sep: char) -> Option<[u32; N]> {
+    let mut out = [0; N];
+    let mut parts = value.split(sep);
+    for slot in out.iter_mut() {
+        *slot = parts.next()?.trim().parse().ok()?;
+    }
+    parts.next().is_none().then_some(out)
+}
+
+/// Split `s` at the commas outside double quotes, or `None` if a quote isn't closed.
+fn split_options(s: &str) -> Option<Vec<&str>> {
+    let mut items = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&s[start..]);
+    (!quoted).then_some(items)
+}
+
+impl std::str::FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kind = None;
+        let mut config = SinkConfig::default();
+        let mut webcam_format = None;
+
+        let items = split_options(s).ok_or_else(|| format!("unclosed quote in sink: {s}"))?;
+        for item in items {
+            let (key, value) = match item.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => (item.trim(), ""),
+            };
+            let invalid = || format!("invalid sink option: {item}");
+
+            match key {
+                "file" if !value.is_empty() => kind = Some(SinkKind::File(value.to_string())),
+                "mirror" => kind = Some(SinkKind::Mirror),
+                "v4l2" => {
+                    kind = Some(SinkKind::Webcam {
+                        device: (!value.is_empty()).then(|| value.to_string()),
+                        format: WebcamFormat::default(),
+                    })
+                }
+                "pipewire" => {
+                    let name = if value.is_empty() {
+                        DEFAULT_NODE_NAME
+                    } else {
+                        value
+                    };
+                    kind = Some(SinkKind::Node(name.to_string()))
+                }
+                "format" => {
+                    webcam_format = Some(match value {
+                        "yuy2" | "YUY2" => WebcamFormat::Yuy2,
+                        "nv12" | "NV12" => WebcamFormat::Nv12,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "fps" => config.max_fps = Some(value.parse().map_err(|_| invalid())?),
+                "crop" => {
+                    let [x, y, w, h] = parse_numbers(value, ':').ok_or_else(invalid)?;
+                    config.crop = Some((x, y, w, h));
+                }
+                "scale" => {
+                    let [w, h] = parse_numbers(value, 'x').ok_or_else(invalid)?;
+                    config.scale = Some((w, h));
+                }
+                "filter" if !value.is_empty() => {
+                    let filter = value
+                        .strip_prefix('"')
+                        .and_then(|v| v.strip_suffix('"'))
+                        .unwrap_or(value);
+                    config.filters.push(filter.to_string())
+                }
+                "hud" => config.hud = true,
+                "gop" => config.gop = Some(value.parse().map_err(|_| invalid())?),
+                "bframes" => {
+                    config.bframes = Some(match value {
+                        "on" | "" => true,
+                        "off" => false,
+                        _ => return Err(invalid()),
+                    })
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let mut kind =
+            kind.ok_or_else(|| format!("sink needs file=PATH, mirror, v4l2 or pipewire: {s}"))?;
+        if config.hud && kind != SinkKind::Mirror {
+            return Err(format!("only mirror sinks have a HUD: {s}"));
+        }
+        let encoded = config.gop.is_some() || config.bframes.is_some();
+        if encoded && !matches!(kind, SinkKind::File(_)) {
+            return Err(format!("only file sinks take gop and bframes: {s}"));
+        }
+        if let Some(webcam_format) = webcam_format {
+            let SinkKind::Webcam { ref mut format, .. } = kind else {
+                return Err(format!("only v4l2 sinks take a format: {s}"));
+            };
+            *format = webcam_format;
+        }
+        Ok(SinkSpec { kind, config })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options_after_the_kind() {
+        let spec: SinkSpec = "mirror,fps=30,crop=0:0:1920:1080,scale=960x540"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.kind, SinkKind::Mirror);
+        assert_eq!(spec.config.max_fps, Some(30));
+        assert_eq!(spec.config.crop, Some((0, 0, 1920, 1080)));
+        assert_eq!(spec.config.scale, Some((960, 540)));
+    }
+
+    #[test]
+    fn keeps_commas_in_quoted_filters() {
+        let spec: SinkSpec = r#"file=out.mkv,filter="videoscale ! video/x-raw,width=640",fps=60"#
+            .parse()
+            .unwrap();
+        assert_eq!(spec.kind, SinkKind::File("out.mkv".into()));
+        assert_eq!(spec.config.filters, ["videoscale ! video/x-raw,width=640"]);
+        assert_eq!(spec.config.max_fps, Some(60));
+    }
+
+    #[test]
+    fn rejects_unclosed_quotes() {
+        let err = r#"mirror,filter="videoscale ! video/x-raw,width=640"#
+            .parse::<SinkSpec>()
+            .unwrap_err();
+        assert!(err.starts_with("unclosed quote"), "{err}");
+    }
+
+    #[test]
+    fn splits_only_outside_quotes() {
+        assert_eq!(
+            split_options(r#"a,b="c,d",e"#),
+            Some(vec!["a", r#"b="c,d""#, "e"])
+        );
+        assert_eq!(split_options(""), Some(vec![""]));
+        assert_eq!(split_options(r#"a,"b"#), None);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::pw_capture::{FrameHeader, PipewireFrameFormat};
+
+/// What the rates are taken over.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// The streams that are running.
+static STREAMS: Mutex<Vec<Arc<Mutex<Tracker>>>> = Mutex::new(vec![]);
+
+/// How a PipeWire stream we read frames from is doing.
+#[derive(Debug, Clone, Default)]
+pub struct StreamStats {
+    pub node_id: u32,
+    /// Frames handed on, not counting buffers with only a new cursor.
+    pub frames: u64,
+    /// Frames left out because a newer one was waiting already.
+    pub skipped: u64,
+    /// Frames the producer numbered but we never got, e.g. because it was behind.
+    pub lost: u64,
+    pub format: Option<PipewireFrameFormat>,
+    /// Frames handed on per second, lately.
+    pub fps: f64,
+    /// From the producer's timestamp to handing the frame on, lately. `None` if the
+    /// producer doesn't timestamp its frames.
+    pub latency: Option<Duration>,
+}
+
+impl std::fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "node {}: {:.1} fps, {} frames, {} skipped, {} lost",
+            self.node_id, self.fps, self.frames, self.skipped, self.lost
+        )?;
+        if let Some(format) = self.format {
+            write!(
+                f,
+                ", {}x{} {:#x}:{:#x}",
+                format.width, format.height, format.format, format.modifier
+            )?;
+        }
+        if let Some(latency) = self.latency {
+            write!(f, ", {:.1} ms latency", latency.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+struct Tracker {
+    stats: StreamStats,
+    window_start: Instant,
+    window_frames: u64,
+    window_latency: Duration,
+    window_timestamped: u32,
+    last_seq: Option<u64>,
+}
+
+impl Tracker {
+    /// The rates of the window so far once it is long enough, so they drop when frames
+    /// stop coming.
+    fn roll(&mut self, force: bool) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < WINDOW && !force {
+            return;
+        }
+        self.stats.fps = self.window_frames as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        if self.window_timestamped > 0 {
+            self.stats.latency = Some(self.window_latency / self.window_timestamped);
+        } else if self.window_frames > 0 {
+            self.stats.latency = None;
+        }
+        self.window_start = Instant::now();
+        self.window_frames = 0;
+        self.window_latency = Duration::ZERO;
+        self.window_timestamped = 0;
+    }
+}
+
+/// In [`STREAMS`] until dropped.
+struct Registered {
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        STREAMS
+            .lock()
+            .unwrap()
+            .retain(|tracker| !Arc::ptr_eq(tracker, &self.tracker));
+    }
+}
+
+/// Counts the frames of one stream, while any clone of it lives. See [`snapshot`].
+#[derive(Clone)]
+pub(crate) struct StreamTracker(Arc<Registered>);
+
+impl StreamTracker {
+    pub(crate) fn register(node_id: u32) -> Self {
+        let tracker = Arc::new(Mutex::new(Tracker {
+            stats: StreamStats {
+                node_id,
+                ..Default::default()
+            },
+            window_start: Instant::now(),
+            window_frames: 0,
+            window_latency: Duration::ZERO,
+            window_timestamped: 0,
+            last_seq: None,
+        }));
+        STREAMS.lock().unwrap().push(tracker.clone());
+        Self(Arc::new(Registered { tracker }))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracker> {
+        self.0.tracker.lock().unwrap()
+    }
+
+    pub(crate) fn format(&self, format: PipewireFrameFormat) {
+        self.lock().stats.format = Some(format);
+    }
+
+    /// A buffer with pixels arrived, whether it is handed on or not.
+    pub(crate) fn received(&self, header: Option<FrameHeader>) {
+        let Some(header) = header else {
+            return;
+        };
+        let mut tracker = self.lock();
+        if let Some(last) = tracker.last_seq {
+            // a discontinuity starts the numbers over
+            if !header.is_discont() && header.seq > last {
+                tracker.stats.lost += header.seq - last - 1;
+            }
+        }
+        tracker.last_seq = Some(header.seq);
+    }
+
+    /// `count` frames were left out for a newer one.
+    pub(crate) fn skipped(&self, count: u64) {
+        self.lock().stats.skipped += count;
+    }
+
+    /// A frame with pixels was handed on.
+    pub(crate) fn delivered(&self, header: Option<FrameHeader>) {
+        let mut tracker = self.lock();
+        tracker.stats.frames += 1;
+        tracker.window_frames += 1;
+        let now = monotonic_ns();
+        // producers that don't timestamp leave it at 0
+        if let Some(header) = header.filter(|h| h.pts > 0 && h.pts <= now) {
+            tracker.window_latency += Duration::from_nanos((now - header.pts) as u64);
+            tracker.window_timestamped += 1;
+        }
+        tracker.roll(false);
+    }
+}
+
+/// `CLOCK_MONOTONIC`, which producers timestamp frames on.
+fn monotonic_ns() -> i64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec * 1_000_000_000 + now.tv_nsec
+}
+
+/// How the PipeWire streams of this process are doing, in the order they started.
+pub fn snapshot() -> Vec<StreamStats> {
+    STREAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|tracker| {
+            let mut tracker = tracker.lock().unwrap();
+            if tracker.window_start.elapsed() >= WINDOW * 2 {
+                tracker.roll(true);
+            }
+            tracker.stats.clone()
+        })
+        .collect()
+}
+
+/// Print a [`snapshot`] every `interval`, for `--stats`.
+pub fn print_every(interval: Duration) {
+    std::thread::Builder::new()
+        .name("stats".into())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            for stats in snapshot() {
+                println!("Stats {stats}");
+            }
+        })
+        .expect("stats thread");
+}
@@ -0,0 +1,794 @@
+use std::{cmp::Reverse, os::fd::RawFd, path::PathBuf, process::Command, time::Duration};
+
+use gstreamer::{
+    glib, prelude::*, EventView, Pad, PadProbeData, PadProbeReturn, PadProbeType, Pipeline,
+};
+
+use crate::{
+    audio::AudioConfig, encode, portal::PortalStream, preset::Tuning,
+    wl_client_desktop::WlClientDesktopState,
+};
+
+/// Where a single captured output lands on the stitched canvas.
+#[derive(Debug, Clone, Copy)]
+pub struct CanvasTile {
+    pub node_id: u32,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl CanvasTile {
+    fn contains(&self, other: &CanvasTile) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+}
+
+/// A still image drawn over the captured outputs, like a logo or a "be right back" screen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSource {
+    pub path: String,
+    pub x: i32,
+    pub y: i32,
+    pub scale: f64,
+    pub opacity: f64,
+    /// Scenes the image shows in; an image without scenes shows in all of them.
+    pub scenes: Vec<String>,
+}
+
+impl ImageSource {
+    fn shows_in(&self, scene: Option<&str>) -> bool {
+        self.scenes.is_empty() || scene.is_some_and(|s| self.scenes.iter().any(|i| i == s))
+    }
+}
+
+/// `PATH,x=X,y=Y,scale=S,opacity=O,scene=NAME`, everything but the path is optional and
+/// `scene` can be given more than once.
+impl std::str::FromStr for ImageSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut items = s.split(',');
+        let path = items.next().unwrap_or_default().trim();
+        if path.is_empty() {
+            return Err(format!("image needs a path: {s}"));
+        }
+        let mut image = ImageSource {
+            path: path.to_string(),
+            x: 0,
+            y: 0,
+            scale: 1.0,
+            opacity: 1.0,
+            scenes: vec![],
+        };
+
+        for item in items {
+            let invalid = || format!("invalid image option: {item}");
+            let (key, value) = item.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match key.trim() {
+                "x" => image.x = value.parse().map_err(|_| invalid())?,
+                "y" => image.y = value.parse().map_err(|_| invalid())?,
+                "scale" => {
+                    image.scale = value
+                        .parse()
+                        .ok()
+                        .filter(|&s: &f64| s > 0.0)
+                        .ok_or_else(invalid)?
+                }
+                "opacity" => {
+                    image.opacity = value
+                        .parse()
+                        .ok()
+                        .filter(|o| (0.0..=1.0).contains(o))
+                        .ok_or_else(invalid)?
+                }
+                "scene" if !value.is_empty() => image.scenes.push(value.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    fn glsl(&self) -> String {
+        let c = |v: u8| v as f32 / 255.0;
+        format!(
+            "vec4({:.4}, {:.4}, {:.4}, 1.0)",
+            c(self.r),
+            c(self.g),
+            c(self.b)
+        )
+    }
+}
+
+/// `#RRGGBB`, the `#` is optional.
+impl std::str::FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim().trim_start_matches('#');
+        let invalid = || format!("invalid color: {s}");
+        if hex.len() != 6 {
+            return Err(invalid());
+        }
+        let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        Ok(Color {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+        })
+    }
+}
+
+/// What shows where no output or image covers the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    Solid(Color),
+    /// Top to bottom, or left to right if `horizontal`.
+    Gradient {
+        from: Color,
+        to: Color,
+        horizontal: bool,
+    },
+}
+
+impl Background {
+    fn fragment_shader(&self) -> String {
+        let color = match self {
+            Background::Solid(color) => color.glsl(),
+            Background::Gradient {
+                from,
+                to,
+                horizontal,
+            } => format!(
+                "mix({}, {}, v_texcoord.{})",
+                from.glsl(),
+                to.glsl(),
+                if *horizontal { 'x' } else { 'y' }
+            ),
+        };
+        format!(
+            "#ifdef GL_ES\n\
+             precision mediump float;\n\
+             #endif\n\
+             varying vec2 v_texcoord;\n\
+             void main() {{ gl_FragColor = {color}; }}\n"
+        )
+    }
+
+    /// A source for a single frame of this background at the given size. The frame is
+    /// rendered on the GPU if GStreamer has its GL elements, which only matters for
+    /// gradients; solid colors fall back to `videotestsrc`.
+    fn source_desc(&self, width: i32, height: i32) -> String {
+        let gl = ["gltestsrc", "glshader", "gldownload"]
+            .iter()
+            .all(|e| encode::has_element(e));
+        if gl {
+            // see set_background_shader
+            return format!(
+                "gltestsrc num-buffers=1 ! video/x-raw(memory:GLMemory),format=RGBA,width={width},height={height} ! glshader name=background ! gldownload"
+            );
+        }
+
+        let color = match self {
+            Background::Solid(color) => *color,
+            Background::Gradient { from, .. } => {
+                println!("GStreamer GL elements are missing, using a solid background");
+                *from
+            }
+        };
+        format!(
+            "videotestsrc num-buffers=1 pattern=solid-color foreground-color=0xff{:02x}{:02x}{:02x} ! video/x-raw,width={width},height={height}",
+            color.r, color.g, color.b
+        )
+    }
+}
+
+/// `#RRGGBB` for a solid color; `#RRGGBB:#RRGGBB` for a vertical gradient, with a
+/// trailing `:horizontal` for a horizontal one.
+impl std::str::FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            [color] => Ok(Background::Solid(color.parse()?)),
+            [from, to] => Ok(Background::Gradient {
+                from: from.parse()?,
+                to: to.parse()?,
+                horizontal: false,
+            }),
+            [from, to, "horizontal"] => Ok(Background::Gradient {
+                from: from.parse()?,
+                to: to.parse()?,
+                horizontal: true,
+            }),
+            _ => Err(format!("invalid background: {s}")),
+        }
+    }
+}
+
+/// Where the text of a [`TextSource`] comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextContent {
+    Static(String),
+    /// The last line of the file, e.g. one a music player writes the current song to.
+    File(PathBuf),
+    /// What the command prints, run with `sh -c`.
+    Command(String),
+}
+
+impl TextContent {
+    fn read(&self) -> Option<String> {
+        match self {
+            TextContent::Static(text) => Some(text.clone()),
+            TextContent::File(path) => {
+                let contents = std::fs::read_to_string(path).ok()?;
+                let line = contents.lines().rev().find(|l| !l.trim().is_empty());
+                Some(line.unwrap_or_default().to_string())
+            }
+            TextContent::Command(command) => {
+                let output = Command::new("sh").arg("-c").arg(command).output().ok()?;
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                Some(stdout.trim_end().to_string())
+            }
+        }
+    }
+}
+
+/// Text drawn over the canvas, refreshed every `interval` unless it is static.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextSource {
+    pub content: TextContent,
+    pub x: i32,
+    pub y: i32,
+    /// A Pango font description, like `Sans Bold 24`.
+    pub font: Option<String>,
+    pub interval: Duration,
+}
+
+/// `x=X,y=Y,font=FONT,interval=SECS,` followed by one of `text=TEXT`, `file=PATH` or
+/// `command=CMD`. The content comes last and takes the rest of the spec, commas included.
+impl std::str::FromStr for TextSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut text = TextSource {
+            content: TextContent::Static(String::new()),
+            x: 0,
+            y: 0,
+            font: None,
+            interval: Duration::from_secs(1),
+        };
+
+        let mut rest = s;
+        loop {
+            let (item, tail) = rest.split_once(',').unwrap_or((rest, ""));
+            let invalid = || format!("invalid text option: {item}");
+            let (key, value) = item.split_once('=').ok_or_else(invalid)?;
+            let content = || rest.split_once('=').map_or("", |(_, v)| v).to_string();
+            match key.trim() {
+                "text" => {
+                    text.content = TextContent::Static(content());
+                    return Ok(text);
+                }
+                "file" => {
+                    text.content = TextContent::File(content().into());
+                    return Ok(text);
+                }
+                "command" => {
+                    text.content = TextContent::Command(content());
+                    return Ok(text);
+                }
+                "x" => text.x = value.trim().parse().map_err(|_| invalid())?,
+                "y" => text.y = value.trim().parse().map_err(|_| invalid())?,
+                "font" if !value.trim().is_empty() => text.font = Some(value.trim().to_string()),
+                "interval" => {
+                    let secs: f64 = value.trim().parse().map_err(|_| invalid())?;
+                    text.interval = Duration::try_from_secs_f64(secs)
+                        .ok()
+                        .filter(|d| !d.is_zero())
+                        .ok_or_else(invalid)?;
+                }
+                _ => return Err(invalid()),
+            }
+            if tail.is_empty() {
+                return Err(format!("text needs text=, file= or command=: {s}"));
+            }
+            rest = tail;
+        }
+    }
+}
+
+/// A web page drawn over the canvas with a transparent background, for alerts, chat and
+/// the like. Rendered by WPE WebKit.
+#[cfg(feature = "html")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlSource {
+    pub url: String,
+    pub x: i32,
+    pub y: i32,
+    /// The size of the page's viewport; the whole canvas if not set.
+    pub size: Option<(u32, u32)>,
+}
+
+/// `URL,x=X,y=Y,size=WxH`, everything but the URL is optional.
+#[cfg(feature = "html")]
+impl std::str::FromStr for HtmlSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut items = s.split(',');
+        let url = items.next().unwrap_or_default().trim();
+        if url.is_empty() {
+            return Err(format!("html needs a URL: {s}"));
+        }
+        let mut page = HtmlSource {
+            url: url.to_string(),
+            x: 0,
+            y: 0,
+            size: None,
+        };
+
+        for item in items {
+            let invalid = || format!("invalid html option: {item}");
+            let (key, value) = item.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match key.trim() {
+                "x" => page.x = value.parse().map_err(|_| invalid())?,
+                "y" => page.y = value.parse().map_err(|_| invalid())?,
+                "size" => {
+                    let (w, h) = value.split_once('x').ok_or_else(invalid)?;
+                    let w = w.parse().map_err(|_| invalid())?;
+                    let h = h.parse().map_err(|_| invalid())?;
+                    page.size = Some((w, h));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(page)
+    }
+}
+
+/// Everything drawn on the canvas besides the captured outputs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overlays {
+    /// Drawn over the tiles, later images on top.
+    pub images: Vec<ImageSource>,
+    /// Black if not set.
+    pub background: Option<Background>,
+    /// Drawn over everything else, in canvas coordinates even when zoomed.
+    pub texts: Vec<TextSource>,
+    /// Drawn over the images.
+    #[cfg(feature = "html")]
+    pub pages: Vec<HtmlSource>,
+}
+
+impl Overlays {
+    #[cfg(feature = "html")]
+    fn has_pages(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    #[cfg(not(feature = "html"))]
+    fn has_pages(&self) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        self.images.is_empty()
+            && self.background.is_none()
+            && self.texts.is_empty()
+            && !self.has_pages()
+    }
+}
+
+/// A video canvas matching the logical desktop layout.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: i32,
+    pub height: i32,
+    pub tiles: Vec<CanvasTile>,
+    pub overlays: Overlays,
+}
+
+impl Canvas {
+    pub fn from_desktop(desktop: &WlClientDesktopState, streams: &[PortalStream]) -> Self {
+        let mut tiles: Vec<CanvasTile> = streams
+            .iter()
+            .filter_map(|s| {
+                // the portal reports logical positions; prefer the compositor's own geometry
+                let (pos, size) = match desktop
+                    .outputs
+                    .iter()
+                    .find(|o| Some(o.logical_pos) == s.position)
+                {
+                    Some(o) => (o.logical_pos, o.logical_size),
+                    None => (s.position?, s.size?),
+                };
+
+                Some(CanvasTile {
+                    node_id: s.node_id,
+                    x: pos.0 - desktop.desktop_origin.0,
+                    y: pos.1 - desktop.desktop_origin.1,
+                    width: size.0,
+                    height: size.1,
+                })
+            })
+            .collect();
+
+        resolve_overlaps(&mut tiles);
+
+        Canvas {
+            width: desktop.desktop_rect.0,
+            height: desktop.desktop_rect.1,
+            tiles,
+            overlays: Overlays::default(),
+        }
+    }
+
+    /// Whether there is nothing to draw besides a single output.
+    fn is_single_output(&self) -> bool {
+        self.tiles.len() == 1 && self.overlays.is_empty()
+    }
+}
+
+/// Mirrored outputs report the same logical region, so only one of them is kept.
+/// Any tile that lies entirely within a larger one is dropped, e.g. a smaller output
+/// mirroring another from the same origin. The rest are ordered top-left first (node id
+/// as tie-breaker), and partial overlaps are drawn with earlier tiles on top.
+fn resolve_overlaps(tiles: &mut Vec<CanvasTile>) {
+    tiles.sort_by_key(|t| {
+        let area = t.width as i64 * t.height as i64;
+        (Reverse(area), t.y, t.x, t.node_id)
+    });
+
+    let mut kept: Vec<CanvasTile> = Vec::with_capacity(tiles.len());
+    for tile in tiles.drain(..) {
+        if kept.iter().any(|k| k.contains(&tile)) {
+            println!(
+                "Node {} is covered by another output, skipping",
+                tile.node_id
+            );
+            continue;
+        }
+        kept.push(tile);
+    }
+
+    kept.sort_by_key(|t| (t.y, t.x, t.node_id));
+    *tiles = kept;
+}
+
+/// Build a pipeline that composites every tile of the canvas into one recording.
+/// A canvas with a single tile and nothing else on it skips the compositor, so the frames
+/// can stay on the GPU.
+pub fn stitch_pipeline(
+    fd: RawFd,
+    canvas: &Canvas,
+    location: &str,
+    audio: Option<&AudioConfig>,
+    tuning: &Tuning,
+    follow_focus: bool,
+) -> Result<Pipeline, glib::Error> {
+    if !follow_focus && canvas.is_single_output() {
+        let node_id = canvas.tiles[0].node_id;
+        return encode::record_stream_pipeline(fd, node_id, location, audio, tuning);
+    }
+
+    let chain = encode::video_chain(false, tuning);
+    println!("Encoder path: {:?}", chain.path);
+
+    let mut pads = String::new();
+    let mut sources = String::new();
+
+    for (i, tile) in canvas.tiles.iter().enumerate() {
+        let zorder = canvas.tiles.len() - i;
+        pads.push_str(&format!(
+            " sink_{i}::xpos={} sink_{i}::ypos={} sink_{i}::zorder={zorder}",
+            tile.x, tile.y
+        ));
+        sources.push_str(&format!(
+            " {} ! {} ! videoconvert ! videoscale ! video/x-raw,width={},height={} ! canvas.sink_{i}",
+            tuning.pipewiresrc_desc(fd, tile.node_id),
+            tuning.queue_desc(),
+            tile.width,
+            tile.height
+        ));
+    }
+
+    // all images start out hidden, see Scenes
+    for (i, image) in canvas.overlays.images.iter().enumerate() {
+        let pad = canvas.tiles.len() + i;
+        let zorder = canvas.tiles.len() + 1 + i;
+        pads.push_str(&format!(
+            " sink_{pad}::xpos={} sink_{pad}::ypos={} sink_{pad}::zorder={zorder} sink_{pad}::alpha=0",
+            image.x, image.y
+        ));
+        sources.push_str(&format!(
+            " filesrc location=\"{}\" ! decodebin ! imagefreeze is-live=true ! videoconvert ! video/x-raw,format=AYUV ! canvas.sink_{pad}",
+            image.path
+        ));
+    }
+
+    #[cfg(feature = "html")]
+    for (i, page) in canvas.overlays.pages.iter().enumerate() {
+        // one past the background, and above the images
+        let pad = canvas.tiles.len() + canvas.overlays.images.len() + 1 + i;
+        let zorder = pad;
+        let (width, height) = page
+            .size
+            .unwrap_or((canvas.width as u32, canvas.height as u32));
+        pads.push_str(&format!(
+            " sink_{pad}::xpos={} sink_{pad}::ypos={} sink_{pad}::zorder={zorder}",
+            page.x, page.y
+        ));
+        sources.push_str(&format!(
+            " wpesrc location=\"{}\" draw-background=false ! video/x-raw,format=BGRA,width={width},height={height} ! {} ! videoconvert ! canvas.sink_{pad}",
+            page.url,
+            tuning.queue_desc()
+        ));
+    }
+
+    // rendered once and repeated, under everything else
+    if let Some(background) = canvas.overlays.background {
+        let pad = canvas.tiles.len() + canvas.overlays.images.len();
+        pads.push_str(&format!(
+            " sink_{pad}::xpos=0 sink_{pad}::ypos=0 sink_{pad}::zorder=0"
+        ));
+        sources.push_str(&format!(
+            " {} ! imagefreeze is-live=true ! videoconvert ! canvas.sink_{pad}",
+            background.source_desc(canvas.width, canvas.height)
+        ));
+    }
+
+    // see zoom::follow_focus
+    let zoom = if follow_focus {
+        format!(
+            " ! videocrop name=zoom ! videoscale add-borders=true ! video/x-raw,width={},height={},pixel-aspect-ratio=1/1",
+            canvas.width, canvas.height
+        )
+    } else {
+        String::new()
+    };
+
+    // the text is set once the pipeline is up, see update_texts
+    let mut texts = String::new();
+    for (i, text) in canvas.overlays.texts.iter().enumerate() {
+        let font = text
+            .font
+            .as_ref()
+            .map(|f| format!(" font-desc=\"{f}\""))
+            .unwrap_or_default();
+        texts.push_str(&format!(
+            " ! textoverlay name=text{i} halignment=left valignment=top deltax={} deltay={}{font}",
+            text.x, text.y
+        ));
+    }
+
+    let mut desc = format!(
+        "compositor name=canvas background=black{pads} ! video/x-raw,width={},height={}{zoom}{texts}{} ! {}{} ! {} ! {}{sources}",
+        canvas.width,
+        canvas.height,
+        encode::frames_in_tap(tuning),
+        chain.desc,
+        encode::frames_out_tap(tuning),
+        encode::mux_desc(location, tuning),
+        encode::writer::sink_desc(location, tuning),
+    );
+
+    if let Some(audio) = audio {
+        desc.push_str(&format!(
+            " {} ! {} ! mux.",
+            audio.encoded_chain(),
+            tuning.queue_desc()
+        ));
+    }
+
+    let pipeline = gstreamer::parse_launch(&desc)?
+        .downcast::<Pipeline>()
+        .expect("pipeline");
+    encode::pin_threads(&pipeline, tuning);
+    encode::writer::attach(&pipeline, location, tuning)?;
+
+    if let Some(background) = canvas.overlays.background {
+        set_background_shader(&pipeline, &background);
+    }
+    update_texts(&pipeline, &canvas.overlays.texts);
+
+    Ok(pipeline)
+}
+
+/// Set the text of every text overlay, and keep the ones that aren't static up to date
+/// for as long as the pipeline is around.
+fn update_texts(pipeline: &Pipeline, texts: &[TextSource]) {
+    for (i, text) in texts.iter().enumerate() {
+        let Some(overlay) = pipeline.by_name(&format!("text{i}")) else {
+            continue;
+        };
+        overlay.set_property("text", text.content.read().unwrap_or_default());
+        if let TextContent::Static(_) = text.content {
+            continue;
+        }
+
+        let overlay = overlay.downgrade();
+        let text = text.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(text.interval);
+            let Some(overlay) = overlay.upgrade() else {
+                return;
+            };
+            // keep showing the last text if the file is gone or the command failed
+            if let Some(content) = text.content.read() {
+                overlay.set_property("text", content);
+            }
+        });
+    }
+}
+
+/// The shader is set after parsing, there is no quoting it in a launch line.
+fn set_background_shader(pipeline: &Pipeline, background: &Background) {
+    if let Some(shader) = pipeline.by_name("background") {
+        shader.set_property("fragment", background.fragment_shader());
+    }
+}
+
+/// Shows and hides the canvas images as scenes are switched. Images without scenes are
+/// always shown.
+pub struct Scenes {
+    images: Vec<(Pad, ImageSource)>,
+    current: Option<String>,
+}
+
+impl Scenes {
+    /// Works on a pipeline from [`stitch_pipeline`] with `canvas`; starts out in `scene`,
+    /// or with only the images that show in every scene.
+    pub fn new(pipeline: &Pipeline, canvas: &Canvas, scene: Option<&str>) -> Self {
+        let compositor = pipeline.by_name("canvas").expect("canvas compositor");
+        let images = canvas
+            .images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                let pad = compositor
+                    .static_pad(&format!("sink_{}", canvas.tiles.len() + i))
+                    .expect("image pad");
+                if image.scale != 1.0 {
+                    scale_on_caps(&pad, image.scale);
+                }
+                (pad, image.clone())
+            })
+            .collect();
+
+        let mut scenes = Scenes {
+            images,
+            current: None,
+        };
+        if let Some(scene) = scene.filter(|s| !scenes.names().contains(s)) {
+            println!("No image shows in scene {scene}");
+        }
+        scenes.apply(scene);
+        scenes
+    }
+
+    /// Every scene any image shows in.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        for scene in self.images.iter().flat_map(|(_, i)| i.scenes.iter()) {
+            if !names.contains(&scene.as_str()) {
+                names.push(scene);
+            }
+        }
+        names
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn switch(&mut self, scene: &str) -> Result<(), String> {
+        if !self.names().contains(&scene) {
+            return Err(format!("no image shows in scene {scene}"));
+        }
+        self.apply(Some(scene));
+        Ok(())
+    }
+
+    fn apply(&mut self, scene: Option<&str>) {
+        for (pad, image) in self.images.iter() {
+            let alpha = if image.shows_in(scene) {
+                image.opacity
+            } else {
+                0.0
+            };
+            pad.set_property("alpha", alpha);
+        }
+        self.current = scene.map(String::from);
+    }
+}
+
+/// The compositor needs an output size for scaled pads, and the image size is only known
+/// once it has been decoded.
+fn scale_on_caps(pad: &Pad, scale: f64) {
+    pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |pad, info| {
+        let Some(PadProbeData::Event(ref event)) = info.data else {
+            return PadProbeReturn::Ok;
+        };
+        let EventView::Caps(caps) = event.view() else {
+            return PadProbeReturn::Ok;
+        };
+        let Some(s) = caps.caps().structure(0) else {
+            return PadProbeReturn::Ok;
+        };
+        if let (Ok(width), Ok(height)) = (s.get::<i32>("width"), s.get::<i32>("height")) {
+            pad.set_property("width", (width as f64 * scale).round() as i32);
+            pad.set_property("height", (height as f64 * scale).round() as i32);
+        }
+        PadProbeReturn::Ok
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(node_id: u32, x: i32, y: i32, width: i32, height: i32) -> CanvasTile {
+        CanvasTile {
+            node_id,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn node_ids(tiles: &[CanvasTile]) -> Vec<u32> {
+        tiles.iter().map(|t| t.node_id).collect()
+    }
+
+    #[test]
+    fn drops_smaller_mirror_at_same_origin() {
+        let mut tiles = vec![tile(1, 0, 0, 1280, 720), tile(2, 0, 0, 1920, 1080)];
+        resolve_overlaps(&mut tiles);
+        assert_eq!(node_ids(&tiles), [2]);
+    }
+
+    #[test]
+    fn keeps_one_of_identical_mirrors() {
+        let mut tiles = vec![tile(7, 0, 0, 1920, 1080), tile(3, 0, 0, 1920, 1080)];
+        resolve_overlaps(&mut tiles);
+        assert_eq!(node_ids(&tiles), [3]);
+    }
+
+    #[test]
+    fn orders_kept_tiles_top_left_first() {
+        let mut tiles = vec![
+            tile(1, 1920, 0, 2560, 1440),
+            tile(2, 0, 0, 1920, 1080),
+            tile(3, 0, 1080, 1920, 1080),
+        ];
+        resolve_overlaps(&mut tiles);
+        assert_eq!(node_ids(&tiles), [2, 1, 3]);
+    }
+
+    #[test]
+    fn keeps_partial_overlaps() {
+        let mut tiles = vec![tile(1, 0, 0, 1920, 1080), tile(2, 960, 0, 1920, 1080)];
+        resolve_overlaps(&mut tiles);
+        assert_eq!(node_ids(&tiles), [1, 2]);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// Portal restore tokens from earlier sessions, keyed by output name, so that the next
+/// run (even after a reboot) can skip the selection dialog.
+///
+/// Tokens are single use: the portal hands out a new one with every session, which has
+/// to replace the old one.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    path: Option<PathBuf>,
+    /// Oldest first.
+    entries: Vec<(String, String)>,
+}
+
+/// `$XDG_STATE_HOME/lensing`, or the same under `~/.local/state`.
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(state.join("lensing"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("restore-tokens"))
+}
+
+impl TokenStore {
+    /// Load the store, starting out empty if there is none yet.
+    pub fn load() -> Self {
+        let Some(path) = default_path() else {
+            return Self::default();
+        };
+        // one `OUTPUT TOKEN` per line
+        let entries = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .map(|(output, token)| (output.to_string(), token.trim().to_string()))
+            .collect();
+        TokenStore {
+            path: Some(path),
+            entries,
+        }
+    }
+
+    pub fn get(&self, output: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(o, _)| o == output)
+            .map(|(_, t)| t.as_str())
+    }
+
+    /// The output of the most recent session and its token.
+    pub fn latest(&self) -> Option<(&str, &str)> {
+        self.entries.last().map(|(o, t)| (o.as_str(), t.as_str()))
+    }
+
+    /// Remember `token` for `output` and save the store.
+    pub fn set(&mut self, output: &str, token: &str) {
+        self.entries.retain(|(o, _)| o != output);
+        self.entries.push((output.to_string(), token.to_string()));
+        if let Err(e) = self.save() {
+            println!("Could not save restore token: {e}");
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = String::new();
+        for (output, token) in self.entries.iter() {
+            contents.push_str(&format!("{output} {token}\n"));
+        }
+        // write and rename, so a crash never leaves a half written store behind
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+}
@@ -0,0 +1,64 @@
+use std::{fs::OpenOptions, os::fd::AsRawFd, os::unix::fs::OpenOptionsExt};
+
+// from linux/videodev2.h
+const VIDIOC_QUERYCAP: libc::c_ulong = 0x80685600;
+const V4L2_CAP_VIDEO_OUTPUT: u32 = 0x2;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x80000000;
+
+const DRIVER: &[u8] = b"v4l2 loopback";
+
+#[repr(C)]
+#[derive(Default)]
+struct Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+fn query(path: &str) -> Option<Capability> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .ok()?;
+    let mut cap = Capability::default();
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), VIDIOC_QUERYCAP, &mut cap) };
+    (result == 0).then_some(cap)
+}
+
+/// Whether `cap` is a v4l2loopback device nobody is feeding yet. With `exclusive_caps=1`,
+/// which browsers need to list it as a camera, a fed device only offers capture.
+fn is_free_loopback(cap: &Capability) -> bool {
+    let driver = cap.driver.split(|b| *b == 0).next().unwrap_or_default();
+    let caps = if cap.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+        cap.device_caps
+    } else {
+        cap.capabilities
+    };
+    driver == DRIVER && caps & V4L2_CAP_VIDEO_OUTPUT != 0
+}
+
+/// The first v4l2loopback device that takes frames, e.g. `/dev/video4`.
+pub fn find() -> Option<String> {
+    let mut devices: Vec<u32> = std::fs::read_dir("/dev")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("video")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    devices.sort_unstable();
+    devices
+        .into_iter()
+        .map(|n| format!("/dev/video{n}"))
+        .find(|path| query(path).is_some_and(|cap| is_free_loopback(&cap)))
+}
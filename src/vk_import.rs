@@ -0,0 +1,376 @@
+use std::ffi::CStr;
+
+use ash::{extensions::khr::ExternalMemoryFd, vk};
+
+use crate::pw_capture::{DrmFormat, PipewireDmabufPlane, PipewireFrameFormat};
+
+/// The DRM fourccs we can import, and the Vulkan format with the same byte order.
+const FORMATS: [(u32, vk::Format); 4] = [
+    // ARGB8888
+    (0x34325241, vk::Format::B8G8R8A8_UNORM),
+    // XRGB8888
+    (0x34325258, vk::Format::B8G8R8A8_UNORM),
+    // ABGR8888
+    (0x34324241, vk::Format::R8G8B8A8_UNORM),
+    // XBGR8888
+    (0x34324258, vk::Format::R8G8B8A8_UNORM),
+];
+
+const MEMORY_PLANES: [vk::ImageAspectFlags; 4] = [
+    vk::ImageAspectFlags::MEMORY_PLANE_0_EXT,
+    vk::ImageAspectFlags::MEMORY_PLANE_1_EXT,
+    vk::ImageAspectFlags::MEMORY_PLANE_2_EXT,
+    vk::ImageAspectFlags::MEMORY_PLANE_3_EXT,
+];
+
+/// The device extensions [`VkImporter`] needs, to enable when creating the device.
+pub fn device_extensions() -> [&'static CStr; 4] {
+    [
+        ExternalMemoryFd::name(),
+        vk::ExtExternalMemoryDmaBufFn::name(),
+        vk::ExtImageDrmFormatModifierFn::name(),
+        vk::ExtQueueFamilyForeignFn::name(),
+    ]
+}
+
+/// Turns dmabuf frames into `VkImage`s without a copy, for Vulkan based overlays.
+///
+/// The instance and device are the caller's, created with [`device_extensions`]. Images
+/// are shared with the producer, so reads have to be enclosed in
+/// [`DmabufImage::acquire_barrier`] and [`DmabufImage::release_barrier`].
+pub struct VkImporter {
+    instance: ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    device: ash::Device,
+    queue_family: u32,
+    external_memory_fd: ExternalMemoryFd,
+}
+
+impl VkImporter {
+    /// `queue_family` is the one the images are used on.
+    ///
+    /// # Safety
+    ///
+    /// `device` has to be created from `physical_device` with [`device_extensions`]
+    /// enabled, and outlive the importer.
+    pub unsafe fn new(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &ash::Device,
+        queue_family: u32,
+    ) -> Self {
+        Self {
+            instance: instance.clone(),
+            physical_device,
+            device: device.clone(),
+            queue_family,
+            external_memory_fd: ExternalMemoryFd::new(instance, device),
+        }
+    }
+
+    /// The formats and modifiers the device can sample from, for [`crate::Capture::run`].
+    pub fn formats(&self) -> Vec<DrmFormat> {
+        let mut formats = vec![];
+        for (code, vk_format) in FORMATS {
+            for modifier in self.modifiers(vk_format) {
+                let features = modifier.drm_format_modifier_tiling_features;
+                if features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE) {
+                    formats.push(DrmFormat {
+                        code,
+                        modifier: modifier.drm_format_modifier,
+                    });
+                }
+            }
+        }
+        formats
+    }
+
+    fn modifiers(&self, format: vk::Format) -> Vec<vk::DrmFormatModifierPropertiesEXT> {
+        // how many, then fill them in
+        let mut list = vk::DrmFormatModifierPropertiesListEXT::default();
+        let mut properties = vk::FormatProperties2::builder().push_next(&mut list);
+        unsafe {
+            self.instance.get_physical_device_format_properties2(
+                self.physical_device,
+                format,
+                &mut properties,
+            )
+        };
+        let mut modifiers = vec![
+            vk::DrmFormatModifierPropertiesEXT::default();
+            list.drm_format_modifier_count as usize
+        ];
+        let mut list = vk::DrmFormatModifierPropertiesListEXT::builder()
+            .drm_format_modifier_properties(&mut modifiers);
+        let mut properties = vk::FormatProperties2::builder().push_next(&mut list);
+        unsafe {
+            self.instance.get_physical_device_format_properties2(
+                self.physical_device,
+                format,
+                &mut properties,
+            )
+        };
+        modifiers
+    }
+
+    /// Import the planes of one frame. The image shares the buffer, so it shows whatever
+    /// the producer writes to it later. Its layout is undefined until the
+    /// [`DmabufImage::acquire_barrier`] ran.
+    pub fn import(
+        &self,
+        format: &PipewireFrameFormat,
+        planes: &[PipewireDmabufPlane],
+    ) -> Result<DmabufImage<'_>, String> {
+        let Some((_, vk_format)) = FORMATS.iter().find(|(code, _)| *code == format.format) else {
+            return Err(format!("can't import format {:#x}", format.format));
+        };
+        if planes.is_empty() || planes.len() > MEMORY_PLANES.len() {
+            return Err(format!("can't import {} planes", planes.len()));
+        }
+        // planes in buffers of their own have to be bound one by one
+        let disjoint = planes.iter().any(|p| !same_buffer(p.fd, planes[0].fd));
+
+        let layouts: Vec<vk::SubresourceLayout> = planes
+            .iter()
+            .map(|p| vk::SubresourceLayout {
+                offset: p.offset as u64,
+                size: 0,
+                row_pitch: p.stride as u64,
+                array_pitch: 0,
+                depth_pitch: 0,
+            })
+            .collect();
+        let mut modifier_info = vk::ImageDrmFormatModifierExplicitCreateInfoEXT::builder()
+            .drm_format_modifier(format.modifier)
+            .plane_layouts(&layouts);
+        let mut external_info = vk::ExternalMemoryImageCreateInfo::builder()
+            .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let extent = vk::Extent2D {
+            width: format.width,
+            height: format.height,
+        };
+        let create_info = vk::ImageCreateInfo::builder()
+            .flags(if disjoint {
+                vk::ImageCreateFlags::DISJOINT
+            } else {
+                vk::ImageCreateFlags::empty()
+            })
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(*vk_format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .push_next(&mut external_info)
+            .push_next(&mut modifier_info);
+
+        let image = unsafe { self.device.create_image(&create_info, None) }
+            .map_err(|e| format!("vkCreateImage for modifier {:#x}: {e}", format.modifier))?;
+        let mut imported = DmabufImage {
+            importer: self,
+            image,
+            memory: vec![],
+            format: *vk_format,
+            extent,
+        };
+
+        if disjoint {
+            let mut plane_infos: Vec<vk::BindImagePlaneMemoryInfo> = vec![];
+            for (plane, aspect) in planes.iter().zip(MEMORY_PLANES) {
+                let mut plane_requirements =
+                    vk::ImagePlaneMemoryRequirementsInfo::builder().plane_aspect(aspect);
+                let info = vk::ImageMemoryRequirementsInfo2::builder()
+                    .image(image)
+                    .push_next(&mut plane_requirements);
+                let mut requirements = vk::MemoryRequirements2::default();
+                unsafe {
+                    self.device
+                        .get_image_memory_requirements2(&info, &mut requirements)
+                };
+                let memory =
+                    self.import_memory(plane.fd, image, requirements.memory_requirements)?;
+                imported.memory.push(memory);
+                plane_infos.push(
+                    vk::BindImagePlaneMemoryInfo::builder()
+                        .plane_aspect(aspect)
+                        .build(),
+                );
+            }
+            let bind_infos: Vec<vk::BindImageMemoryInfo> = imported
+                .memory
+                .iter()
+                .zip(plane_infos.iter_mut())
+                .map(|(memory, plane_info)| {
+                    vk::BindImageMemoryInfo::builder()
+                        .image(image)
+                        .memory(*memory)
+                        .push_next(plane_info)
+                        .build()
+                })
+                .collect();
+            unsafe { self.device.bind_image_memory2(&bind_infos) }
+                .map_err(|e| format!("binding the dmabuf planes: {e}"))?;
+        } else {
+            let requirements = unsafe { self.device.get_image_memory_requirements(image) };
+            let memory = self.import_memory(planes[0].fd, image, requirements)?;
+            imported.memory.push(memory);
+            unsafe { self.device.bind_image_memory(image, memory, 0) }
+                .map_err(|e| format!("binding the dmabuf: {e}"))?;
+        }
+        Ok(imported)
+    }
+
+    /// Memory for `image` backed by the dmabuf `fd`, which stays the caller's.
+    fn import_memory(
+        &self,
+        fd: i32,
+        image: vk::Image,
+        requirements: vk::MemoryRequirements,
+    ) -> Result<vk::DeviceMemory, String> {
+        let properties = unsafe {
+            self.external_memory_fd
+                .get_memory_fd_properties(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT, fd)
+        }
+        .map_err(|e| format!("dmabuf memory properties: {e}"))?;
+        let types = properties.memory_type_bits & requirements.memory_type_bits;
+        if types == 0 {
+            return Err("no memory type fits the dmabuf".into());
+        }
+
+        // a successful import takes ownership of the fd
+        let owned_fd = unsafe { libc::dup(fd) };
+        if owned_fd < 0 {
+            return Err(format!(
+                "duplicating the dmabuf fd: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let mut import_info = vk::ImportMemoryFdInfoKHR::builder()
+            .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT)
+            .fd(owned_fd);
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+        let allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(types.trailing_zeros())
+            .push_next(&mut import_info)
+            .push_next(&mut dedicated_info);
+        unsafe { self.device.allocate_memory(&allocate_info, None) }.map_err(|e| {
+            unsafe { libc::close(owned_fd) };
+            format!("importing the dmabuf: {e}")
+        })
+    }
+}
+
+/// Whether two dmabuf fds are the same buffer.
+fn same_buffer(a: i32, b: i32) -> bool {
+    if a == b {
+        return true;
+    }
+    let inode = |fd: i32| {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::zeroed();
+        (unsafe { libc::fstat(fd, stat.as_mut_ptr()) } == 0)
+            .then(|| unsafe { stat.assume_init() }.st_ino)
+    };
+    matches!((inode(a), inode(b)), (Some(a), Some(b)) if a == b)
+}
+
+/// A `VkImage` of a dmabuf frame; destroyed on drop, which the GPU has to be done with.
+pub struct DmabufImage<'a> {
+    importer: &'a VkImporter,
+    pub image: vk::Image,
+    memory: Vec<vk::DeviceMemory>,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl DmabufImage<'_> {
+    /// Take the image over from the producer, to record before sampling from it. Leaves it
+    /// in `SHADER_READ_ONLY_OPTIMAL` on the importer's queue family.
+    pub fn acquire_barrier(&self) -> vk::ImageMemoryBarrier {
+        // GENERAL rather than UNDEFINED, which would allow the driver to drop the pixels
+        vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_FOREIGN_EXT)
+            .dst_queue_family_index(self.importer.queue_family)
+            .image(self.image)
+            .subresource_range(color_range())
+            .build()
+    }
+
+    /// Hand the image back to the producer, to record after the last read.
+    pub fn release_barrier(&self) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(self.importer.queue_family)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_FOREIGN_EXT)
+            .image(self.image)
+            .subresource_range(color_range())
+            .build()
+    }
+
+    /// The image without the borrow of the importer, for owners that outlive it, like
+    /// wgpu textures.
+    pub fn into_owned(mut self) -> OwnedDmabufImage {
+        // destroying the null handle that is left is a no-op
+        OwnedDmabufImage {
+            device: self.importer.device.clone(),
+            image: std::mem::replace(&mut self.image, vk::Image::null()),
+            memory: std::mem::take(&mut self.memory),
+        }
+    }
+}
+
+fn color_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// A [`DmabufImage`] that holds on to the device instead of the importer; destroyed on
+/// drop too.
+pub struct OwnedDmabufImage {
+    device: ash::Device,
+    pub image: vk::Image,
+    memory: Vec<vk::DeviceMemory>,
+}
+
+impl Drop for OwnedDmabufImage {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image(self.image, None);
+            for memory in self.memory.drain(..) {
+                self.device.free_memory(memory, None);
+            }
+        }
+    }
+}
+
+impl Drop for DmabufImage<'_> {
+    fn drop(&mut self) {
+        let device = &self.importer.device;
+        unsafe {
+            device.destroy_image(self.image, None);
+            for memory in self.memory.drain(..) {
+                device.free_memory(memory, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+};
+
+#[cfg(feature = "vulkan")]
+use crate::vk_import::{self, DmabufImage, VkImporter};
+use crate::{
+    backend::region::PixelRect,
+    capture_manager::{OwnedDmabufPlane, OwnedFrame},
+    pw_capture::{DrmFormat, PipewireFrameFormat},
+};
+
+/// The DRM fourccs we can sample from, and the texture format with the same byte order.
+const FORMATS: [(u32, wgpu::TextureFormat); 4] = [
+    // ARGB8888
+    (0x34325241, wgpu::TextureFormat::Bgra8Unorm),
+    // XRGB8888
+    (0x34325258, wgpu::TextureFormat::Bgra8Unorm),
+    // ABGR8888
+    (0x34324241, wgpu::TextureFormat::Rgba8Unorm),
+    // XBGR8888
+    (0x34324258, wgpu::TextureFormat::Rgba8Unorm),
+];
+
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Released textures kept for reuse, beyond that they are freed.
+const POOL_SIZE: usize = 4;
+
+// from linux/dma-buf.h
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x40086200;
+const DMA_BUF_SYNC_READ: u64 = 1;
+const DMA_BUF_SYNC_END: u64 = 4;
+
+/// The dmabuf formats [`WgpuImporter`] can take, for
+/// [`crate::backend::CaptureBackend::run`].
+///
+/// wgpu can't import dmabufs, so they are mapped and uploaded like shared memory,
+/// which only works for linear buffers. With the `vulkan` feature, `device_formats`
+/// has those a device imports.
+pub fn formats() -> Vec<DrmFormat> {
+    FORMATS
+        .iter()
+        .map(|(code, _)| DrmFormat {
+            code: *code,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+        })
+        .collect()
+}
+
+/// The dmabuf formats [`WgpuImporter`] can take on `device`: all that it samples from if
+/// it's a Vulkan device with [`vk_import::device_extensions`] enabled, which imports
+/// them without a copy, [`formats`] otherwise.
+#[cfg(feature = "vulkan")]
+pub fn device_formats(device: &wgpu::Device) -> Vec<DrmFormat> {
+    with_vk_importer(device, VkImporter::formats).unwrap_or_else(formats)
+}
+
+/// Signaled once the GPU is done with everything submitted before the texture was
+/// released, after which it may be written again.
+#[derive(Debug, Clone, Default)]
+pub struct ReleaseFence(Arc<AtomicBool>);
+
+impl ReleaseFence {
+    pub fn is_signaled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+struct Released {
+    texture: wgpu::Texture,
+    fence: ReleaseFence,
+}
+
+type Pool = Mutex<Vec<Released>>;
+
+/// A captured frame in a `wgpu::Texture`, for apps that draw with wgpu (or egui on top
+/// of it) and don't want to deal with PipeWire or DRM formats.
+///
+/// Sample [`FrameTexture::crop`] of it; the rest is whatever the producer didn't mean
+/// to show. Hand it back with [`FrameTexture::release`] after the last submit that
+/// reads it, so the next frame can reuse its memory.
+///
+/// Frames are uploaded into the texture, except for dmabufs that a Vulkan device
+/// imports, see `device_formats`: those textures share the buffer of the producer.
+pub struct FrameTexture {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    frame_format: PipewireFrameFormat,
+    crop: PixelRect,
+    pool: Weak<Pool>,
+}
+
+impl FrameTexture {
+    pub fn texture(&self) -> &wgpu::Texture {
+        &self.texture
+    }
+
+    /// A view of the whole texture.
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.view
+    }
+
+    /// A non-sRGB format, the frames are already sRGB encoded.
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.texture.format()
+    }
+
+    /// Size of the texture in pixels.
+    pub fn extent(&self) -> (u32, u32) {
+        (self.texture.width(), self.texture.height())
+    }
+
+    /// The format the frame was captured in.
+    pub fn frame_format(&self) -> &PipewireFrameFormat {
+        &self.frame_format
+    }
+
+    /// The part of the texture to show, in pixels.
+    pub fn crop(&self) -> PixelRect {
+        self.crop
+    }
+
+    /// [`FrameTexture::crop`] as texture coordinates: left, top, right, bottom.
+    pub fn uv_crop(&self) -> [f32; 4] {
+        let (width, height) = self.extent();
+        let (width, height) = (width as f32, height as f32);
+        let crop = self.crop;
+        [
+            crop.x as f32 / width,
+            crop.y as f32 / height,
+            (crop.x + crop.width) as f32 / width,
+            (crop.y + crop.height) as f32 / height,
+        ]
+    }
+
+    /// Write `damage` of `frame`, a later frame of the same format, over the texture,
+    /// for frames that only changed there. Cheaper than importing all of it again.
+    /// Textures that share a dmabuf can't be written, import those frames instead.
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        frame: &OwnedFrame,
+        damage: &[PixelRect],
+    ) -> Result<(), String> {
+        if damage.is_empty() {
+            return Ok(());
+        }
+        if !self.texture.usage().contains(wgpu::TextureUsages::COPY_DST) {
+            return Err("the texture shares a dmabuf, import the frame instead".into());
+        }
+        with_pixels(&self.frame_format, frame, |data, stride| {
+            for rect in damage {
+                write_texture(queue, &self.texture, data, stride, rect);
+            }
+        })
+    }
+
+    /// Give the texture back once the GPU is done with what was submitted to `queue` so
+    /// far. Dropping it instead frees it, and so does releasing a texture that shares a
+    /// dmabuf; the fence then tells when the GPU stopped reading the producer's buffer.
+    pub fn release(self, queue: &wgpu::Queue) -> ReleaseFence {
+        let fence = ReleaseFence::default();
+        let signal = fence.clone();
+        queue.on_submitted_work_done(move || signal.0.store(true, Ordering::Release));
+        if let Some(pool) = self.pool.upgrade() {
+            let mut pool = pool.lock().unwrap();
+            if pool.len() >= POOL_SIZE {
+                pool.remove(0);
+            }
+            pool.push(Released {
+                texture: self.texture,
+                fence: fence.clone(),
+            });
+        }
+        fence
+    }
+}
+
+/// Turns captured frames into [`FrameTexture`]s, reusing the memory of released ones.
+#[derive(Default)]
+pub struct WgpuImporter {
+    pool: Arc<Pool>,
+}
+
+impl WgpuImporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Upload a frame, or import it on Vulkan, see [`FrameTexture`]. `crop` is the part
+    /// of it to show, all of it if `None`.
+    pub fn import(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: &PipewireFrameFormat,
+        frame: &OwnedFrame,
+        crop: Option<PixelRect>,
+    ) -> Result<FrameTexture, String> {
+        let Some((_, texture_format)) = FORMATS.iter().find(|(code, _)| *code == format.format)
+        else {
+            return Err(format!("can't sample format {:#x}", format.format));
+        };
+        let crop = crop.unwrap_or(PixelRect {
+            x: 0,
+            y: 0,
+            width: format.width,
+            height: format.height,
+        });
+        if crop.x + crop.width > format.width || crop.y + crop.height > format.height {
+            return Err(format!(
+                "crop {crop:?} is outside the {}x{} frame",
+                format.width, format.height
+            ));
+        }
+
+        #[cfg(feature = "vulkan")]
+        if let OwnedFrame::Dmabuf { planes } = frame {
+            if let Some(texture) = import_dmabuf(device, format, planes, *texture_format) {
+                let texture = texture?;
+                return Ok(FrameTexture {
+                    view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                    texture,
+                    frame_format: *format,
+                    crop,
+                    // not ours to reuse
+                    pool: Weak::new(),
+                });
+            }
+        }
+
+        let texture = self.texture(device, format, *texture_format);
+        let whole = PixelRect {
+            x: 0,
+            y: 0,
+            width: format.width,
+            height: format.height,
+        };
+        with_pixels(format, frame, |data, stride| {
+            write_texture(queue, &texture, data, stride, &whole)
+        })?;
+
+        Ok(FrameTexture {
+            view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            texture,
+            frame_format: *format,
+            crop,
+            pool: Arc::downgrade(&self.pool),
+        })
+    }
+
+    /// A released texture that fits and the GPU is done with, or a new one.
+    fn texture(
+        &self,
+        device: &wgpu::Device,
+        format: &PipewireFrameFormat,
+        texture_format: wgpu::TextureFormat,
+    ) -> wgpu::Texture {
+        // runs the callbacks of finished submissions
+        device.poll(wgpu::Maintain::Poll);
+        let mut pool = self.pool.lock().unwrap();
+        let reusable = pool.iter().position(|r| {
+            r.fence.is_signaled()
+                && r.texture.format() == texture_format
+                && (r.texture.width(), r.texture.height()) == (format.width, format.height)
+        });
+        if let Some(index) = reusable {
+            return pool.remove(index).texture;
+        }
+        drop(pool);
+
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("lensing frame"),
+            size: wgpu::Extent3d {
+                width: format.width,
+                height: format.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture_format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        })
+    }
+}
+
+/// Call `f` with a [`VkImporter`] for `device`, or `None` if it isn't a Vulkan device
+/// with [`vk_import::device_extensions`] enabled.
+#[cfg(feature = "vulkan")]
+fn with_vk_importer<R>(device: &wgpu::Device, f: impl FnOnce(&VkImporter) -> R) -> Option<R> {
+    unsafe {
+        device.as_hal::<wgpu::hal::api::Vulkan, _, _>(|device| {
+            let device = device?;
+            let enabled = device.enabled_device_extensions();
+            if !vk_import::device_extensions()
+                .iter()
+                .all(|e| enabled.contains(e))
+            {
+                return None;
+            }
+            // the device outlives the importer, which only lives for this call
+            let importer = VkImporter::new(
+                device.shared_instance().raw_instance(),
+                device.raw_physical_device(),
+                device.raw_device(),
+                device.queue_family_index(),
+            );
+            Some(f(&importer))
+        })
+    }
+    .flatten()
+}
+
+/// A texture that shares the dmabuf `planes`, or `None` if `device` can't import them.
+///
+/// wgpu can't record the transfer from the producer's queue family that
+/// [`DmabufImage::acquire_barrier`] does, so the texture is used as if it had always
+/// been the device's, which the drivers that export dmabufs put up with.
+#[cfg(feature = "vulkan")]
+fn import_dmabuf(
+    device: &wgpu::Device,
+    format: &PipewireFrameFormat,
+    planes: &[OwnedDmabufPlane],
+    texture_format: wgpu::TextureFormat,
+) -> Option<Result<wgpu::Texture, String>> {
+    use wgpu::hal;
+
+    let planes: Vec<_> = planes.iter().map(OwnedDmabufPlane::as_plane).collect();
+    let image = match with_vk_importer(device, |importer| {
+        importer
+            .import(format, &planes)
+            .map(DmabufImage::into_owned)
+    })? {
+        Ok(image) => image,
+        Err(e) => return Some(Err(e)),
+    };
+    let raw = image.image;
+    let size = wgpu::Extent3d {
+        width: format.width,
+        height: format.height,
+        depth_or_array_layers: 1,
+    };
+
+    let texture = unsafe {
+        let hal_texture = hal::vulkan::Device::texture_from_raw(
+            raw,
+            &hal::TextureDescriptor {
+                label: Some("lensing dmabuf"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: texture_format,
+                usage: hal::TextureUses::RESOURCE | hal::TextureUses::COPY_SRC,
+                memory_flags: hal::MemoryFlags::empty(),
+                view_formats: vec![],
+            },
+            // wgpu drops it once the GPU is done with the texture, which frees the image
+            Some(Box::new(image)),
+        );
+        device.create_texture_from_hal::<hal::api::Vulkan>(
+            hal_texture,
+            &wgpu::TextureDescriptor {
+                label: Some("lensing dmabuf"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: texture_format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            },
+        )
+    };
+    Some(Ok(texture))
+}
+
+/// Call `f` with the pixels of `frame` and their stride.
+fn with_pixels(
+    format: &PipewireFrameFormat,
+    frame: &OwnedFrame,
+    f: impl FnOnce(&[u8], u32),
+) -> Result<(), String> {
+    match frame {
+        OwnedFrame::Shm { data, stride } => f(data, *stride as u32),
+        OwnedFrame::Dmabuf { planes } => {
+            if format.modifier != DRM_FORMAT_MOD_LINEAR {
+                return Err(format!("can't map modifier {:#x}", format.modifier));
+            }
+            let plane = planes.first().ok_or("dmabuf without planes")?;
+            with_mapped(plane, format.height, |data| f(data, plane.stride as u32))
+                .map_err(|e| format!("mapping the dmabuf: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy `rect` of the pixels in `data` to the same place in `texture`.
+fn write_texture(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    data: &[u8],
+    stride: u32,
+    rect: &PixelRect,
+) {
+    // every format we sample has 32 bit pixels
+    let offset = rect.y as u64 * stride as u64 + rect.x as u64 * 4;
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: rect.x,
+                y: rect.y,
+                z: 0,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        data,
+        wgpu::ImageDataLayout {
+            offset,
+            bytes_per_row: Some(stride),
+            rows_per_image: None,
+        },
+        wgpu::Extent3d {
+            width: rect.width,
+            height: rect.height,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+/// Call `f` with the pixels of a linear dmabuf plane, `height` rows of it.
+fn with_mapped<R>(
+    plane: &OwnedDmabufPlane,
+    height: u32,
+    f: impl FnOnce(&[u8]) -> R,
+) -> std::io::Result<R> {
+    use std::os::fd::AsRawFd;
+
+    let fd = plane.fd.as_raw_fd();
+    let offset = plane.offset as usize;
+    let len = offset + plane.stride as usize * height as usize;
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // lets the driver flush caches, the GPU may still be writing otherwise
+    let sync = |flags: u64| unsafe { libc::ioctl(fd, DMA_BUF_IOCTL_SYNC, &flags) };
+    sync(DMA_BUF_SYNC_READ);
+    let data = unsafe { std::slice::from_raw_parts((ptr as *const u8).add(offset), len - offset) };
+    let result = f(data);
+    sync(DMA_BUF_SYNC_READ | DMA_BUF_SYNC_END);
+
+    unsafe { libc::munmap(ptr, len) };
+    Ok(result)
+}
@@ -3,79 +3,579 @@ use smithay_client_toolkit::reexports::{
         zxdg_output_manager_v1::ZxdgOutputManagerV1,
         zxdg_output_v1::{self, ZxdgOutputV1},
     },
-    protocols_wlr::export_dmabuf::v1::client::zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1,
+    protocols_wlr::{
+        export_dmabuf::v1::client::zwlr_export_dmabuf_manager_v1::ZwlrExportDmabufManagerV1,
+        foreign_toplevel::v1::client::{
+            zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+            zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+        },
+    },
 };
 use wayland_client::{
+    event_created_child,
     globals::{registry_queue_init, GlobalListContents},
     protocol::{
-        wl_output::{Transform, WlOutput},
-        wl_registry::WlRegistry,
+        wl_output::{self, Transform, WlOutput},
+        wl_registry::{self, WlRegistry},
     },
-    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+    Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle, WEnum,
 };
 
+use crate::{crash_report, error::LensingError};
+
+/// Globals we can do without, and the interfaces whose protocol errors they are to blame
+/// for.
+const OPTIONAL_GLOBALS: [(&str, &[&str]); 2] = [
+    (
+        "zwlr_foreign_toplevel_manager_v1",
+        &[
+            "zwlr_foreign_toplevel_manager_v1",
+            "zwlr_foreign_toplevel_handle_v1",
+        ],
+    ),
+    (
+        "zwlr_export_dmabuf_manager_v1",
+        &[
+            "zwlr_export_dmabuf_manager_v1",
+            "zwlr_export_dmabuf_frame_v1",
+        ],
+    ),
+];
+
 pub struct OutputState {
     pub wl_output: WlOutput,
     pub id: u32,
     pub name: String,
+    pub make: String,
     pub model: String,
     pub size: (i32, i32),
     pub logical_pos: (i32, i32),
     pub logical_size: (i32, i32),
-    transform: WEnum<Transform>, // TODO: support upright displays
+    transform: WEnum<Transform>,
+    done: bool,
+    /// Whether an [`OutputEvent::Added`] went out for this output.
+    announced: bool,
+    /// Whether anything changed since the last `done`.
+    changed: bool,
+}
+
+/// What tells a monitor apart once its wl_output global is gone, since one that is
+/// plugged back in comes back as a new global: its connector, and its make and model
+/// for another monitor on the same connector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputIdentity {
+    pub name: String,
+    pub make: String,
+    pub model: String,
+}
+
+impl OutputIdentity {
+    pub fn matches(&self, output: &OutputState) -> bool {
+        output.name == self.name && output.make == self.make && output.model == self.model
+    }
+}
+
+impl std::fmt::Display for OutputIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Outputs coming, going, or changing mode or place, see
+/// [`WlClientDesktopState::output_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputEvent {
+    Added(String),
+    Removed(String),
+    /// The output's mode, transform, position or logical size changed.
+    Changed(String),
+}
+
+impl OutputState {
+    /// Pixels per logical unit, e.g. 1.5 for an output scaled to 150%.
+    ///
+    /// wl_output only advertises integer scales, so this is the ratio of the current mode
+    /// to the logical size, which is what fractionally scaled outputs are captured at.
+    pub fn scale(&self) -> f64 {
+        // the mode is before the transform, the logical size after
+        let width = if self.is_rotated() {
+            self.size.1
+        } else {
+            self.size.0
+        };
+        if width <= 0 || self.logical_size.0 <= 0 {
+            return 1.0;
+        }
+        width as f64 / self.logical_size.0 as f64
+    }
+
+    /// A point in logical desktop coordinates, like the portal and the cursor use, in
+    /// pixels of this output's frames.
+    pub fn to_pixels(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let scale = self.scale();
+        (
+            (x - self.logical_pos.0 as f64) * scale,
+            (y - self.logical_pos.1 as f64) * scale,
+        )
+    }
+
+    /// A point in pixels of this output's frames, in logical desktop coordinates.
+    pub fn to_logical(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let scale = self.scale();
+        (
+            x / scale + self.logical_pos.0 as f64,
+            y / scale + self.logical_pos.1 as f64,
+        )
+    }
+
+    pub fn identity(&self) -> OutputIdentity {
+        OutputIdentity {
+            name: self.name.clone(),
+            make: self.make.clone(),
+            model: self.model.clone(),
+        }
+    }
+
+    /// How the output is rotated and flipped, e.g. `_90` for a monitor turned upright.
+    pub fn transform(&self) -> Transform {
+        match self.transform {
+            WEnum::Value(transform) => transform,
+            WEnum::Unknown(_) => Transform::Normal,
+        }
+    }
+
+    /// Whether the output is turned by 90 or 270 degrees, so its mode is taller than wide
+    /// or the other way round compared to its logical size.
+    pub fn is_rotated(&self) -> bool {
+        matches!(
+            self.transform(),
+            Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270
+        )
+    }
+}
+
+pub struct ToplevelState {
+    pub handle: ZwlrForeignToplevelHandleV1,
+    pub app_id: String,
+    pub title: String,
+    pub activated: bool,
+    pub outputs: Vec<WlOutput>,
+    pub closed: bool,
     done: bool,
 }
 
+/// Picks windows by app id, title, or both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowFilter {
+    pub app_id: Option<String>,
+    /// Matches any window whose title contains this, ignoring case.
+    pub title: Option<String>,
+}
+
+impl WindowFilter {
+    pub fn matches(&self, toplevel: &ToplevelState) -> bool {
+        // for XWayland clients the app id is the WM_CLASS, and compositors disagree on its case
+        let app_id = self
+            .app_id
+            .as_ref()
+            .is_none_or(|a| toplevel.app_id.eq_ignore_ascii_case(a));
+        let title = self
+            .title
+            .as_ref()
+            .is_none_or(|t| toplevel.title.to_lowercase().contains(&t.to_lowercase()));
+        app_id && title
+    }
+}
+
+impl std::fmt::Display for WindowFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.app_id, &self.title) {
+            (Some(app_id), Some(title)) => write!(f, "{app_id} \"{title}\""),
+            (Some(app_id), None) => write!(f, "{app_id}"),
+            (None, Some(title)) => write!(f, "\"{title}\""),
+            (None, None) => write!(f, "any window"),
+        }
+    }
+}
+
+/// Picks an output: `DP-3`, `position:1920,0` or `primary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputSelector {
+    /// The connector name, or failing that the model, ignoring case.
+    Name(String),
+    /// The output whose top left corner is at this logical position.
+    Position(i32, i32),
+    /// Wayland has no primary output; this is the one at the origin of the desktop, or
+    /// the first one if none is there.
+    Primary,
+}
+
+impl std::str::FromStr for OutputSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "primary" {
+            return Ok(OutputSelector::Primary);
+        }
+        if let Some(position) = s.strip_prefix("position:") {
+            let parsed = position
+                .split_once(',')
+                .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)));
+            return match parsed {
+                Some((x, y)) => Ok(OutputSelector::Position(x, y)),
+                None => Err(format!("not a position X,Y: {position}")),
+            };
+        }
+        if s.is_empty() {
+            return Err("no output name".into());
+        }
+        Ok(OutputSelector::Name(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for OutputSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputSelector::Name(name) => write!(f, "{name}"),
+            OutputSelector::Position(x, y) => write!(f, "position:{x},{y}"),
+            OutputSelector::Primary => write!(f, "primary"),
+        }
+    }
+}
+
 pub struct WlClientDesktopState {
     pub connection: Connection,
+    queue: Option<EventQueue<Self>>,
     pub xdg_output_mgr: ZxdgOutputManagerV1,
     pub maybe_wlr_dmabuf_mgr: Option<ZwlrExportDmabufManagerV1>,
+    pub maybe_toplevel_mgr: Option<ZwlrForeignToplevelManagerV1>,
     pub outputs: Vec<OutputState>,
+    output_events: Vec<OutputEvent>,
+    pub toplevels: Vec<ToplevelState>,
+    pub desktop_origin: (i32, i32),
     pub desktop_rect: (i32, i32),
+    /// Optional globals left unbound, because the compositor sent protocol errors for them.
+    disabled: Vec<&'static str>,
 }
 
 impl WlClientDesktopState {
-    pub fn new() -> Self {
-        let connection = Connection::connect_to_env().expect("wayland connection");
-        let (globals, mut queue) =
-            registry_queue_init::<Self>(&connection).expect("wayland globals");
-        let qh = queue.handle();
-
-        let mut state = Self {
-            connection,
-            xdg_output_mgr: globals
-                .bind(&qh, 2..=3, ())
-                .expect(ZxdgOutputManagerV1::interface().name),
-            maybe_wlr_dmabuf_mgr: globals.bind(&qh, 1..=1, ()).ok(),
-            outputs: vec![],
-            desktop_rect: (0, 0),
+    /// Connect to the compositor of `WAYLAND_DISPLAY`; fails without one, e.g. on a
+    /// headless session.
+    pub fn new() -> Result<Self, LensingError> {
+        Self::connect(vec![])
+    }
+
+    fn connect(mut disabled: Vec<&'static str>) -> Result<Self, LensingError> {
+        loop {
+            let connection = Connection::connect_to_env()
+                .map_err(|e| LensingError::Wayland(format!("connecting: {e}")))?;
+            let (globals, mut queue) = registry_queue_init::<Self>(&connection)
+                .map_err(|e| LensingError::Wayland(format!("globals: {e}")))?;
+            let qh = queue.handle();
+            let enabled = |global: &str| !disabled.contains(&global);
+
+            let mut state = Self {
+                connection,
+                queue: None,
+                xdg_output_mgr: globals.bind(&qh, 2..=3, ()).map_err(|e| {
+                    let name = ZxdgOutputManagerV1::interface().name;
+                    LensingError::Wayland(format!("{name}: {e}"))
+                })?,
+                maybe_wlr_dmabuf_mgr: if enabled(ZwlrExportDmabufManagerV1::interface().name) {
+                    globals.bind(&qh, 1..=1, ()).ok()
+                } else {
+                    None
+                },
+                maybe_toplevel_mgr: if enabled(ZwlrForeignToplevelManagerV1::interface().name) {
+                    globals.bind(&qh, 1..=3, ()).ok()
+                } else {
+                    None
+                },
+                outputs: vec![],
+                output_events: vec![],
+                toplevels: vec![],
+                desktop_origin: (0, 0),
+                desktop_rect: (0, 0),
+                disabled: disabled.clone(),
+            };
+
+            for o in globals.contents().clone_list().iter() {
+                if o.interface == WlOutput::interface().name {
+                    state.add_output(globals.registry(), o.name, o.version, &qh);
+                }
+            }
+
+            if let Err(e) = queue.blocking_dispatch(&mut state) {
+                disabled.push(state.culprit(e)?);
+                continue;
+            }
+            state.update_desktop_rect();
+            state.queue = Some(queue);
+            // the outputs that were there from the start aren't news
+            state.output_events.clear();
+
+            return Ok(state);
+        }
+    }
+
+    /// The optional global to blame for `error`. Protocol errors end the connection, so
+    /// anything else is as fatal as ever.
+    fn culprit(&self, error: DispatchError) -> Result<&'static str, LensingError> {
+        let protocol_error = self.connection.protocol_error();
+        let culprit = protocol_error.as_ref().and_then(|e| {
+            OPTIONAL_GLOBALS
+                .iter()
+                .find(|(_, interfaces)| interfaces.contains(&e.object_interface.as_str()))
+                .map(|(global, _)| *global)
+        });
+        let (Some(culprit), Some(protocol_error)) = (culprit, protocol_error) else {
+            return Err(LensingError::Wayland(error.to_string()));
         };
+        println!(
+            "Compositor sent a protocol error for {}: {}, continuing without {culprit}",
+            protocol_error.object_interface, protocol_error.message
+        );
+        Ok(culprit)
+    }
+
+    /// Connect again without the global to blame for `error`. The outputs come back
+    /// with new ids; ones that went away meanwhile are reported as removed.
+    fn reconnect(&mut self, error: DispatchError) -> Result<(), LensingError> {
+        let mut disabled = self.disabled.clone();
+        disabled.push(self.culprit(error)?);
+        crash_report::note("disabled globals", disabled.join(", "));
+
+        let before: Vec<String> = self.outputs.iter().map(|o| o.name.clone()).collect();
+        let mut output_events = std::mem::take(&mut self.output_events);
+        *self = Self::connect(disabled)?;
+
+        for name in before.iter() {
+            if !self.outputs.iter().any(|o| &o.name == name) {
+                output_events.push(OutputEvent::Removed(name.clone()));
+            }
+        }
+        for output in self.outputs.iter() {
+            if !before.contains(&output.name) {
+                output_events.push(OutputEvent::Added(output.name.clone()));
+            }
+        }
+        self.output_events = output_events;
+        Ok(())
+    }
+
+    fn add_output(
+        &mut self,
+        registry: &WlRegistry,
+        name: u32,
+        version: u32,
+        qh: &QueueHandle<Self>,
+    ) {
+        let wl_output: WlOutput = registry.bind(name, version, qh, name);
 
-        for o in globals.contents().clone_list().iter() {
-            if o.interface == WlOutput::interface().name {
-                let wl_output: WlOutput = globals.registry().bind(o.name, o.version, &qh, o.name);
+        self.xdg_output_mgr.get_xdg_output(&wl_output, qh, name);
 
-                state.xdg_output_mgr.get_xdg_output(&wl_output, &qh, o.name);
+        let output = OutputState {
+            wl_output,
+            id: name,
+            name: String::new(),
+            make: String::new(),
+            model: String::new(),
+            size: (0, 0),
+            logical_pos: (0, 0),
+            logical_size: (0, 0),
+            transform: WEnum::Unknown(0),
+            done: false,
+            announced: false,
+            changed: false,
+        };
+
+        self.outputs.push(output);
+    }
+
+    /// Block until the compositor has sent us something, then handle it.
+    pub fn dispatch(&mut self) -> Result<(), LensingError> {
+        let mut queue = self.queue.take().expect("event queue");
+        let result = queue.blocking_dispatch(self);
+        self.queue = Some(queue);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => self.reconnect(e),
+        }
+    }
 
-                let output = OutputState {
-                    wl_output,
-                    id: o.name,
-                    name: String::new(),
-                    model: String::new(),
-                    size: (0, 0),
-                    logical_pos: (0, 0),
-                    logical_size: (0, 0),
-                    transform: WEnum::Unknown(0),
-                    done: false,
-                };
+    /// Handle everything the compositor has sent up to now.
+    pub fn roundtrip(&mut self) -> Result<(), LensingError> {
+        let mut queue = self.queue.take().expect("event queue");
+        let result = queue.roundtrip(self);
+        self.queue = Some(queue);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => self.reconnect(e),
+        }
+    }
+
+    /// Returns once an output with the given connector name is back.
+    pub fn wait_for_output(&mut self, name: &str) -> Result<(), LensingError> {
+        self.roundtrip()?;
+        while !self.outputs.iter().any(|o| o.done && o.name == name) {
+            self.dispatch()?;
+        }
+        Ok(())
+    }
 
-                state.outputs.push(output);
+    /// Returns once the monitor of `identity` is back, on the same connector.
+    pub fn wait_for_monitor(&mut self, identity: &OutputIdentity) -> Result<(), LensingError> {
+        self.roundtrip()?;
+        while !self.outputs.iter().any(|o| o.done && identity.matches(o)) {
+            self.dispatch()?;
+        }
+        Ok(())
+    }
+
+    /// Outputs added, removed or changed since the last call. Events arrive whenever the
+    /// queue is dispatched, e.g. with [`Self::roundtrip`].
+    pub fn output_events(&mut self) -> Vec<OutputEvent> {
+        std::mem::take(&mut self.output_events)
+    }
+
+    /// Handle everything the compositor has sent up to now, then return the output events.
+    pub fn poll_output_events(&mut self) -> Result<Vec<OutputEvent>, LensingError> {
+        self.roundtrip()?;
+        Ok(self.output_events())
+    }
+
+    /// Block until an output is added, removed or changed.
+    pub fn wait_for_output_events(&mut self) -> Result<Vec<OutputEvent>, LensingError> {
+        self.roundtrip()?;
+        while self.output_events.is_empty() {
+            self.dispatch()?;
+        }
+        Ok(self.output_events())
+    }
+
+    /// All properties of an output have arrived, possibly again after a change.
+    fn output_done(&mut self, id: u32) {
+        let Some(output) = self.outputs.iter_mut().find(|o| o.id == id) else {
+            return;
+        };
+        output.done = true;
+        self.update_desktop_rect();
+
+        // with xdg_output v2 the wl_output may be done before it has a name
+        let Some(output) = self
+            .outputs
+            .iter_mut()
+            .find(|o| o.id == id && !o.name.is_empty())
+        else {
+            return;
+        };
+        let name = output.name.clone();
+        if !output.announced {
+            output.announced = true;
+            self.output_events.push(OutputEvent::Added(name));
+        } else if output.changed {
+            self.output_events.push(OutputEvent::Changed(name));
+        }
+        output.changed = false;
+    }
+
+    /// If this compositor runs in a window of another session, which kind of session.
+    /// wlroots names the outputs of its nested backends `WL-n` and `X11-n`.
+    pub fn nested_backend(&self) -> Option<&'static str> {
+        self.outputs.iter().find_map(|o| {
+            if o.name.starts_with("WL-") {
+                Some("Wayland")
+            } else if o.name.starts_with("X11-") {
+                Some("X11")
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The output that the focused window is on, if any.
+    pub fn focused_output(&self) -> Option<&OutputState> {
+        let toplevel = self.toplevels.iter().find(|t| t.activated && !t.closed)?;
+        let wl_output = toplevel.outputs.first()?;
+        self.outputs.iter().find(|o| &o.wl_output == wl_output)
+    }
+
+    /// The output `selector` picks, if it is there.
+    pub fn find_output(&self, selector: &OutputSelector) -> Option<&OutputState> {
+        let mut outputs = self.outputs.iter().filter(|o| o.done);
+        match selector {
+            OutputSelector::Name(name) => {
+                let outputs: Vec<_> = outputs.collect();
+                outputs
+                    .iter()
+                    .find(|o| o.name.eq_ignore_ascii_case(name))
+                    .or_else(|| outputs.iter().find(|o| o.model.eq_ignore_ascii_case(name)))
+                    .copied()
+            }
+            OutputSelector::Position(x, y) => outputs.find(|o| o.logical_pos == (*x, *y)),
+            OutputSelector::Primary => {
+                let outputs: Vec<_> = outputs.collect();
+                outputs
+                    .iter()
+                    .find(|o| o.logical_pos == self.desktop_origin)
+                    .or_else(|| outputs.first())
+                    .copied()
             }
         }
+    }
 
-        queue.blocking_dispatch(&mut state).expect("dispatch");
+    /// Open windows, if the compositor lists them.
+    pub fn windows(&self) -> impl Iterator<Item = &ToplevelState> {
+        self.toplevels.iter().filter(|t| t.done && !t.closed)
+    }
+
+    /// Returns once a window with the given app id is mapped.
+    pub fn wait_for_app(&mut self, app_id: &str) -> Result<bool, LensingError> {
+        let filter = WindowFilter {
+            app_id: Some(app_id.to_string()),
+            title: None,
+        };
+        Ok(self.wait_for_window(&filter)?.is_some())
+    }
+
+    /// Returns the first open window that matches, waiting for one to be mapped if
+    /// there is none yet. `None` if the compositor doesn't list windows.
+    pub fn wait_for_window(
+        &mut self,
+        filter: &WindowFilter,
+    ) -> Result<Option<&ToplevelState>, LensingError> {
+        if self.maybe_toplevel_mgr.is_none() {
+            return Ok(None);
+        }
+
+        // make sure we've seen the old window close
+        self.roundtrip()?;
+
+        while !self.windows().any(|t| filter.matches(t)) {
+            self.dispatch()?;
+        }
+        Ok(self.windows().find(|t| filter.matches(t)))
+    }
+
+    /// Bounding box of all outputs in logical coordinates.
+    fn update_desktop_rect(&mut self) {
+        let min_x = self.outputs.iter().map(|o| o.logical_pos.0).min();
+        let min_y = self.outputs.iter().map(|o| o.logical_pos.1).min();
+        let max_x = self
+            .outputs
+            .iter()
+            .map(|o| o.logical_pos.0 + o.logical_size.0)
+            .max();
+        let max_y = self
+            .outputs
+            .iter()
+            .map(|o| o.logical_pos.1 + o.logical_size.1)
+            .max();
 
-        state
+        if let (Some(min_x), Some(min_y), Some(max_x), Some(max_y)) = (min_x, min_y, max_x, max_y) {
+            self.desktop_origin = (min_x, min_y);
+            self.desktop_rect = (max_x - min_x, max_y - min_y);
+        }
     }
 }
 
@@ -96,19 +596,17 @@ impl Dispatch<ZxdgOutputV1, u32> for WlClientDesktopState {
             }
             zxdg_output_v1::Event::LogicalPosition { x, y } => {
                 if let Some(output) = state.outputs.iter_mut().find(|o| o.id == *data) {
+                    output.changed |= output.logical_pos != (x, y);
                     output.logical_pos = (x, y);
                 }
             }
             zxdg_output_v1::Event::LogicalSize { width, height } => {
                 if let Some(output) = state.outputs.iter_mut().find(|o| o.id == *data) {
+                    output.changed |= output.logical_size != (width, height);
                     output.logical_size = (width, height);
                 }
             }
-            zxdg_output_v1::Event::Done => {
-                if let Some(output) = state.outputs.iter_mut().find(|o| o.id == *data) {
-                    output.done = true;
-                }
-            }
+            zxdg_output_v1::Event::Done => state.output_done(*data),
             _ => {}
         }
     }
@@ -124,19 +622,104 @@ impl Dispatch<WlOutput, u32> for WlClientDesktopState {
         _qhandle: &QueueHandle<Self>,
     ) {
         match event {
-            wayland_client::protocol::wl_output::Event::Mode { width, height, .. } => {
+            // the other modes are only what the output could do
+            wayland_client::protocol::wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                ..
+            } if flags.contains(wl_output::Mode::Current) => {
                 if let Some(output) = state.outputs.iter_mut().find(|o| o.id == *data) {
+                    output.changed |= output.size != (width, height);
                     output.size = (width, height);
                 }
             }
             wayland_client::protocol::wl_output::Event::Geometry {
-                model, transform, ..
+                make,
+                model,
+                transform,
+                ..
             } => {
                 if let Some(output) = state.outputs.iter_mut().find(|o| o.id == *data) {
+                    output.make = make;
                     output.model = model;
+                    output.changed |= output.transform != transform;
                     output.transform = transform;
                 }
             }
+            // xdg_output v3 no longer sends its own done event
+            wayland_client::protocol::wl_output::Event::Done => state.output_done(*data),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WlClientDesktopState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrForeignToplevelManagerV1,
+        event: <ZwlrForeignToplevelManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
+            state.toplevels.push(ToplevelState {
+                handle: toplevel,
+                app_id: String::new(),
+                title: String::new(),
+                activated: false,
+                outputs: vec![],
+                closed: false,
+                done: false,
+            });
+        }
+    }
+
+    event_created_child!(WlClientDesktopState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WlClientDesktopState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrForeignToplevelHandleV1,
+        event: <ZwlrForeignToplevelHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(toplevel) = state.toplevels.iter_mut().find(|t| &t.handle == proxy) else {
+            return;
+        };
+
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                toplevel.app_id = app_id;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
+                toplevel.title = title;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::State { state } => {
+                toplevel.activated = state
+                    .chunks_exact(4)
+                    .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                    .any(|s| s == zwlr_foreign_toplevel_handle_v1::State::Activated as u32);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output } => {
+                toplevel.outputs.push(output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputLeave { output } => {
+                toplevel.outputs.retain(|o| o != &output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Done => {
+                toplevel.done = true;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                toplevel.closed = true;
+                proxy.destroy();
+            }
             _ => {}
         }
     }
@@ -182,12 +765,69 @@ impl Dispatch<ZwlrExportDmabufManagerV1, ()> for WlClientDesktopState {
 
 impl Dispatch<WlRegistry, GlobalListContents> for WlClientDesktopState {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlRegistry,
-        _event: <WlRegistry as Proxy>::Event,
+        state: &mut Self,
+        proxy: &WlRegistry,
+        event: <WlRegistry as Proxy>::Event,
         _data: &GlobalListContents,
         _conn: &Connection,
-        _qhandle: &QueueHandle<Self>,
+        qhandle: &QueueHandle<Self>,
     ) {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == WlOutput::interface().name => {
+                state.add_output(proxy, name, version, qhandle);
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                if let Some(idx) = state.outputs.iter().position(|o| o.id == name) {
+                    let output = state.outputs.remove(idx);
+                    println!("Output {} is gone", output.name);
+                    if output.announced {
+                        let event = OutputEvent::Removed(output.name.clone());
+                        state.output_events.push(event);
+                    }
+                    if output.wl_output.version() >= 3 {
+                        output.wl_output.release();
+                    }
+                    state.update_desktop_rect();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_output_selectors() {
+        assert_eq!("primary".parse(), Ok(OutputSelector::Primary));
+        assert_eq!(
+            "position:1920,0".parse(),
+            Ok(OutputSelector::Position(1920, 0))
+        );
+        assert_eq!(
+            "position:-1280, 360".parse(),
+            Ok(OutputSelector::Position(-1280, 360))
+        );
+        assert_eq!("DP-3".parse(), Ok(OutputSelector::Name("DP-3".into())));
+    }
+
+    #[test]
+    fn rejects_bad_output_selectors() {
+        assert!("".parse::<OutputSelector>().is_err());
+        assert!("position:1920".parse::<OutputSelector>().is_err());
+        assert!("position:x,0".parse::<OutputSelector>().is_err());
+    }
+
+    #[test]
+    fn prints_output_selectors_as_parsed() {
+        for s in ["primary", "position:-1280,360", "HDMI-A-1"] {
+            assert_eq!(s.parse::<OutputSelector>().unwrap().to_string(), s);
+        }
     }
 }
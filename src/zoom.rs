@@ -0,0 +1,185 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer::{prelude::*, Element};
+
+use crate::wl_client_desktop::WlClientDesktopState;
+
+/// How quickly the view catches up with its target, per second.
+const EASE_SPEED: f64 = 6.0;
+const TICK: Duration = Duration::from_millis(16);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    fn lerp(&self, other: &Rect, t: f64) -> Rect {
+        Rect {
+            x: self.x + (other.x - self.x) * t,
+            y: self.y + (other.y - self.y) * t,
+            width: self.width + (other.width - self.width) * t,
+            height: self.height + (other.height - self.height) * t,
+        }
+    }
+
+    /// This rectangle grown to the aspect ratio of `bounds` around its centre and moved
+    /// to lie within them, so that scaling the view to `bounds` doesn't stretch it.
+    fn fit_aspect(&self, bounds: &Rect) -> Rect {
+        let aspect = bounds.width / bounds.height;
+        let width = self.width.max(self.height * aspect).min(bounds.width);
+        let height = self.height.max(self.width / aspect).min(bounds.height);
+        let x = self.x + (self.width - width) / 2.0;
+        let y = self.y + (self.height - height) / 2.0;
+        Rect {
+            x: x.clamp(bounds.x, bounds.x + bounds.width - width),
+            y: y.clamp(bounds.y, bounds.y + bounds.height - height),
+            width,
+            height,
+        }
+    }
+}
+
+/// Smoothly moves a view rectangle towards a target.
+pub struct ZoomAnimator {
+    pub current: Rect,
+    pub target: Rect,
+}
+
+impl ZoomAnimator {
+    pub fn new(initial: Rect) -> Self {
+        Self {
+            current: initial,
+            target: initial,
+        }
+    }
+
+    pub fn step(&mut self, dt: Duration) -> Rect {
+        let t = 1.0 - (-dt.as_secs_f64() * EASE_SPEED).exp();
+        self.current = self.current.lerp(&self.target, t);
+        self.current
+    }
+}
+
+/// Pan and zoom the `videocrop` element to whatever the focused window is on.
+///
+/// wlr-foreign-toplevel doesn't tell us where a window is, only which outputs
+/// it's on, so the view follows the focused window's output.
+pub fn follow_focus(crop: Element, canvas_size: (i32, i32)) {
+    let full = Rect {
+        x: 0.0,
+        y: 0.0,
+        width: canvas_size.0 as f64,
+        height: canvas_size.1 as f64,
+    };
+    let target = Arc::new(Mutex::new(full));
+
+    let target_clone = target.clone();
+    std::thread::spawn(move || {
+        // our own connection, so that blocking here doesn't stall anyone else
+        let mut desktop = match WlClientDesktopState::new() {
+            Ok(desktop) => desktop,
+            Err(e) => {
+                println!("Cannot follow focus: {e}");
+                return;
+            }
+        };
+        if desktop.maybe_toplevel_mgr.is_none() {
+            println!("Compositor does not list toplevels, cannot follow focus");
+            return;
+        }
+
+        loop {
+            if let Err(e) = desktop.dispatch() {
+                println!("Stopped following focus: {e}");
+                return;
+            }
+            let origin = desktop.desktop_origin;
+            if let Some(output) = desktop.focused_output() {
+                let output = Rect {
+                    x: (output.logical_pos.0 - origin.0) as f64,
+                    y: (output.logical_pos.1 - origin.1) as f64,
+                    width: output.logical_size.0 as f64,
+                    height: output.logical_size.1 as f64,
+                };
+                *target_clone.lock().unwrap() = output.fit_aspect(&full);
+            }
+        }
+    });
+
+    std::thread::spawn(move || {
+        let mut animator = ZoomAnimator::new(full);
+        let mut last = Instant::now();
+        let mut applied: Option<(i32, i32, i32, i32)> = None;
+
+        loop {
+            std::thread::sleep(TICK);
+            let now = Instant::now();
+            animator.target = *target.lock().unwrap();
+            let view = animator.step(now - last);
+            last = now;
+
+            let left = view.x.round() as i32;
+            let top = view.y.round() as i32;
+            let right = (full.width - view.x - view.width).round() as i32;
+            let bottom = (full.height - view.y - view.height).round() as i32;
+
+            let crop_values = (left, top, right.max(0), bottom.max(0));
+            if applied == Some(crop_values) {
+                continue;
+            }
+            applied = Some(crop_values);
+
+            crop.set_property("left", crop_values.0);
+            crop.set_property("top", crop_values.1);
+            crop.set_property("right", crop_values.2);
+            crop.set_property("bottom", crop_values.3);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCREEN: Rect = Rect {
+        x: 0.0,
+        y: 0.0,
+        width: 1920.0,
+        height: 1080.0,
+    };
+
+    fn rect(x: f64, y: f64, width: f64, height: f64) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn grows_to_the_bounds_aspect_around_the_centre() {
+        let fitted = rect(600.0, 300.0, 400.0, 400.0).fit_aspect(&SCREEN);
+        assert!((fitted.width / fitted.height - 1920.0 / 1080.0).abs() < 1e-9);
+        assert_eq!(fitted.height, 400.0);
+        assert!((fitted.x + fitted.width / 2.0 - 800.0).abs() < 1e-9);
+        assert_eq!(fitted.y, 300.0);
+    }
+
+    #[test]
+    fn stays_within_the_bounds() {
+        let fitted = rect(1800.0, 0.0, 400.0, 400.0).fit_aspect(&SCREEN);
+        assert!((fitted.x + fitted.width - SCREEN.width).abs() < 1e-9);
+        assert_eq!(fitted.y, 0.0);
+
+        let fitted = rect(-960.0, -540.0, 3840.0, 2160.0).fit_aspect(&SCREEN);
+        assert_eq!(fitted, SCREEN);
+    }
+}